@@ -13,12 +13,67 @@
 //! - Idempotency using an operation identifier (UUID v4)
 //! - LWW conflict resolution using a physical or logical timestamp
 //! - Optional Merkle hash pointers to support anti-entropy protocols
+//! - Optional per-key version vectors to tell causal succession from true
+//!   concurrency
 //!
 //! The event’s `val` carries the resulting value after the operation (for SET,
 //! INCR/DECR, APPEND/PREPEND). This choice makes idempotent application simple
 //! and makes LWW straightforward: the winner simply becomes “the value”.
+//!
+//! Collection writes (SADD/SREM/HSET/HDEL) are the exception: their `val`
+//! carries a CRDT *delta* (see `store::crdt`) which receivers merge rather than
+//! overwrite. Merges commute, so these events bypass the LWW timestamp check.
+//!
+//! ## Wire Framing and Versioning
+//!
+//! On the wire every payload starts with a one-byte header: the high nibble is
+//! the framing version (`WIRE_VERSION`) and the low nibble identifies the codec
+//! (1 = JSON, 2 = CBOR, 3 = Bincode). Decoding is therefore unambiguous rather
+//! than trial-and-error. Payloads without a recognised header (sent by nodes
+//! that predate framing) fall back to `decode_any`. Until every node decodes
+//! headers, a `WireFormat` with `legacy` set keeps sending bare codec bodies,
+//! which older nodes read with `decode_any`.
+//!
+//! Several framed events may be coalesced into a *batch envelope*, whose
+//! header carries `BATCH_WIRE_VERSION` in the high nibble and the compression
+//! scheme in the low nibble (0 = none, 1 = zstd, 2 = lz4). The (decompressed)
+//! body is a sequence of `[u32 len][framed event]` records. `decode_payload`
+//! accepts both single events and batches.
+//!
+//! The event's own `v` field versions the schema. Schema changes are additive:
+//! a node accepts any `v` from `MIN_SCHEMA_VERSION` upwards, so during a
+//! rolling upgrade old nodes keep applying events from newer ones (unknown
+//! fields are ignored by the self-describing codecs) and vice versa. Bincode
+//! is not self-describing: new fields go at the end, trailing bytes are
+//! ignored, and `from_bincode` picks the layout from the leading `v` field,
+//! so schema 1 events (which lack `vv`) still decode.
+//!
+//! ## Version Vectors
+//!
+//! Schema version 2 adds an optional `vv`: for every node that has written
+//! the key, the counter of its latest write the writer had seen. Comparing
+//! two vectors shows whether one write causally follows the other (the
+//! writer had seen it) or whether they are concurrent, which timestamps
+//! alone cannot tell under clock skew. Counters are seeded from the write
+//! timestamp so they keep increasing across restarts.
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Schema version stamped on events produced by this build.
+pub const SCHEMA_VERSION: u16 = 2;
+
+/// Oldest schema version this build can still apply.
+pub const MIN_SCHEMA_VERSION: u16 = 1;
+
+/// Framing version carried in the high nibble of the header byte.
+pub const WIRE_VERSION: u8 = 1;
+
+/// High-nibble marker of a batch envelope header byte.
+pub const BATCH_WIRE_VERSION: u8 = 2;
+
+/// Largest decompressed batch body accepted (guards against decompression bombs).
+const MAX_BATCH_BYTES: usize = 64 * 1024 * 1024;
 
 /// The operation kind carried by a change event.
 ///
@@ -41,6 +96,87 @@ pub enum OpKind {
     Append,
     /// String prepend; event value contains the resulting string as bytes
     Prepend,
+    /// Set add; event value contains the CBOR-encoded OR-Set delta
+    SAdd,
+    /// Set remove; event value contains the CBOR-encoded OR-Set delta
+    SRem,
+    /// Hash field write; event value contains the CBOR-encoded LWW-Map delta
+    HSet,
+    /// Hash field delete; event value contains the CBOR-encoded LWW-Map delta
+    HDel,
+}
+
+impl OpKind {
+    /// Whether events of this kind carry a CRDT delta to be merged (rather
+    /// than a resulting value to be overwritten under LWW).
+    pub fn is_crdt(self) -> bool {
+        matches!(self, OpKind::SAdd | OpKind::SRem | OpKind::HSet | OpKind::HDel)
+    }
+}
+
+/// Causal relation between two version vectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    /// Both describe the same history
+    Equal,
+    /// The left history is a strict prefix of the right one
+    Before,
+    /// The left history strictly contains the right one
+    After,
+    /// Neither has seen the other's latest write
+    Concurrent,
+}
+
+/// Per-key causal history: the latest write counter seen from each node.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+impl VersionVector {
+    /// Create an empty vector (no writes seen).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counter of the latest write seen from `node` (0 if none).
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+
+    /// Record a new write by `node` at timestamp `ts`.
+    ///
+    /// The counter becomes `max(current + 1, ts)`: strictly increasing, and
+    /// still ahead of writes made before a restart lost the in-memory state.
+    pub fn advance(&mut self, node: &str, ts: u64) {
+        let counter = self.get(node).saturating_add(1).max(ts);
+        self.0.insert(node.to_string(), counter);
+    }
+
+    /// Merge another history into this one (entry-wise maximum).
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (node, &counter) in &other.0 {
+            let entry = self.0.entry(node.clone()).or_insert(0);
+            *entry = (*entry).max(counter);
+        }
+    }
+
+    /// Compare two histories (missing entries count as 0).
+    pub fn compare(&self, other: &VersionVector) -> Causality {
+        let (mut ahead, mut behind) = (false, false);
+        for node in self.0.keys().chain(other.0.keys()) {
+            match self.get(node).cmp(&other.get(node)) {
+                std::cmp::Ordering::Greater => ahead = true,
+                std::cmp::Ordering::Less => behind = true,
+                std::cmp::Ordering::Equal => {}
+            }
+        }
+        match (ahead, behind) {
+            (false, false) => Causality::Equal,
+            (false, true) => Causality::Before,
+            (true, false) => Causality::After,
+            (true, true) => Causality::Concurrent,
+        }
+    }
 }
 
 /// Canonical change-event structure used to replicate writes.
@@ -56,6 +192,7 @@ pub enum OpKind {
 /// - `op_id`: A 128-bit identifier (UUID v4) for idempotency/deduplication.
 /// - `prev`: Optional 32-byte Merkle root (or leaf) hash to assist anti-entropy.
 /// - `ttl`: Optional TTL-in-seconds hint (not enforced by the in-memory engine).
+/// - `vv`: Optional per-key version vector (schema version 2).
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ChangeEvent {
     /// Schema version (allows additive, backward-compatible upgrades)
@@ -76,6 +213,30 @@ pub struct ChangeEvent {
     pub prev: Option<[u8; 32]>,
     /// Optional TTL in seconds (advisory in this prototype)
     pub ttl: Option<u64>,
+    /// Optional version vector of the key after this write
+    #[serde(default)]
+    pub vv: Option<VersionVector>,
+}
+
+/// Bincode layout of schema version 1 events, which end before `vv`.
+#[derive(Deserialize)]
+struct ChangeEventV1 {
+    v: u16,
+    op: OpKind,
+    key: String,
+    val: Option<Vec<u8>>,
+    ts: u64,
+    src: String,
+    op_id: [u8; 16],
+    prev: Option<[u8; 32]>,
+    ttl: Option<u64>,
+}
+
+impl From<ChangeEventV1> for ChangeEvent {
+    fn from(ev: ChangeEventV1) -> Self {
+        let ChangeEventV1 { v, op, key, val, ts, src, op_id, prev, ttl } = ev;
+        Self { v, op, key, val, ts, src, op_id, prev, ttl, vv: None }
+    }
 }
 
 impl ChangeEvent {
@@ -106,6 +267,7 @@ impl ChangeEvent {
             op_id,
             prev,
             ttl,
+            vv: None,
         }
     }
 
@@ -124,6 +286,12 @@ impl ChangeEvent {
         Self::new(v, op, key, val, ts, src, prev, ttl)
     }
 
+    /// Attach a version vector (see the module docs).
+    pub fn with_vv(mut self, vv: VersionVector) -> Self {
+        self.vv = Some(vv);
+        self
+    }
+
     /// Serialize the event to JSON. Human-readable and easy to debug.
     pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
         serde_json::to_vec(self)
@@ -149,9 +317,13 @@ impl ChangeEvent {
         serde_cbor::from_slice(bytes)
     }
 
-    /// Deserialize from Bincode bytes.
+    /// Deserialize from Bincode bytes, in the layout of the schema version
+    /// the leading `v` field announces.
     pub fn from_bincode(bytes: &[u8]) -> bincode::Result<Self> {
-        bincode::deserialize(bytes)
+        match bincode::deserialize::<u16>(bytes)? {
+            1 => bincode::deserialize::<ChangeEventV1>(bytes).map(Self::from),
+            _ => bincode::deserialize(bytes),
+        }
     }
 
     /// Attempt to decode using CBOR, then Bincode, then JSON.
@@ -170,25 +342,213 @@ impl ChangeEvent {
         }
         Err("Failed to decode ChangeEvent with CBOR, Bincode, or JSON".into())
     }
+
+    /// Decode a payload produced by `ChangeCodec::encode`.
+    ///
+    /// The header byte selects the codec; headerless payloads from older
+    /// nodes are decoded with `decode_any`. The schema version is checked
+    /// after decoding so events too old to interpret are rejected explicitly.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let ev = match bytes.split_first() {
+            Some((&header, body)) if header >> 4 == WIRE_VERSION => {
+                let codec = ChangeCodec::from_id(header & 0x0f)
+                    .ok_or_else(|| format!("Unknown codec id {} in ChangeEvent header", header & 0x0f))?;
+                codec.decode_body(body)?
+            }
+            _ => Self::decode_any(bytes)?,
+        };
+        ev.check_version()?;
+        Ok(ev)
+    }
+
+    /// Reject events whose schema version this build cannot apply.
+    ///
+    /// Versions newer than `SCHEMA_VERSION` are accepted: schema changes are
+    /// additive, so the fields this build knows about keep their meaning.
+    pub fn check_version(&self) -> Result<(), String> {
+        if self.v < MIN_SCHEMA_VERSION {
+            return Err(format!(
+                "Unsupported ChangeEvent schema version {} (minimum {})",
+                self.v, MIN_SCHEMA_VERSION
+            ));
+        }
+        Ok(())
+    }
 }
 
 /// Preferred encoding for on-wire messages.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ChangeCodec {
     Json,
+    #[default]
     Cbor,
     Bincode,
 }
 
 impl ChangeCodec {
-    /// Serialize according to the selected codec.
+    /// Codec identifier carried in the low nibble of the header byte.
+    fn id(self) -> u8 {
+        match self {
+            ChangeCodec::Json => 1,
+            ChangeCodec::Cbor => 2,
+            ChangeCodec::Bincode => 3,
+        }
+    }
+
+    /// Look up a codec by its header identifier.
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(ChangeCodec::Json),
+            2 => Some(ChangeCodec::Cbor),
+            3 => Some(ChangeCodec::Bincode),
+            _ => None,
+        }
+    }
+
+    /// Serialize according to the selected codec, prefixed with the header byte.
     pub fn encode(self, ev: &ChangeEvent) -> Result<Vec<u8>, String> {
+        let body = self.encode_body(ev)?;
+        let mut out = Vec::with_capacity(body.len() + 1);
+        out.push((WIRE_VERSION << 4) | self.id());
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+
+    /// Serialize according to the selected codec, without the header byte.
+    fn encode_body(self, ev: &ChangeEvent) -> Result<Vec<u8>, String> {
         match self {
             ChangeCodec::Json => ev.to_json().map_err(|e| e.to_string()),
             ChangeCodec::Cbor => ev.to_cbor().map_err(|e| e.to_string()),
             ChangeCodec::Bincode => ev.to_bincode().map_err(|e| e.to_string()),
         }
     }
+
+    /// Deserialize a body (without header) according to the selected codec.
+    fn decode_body(self, bytes: &[u8]) -> Result<ChangeEvent, String> {
+        match self {
+            ChangeCodec::Json => ChangeEvent::from_json(bytes).map_err(|e| e.to_string()),
+            ChangeCodec::Cbor => ChangeEvent::from_cbor(bytes).map_err(|e| e.to_string()),
+            ChangeCodec::Bincode => ChangeEvent::from_bincode(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Compression applied to batch envelopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    /// Compression identifier carried in the low nibble of the batch header.
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    /// Look up a compression scheme by its header identifier.
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    fn compress(self, body: Vec<u8>) -> Result<Vec<u8>, String> {
+        match self {
+            Compression::None => Ok(body),
+            Compression::Zstd => zstd::bulk::compress(&body, 0).map_err(|e| e.to_string()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(&body)),
+        }
+    }
+
+    fn decompress(self, body: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Compression::None => Ok(body.to_vec()),
+            Compression::Zstd => zstd::bulk::decompress(body, MAX_BATCH_BYTES).map_err(|e| e.to_string()),
+            Compression::Lz4 => {
+                let size = body
+                    .get(..4)
+                    .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+                    .ok_or("Truncated lz4 batch body")?;
+                if size > MAX_BATCH_BYTES {
+                    return Err(format!("Batch of {} bytes exceeds limit", size));
+                }
+                lz4_flex::decompress_size_prepended(body).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+/// How outgoing events are put on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WireFormat {
+    /// Codec of the event body
+    pub codec: ChangeCodec,
+    /// Send bare bodies without the header byte, one event per payload, so
+    /// nodes from before framing can decode them (`legacy_wire_format`)
+    pub legacy: bool,
+}
+
+impl WireFormat {
+    /// Serialize one event as a transport payload.
+    pub fn encode(self, ev: &ChangeEvent) -> Result<Vec<u8>, String> {
+        if self.legacy {
+            self.codec.encode_body(ev)
+        } else {
+            self.codec.encode(ev)
+        }
+    }
+}
+
+/// Wrap already-framed event payloads (from `ChangeCodec::encode`) into a
+/// single, optionally compressed, batch envelope.
+pub fn encode_batch(events: &[Vec<u8>], compression: Compression) -> Result<Vec<u8>, String> {
+    let mut body = Vec::with_capacity(events.iter().map(|e| e.len() + 4).sum());
+    for ev in events {
+        let len = u32::try_from(ev.len()).map_err(|_| "Event too large for batch")?;
+        body.extend_from_slice(&len.to_be_bytes());
+        body.extend_from_slice(ev);
+    }
+    let body = compression.compress(body)?;
+    let mut out = Vec::with_capacity(body.len() + 1);
+    out.push((BATCH_WIRE_VERSION << 4) | compression.id());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+/// Decode a transport payload holding either one event or a batch envelope.
+pub fn decode_payload(bytes: &[u8]) -> Result<Vec<ChangeEvent>, String> {
+    let header = match bytes.first() {
+        Some(&h) if h >> 4 == BATCH_WIRE_VERSION => h,
+        _ => return ChangeEvent::decode(bytes).map(|ev| vec![ev]),
+    };
+    let compression = Compression::from_id(header & 0x0f)
+        .ok_or_else(|| format!("Unknown compression id {} in batch header", header & 0x0f))?;
+    let body = compression.decompress(&bytes[1..])?;
+
+    let mut events = Vec::new();
+    let mut rest = body.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err("Truncated batch record".into());
+        }
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let record = rest.get(4..4 + len).ok_or("Truncated batch record")?;
+        events.push(ChangeEvent::decode(record)?);
+        rest = &rest[4 + len..];
+    }
+    Ok(events)
 }
 
 #[cfg(test)]
@@ -421,5 +781,156 @@ fn ttl_is_advisory_only() {
     a.apply(&ev);
     assert_eq!(a.store.get("ttl").cloned(), Some("x".into()));
 }
+#[test]
+fn crdt_delta_event_roundtrip() {
+    use crate::store::crdt::{CrdtValue, OrSet};
+    let mut set = OrSet::new();
+    let delta = CrdtValue::Set(set.add("member", [3; 16]));
+    let ev = ChangeEvent::new(1, OpKind::SAdd, "s", Some(delta.canonical_bytes()), 7, "nodeA", None, None);
+    assert!(ev.op.is_crdt());
+    assert!(!OpKind::Set.is_crdt());
+    let de = ChangeEvent::from_cbor(&ev.to_cbor().unwrap()).unwrap();
+    assert_eq!(de.op, OpKind::SAdd);
+    assert_eq!(CrdtValue::from_bytes(de.val.as_deref().unwrap()).unwrap(), delta);
+    assert!(String::from_utf8(ev.to_json().unwrap()).unwrap().contains("\"sadd\""));
+}
+
+#[test]
+fn framed_payload_selects_codec_from_header() {
+    let ev = sample_event(OpKind::Set, "k", Some("v"), 42);
+    for codec in [ChangeCodec::Json, ChangeCodec::Cbor, ChangeCodec::Bincode] {
+        let bytes = codec.encode(&ev).unwrap();
+        assert_eq!(bytes[0] >> 4, WIRE_VERSION);
+        assert_eq!(ChangeEvent::decode(&bytes).unwrap(), ev);
+    }
+}
+#[test]
+fn legacy_wire_format_is_read_by_trial_decoding() {
+    let ev = sample_event(OpKind::Set, "k", Some("v"), 42);
+    for codec in [ChangeCodec::Json, ChangeCodec::Cbor, ChangeCodec::Bincode] {
+        let bytes = WireFormat { codec, legacy: true }.encode(&ev).unwrap();
+        // How nodes from before framing decode every payload
+        assert_eq!(ChangeEvent::decode_any(&bytes).unwrap(), ev, "{:?}", codec);
+        assert_eq!(decode_payload(&bytes).unwrap(), vec![ev.clone()]);
+        assert_eq!(WireFormat { codec, legacy: false }.encode(&ev).unwrap()[1..], bytes[..]);
+    }
+}
+#[test]
+fn headerless_legacy_payload_still_decodes() {
+    let ev = sample_event(OpKind::Set, "k", Some("v"), 42);
+    assert_eq!(ChangeEvent::decode(&ev.to_cbor().unwrap()).unwrap(), ev);
+    assert_eq!(ChangeEvent::decode(&ev.to_json().unwrap()).unwrap(), ev);
+    assert_eq!(ChangeEvent::decode(&ev.to_bincode().unwrap()).unwrap(), ev);
+}
+#[test]
+fn schema_1_bincode_events_decode() {
+    // Bytes as a schema 1 node encodes them: the struct ends after `ttl`
+    #[derive(Serialize)]
+    struct V1<'a>(u16, OpKind, &'a str, Option<Vec<u8>>, u64, &'a str, [u8; 16], Option<[u8; 32]>, Option<u64>);
+    let expected = sample_event(OpKind::Set, "k", Some("v"), 42);
+    let v1 = V1(1, OpKind::Set, "k", Some(b"v".to_vec()), 42, "nodeA", expected.op_id, None, Some(60));
+    let bytes = bincode::serialize(&v1).unwrap();
+    assert_eq!(ChangeEvent::from_bincode(&bytes).unwrap(), ChangeEvent { ttl: Some(60), ..expected.clone() });
+
+    let mut framed = vec![(WIRE_VERSION << 4) | ChangeCodec::Bincode.id()];
+    framed.extend(&bytes);
+    assert_eq!(ChangeEvent::decode(&framed).unwrap().key, "k");
+
+    // Schema 2 events carry their version vector
+    let mut vv = VersionVector::new();
+    vv.advance("nodeA", 42);
+    let v2 = ChangeEvent { v: SCHEMA_VERSION, ..expected }.with_vv(vv);
+    assert_eq!(ChangeEvent::from_bincode(&v2.to_bincode().unwrap()).unwrap(), v2);
+}
+#[test]
+fn unknown_codec_id_rejected() {
+    let ev = sample_event(OpKind::Set, "k", Some("v"), 42);
+    let mut bytes = ChangeCodec::Cbor.encode(&ev).unwrap();
+    bytes[0] = (WIRE_VERSION << 4) | 0x0f;
+    assert!(ChangeEvent::decode(&bytes).unwrap_err().contains("codec id"));
+}
+#[test]
+fn schema_version_handling() {
+    // Too old: rejected explicitly
+    let mut old = sample_event(OpKind::Set, "k", Some("v"), 1);
+    old.v = 0;
+    let bytes = ChangeCodec::Cbor.encode(&old).unwrap();
+    assert!(ChangeEvent::decode(&bytes).unwrap_err().contains("schema version"));
+
+    // Newer with an additive field: known fields still apply
+    let mut newer = sample_event(OpKind::Set, "k", Some("v"), 1);
+    newer.v = SCHEMA_VERSION + 1;
+    let mut json: serde_json::Value = serde_json::from_slice(&newer.to_json().unwrap()).unwrap();
+    json["future_field"] = serde_json::json!("ignored");
+    let mut bytes = vec![(WIRE_VERSION << 4) | ChangeCodec::Json.id()];
+    bytes.extend(serde_json::to_vec(&json).unwrap());
+    assert_eq!(ChangeEvent::decode(&bytes).unwrap(), newer);
+}
+
+#[test]
+fn batch_envelope_roundtrip_with_each_compression() {
+    let events: Vec<ChangeEvent> = (0..50)
+        .map(|i| sample_event(OpKind::Set, &format!("key:{}", i), Some("value"), i))
+        .collect();
+    let framed: Vec<Vec<u8>> = events.iter().map(|e| ChangeCodec::Cbor.encode(e).unwrap()).collect();
+    let raw_len: usize = framed.iter().map(|f| f.len()).sum();
+    for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+        let batch = encode_batch(&framed, compression).unwrap();
+        assert_eq!(batch[0] >> 4, BATCH_WIRE_VERSION);
+        if compression != Compression::None {
+            assert!(batch.len() < raw_len, "{:?} did not shrink the batch", compression);
+        }
+        assert_eq!(decode_payload(&batch).unwrap(), events);
+    }
+}
+#[test]
+fn decode_payload_accepts_single_event_and_rejects_truncated_batch() {
+    let ev = sample_event(OpKind::Set, "k", Some("v"), 1);
+    let single = ChangeCodec::Json.encode(&ev).unwrap();
+    assert_eq!(decode_payload(&single).unwrap(), vec![ev]);
+
+    let mut batch = encode_batch(&[single], Compression::None).unwrap();
+    batch.truncate(batch.len() - 3);
+    assert!(decode_payload(&batch).unwrap_err().contains("Truncated"));
+}
+
+#[test]
+fn version_vector_causality() {
+    let mut a = VersionVector::new();
+    a.advance("n1", 10);
+    let mut b = a.clone();
+    b.advance("n2", 5);
+    assert_eq!(a.compare(&b), Causality::Before);
+    assert_eq!(b.compare(&a), Causality::After);
+    assert_eq!(b.compare(&b.clone()), Causality::Equal);
+
+    let mut c = a.clone();
+    c.advance("n1", 1); // counters stay monotonic despite an older timestamp
+    assert_eq!(c.get("n1"), 11);
+    assert_eq!(b.compare(&c), Causality::Concurrent);
+
+    b.merge(&c);
+    assert_eq!(b.compare(&c), Causality::After);
+    assert_eq!(b.get("n1"), 11);
+    assert_eq!(b.get("n2"), 5);
+}
+#[test]
+fn version_vector_roundtrip_and_legacy_events() {
+    let mut vv = VersionVector::new();
+    vv.advance("nodeA", 7);
+    let ev = ChangeEvent { v: SCHEMA_VERSION, ..sample_event(OpKind::Set, "k", Some("v"), 7) }.with_vv(vv);
+    for codec in [ChangeCodec::Json, ChangeCodec::Cbor, ChangeCodec::Bincode] {
+        assert_eq!(ChangeEvent::decode(&codec.encode(&ev).unwrap()).unwrap(), ev);
+    }
+    assert!(String::from_utf8(ev.to_json().unwrap()).unwrap().contains("\"vv\":{\"nodeA\":7}"));
+
+    // Events from schema version 1 nodes have no vector
+    let mut json: serde_json::Value = serde_json::from_slice(&ev.to_json().unwrap()).unwrap();
+    json.as_object_mut().unwrap().remove("vv");
+    json["v"] = 1.into();
+    let legacy = ChangeEvent::from_json(&serde_json::to_vec(&json).unwrap()).unwrap();
+    assert_eq!(legacy.vv, None);
+    assert!(legacy.check_version().is_ok());
+}
 
 }
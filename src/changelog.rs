@@ -0,0 +1,407 @@
+//! # Change Log
+//!
+//! A bounded, persisted log of the `ChangeEvent`s this node has produced or
+//! applied from peers. Every entry gets a monotonically increasing sequence
+//! number, so a consumer that disconnects can resume with `REPLAY <seq>`
+//! from the last sequence number it processed, and a replica that was briefly
+//! offline can re-apply what it missed (events are idempotent by `op_id`).
+//!
+//! ## Storage Layout
+//!
+//! Entries live in a dedicated sled database, keyed by big-endian `u64`
+//! sequence number, with values framed by `ChangeCodec::encode` (CBOR). An
+//! empty path selects a temporary database (useful for tests).
+//!
+//! ## Retention
+//!
+//! The log keeps the newest `max_events` entries; appending beyond that
+//! drops the oldest. Sequence numbers are never reused, so a gap between the
+//! requested and the first available sequence number means entries were
+//! trimmed.
+//!
+//! A sequence number is assigned and its entry written under one lock, so
+//! entries become visible in sequence order and a REPLAY reader never sees
+//! a later entry before an earlier one.
+//!
+//! ## Indexes
+//!
+//! More trees, written in the same transaction as the entry, serve repairs
+//! and returning replicas (see `repair`) without scanning the log:
+//! - `change_log_by_key`: `(key, seq)` of every retained entry, so the
+//!   entries of one key or key prefix can be read back
+//! - `change_log_by_ts`: `(ts, seq)` of every retained entry, so entries can
+//!   be read back from a write timestamp on
+//! - `change_log_sources`: the newest write timestamp logged per writer,
+//!   i.e. how far this node has caught up with each node
+//!
+//! Logs written before an index existed are indexed when opened.
+
+use crate::change_event::{ChangeCodec, ChangeEvent};
+use anyhow::{anyhow, Result};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Durable, bounded log of change events with sequence numbers.
+pub struct ChangeLog {
+    /// Database owning the log tree (kept so it is flushed on drop)
+    db: sled::Db,
+
+    /// Sled tree holding `seq -> framed event` entries
+    tree: sled::Tree,
+
+    /// `(key, seq) -> ()` for every entry in `tree`
+    by_key: sled::Tree,
+
+    /// `(ts, seq) -> ()` for every entry in `tree`
+    by_ts: sled::Tree,
+
+    /// `writer -> newest logged ts` of every writer ever logged
+    sources: sled::Tree,
+
+    /// Sequence number assigned to the next appended event, held while it
+    /// is written
+    next_seq: Mutex<u64>,
+
+    /// Number of newest entries retained
+    max_events: u64,
+}
+
+impl ChangeLog {
+    /// Open (or create) the change log stored at `path`.
+    ///
+    /// # Arguments
+    /// * `path` - Directory for the sled database; empty for a temporary log
+    /// * `max_events` - Number of newest entries to retain
+    ///
+    /// # Returns
+    /// * `Result<ChangeLog>` - Log resuming sequence numbers from a previous run
+    pub fn open(path: &str, max_events: u64) -> Result<Self> {
+        let db = if path.is_empty() {
+            sled::Config::new().temporary(true).open()?
+        } else {
+            sled::open(path)?
+        };
+        let tree = db.open_tree("change_log")?;
+        let by_key = db.open_tree("change_log_by_key")?;
+        let by_ts = db.open_tree("change_log_by_ts")?;
+        let sources = db.open_tree("change_log_sources")?;
+        let next_seq = match tree.last()? {
+            Some((key, _)) => Self::decode_seq(&key)? + 1,
+            None => 0,
+        };
+        let log = Self { db, tree, by_key, by_ts, sources, next_seq: Mutex::new(next_seq), max_events };
+        if (log.by_key.is_empty() || log.by_ts.is_empty()) && !log.tree.is_empty() {
+            log.build_index()?;
+        }
+        // Apply a retention limit lowered since the previous run
+        log.trim_before(next_seq.saturating_sub(max_events))?;
+        Ok(log)
+    }
+
+    /// Append an event, dropping the oldest entry once the log is full.
+    ///
+    /// # Returns
+    /// * `Result<u64>` - Sequence number assigned to the event
+    pub fn append(&self, ev: &ChangeEvent) -> Result<u64> {
+        let bytes = ChangeCodec::Cbor.encode(ev).map_err(|e| anyhow!(e))?;
+        let mut next_seq = self.next_seq.lock().unwrap_or_else(|e| e.into_inner());
+        let seq = *next_seq;
+        let trimmed = seq.checked_sub(self.max_events);
+        (&self.tree, &self.by_key, &self.by_ts, &self.sources)
+            .transaction(|(tree, by_key, by_ts, sources)| {
+                tree.insert(&seq.to_be_bytes(), bytes.as_slice())?;
+                by_key.insert(Self::key_key(&ev.key, seq), &[])?;
+                by_ts.insert(&Self::ts_key(ev.ts, seq), &[])?;
+                let newest = sources.get(ev.src.as_bytes())?.map(|ts| Self::decode_seq(&ts));
+                if !matches!(newest, Some(Ok(ts)) if ts >= ev.ts) {
+                    sources.insert(ev.src.as_bytes(), &ev.ts.to_be_bytes())?;
+                }
+                if let Some(old_seq) = trimmed {
+                    if let Some(old) = tree.remove(&old_seq.to_be_bytes())? {
+                        let old = ChangeEvent::decode(&old)
+                            .map_err(|e| ConflictableTransactionError::Abort(format!("corrupt change log entry: {}", e)))?;
+                        by_key.remove(Self::key_key(&old.key, old_seq))?;
+                        by_ts.remove(&Self::ts_key(old.ts, old_seq))?;
+                    }
+                }
+                Ok(())
+            })
+            .map_err(|e: TransactionError<String>| anyhow!("failed to append to change log: {}", e))?;
+        *next_seq = seq + 1;
+        Ok(seq)
+    }
+
+    /// Return up to `limit` entries with sequence numbers `>= from_seq`.
+    pub fn range(&self, from_seq: u64, limit: usize) -> Result<Vec<(u64, ChangeEvent)>> {
+        let mut entries = Vec::new();
+        for item in self.tree.range(from_seq.to_be_bytes()..).take(limit) {
+            let (key, value) = item?;
+            let ev = ChangeEvent::decode(&value).map_err(|e| anyhow!("corrupt change log entry: {}", e))?;
+            entries.push((Self::decode_seq(&key)?, ev));
+        }
+        Ok(entries)
+    }
+
+    /// Return up to `limit` entries written at or after `ts`, oldest write
+    /// first; of the entries written at `ts`, those numbered below `seq` are
+    /// skipped (so a caller can resume after the last entry it read).
+    pub fn since(&self, ts: u64, seq: u64, limit: usize) -> Result<Vec<(u64, ChangeEvent)>> {
+        let mut entries = Vec::new();
+        for item in self.by_ts.range(Self::ts_key(ts, seq)..) {
+            if entries.len() == limit {
+                break;
+            }
+            let (key, _) = item?;
+            if let Some(entry) = self.entry(Self::decode_seq(&key[8..])?)? {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Return the entries of `key` (of every key starting with `key` unless
+    /// `exact`), ordered by key, then sequence number.
+    pub fn for_keys(&self, key: &str, exact: bool) -> Result<Vec<(u64, ChangeEvent)>> {
+        let index = if exact {
+            self.by_key.range(Self::key_key(key, 0)..=Self::key_key(key, u64::MAX))
+        } else {
+            self.by_key.scan_prefix(key.as_bytes())
+        };
+        let mut entries = Vec::new();
+        for item in index {
+            let (index_key, _) = item?;
+            // A longer key can sort between the bounds of an exact one
+            if index_key.len() < 8 || (exact && index_key.len() != key.len() + 8) {
+                continue;
+            }
+            if let Some(entry) = self.entry(Self::decode_seq(&index_key[index_key.len() - 8..])?)? {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The entry at `seq`, if still retained (an index may point at an entry
+    /// trimmed since it was read).
+    fn entry(&self, seq: u64) -> Result<Option<(u64, ChangeEvent)>> {
+        let Some(value) = self.tree.get(seq.to_be_bytes())? else { return Ok(None) };
+        let ev = ChangeEvent::decode(&value).map_err(|e| anyhow!("corrupt change log entry: {}", e))?;
+        Ok(Some((seq, ev)))
+    }
+
+    /// Newest logged write timestamp of every writer.
+    pub fn sources(&self) -> Result<BTreeMap<String, u64>> {
+        let mut sources = BTreeMap::new();
+        for item in self.sources.iter() {
+            let (src, ts) = item?;
+            sources.insert(String::from_utf8_lossy(&src).into_owned(), Self::decode_seq(&ts)?);
+        }
+        Ok(sources)
+    }
+
+    /// Oldest retained sequence number, or `None` if the log is empty.
+    pub fn first_seq(&self) -> Result<Option<u64>> {
+        self.tree.first()?.map(|(key, _)| Self::decode_seq(&key)).transpose()
+    }
+
+    /// Sequence number the next appended event will get.
+    pub fn next_seq(&self) -> u64 {
+        *self.next_seq.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn trim_before(&self, seq: u64) -> Result<()> {
+        let (mut batch, mut keys, mut times) = (sled::Batch::default(), sled::Batch::default(), sled::Batch::default());
+        for item in self.tree.range(..seq.to_be_bytes()) {
+            let (key, value) = item?;
+            let ev = ChangeEvent::decode(&value).map_err(|e| anyhow!("corrupt change log entry: {}", e))?;
+            let seq = Self::decode_seq(&key)?;
+            keys.remove(Self::key_key(&ev.key, seq));
+            times.remove(&Self::ts_key(ev.ts, seq));
+            batch.remove(key);
+        }
+        self.by_key.apply_batch(keys)?;
+        self.by_ts.apply_batch(times)?;
+        self.tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// Index the entries of a log written without `by_key`, `by_ts` or
+    /// `sources`.
+    fn build_index(&self) -> Result<()> {
+        let mut newest: BTreeMap<String, u64> = BTreeMap::new();
+        for item in self.tree.iter() {
+            let (key, value) = item?;
+            let ev = ChangeEvent::decode(&value).map_err(|e| anyhow!("corrupt change log entry: {}", e))?;
+            let seq = Self::decode_seq(&key)?;
+            self.by_key.insert(Self::key_key(&ev.key, seq), &[])?;
+            self.by_ts.insert(Self::ts_key(ev.ts, seq), &[])?;
+            let ts = newest.entry(ev.src).or_default();
+            *ts = (*ts).max(ev.ts);
+        }
+        for (src, ts) in newest {
+            self.sources.insert(src.as_bytes(), &ts.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// `by_key` key: the key's bytes, then the big-endian sequence number.
+    fn key_key(key: &str, seq: u64) -> Vec<u8> {
+        let mut index_key = Vec::with_capacity(key.len() + 8);
+        index_key.extend_from_slice(key.as_bytes());
+        index_key.extend_from_slice(&seq.to_be_bytes());
+        index_key
+    }
+
+    /// `by_ts` key: big-endian timestamp, then sequence number.
+    fn ts_key(ts: u64, seq: u64) -> [u8; 16] {
+        let mut key = [0; 16];
+        key[..8].copy_from_slice(&ts.to_be_bytes());
+        key[8..].copy_from_slice(&seq.to_be_bytes());
+        key
+    }
+
+    fn decode_seq(key: &[u8]) -> Result<u64> {
+        let bytes: [u8; 8] = key.try_into().map_err(|_| anyhow!("corrupt change log key"))?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+impl Drop for ChangeLog {
+    fn drop(&mut self) {
+        if let Err(e) = self.db.flush() {
+            log::error!("Failed to flush change log on drop: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::change_event::OpKind;
+    use tempfile::TempDir;
+
+    fn event(key: &str) -> ChangeEvent {
+        ChangeEvent::new(1, OpKind::Set, key, Some(b"v".to_vec()), 1, "node1", None, None)
+    }
+
+    fn written(key: &str, ts: u64, src: &str) -> ChangeEvent {
+        ChangeEvent::new(1, OpKind::Set, key, Some(b"v".to_vec()), ts, src, None, None)
+    }
+
+    #[test]
+    fn append_range_and_trim() {
+        let log = ChangeLog::open("", 3).unwrap();
+        for i in 0..5 {
+            assert_eq!(log.append(&event(&format!("k{}", i))).unwrap(), i);
+        }
+        assert_eq!(log.first_seq().unwrap(), Some(2));
+        assert_eq!(log.next_seq(), 5);
+
+        let keys: Vec<(u64, String)> = log.range(0, 10).unwrap().into_iter().map(|(s, ev)| (s, ev.key)).collect();
+        assert_eq!(keys, vec![(2, "k2".into()), (3, "k3".into()), (4, "k4".into())]);
+        assert_eq!(log.range(4, 10).unwrap().len(), 1);
+        assert_eq!(log.range(2, 1).unwrap()[0].0, 2);
+        assert!(log.range(5, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sequence_numbers_survive_reopen() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("changelog");
+        let path = path.to_str().unwrap();
+        {
+            let log = ChangeLog::open(path, 10).unwrap();
+            for i in 0..4 {
+                log.append(&event(&format!("k{}", i))).unwrap();
+            }
+        }
+        // Reopening with a smaller limit trims down to the newest entries
+        let log = ChangeLog::open(path, 2).unwrap();
+        assert_eq!(log.first_seq().unwrap(), Some(2));
+        assert_eq!(log.append(&event("k4")).unwrap(), 4);
+        assert_eq!(log.first_seq().unwrap(), Some(3));
+    }
+
+    #[test]
+    fn readers_never_see_a_later_entry_first() {
+        let log = std::sync::Arc::new(ChangeLog::open("", 100_000).unwrap());
+        let writers: Vec<_> = (0..4)
+            .map(|w| {
+                let log = log.clone();
+                std::thread::spawn(move || {
+                    for i in 0..250 {
+                        log.append(&event(&format!("k{}:{}", w, i))).unwrap();
+                    }
+                })
+            })
+            .collect();
+        // A consumer resuming after the last entry it saw must not skip any
+        let mut next = 0;
+        while next < 1000 {
+            for (seq, _) in log.range(next, 1000).unwrap() {
+                assert_eq!(seq, next);
+                next += 1;
+            }
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_back_by_write_time_and_tracks_writers() {
+        let log = ChangeLog::open("", 3).unwrap();
+        for (key, ts, src) in [("a", 30, "n1"), ("b", 10, "n2"), ("c", 20, "n1"), ("d", 40, "n2")] {
+            log.append(&written(key, ts, src)).unwrap();
+        }
+        // "a" was trimmed, along with its index entry
+        let keys: Vec<String> = log.since(0, 0, 10).unwrap().into_iter().map(|(_, ev)| ev.key).collect();
+        assert_eq!(keys, vec!["b".to_string(), "c".to_string(), "d".to_string()]);
+        assert_eq!(log.since(15, 0, 1).unwrap()[0].1.key, "c");
+        // Resuming after "c" (ts 20, seq 2)
+        assert_eq!(log.since(20, 3, 10).unwrap()[0].1.key, "d");
+        assert!(log.since(41, 0, 10).unwrap().is_empty());
+        // Trimming does not forget how far each writer got
+        let sources = log.sources().unwrap();
+        assert_eq!(sources.get("n1"), Some(&30));
+        assert_eq!(sources.get("n2"), Some(&40));
+    }
+
+    #[test]
+    fn logs_without_an_index_are_indexed() {
+        let log = ChangeLog::open("", 10).unwrap();
+        log.append(&written("a", 5, "n1")).unwrap();
+        log.append(&written("b", 7, "n2")).unwrap();
+        log.by_key.clear().unwrap();
+        log.by_ts.clear().unwrap();
+        log.sources.clear().unwrap();
+        assert!(log.since(0, 0, 10).unwrap().is_empty());
+
+        // What `open` does for a log written before the indexes existed
+        log.build_index().unwrap();
+        assert_eq!(log.since(6, 0, 10).unwrap()[0].1.key, "b");
+        assert_eq!(log.for_keys("a", true).unwrap().len(), 1);
+        assert_eq!(log.sources().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reads_back_by_key_or_prefix() {
+        let log = ChangeLog::open("", 5).unwrap();
+        for key in ["foo", "user:1", "foobar", "foo", "user:2", "fooABCDEFGH", "user:1"] {
+            log.append(&event(key)).unwrap();
+        }
+        let found = |key: &str, exact: bool| -> Vec<(u64, String)> {
+            log.for_keys(key, exact).unwrap().into_iter().map(|(seq, ev)| (seq, ev.key)).collect()
+        };
+        // The first two entries were trimmed along with their index entries
+        assert_eq!(found("foo", true), vec![(3, "foo".to_string())]);
+        assert_eq!(
+            found("foo", false),
+            vec![(3, "foo".to_string()), (5, "fooABCDEFGH".to_string()), (2, "foobar".to_string())]
+        );
+        assert_eq!(found("user:", false), vec![(6, "user:1".to_string()), (4, "user:2".to_string())]);
+        assert!(found("user:", true).is_empty());
+    }
+}
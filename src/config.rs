@@ -6,20 +6,30 @@
 //! - Storage engine selection and configuration
 //! - MQTT replication settings
 //! - Synchronization intervals
+//! - Client connection limits and timeouts
 //!
 //! ## Example Configuration File (config.toml)
 //! ```toml
 //! host = "127.0.0.1"
 //! port = 7379
 //! sync_interval_seconds = 60
+//! shutdown_timeout_seconds = 10  # drain deadline on SIGTERM/SIGINT/SHUTDOWN
 //!
 //! [storage]
-//! engine = "sled"  # "memory", "rwlock", or "sled"
+//! engine = "sled"  # "memory", "rwlock", "sharded", or "sled"
 //! path = "./data/merkle_kv.db"
 //! compression = true
 //! cache_size_mb = 100
 //! flush_interval_ms = 1000
 //! max_db_size_mb = 1024
+//! shards = 16       # sharded engine only
+//!
+//! [limits]
+//! max_connections = 1024      # further connections are refused (0 = unlimited)
+//! rate_limit_per_sec = 0      # commands per second per client IP (0 = unlimited)
+//! rate_limit_burst = 0        # bucket size (0 = one second's worth)
+//! read_timeout_seconds = 30   # to finish a command once it has started
+//! idle_timeout_seconds = 0    # close connections idle this long (0 = never)
 //!
 //! [replication]
 //! enabled = true
@@ -27,20 +37,52 @@
 //! mqtt_port = 1883
 //! topic_prefix = "merkle_kv"
 //! client_id = "node1"
+//! transport = "mqtt"  # "mqtt" or "tcp"
+//! # mqtt_username = "replicator"           # broker authentication
+//! # mqtt_password = "secret"
+//! # mqtt_tls = true                        # TLS, usually with mqtt_port = 8883
+//! # mqtt_ca_file = "certs/ca.pem"
+//! # mqtt_client_cert_file = "certs/node1.pem"  # mutual TLS
+//! # mqtt_client_key_file = "certs/node1.key"
+//! # tcp_listen = "0.0.0.0:7380"            # tcp transport only
+//! # tcp_peers = ["10.0.0.2:7380"]          # tcp transport only
+//! codec = "cbor"      # "cbor", "json" or "bincode"
+//! legacy_wire_format = false # true while nodes from before framing remain
+//! compression = "none" # "none", "zstd" or "lz4"
+//! batch_max_events = 1 # >1 coalesces writes into batch envelopes
+//! queue_path = "./data/replication_queue"  # durable outbound queue
+//! queue_max_events = 100000                # writes refused beyond this
+//! changelog_path = "./data/change_log"      # persisted log served by REPLAY
+//! changelog_max_events = 100000             # newest entries retained
+//! # include_keys = ["user:*"]              # replicate only matching keys
+//! # exclude_keys = ["*:tmp"]               # never replicate these
+//! # namespace_topics = true                # publish to events/{namespace}
+//! # namespaces = ["user"]                  # subscribe only to these
+//! conflict_policy = "lww" # "lww", "first_writer", "highest_value" or "keep_siblings"
+//! # conflict_policies = { "user:" = "first_writer" }  # per key prefix
+//! version_vectors = false                  # attach causal metadata to events
+//! repair_on_mismatch = true                # repair a key range when `prev` differs
+//! repair_cooldown_ms = 5000                # per peer and key range
+//! catch_up_on_start = true                 # replay peers' logs for missed writes
 //! ```
 
 use anyhow::Result;
 use config::{Config as ConfigLib, File};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::Path;
 
+use crate::change_event::{ChangeCodec, Compression};
+
 /// Storage engine types supported by MerkleKV.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum StorageEngine {
-    /// In-memory storage using Arc<HashMap> (non-thread-safe)
+    /// In-memory storage with copy-on-write Arc<HashMap> snapshots
     Memory,
     /// Thread-safe in-memory storage using RwLock<HashMap>
     RwLock,
+    /// Thread-safe in-memory storage striped over `shards` RwLock<HashMap>s
+    Sharded,
     /// Persistent disk-based storage using Sled
     Sled,
 }
@@ -52,6 +94,7 @@ impl std::str::FromStr for StorageEngine {
         match s.to_lowercase().as_str() {
             "memory" | "kv" => Ok(StorageEngine::Memory),
             "rwlock" => Ok(StorageEngine::RwLock),
+            "sharded" => Ok(StorageEngine::Sharded),
             "sled" => Ok(StorageEngine::Sled),
             _ => Err(format!("Unknown storage engine: {}", s)),
         }
@@ -63,6 +106,7 @@ impl std::fmt::Display for StorageEngine {
         match self {
             StorageEngine::Memory => write!(f, "memory"),
             StorageEngine::RwLock => write!(f, "rwlock"),
+            StorageEngine::Sharded => write!(f, "sharded"),
             StorageEngine::Sled => write!(f, "sled"),
         }
     }
@@ -83,6 +127,9 @@ pub struct StorageConfig {
     pub flush_interval_ms: u64,
     /// Maximum database size in MB (for Sled engine)
     pub max_db_size_mb: usize,
+    /// Number of lock-striped shards (for Sharded engine)
+    #[serde(default = "default_shards")]
+    pub shards: usize,
 }
 
 impl Default for StorageConfig {
@@ -94,10 +141,89 @@ impl Default for StorageConfig {
             cache_size_mb: 100,
             flush_interval_ms: 1000,
             max_db_size_mb: 1024,
+            shards: default_shards(),
+        }
+    }
+}
+
+/// Limits protecting the server from misbehaving clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Maximum concurrent client connections; further ones are refused (0 = unlimited)
+    pub max_connections: usize,
+    /// Commands per second allowed per client IP, across its connections (0 = unlimited)
+    pub rate_limit_per_sec: u32,
+    /// Commands a client IP may send in a burst (0 = `rate_limit_per_sec`)
+    pub rate_limit_burst: u32,
+    /// Seconds a client may take to send the rest of a command once its
+    /// first bytes arrived, up to the line ending (0 = no limit)
+    pub read_timeout_seconds: u64,
+    /// Seconds a connection may wait between commands before it is closed;
+    /// WATCH and SUBSCRIBE streams are exempt (0 = no limit)
+    pub idle_timeout_seconds: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            rate_limit_per_sec: 0,
+            rate_limit_burst: 0,
+            read_timeout_seconds: 30,
+            idle_timeout_seconds: 0,
+        }
+    }
+}
+
+/// Optional HTTP/JSON front end (see `http`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Serve the HTTP API alongside the TCP protocol
+    pub enabled: bool,
+    /// Address the HTTP listener binds to
+    pub listen: String,
+    /// Largest request body accepted, in bytes; larger requests get 413
+    pub max_body_bytes: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:7380".to_string(),
+            max_body_bytes: 1024 * 1024,
         }
     }
 }
 
+/// Transports that can carry replication traffic between nodes.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicationTransportKind {
+    /// Publish through an MQTT broker
+    #[default]
+    Mqtt,
+    /// Push directly to a static list of peers over TCP
+    Tcp,
+}
+
+/// How concurrent replicated writes to the same key are resolved (see `conflict`).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Last writer wins
+    #[default]
+    Lww,
+    /// First writer wins (write-once keys)
+    FirstWriter,
+    /// Highest value wins
+    HighestValue,
+    /// Keep concurrent values as siblings
+    KeepSiblings,
+}
+
 /// Main configuration structure for the MerkleKV server.
 ///
 /// Contains all settings needed to run a node, including network configuration,
@@ -119,6 +245,19 @@ pub struct Config {
     /// How often (in seconds) to run anti-entropy synchronization with peers
     /// TODO: Implement the actual synchronization logic
     pub sync_interval_seconds: u64,
+
+    /// How long a shutdown waits for in-flight commands, and then for the
+    /// replication queue to drain, before giving up
+    #[serde(default = "default_shutdown_timeout_seconds")]
+    pub shutdown_timeout_seconds: u64,
+
+    /// Client connection limits, rate limiting and timeouts
+    #[serde(default)]
+    pub limits: LimitsConfig,
+
+    /// HTTP/JSON API listener
+    #[serde(default)]
+    pub http: HttpConfig,
 }
 
 /// Configuration for MQTT-based replication.
@@ -143,6 +282,252 @@ pub struct ReplicationConfig {
     /// Unique identifier for this node in MQTT communications
     /// Should be unique across all nodes in the cluster
     pub client_id: String,
+
+    /// Username for MQTT broker authentication
+    #[serde(default)]
+    pub mqtt_username: Option<String>,
+
+    /// Password for MQTT broker authentication (used with `mqtt_username`)
+    #[serde(default)]
+    pub mqtt_password: Option<String>,
+
+    /// Connect to the broker over TLS (typically on port 8883)
+    #[serde(default)]
+    pub mqtt_tls: bool,
+
+    /// PEM file with the CA certificate(s) used to verify the broker;
+    /// without it the platform's root certificates are used
+    #[serde(default)]
+    pub mqtt_ca_file: Option<String>,
+
+    /// PEM client certificate for mutual TLS (requires `mqtt_client_key_file`)
+    #[serde(default)]
+    pub mqtt_client_cert_file: Option<String>,
+
+    /// PEM private key for the client certificate
+    #[serde(default)]
+    pub mqtt_client_key_file: Option<String>,
+
+    /// Start a clean MQTT session on connect (default: true)
+    #[serde(default = "default_mqtt_clean_session")]
+    pub mqtt_clean_session: bool,
+
+    /// MQTT keep-alive interval in seconds (default: 30)
+    #[serde(default = "default_mqtt_keep_alive_secs")]
+    pub mqtt_keep_alive_secs: u64,
+
+    /// Maximum unacknowledged QoS 1 publishes in flight (default: 100)
+    #[serde(default = "default_mqtt_inflight")]
+    pub mqtt_inflight: u16,
+
+    /// Transport used to carry replication traffic (default: "mqtt")
+    #[serde(default)]
+    pub transport: ReplicationTransportKind,
+
+    /// Address the TCP transport accepts peer connections on
+    #[serde(default = "default_tcp_listen")]
+    pub tcp_listen: String,
+
+    /// Addresses of peer nodes' TCP transport listeners
+    #[serde(default)]
+    pub tcp_peers: Vec<String>,
+
+    /// Codec for outgoing change events: "cbor" (default), "json" or "bincode".
+    /// Receivers decode any codec, so nodes may differ.
+    #[serde(default)]
+    pub codec: ChangeCodec,
+
+    /// Send events without the codec header byte and never batch them, so
+    /// nodes from before wire framing can decode them; set during a rolling
+    /// upgrade until every node runs this build (default: false)
+    #[serde(default)]
+    pub legacy_wire_format: bool,
+
+    /// Compression for outgoing payloads: "none" (default), "zstd" or "lz4"
+    #[serde(default)]
+    pub compression: Compression,
+
+    /// Maximum events coalesced into one publish; 1 (default) disables batching
+    #[serde(default = "default_batch_max_events")]
+    pub batch_max_events: usize,
+
+    /// Flush a batch once its encoded events reach this many bytes
+    #[serde(default = "default_batch_max_bytes")]
+    pub batch_max_bytes: usize,
+
+    /// Flush a batch this long after its first event, even if not full
+    #[serde(default = "default_batch_linger_ms")]
+    pub batch_linger_ms: u64,
+
+    /// Directory of the durable outbound replication queue ("" keeps it in memory)
+    #[serde(default = "default_queue_path")]
+    pub queue_path: String,
+
+    /// Queued events at which new replicated writes are refused
+    #[serde(default = "default_queue_max_events")]
+    pub queue_max_events: usize,
+
+    /// Directory of the persisted change log served by REPLAY, used even when
+    /// replication is disabled ("" keeps it in memory)
+    #[serde(default = "default_changelog_path")]
+    pub changelog_path: String,
+
+    /// Number of newest change log entries retained
+    #[serde(default = "default_changelog_max_events")]
+    pub changelog_max_events: u64,
+
+    /// Glob patterns of keys to publish and apply (empty means all keys)
+    #[serde(default)]
+    pub include_keys: Vec<String>,
+
+    /// Glob patterns of keys never published or applied (wins over include)
+    #[serde(default)]
+    pub exclude_keys: Vec<String>,
+
+    /// Route events to `events/{namespace}` subtopics instead of `events`
+    #[serde(default)]
+    pub namespace_topics: bool,
+
+    /// Separator between a key's namespace and the rest of the key
+    #[serde(default = "default_namespace_separator")]
+    pub namespace_separator: String,
+
+    /// Namespaces to subscribe to when `namespace_topics` is on (empty means all)
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+
+    /// Acknowledge events applied from peers, enabling `WAIT` write concerns
+    #[serde(default = "default_send_acks")]
+    pub send_acks: bool,
+
+    /// Fan client PUBLISH messages out to peers over the replication transport
+    #[serde(default)]
+    pub pubsub_cluster: bool,
+
+    /// Conflict policy for keys matching no entry in `conflict_policies`
+    #[serde(default)]
+    pub conflict_policy: ConflictPolicy,
+
+    /// Conflict policies by key prefix (longest matching prefix wins)
+    #[serde(default)]
+    pub conflict_policies: BTreeMap<String, ConflictPolicy>,
+
+    /// Writes within this many milliseconds of the newest are kept as siblings
+    #[serde(default = "default_sibling_window_ms")]
+    pub sibling_window_ms: u64,
+
+    /// Attach per-key version vectors to published events
+    #[serde(default)]
+    pub version_vectors: bool,
+
+    /// Repair a key range with the publisher when an event's `prev` hash
+    /// does not match the local state
+    #[serde(default = "default_repair_on_mismatch")]
+    pub repair_on_mismatch: bool,
+
+    /// Minimum time between repairs of the same key range with the same peer
+    #[serde(default = "default_repair_cooldown_ms")]
+    pub repair_cooldown_ms: u64,
+
+    /// Ask peers for the writes this node missed while it was down when
+    /// the apply loop starts
+    #[serde(default = "default_catch_up_on_start")]
+    pub catch_up_on_start: bool,
+}
+
+impl ReplicationConfig {
+    /// Transport channel filters this node subscribes to (MQTT wildcard syntax,
+    /// relative to `topic_prefix`).
+    pub fn subscribe_channels(&self) -> Vec<String> {
+        if self.namespace_topics && !self.namespaces.is_empty() {
+            let mut channels: Vec<String> = self.namespaces.iter().map(|ns| format!("events/{}", ns)).collect();
+            channels.push(format!("acks/{}", self.client_id));
+            channels.push(format!("repair/{}/#", self.client_id));
+            channels.push("catchup".to_string());
+            if self.pubsub_cluster {
+                channels.push("pubsub".to_string());
+            }
+            channels
+        } else {
+            vec!["#".to_string()]
+        }
+    }
+}
+
+fn default_shards() -> usize {
+    16
+}
+
+fn default_mqtt_clean_session() -> bool {
+    true
+}
+
+fn default_mqtt_keep_alive_secs() -> u64 {
+    30
+}
+
+fn default_mqtt_inflight() -> u16 {
+    100
+}
+
+fn default_tcp_listen() -> String {
+    "0.0.0.0:7380".to_string()
+}
+
+fn default_batch_max_events() -> usize {
+    1
+}
+
+fn default_batch_max_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_batch_linger_ms() -> u64 {
+    5
+}
+
+fn default_queue_path() -> String {
+    "./data/replication_queue".to_string()
+}
+
+fn default_queue_max_events() -> usize {
+    100_000
+}
+
+fn default_changelog_path() -> String {
+    "./data/change_log".to_string()
+}
+
+fn default_changelog_max_events() -> u64 {
+    100_000
+}
+
+fn default_sibling_window_ms() -> u64 {
+    1000
+}
+
+fn default_shutdown_timeout_seconds() -> u64 {
+    10
+}
+
+fn default_repair_on_mismatch() -> bool {
+    true
+}
+
+fn default_repair_cooldown_ms() -> u64 {
+    5000
+}
+
+fn default_catch_up_on_start() -> bool {
+    true
+}
+
+fn default_namespace_separator() -> String {
+    ":".to_string()
+}
+
+fn default_send_acks() -> bool {
+    true
 }
 
 impl Config {
@@ -188,8 +573,47 @@ impl Config {
                 mqtt_port: 1883,
                 topic_prefix: "merkle_kv".to_string(),
                 client_id: "node1".to_string(),
+                mqtt_username: None,
+                mqtt_password: None,
+                mqtt_tls: false,
+                mqtt_ca_file: None,
+                mqtt_client_cert_file: None,
+                mqtt_client_key_file: None,
+                mqtt_clean_session: default_mqtt_clean_session(),
+                mqtt_keep_alive_secs: default_mqtt_keep_alive_secs(),
+                mqtt_inflight: default_mqtt_inflight(),
+                transport: ReplicationTransportKind::Mqtt,
+                tcp_listen: default_tcp_listen(),
+                tcp_peers: Vec::new(),
+                codec: ChangeCodec::Cbor,
+                legacy_wire_format: false,
+                compression: Compression::None,
+                batch_max_events: default_batch_max_events(),
+                batch_max_bytes: default_batch_max_bytes(),
+                batch_linger_ms: default_batch_linger_ms(),
+                queue_path: default_queue_path(),
+                queue_max_events: default_queue_max_events(),
+                changelog_path: default_changelog_path(),
+                changelog_max_events: default_changelog_max_events(),
+                include_keys: Vec::new(),
+                exclude_keys: Vec::new(),
+                namespace_topics: false,
+                namespace_separator: default_namespace_separator(),
+                namespaces: Vec::new(),
+                send_acks: default_send_acks(),
+                pubsub_cluster: false,
+                conflict_policy: ConflictPolicy::default(),
+                conflict_policies: BTreeMap::new(),
+                sibling_window_ms: default_sibling_window_ms(),
+                version_vectors: false,
+                repair_on_mismatch: default_repair_on_mismatch(),
+                repair_cooldown_ms: default_repair_cooldown_ms(),
+                catch_up_on_start: default_catch_up_on_start(),
             },
             sync_interval_seconds: 60,
+            shutdown_timeout_seconds: default_shutdown_timeout_seconds(),
+            limits: LimitsConfig::default(),
+            http: HttpConfig::default(),
         }
     }
 
@@ -209,6 +633,7 @@ impl Config {
         match self.storage.engine {
             StorageEngine::Memory => "kv",
             StorageEngine::RwLock => "rwlock",
+            StorageEngine::Sharded => "sharded",
             StorageEngine::Sled => "sled",
         }
     }
@@ -290,4 +715,24 @@ client_id = "node1"
         assert_eq!(config.storage_path(), "data");
         assert_eq!(config.engine(), "rwlock");
     }
+
+    #[test]
+    fn test_replication_subscribe_channels() {
+        let mut repl = Config::default().replication;
+        assert_eq!(repl.subscribe_channels(), vec!["#".to_string()]);
+        repl.namespaces = vec!["user".to_string()];
+        assert_eq!(repl.subscribe_channels(), vec!["#".to_string()]);
+        repl.namespace_topics = true;
+        assert_eq!(
+            repl.subscribe_channels(),
+            vec![
+                "events/user".to_string(),
+                "acks/node1".to_string(),
+                "repair/node1/#".to_string(),
+                "catchup".to_string()
+            ]
+        );
+        repl.pubsub_cluster = true;
+        assert_eq!(repl.subscribe_channels().last().map(String::as_str), Some("pubsub"));
+    }
 }
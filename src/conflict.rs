@@ -0,0 +1,612 @@
+//! # Conflict Resolution Policies
+//!
+//! When a replicated write reaches a node, a `ConflictResolver` decides how it
+//! combines with the version the node already holds for that key. Policies are
+//! selected per key prefix (`replication.conflict_policies`, longest prefix
+//! wins) with `replication.conflict_policy` as the fallback.
+//!
+//! ## Built-in Policies
+//!
+//! - **`lww`** (Last Writer Wins, default): the greater `(ts, src)` wins.
+//! - **`first_writer`**: the smaller `(ts, src)` wins, making keys write-once.
+//!   Local writes (including DEL) to a key that already has a version are
+//!   refused.
+//! - **`highest_value`**: the greater value wins; numbers compare numerically
+//!   and sort below non-numeric strings, which compare bytewise. Local writes
+//!   that would lower the value (including DEL) are refused.
+//! - **`keep_siblings`**: a multi-value register. Writes from different nodes
+//!   within `sibling_window_ms` of the newest write are all kept, and GET
+//!   returns every live sibling; the store holds the newest one.
+//!
+//! ## Causality
+//!
+//! When writes carry version vectors (`replication.version_vectors`), `lww`
+//! and `keep_siblings` keys follow causality: a write that has seen another
+//! replaces it whatever their timestamps, and one already seen is stale.
+//! Only truly concurrent writes reach the policy. All of them are kept, so
+//! a later write that has seen only some of them cannot resurrect a loser.
+//! Under `keep_siblings` every concurrent write is a sibling (no window)
+//! until a write that has seen them all replaces them. Each concurrent
+//! write is logged and counted (`conflicts` in REPLSTATUS).
+//!
+//! ## Convergence
+//!
+//! Every policy is a deterministic function of the set of writes seen, so
+//! nodes converge regardless of delivery order. Local writes are checked
+//! against the same policy before they are applied, which is what keeps
+//! `first_writer` and `highest_value` from diverging; `KeyLocks` makes the
+//! check and the store write one step per key.
+//!
+//! Version metadata is kept in memory. A key the table does not know yet is
+//! seeded from the store as the oldest possible write, so after a restart
+//! `first_writer` and `highest_value` still protect existing values (with
+//! version vectors, such a key is treated as concurrent with what peers
+//! hold). `lww` keys keep only their stamps, not their values.
+//!
+//! Collection types (sets, hashes) are CRDTs and never go through a resolver.
+
+use crate::change_event::{Causality, VersionVector};
+use crate::config::{ConflictPolicy, ReplicationConfig};
+use log::info;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+
+/// One write to a key: its resulting value (`None` for a delete) and stamp.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// Resulting value; `None` for deletions
+    pub value: Option<String>,
+    /// Write timestamp (unix nanoseconds)
+    pub ts: u64,
+    /// Writing node id
+    pub src: String,
+    /// Version vector of the key after this write, if the writer sent one
+    pub vv: Option<VersionVector>,
+}
+
+impl Version {
+    /// LWW order: timestamp first, node id as the tie-breaker.
+    fn stamp(&self) -> (u64, &str) {
+        (self.ts, &self.src)
+    }
+}
+
+/// Decides how an incoming write combines with a key's current versions.
+pub trait ConflictResolver: Send + Sync {
+    /// Policy name, as used in configuration.
+    fn name(&self) -> &'static str;
+
+    /// Combine `incoming` with the key's `current` versions (empty if the
+    /// key has none).
+    ///
+    /// # Returns
+    /// * `Some(versions)` - The key's new versions (more than one only for siblings)
+    /// * `None` - The incoming write loses; the key is unchanged
+    fn resolve(&self, current: &[Version], incoming: &Version) -> Option<Vec<Version>>;
+
+    /// Whether version vectors, when present, decide between causally
+    /// related writes, leaving this policy to arbitrate concurrent ones.
+    fn follows_causality(&self) -> bool {
+        false
+    }
+
+    /// Versions readers see for a set of mutually concurrent writes (ordered
+    /// by `(ts, src)`); by default the outcome of resolving them in turn.
+    fn resolve_concurrent(&self, concurrent: &[Version]) -> Vec<Version> {
+        concurrent.iter().fold(Vec::new(), |current, v| self.resolve(&current, v).unwrap_or(current))
+    }
+}
+
+/// Greater `(ts, src)` wins.
+pub struct LastWriterWins;
+
+impl ConflictResolver for LastWriterWins {
+    fn name(&self) -> &'static str {
+        "lww"
+    }
+
+    fn resolve(&self, current: &[Version], incoming: &Version) -> Option<Vec<Version>> {
+        match current.iter().max_by(|a, b| a.stamp().cmp(&b.stamp())) {
+            Some(winner) if winner.stamp() > incoming.stamp() => None,
+            _ => Some(vec![incoming.clone()]),
+        }
+    }
+
+    fn follows_causality(&self) -> bool {
+        true
+    }
+}
+
+/// Smaller `(ts, src)` wins.
+pub struct FirstWriterWins;
+
+impl ConflictResolver for FirstWriterWins {
+    fn name(&self) -> &'static str {
+        "first_writer"
+    }
+
+    fn resolve(&self, current: &[Version], incoming: &Version) -> Option<Vec<Version>> {
+        match current.iter().min_by(|a, b| a.stamp().cmp(&b.stamp())) {
+            Some(winner) if winner.stamp() <= incoming.stamp() => None,
+            _ => Some(vec![incoming.clone()]),
+        }
+    }
+}
+
+/// Greater value wins (see the module docs for the value order).
+pub struct HighestValueWins;
+
+impl HighestValueWins {
+    /// Total order over values: deleted < numbers (numerically) < strings (bytewise).
+    fn compare(a: &Version, b: &Version) -> Ordering {
+        fn rank(value: &Option<String>) -> (u8, Option<f64>, Option<&str>) {
+            match value.as_deref() {
+                None => (0, None, None),
+                Some(v) => match v.parse::<f64>() {
+                    Ok(n) if n.is_finite() => (1, Some(n), None),
+                    _ => (2, None, Some(v)),
+                },
+            }
+        }
+        let (ra, rb) = (rank(&a.value), rank(&b.value));
+        ra.0.cmp(&rb.0)
+            .then_with(|| ra.1.partial_cmp(&rb.1).unwrap_or(Ordering::Equal))
+            .then_with(|| ra.2.cmp(&rb.2))
+            .then_with(|| a.stamp().cmp(&b.stamp()))
+    }
+}
+
+impl ConflictResolver for HighestValueWins {
+    fn name(&self) -> &'static str {
+        "highest_value"
+    }
+
+    fn resolve(&self, current: &[Version], incoming: &Version) -> Option<Vec<Version>> {
+        match current.iter().max_by(|a, b| Self::compare(a, b)) {
+            Some(winner) if Self::compare(winner, incoming) != Ordering::Less => None,
+            _ => Some(vec![incoming.clone()]),
+        }
+    }
+}
+
+/// Keep concurrent writes from different nodes as siblings.
+pub struct KeepSiblings {
+    /// Writes older than the newest by more than this are superseded
+    window_ns: u64,
+}
+
+impl KeepSiblings {
+    /// Create a resolver treating writes within `window_ms` of each other as concurrent.
+    pub fn new(window_ms: u64) -> Self {
+        Self { window_ns: window_ms.saturating_mul(1_000_000) }
+    }
+}
+
+impl ConflictResolver for KeepSiblings {
+    fn name(&self) -> &'static str {
+        "keep_siblings"
+    }
+
+    fn resolve(&self, current: &[Version], incoming: &Version) -> Option<Vec<Version>> {
+        // Per node, only the latest write counts
+        if current.iter().any(|v| v.src == incoming.src && v.stamp() >= incoming.stamp()) {
+            return None;
+        }
+        let mut versions: Vec<Version> = current.iter().filter(|v| v.src != incoming.src).cloned().collect();
+        versions.push(incoming.clone());
+        let newest = versions.iter().map(|v| v.ts).max().unwrap_or(0);
+        versions.retain(|v| v.ts.saturating_add(self.window_ns) >= newest);
+        if !versions.contains(incoming) {
+            return None;
+        }
+        versions.sort_by(|a, b| a.stamp().cmp(&b.stamp()));
+        Some(versions)
+    }
+
+    fn follows_causality(&self) -> bool {
+        true
+    }
+
+    fn resolve_concurrent(&self, concurrent: &[Version]) -> Vec<Version> {
+        concurrent.to_vec()
+    }
+}
+
+/// Build the resolver for a configured policy.
+pub fn resolver_for(policy: ConflictPolicy, repl: &ReplicationConfig) -> Arc<dyn ConflictResolver> {
+    match policy {
+        ConflictPolicy::Lww => Arc::new(LastWriterWins),
+        ConflictPolicy::FirstWriter => Arc::new(FirstWriterWins),
+        ConflictPolicy::HighestValue => Arc::new(HighestValueWins),
+        ConflictPolicy::KeepSiblings => Arc::new(KeepSiblings::new(repl.sibling_window_ms)),
+    }
+}
+
+/// Per-prefix resolver selection.
+#[derive(Clone)]
+pub struct Resolvers {
+    /// `(prefix, resolver)` pairs, longest prefix first
+    by_prefix: Vec<(String, Arc<dyn ConflictResolver>)>,
+    /// Resolver for keys matching no prefix
+    default: Arc<dyn ConflictResolver>,
+}
+
+impl Resolvers {
+    /// Build the resolvers configured in `repl`.
+    pub fn from_config(repl: &ReplicationConfig) -> Self {
+        let mut by_prefix: Vec<(String, Arc<dyn ConflictResolver>)> = repl
+            .conflict_policies
+            .iter()
+            .map(|(prefix, policy)| (prefix.clone(), resolver_for(*policy, repl)))
+            .collect();
+        by_prefix.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Self { by_prefix, default: resolver_for(repl.conflict_policy, repl) }
+    }
+
+    /// Resolver responsible for `key`.
+    pub fn for_key(&self, key: &str) -> &dyn ConflictResolver {
+        self.by_prefix
+            .iter()
+            .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .map(|(_, r)| r.as_ref())
+            .unwrap_or(self.default.as_ref())
+    }
+
+    /// Whether every key uses LWW, under which local writes always win.
+    pub fn is_lww_only(&self) -> bool {
+        std::iter::once(&self.default)
+            .chain(self.by_prefix.iter().map(|(_, r)| r))
+            .all(|r| r.name() == "lww")
+    }
+}
+
+/// Outcome of resolving one write against a key's stored versions.
+struct Resolution {
+    /// Versions to remember for the key
+    stored: Vec<Version>,
+    /// Versions readers see (the winners)
+    visible: Vec<Version>,
+    /// Stored versions the write was concurrent with
+    concurrent: Vec<Version>,
+}
+
+/// Current versions of every plain-value key written since startup.
+///
+/// For keys resolved by causality the table keeps every concurrent write,
+/// and the resolver picks the visible ones; otherwise it keeps the winners.
+/// Under `lww` only the stamps are kept.
+#[derive(Default)]
+pub struct VersionTable {
+    versions: std::sync::Mutex<HashMap<String, Vec<Version>>>,
+    /// Writes found concurrent with a stored version
+    conflicts: AtomicU64,
+}
+
+impl VersionTable {
+    /// Resolve `incoming` against the key's versions, storing the outcome.
+    ///
+    /// # Returns
+    /// * `Some(versions)` - The key's new visible versions
+    /// * `None` - The write lost (it may still be kept as a concurrent version)
+    pub fn apply(&self, resolver: &dyn ConflictResolver, key: &str, incoming: &Version) -> Option<Vec<Version>> {
+        let mut versions = self.versions.lock().unwrap_or_else(|e| e.into_inner());
+        let current = versions.get(key).map(Vec::as_slice).unwrap_or(&[]);
+        let resolution = Self::resolve(resolver, current, incoming)?;
+        if !resolution.concurrent.is_empty() {
+            self.conflicts.fetch_add(1, AtomicOrdering::Relaxed);
+            let others: Vec<&str> = resolution.concurrent.iter().map(|v| v.src.as_str()).collect();
+            info!("Concurrent writes to '{}' from {} and {:?}, resolved by {}", key, incoming.src, others, resolver.name());
+        }
+        let mut stored = resolution.stored;
+        if !Self::keeps_values(resolver) {
+            stored.iter_mut().for_each(|v| v.value = None);
+        }
+        versions.insert(key.to_string(), stored);
+        resolution.visible.contains(incoming).then_some(resolution.visible)
+    }
+
+    /// Record the store's value of a key the table does not know yet as its
+    /// oldest possible write. `current` is only read for policies that
+    /// compare against existing values.
+    pub fn seed(&self, resolver: &dyn ConflictResolver, key: &str, current: impl FnOnce() -> Option<String>) {
+        if !Self::keeps_values(resolver) {
+            return;
+        }
+        let mut versions = self.versions.lock().unwrap_or_else(|e| e.into_inner());
+        if !versions.contains_key(key) {
+            if let Some(value) = current() {
+                versions.insert(key.to_string(), vec![Version { value: Some(value), ts: 0, src: String::new(), vv: None }]);
+            }
+        }
+    }
+
+    /// Whether `incoming` would win, without recording it.
+    pub fn admits(&self, resolver: &dyn ConflictResolver, key: &str, incoming: &Version) -> bool {
+        let versions = self.versions.lock().unwrap_or_else(|e| e.into_inner());
+        let current = versions.get(key).map(Vec::as_slice).unwrap_or(&[]);
+        Self::resolve(resolver, current, incoming).is_some_and(|r| r.visible.contains(incoming))
+    }
+
+    /// Version vector for a new local write to `key` by `node` at `ts`: the
+    /// merged history of every stored version, advanced by the write.
+    pub fn next_vv(&self, key: &str, node: &str, ts: u64) -> VersionVector {
+        let versions = self.versions.lock().unwrap_or_else(|e| e.into_inner());
+        let mut vv = VersionVector::new();
+        for v in versions.get(key).into_iter().flatten() {
+            if let Some(seen) = &v.vv {
+                vv.merge(seen);
+            }
+        }
+        vv.advance(node, ts);
+        vv
+    }
+
+    /// Live values of a key holding more than one sibling, oldest first.
+    pub fn siblings(&self, resolver: &dyn ConflictResolver, key: &str) -> Vec<String> {
+        let versions = self.versions.lock().unwrap_or_else(|e| e.into_inner());
+        let stored = versions.get(key).map(Vec::as_slice).unwrap_or(&[]);
+        let live: Vec<String> = Self::visible(resolver, stored).into_iter().filter_map(|v| v.value).collect();
+        if live.len() > 1 { live } else { Vec::new() }
+    }
+
+    /// Number of writes found concurrent with a stored version.
+    pub fn conflicts(&self) -> u64 {
+        self.conflicts.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Whether the policy needs stored values (`lww` compares stamps only).
+    fn keeps_values(resolver: &dyn ConflictResolver) -> bool {
+        resolver.name() != "lww"
+    }
+
+    /// Whether `stored` holds concurrent writes for the resolver to pick from.
+    fn is_causal(resolver: &dyn ConflictResolver, stored: &[Version]) -> bool {
+        resolver.follows_causality() && stored.iter().all(|v| v.vv.is_some())
+    }
+
+    fn visible(resolver: &dyn ConflictResolver, stored: &[Version]) -> Vec<Version> {
+        if stored.len() > 1 && Self::is_causal(resolver, stored) {
+            resolver.resolve_concurrent(stored)
+        } else {
+            stored.to_vec()
+        }
+    }
+
+    fn resolve(resolver: &dyn ConflictResolver, stored: &[Version], incoming: &Version) -> Option<Resolution> {
+        let vv = match &incoming.vv {
+            Some(vv) if Self::is_causal(resolver, stored) => vv,
+            // Without causal metadata the policy sees only the current winners
+            _ => {
+                let resolved = resolver.resolve(&Self::visible(resolver, stored), incoming)?;
+                return Some(Resolution { stored: resolved.clone(), visible: resolved, concurrent: Vec::new() });
+            }
+        };
+        let mut concurrent = Vec::new();
+        for v in stored {
+            match v.vv.as_ref().map(|seen| seen.compare(vv)) {
+                // Already seen (or a duplicate): nothing changes
+                Some(Causality::After) | Some(Causality::Equal) => return None,
+                Some(Causality::Concurrent) => concurrent.push(v.clone()),
+                // Replaced by a write that has seen it
+                _ => {}
+            }
+        }
+        let mut stored = concurrent.clone();
+        stored.push(incoming.clone());
+        stored.sort_by(|a, b| a.stamp().cmp(&b.stamp()));
+        let visible = resolver.resolve_concurrent(&stored);
+        Some(Resolution { stored, visible, concurrent })
+    }
+}
+
+/// Striped per-key locks held across a write's conflict check and the store
+/// write it guards, so concurrent writes to a key resolve one at a time.
+pub struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl KeyLocks {
+    /// Create `stripes` locks shared by all keys.
+    pub fn new(stripes: usize) -> Self {
+        Self { stripes: (0..stripes.max(1)).map(|_| Mutex::new(())).collect() }
+    }
+
+    /// Lock every key in `keys` (in stripe order, so callers cannot deadlock).
+    pub async fn lock<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = keys
+            .into_iter()
+            .map(|key| {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hasher.finish() as usize % self.stripes.len()
+            })
+            .collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        let mut guards = Vec::with_capacity(stripes.len());
+        for stripe in stripes {
+            guards.push(self.stripes[stripe].lock().await);
+        }
+        guards
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(value: Option<&str>, ts: u64, src: &str) -> Version {
+        Version { value: value.map(str::to_string), ts, src: src.to_string(), vv: None }
+    }
+
+    /// A write carrying a version vector built from `(node, counter)` pairs.
+    fn vv(value: Option<&str>, ts: u64, src: &str, history: &[(&str, u64)]) -> Version {
+        let mut vector = VersionVector::new();
+        for (node, counter) in history {
+            vector.advance(node, *counter);
+        }
+        Version { vv: Some(vector), ..v(value, ts, src) }
+    }
+
+    /// What the table keeps of an `lww` write: its stamps, not its value.
+    fn stamps(version: &Version) -> Version {
+        Version { value: None, ..version.clone() }
+    }
+
+    /// Fold writes in the given order and return the surviving versions.
+    fn fold(resolver: &dyn ConflictResolver, writes: &[Version]) -> Vec<Version> {
+        let table = VersionTable::default();
+        for w in writes {
+            table.apply(resolver, "k", w);
+        }
+        let versions = table.versions.lock().unwrap();
+        versions.get("k").cloned().unwrap_or_default()
+    }
+
+    /// Every policy must converge regardless of delivery order.
+    fn assert_order_independent(resolver: &dyn ConflictResolver, writes: &[Version]) -> Vec<Version> {
+        let forward = fold(resolver, writes);
+        let reversed: Vec<Version> = writes.iter().rev().cloned().collect();
+        assert_eq!(forward, fold(resolver, &reversed), "{} diverged", resolver.name());
+        forward
+    }
+
+    #[test]
+    fn lww_and_first_writer() {
+        let writes = [v(Some("a"), 10, "n1"), v(Some("b"), 20, "n2"), v(Some("c"), 20, "n1")];
+        assert_eq!(assert_order_independent(&LastWriterWins, &writes), vec![stamps(&writes[1])]);
+        assert_eq!(assert_order_independent(&FirstWriterWins, &writes), vec![writes[0].clone()]);
+    }
+
+    #[test]
+    fn highest_value_orders_numbers_then_strings() {
+        let writes = [v(Some("9"), 30, "n1"), v(Some("10"), 10, "n2"), v(None, 40, "n3")];
+        assert_eq!(assert_order_independent(&HighestValueWins, &writes), vec![writes[1].clone()]);
+
+        let writes = [v(Some("100"), 10, "n1"), v(Some("abc"), 5, "n2")];
+        assert_eq!(assert_order_independent(&HighestValueWins, &writes), vec![writes[1].clone()]);
+    }
+
+    #[test]
+    fn keep_siblings_within_window() {
+        let resolver = KeepSiblings::new(1); // 1ms = 1_000_000ns
+        let writes = [
+            v(Some("a"), 1_000_000, "n1"),
+            v(Some("b"), 1_500_000, "n2"),
+            v(Some("a2"), 1_200_000, "n1"),
+        ];
+        let siblings = assert_order_independent(&resolver, &writes);
+        assert_eq!(siblings, vec![writes[2].clone(), writes[1].clone()]);
+
+        // A write well after the others supersedes them all
+        let mut later = writes.to_vec();
+        later.push(v(Some("z"), 9_000_000, "n3"));
+        assert_eq!(assert_order_independent(&resolver, &later), vec![later[3].clone()]);
+    }
+
+    #[test]
+    fn prefix_selection_and_siblings_view() {
+        let mut repl = crate::config::Config::default().replication;
+        repl.conflict_policies.insert("user:".into(), ConflictPolicy::FirstWriter);
+        repl.conflict_policies.insert("user:score:".into(), ConflictPolicy::HighestValue);
+        let resolvers = Resolvers::from_config(&repl);
+        assert_eq!(resolvers.for_key("user:1").name(), "first_writer");
+        assert_eq!(resolvers.for_key("user:score:1").name(), "highest_value");
+        assert_eq!(resolvers.for_key("order:1").name(), "lww");
+        assert!(!resolvers.is_lww_only());
+
+        let table = VersionTable::default();
+        let siblings = KeepSiblings::new(1000);
+        table.apply(&siblings, "k", &v(Some("x"), 1, "n1"));
+        assert!(table.siblings(&siblings, "k").is_empty());
+        table.apply(&siblings, "k", &v(Some("y"), 2, "n2"));
+        assert_eq!(table.siblings(&siblings, "k"), vec!["x".to_string(), "y".to_string()]);
+        assert!(!table.admits(&FirstWriterWins, "k", &v(Some("z"), 3, "n3")));
+    }
+
+    #[test]
+    fn causal_successor_wins_despite_older_timestamp() {
+        // n2 saw n1's write but its clock lags behind
+        let first = vv(Some("a"), 100, "n1", &[("n1", 100)]);
+        let successor = vv(Some("b"), 50, "n2", &[("n1", 100), ("n2", 50)]);
+        let writes = [first, successor.clone()];
+        assert_eq!(assert_order_independent(&LastWriterWins, &writes), vec![stamps(&successor)]);
+
+        // Replaying the superseded write is stale
+        let table = VersionTable::default();
+        table.apply(&LastWriterWins, "k", &successor);
+        assert!(table.apply(&LastWriterWins, "k", &writes[0]).is_none());
+        assert_eq!(table.conflicts(), 0);
+    }
+
+    #[test]
+    fn concurrent_writes_are_kept_and_counted() {
+        let base = vv(Some("base"), 10, "n1", &[("n1", 10)]);
+        let left = vv(Some("left"), 30, "n1", &[("n1", 30)]);
+        let right = vv(Some("right"), 20, "n2", &[("n1", 10), ("n2", 20)]);
+        // n3 has seen the right branch only; its clock lags behind
+        let after_right = vv(Some("after"), 5, "n3", &[("n1", 10), ("n2", 20), ("n3", 5)]);
+        let writes = [base, left.clone(), right.clone(), after_right.clone()];
+
+        // The LWW loser survives internally, so it still beats the newer branch
+        let table = VersionTable::default();
+        for w in &writes {
+            table.apply(&LastWriterWins, "k", w);
+        }
+        assert_eq!(table.conflicts(), 2);
+        let versions = assert_order_independent(&LastWriterWins, &writes);
+        assert_eq!(versions, vec![stamps(&after_right), stamps(&left)]);
+        assert_eq!(LastWriterWins.resolve_concurrent(&versions), vec![stamps(&left)]);
+
+        // Under keep_siblings every concurrent write is visible, whatever the window
+        let siblings = KeepSiblings::new(0);
+        assert_order_independent(&siblings, &writes);
+        let table = VersionTable::default();
+        for w in &writes {
+            table.apply(&siblings, "k", w);
+        }
+        assert_eq!(table.siblings(&siblings, "k"), vec!["after".to_string(), "left".to_string()]);
+
+        // A local write merges everything seen and replaces all siblings
+        let next = table.next_vv("k", "n1", 40);
+        let merged = Version { vv: Some(next), ..v(Some("merged"), 40, "n1") };
+        assert_eq!(table.apply(&siblings, "k", &merged), Some(vec![merged]));
+        assert!(table.siblings(&siblings, "k").is_empty());
+    }
+
+    #[test]
+    fn seeded_store_values_are_the_oldest_writes() {
+        // After a restart the table is empty but the store still holds values
+        let table = VersionTable::default();
+        table.seed(&FirstWriterWins, "once", || Some("first".to_string()));
+        table.seed(&HighestValueWins, "max", || Some("10".to_string()));
+        table.seed(&LastWriterWins, "plain", || panic!("lww keys are not seeded"));
+        assert!(!table.admits(&FirstWriterWins, "once", &v(Some("second"), 1, "n1")));
+        assert!(!table.admits(&HighestValueWins, "max", &v(Some("9"), 1, "n1")));
+        assert!(table.admits(&HighestValueWins, "max", &v(Some("11"), 1, "n1")));
+
+        // Known keys are not reseeded
+        table.seed(&FirstWriterWins, "once", || Some("other".to_string()));
+        assert_eq!(table.versions.lock().unwrap()["once"][0].value.as_deref(), Some("first"));
+    }
+
+    #[tokio::test]
+    async fn key_locks_serialize_writers_of_a_key() {
+        let locks = Arc::new(KeyLocks::new(4));
+        let guards = locks.lock(["a", "b", "a"]).await;
+        assert!(guards.len() <= 2);
+        let waiting = {
+            let locks = locks.clone();
+            tokio::spawn(async move { locks.lock(["a"]).await.len() })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        drop(guards);
+        assert_eq!(waiting.await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,114 @@
+//! # Error Codes
+//!
+//! Every error reported to a client carries a stable code, so clients can
+//! tell failures apart without matching on the message:
+//!
+//! ```text
+//! ERROR ERR_WRONGTYPE Value for key 'name' is not a valid number
+//! ```
+//!
+//! Parse errors are `ERR_SYNTAX`. Storage engines and the replicator return
+//! `anyhow` errors; those that wrap a `KvError` keep its code, and any other
+//! error (I/O, corruption) is reported as `ERR_INTERNAL`.
+
+use std::fmt;
+
+/// Machine-readable category of an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Malformed command, unknown command or bad argument
+    Syntax,
+    /// Operation against a value of the wrong kind (INC on text, SADD on a hash)
+    WrongType,
+    /// Numeric result or argument outside the 64-bit signed range
+    Overflow,
+    /// The key (or field) does not exist
+    NotFound,
+    /// The node is not accepting writes (replication queue full)
+    ReadOnly,
+    /// The write was rejected by the key's conflict policy
+    Conflict,
+    /// A deadline expired (write concern, slow client)
+    Timeout,
+    /// A client limit was exceeded (rate limit, command size, connections)
+    Limit,
+    /// The command needs a feature that is disabled or a streaming connection
+    Unavailable,
+    /// Storage or other internal failure
+    Internal,
+}
+
+impl ErrorCode {
+    /// The code as sent to clients.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Syntax => "ERR_SYNTAX",
+            ErrorCode::WrongType => "ERR_WRONGTYPE",
+            ErrorCode::Overflow => "ERR_OVERFLOW",
+            ErrorCode::NotFound => "ERR_NOTFOUND",
+            ErrorCode::ReadOnly => "ERR_READONLY",
+            ErrorCode::Conflict => "ERR_CONFLICT",
+            ErrorCode::Timeout => "ERR_TIMEOUT",
+            ErrorCode::Limit => "ERR_LIMIT",
+            ErrorCode::Unavailable => "ERR_UNAVAILABLE",
+            ErrorCode::Internal => "ERR_INTERNAL",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An error with a code and a human-readable message.
+///
+/// Displays as the message alone, so wrapping it in `anyhow` leaves log
+/// lines unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl KvError {
+    /// Create an error.
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for KvError {}
+
+impl From<anyhow::Error> for KvError {
+    /// Recover the `KvError` an `anyhow` error wraps, or classify it as internal.
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<KvError>() {
+            Ok(e) => e,
+            Err(e) => KvError::new(ErrorCode::Internal, e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_survive_anyhow() {
+        let wrapped: anyhow::Error = KvError::new(ErrorCode::Overflow, "too big").into();
+        assert_eq!(wrapped.to_string(), "too big");
+        assert_eq!(KvError::from(wrapped), KvError::new(ErrorCode::Overflow, "too big"));
+
+        let other = KvError::from(anyhow::anyhow!("disk on fire"));
+        assert_eq!((other.code, other.message.as_str()), (ErrorCode::Internal, "disk on fire"));
+        assert_eq!(ErrorCode::ReadOnly.to_string(), "ERR_READONLY");
+    }
+}
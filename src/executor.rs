@@ -0,0 +1,866 @@
+//! # Command Execution
+//!
+//! `CommandExecutor` owns the semantics of every command: it runs a parsed
+//! `Command` against the storage engine, publishes the resulting writes to
+//! the replicator, records statistics and returns a typed `Response`. It
+//! does no I/O of its own, so the TCP server, other front ends and unit
+//! tests all share the same logic.
+//!
+//! ## Session Commands
+//!
+//! `WATCH`, `SUBSCRIBE`, `PSUBSCRIBE`, `FORMAT` and `SHUTDOWN` act on the client
+//! connection or the server rather than on data, so front ends handle them
+//! themselves; the executor answers them with an error.
+//!
+//! ## Write Path
+//!
+//! 1. A `WAIT <replicas> <timeout>` write concern is split off the command
+//! 2. Replicated writes are refused while the replication queue is full, or
+//!    when the key's conflict policy would reject them on peers
+//! 3. The command runs against the store
+//! 4. Successful writes are published to the replicator, or straight to
+//!    WATCH streams when replication is disabled
+//! 5. The write concern waits for acknowledgements from peers
+
+use crate::change_event::{ChangeEvent, OpKind, SCHEMA_VERSION};
+use crate::changelog::ChangeLog;
+use crate::error::ErrorCode;
+use crate::protocol::{counters, ArrayKind, Command, MapKind, Response};
+use crate::pubsub::PubSub;
+use crate::replication::{OpId, Replicator};
+use crate::server::ServerStats;
+use crate::store::crdt::{new_tag, LwwMap, OrSet};
+use crate::store::merkle::MerkleTree;
+use crate::store::{CrdtValue, KVEngineStoreTrait};
+use crate::watch::render_event;
+use log::warn;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Change events buffered per WATCH stream when replication is disabled.
+const WATCH_BUFFER: usize = 1024;
+
+/// Replicated write to publish once the store operation succeeded.
+enum Publish {
+    Set(String, String),
+    Delete(String),
+    Incr(String, i64),
+    Decr(String, i64),
+    Append(String, String),
+    Prepend(String, String),
+    Crdt(OpKind, String, CrdtValue),
+}
+
+impl Publish {
+    /// The change event of this write, as the replicator would publish it.
+    fn into_event(self, node_id: &str) -> ChangeEvent {
+        let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let event = |op, key: String, value: Option<&str>| {
+            ChangeEvent::with_str_value(SCHEMA_VERSION, op, key, value, ts, node_id, None, None)
+        };
+        match self {
+            Publish::Set(k, v) => event(OpKind::Set, k, Some(&v)),
+            Publish::Delete(k) => event(OpKind::Del, k, None),
+            Publish::Incr(k, nv) => event(OpKind::Incr, k, Some(&nv.to_string())),
+            Publish::Decr(k, nv) => event(OpKind::Decr, k, Some(&nv.to_string())),
+            Publish::Append(k, nv) => event(OpKind::Append, k, Some(&nv)),
+            Publish::Prepend(k, nv) => event(OpKind::Prepend, k, Some(&nv)),
+            Publish::Crdt(op, k, delta) => {
+                ChangeEvent::new(SCHEMA_VERSION, op, k, Some(delta.canonical_bytes()), ts, node_id, None, None)
+            }
+        }
+    }
+}
+
+const WRONGTYPE: &str = "Operation against a key holding the wrong kind of value";
+
+/// Executes commands against shared storage, replication and statistics.
+///
+/// Cloning is cheap; clones share the same state.
+#[derive(Clone)]
+pub struct CommandExecutor {
+    store: Arc<dyn KVEngineStoreTrait>,
+    replicator: Option<Replicator>,
+    /// Produced (and, with replication, applied) events served by REPLAY
+    changelog: Arc<ChangeLog>,
+    stats: Arc<ServerStats>,
+    /// Channel messaging broker
+    pubsub: Arc<PubSub>,
+    /// This node's id, used to stamp hash (LWW-Map) field writes
+    node_id: String,
+    /// WATCH feed of this node's writes while replication is disabled
+    events: broadcast::Sender<ChangeEvent>,
+}
+
+impl CommandExecutor {
+    /// Create an executor.
+    ///
+    /// # Arguments
+    /// * `store` - Storage engine shared with the rest of the server
+    /// * `replicator` - Replicator writes are published to, if replication is enabled
+    /// * `changelog` - Change log served by REPLAY; shared with `replicator`,
+    ///   which records events in it, or else written by the executor
+    /// * `stats` - Statistics updated for every executed command
+    /// * `pubsub` - Broker for PUBLISH
+    /// * `node_id` - This node's id
+    pub fn new(
+        store: Arc<dyn KVEngineStoreTrait>,
+        replicator: Option<Replicator>,
+        changelog: Arc<ChangeLog>,
+        stats: Arc<ServerStats>,
+        pubsub: Arc<PubSub>,
+        node_id: String,
+    ) -> Self {
+        let (events, _) = broadcast::channel(WATCH_BUFFER);
+        Self { store, replicator, changelog, stats, pubsub, node_id, events }
+    }
+
+    /// The replicator, if replication is enabled.
+    pub fn replicator(&self) -> Option<&Replicator> {
+        self.replicator.as_ref()
+    }
+
+    /// Storage engine.
+    pub fn store(&self) -> &Arc<dyn KVEngineStoreTrait> {
+        &self.store
+    }
+
+    /// Server statistics.
+    pub fn stats(&self) -> &Arc<ServerStats> {
+        &self.stats
+    }
+
+    /// Channel messaging broker.
+    pub fn pubsub(&self) -> &Arc<PubSub> {
+        &self.pubsub
+    }
+
+    /// Subscribe to this node's change events for WATCH: its writes, and
+    /// with replication enabled also the events received from peers.
+    pub fn watch(&self) -> broadcast::Receiver<ChangeEvent> {
+        match &self.replicator {
+            Some(r) => r.watch(),
+            None => self.events.subscribe(),
+        }
+    }
+
+    /// Execute a command and count it in the statistics.
+    ///
+    /// Failures are reported as `Response::Error`, never as a Rust error.
+    pub async fn execute(&self, command: Command) -> Response {
+        self.stats.increment_command_counter(&command);
+        let name = command.name();
+        let started = Instant::now();
+        let response = self.run(command).await;
+        self.stats.commands.observe(name, started.elapsed(), response.is_error());
+        response
+    }
+
+    /// Run a command and honour its write concern.
+    async fn run(&self, command: Command) -> Response {
+        // Split off an optional `WAIT <replicas> <timeout>` write concern
+        let (command, write_concern) = match command {
+            Command::Wait { command, replicas, timeout_ms } => (*command, Some((replicas, timeout_ms))),
+            command => (command, None),
+        };
+
+        if let Some(message) = Self::invalid_input(&command) {
+            return Response::error(ErrorCode::Syntax, message);
+        }
+        // A write concern cannot be met without replication; refuse it before
+        // the write runs so a client retrying the error does not apply it twice
+        if matches!(write_concern, Some((replicas, _)) if replicas > 0) && self.replicator.is_none() {
+            return Response::error(ErrorCode::Unavailable, "WAIT requires replication to be enabled");
+        }
+
+        // Strings and CRDTs share one keyspace: a string write must not
+        // shadow a live set or hash
+        let keys = Self::value_write_keys(&command);
+        if !matches!(command, Command::Delete { .. }) && keys.iter().any(|key| self.live_crdt(key).is_some()) {
+            return Response::error(ErrorCode::WrongType, WRONGTYPE);
+        }
+
+        // Peers' writes to the same keys wait until this one is applied and
+        // published, so the conflict check holds when the store is written
+        let locks = match &self.replicator {
+            Some(r) if command.is_replicated_write() => r.lock_keys(keys.iter().map(String::as_str)).await,
+            _ => Vec::new(),
+        };
+        if let Err(e) = self.admit(&command) {
+            return Response::Error(e.into());
+        }
+
+        let mut publishes = Vec::new();
+        let response = self.apply(command, &mut publishes).await;
+        let op_ids = self.publish(publishes).await;
+        drop(locks);
+
+        // Honour the write concern; the write itself is already applied locally
+        match (write_concern, &self.replicator) {
+            (Some((replicas, timeout_ms)), Some(r)) if replicas > 0 && !response.is_error() => {
+                let acked = r.wait_for_acks(&op_ids, replicas, Duration::from_millis(timeout_ms)).await;
+                if acked < replicas {
+                    return Response::error(ErrorCode::Timeout, format!(
+                        "WAIT timeout: acknowledged by {} of {} replicas",
+                        acked, replicas
+                    ));
+                }
+                response
+            }
+            _ => response,
+        }
+    }
+
+    /// Refuse replicated writes up front while the outbound queue is full, so
+    /// a write is never applied locally without being queued, and (under
+    /// non-LWW conflict policies) writes that would lose on peers.
+    fn admit(&self, command: &Command) -> anyhow::Result<()> {
+        let Some(r) = &self.replicator else { return Ok(()) };
+        if !command.is_replicated_write() {
+            return Ok(());
+        }
+        r.check_capacity()?;
+        if r.checks_local_writes() {
+            for (key, value) in Self::write_candidates(&*self.store, command) {
+                r.admit(&key, self.store.get(&key).as_deref(), value.as_deref())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a command against the store, collecting the writes to publish.
+    async fn apply(&self, command: Command, publishes: &mut Vec<Publish>) -> Response {
+        let store = &self.store;
+        match command {
+            Command::Get { key } => {
+                // Concurrent values kept by the keep_siblings policy
+                let siblings = self.replicator.as_ref().map(|r| r.siblings(&key)).unwrap_or_default();
+                if !siblings.is_empty() {
+                    Response::Array(ArrayKind::Siblings, siblings.into_iter().map(Response::Value).collect())
+                } else {
+                    match store.get(&key) {
+                        Some(value) => Response::Value(value),
+                        None => Response::NotFound,
+                    }
+                }
+            }
+            Command::Set { key, value } => match store.set(key.clone(), value.clone()) {
+                Ok(_) => {
+                    publishes.push(Publish::Set(key, value));
+                    Response::Ok
+                }
+                Err(e) => Response::Error(e.into()),
+            },
+            Command::Delete { key } => {
+                store.delete(&key);
+                publishes.push(Publish::Delete(key.clone()));
+                // A set or hash is cleared by tombstoning everything observed,
+                // so concurrent adds on other nodes still win
+                let cleared = match self.live_crdt(&key) {
+                    Some(CrdtValue::Set(mut set)) => set.clear().map(|d| (OpKind::SRem, CrdtValue::Set(d))),
+                    Some(CrdtValue::Map(mut map)) => {
+                        map.clear(now_nanos(), &self.node_id).map(|d| (OpKind::HDel, CrdtValue::Map(d)))
+                    }
+                    None => None,
+                };
+                if let Some((op, delta)) = cleared {
+                    if let Err(e) = store.merge_crdt(&key, &delta) {
+                        return Response::Error(e.into());
+                    }
+                    publishes.push(Publish::Crdt(op, key, delta));
+                }
+                Response::Ok
+            }
+            // Read-modify-write commands are single atomic engine operations;
+            // the executor only reports and publishes the result
+            Command::Increment { key, amount } => match store.increment(&key, amount) {
+                Ok(new_value) => {
+                    publishes.push(Publish::Incr(key, new_value));
+                    Response::Integer(new_value)
+                }
+                Err(e) => Response::Error(e.into()),
+            },
+            Command::Decrement { key, amount } => match store.decrement(&key, amount) {
+                Ok(new_value) => {
+                    publishes.push(Publish::Decr(key, new_value));
+                    Response::Integer(new_value)
+                }
+                Err(e) => Response::Error(e.into()),
+            },
+            // An empty APPEND/PREPEND changes nothing and just reads the value
+            Command::Append { key, value } if value.is_empty() => match store.get(&key) {
+                Some(current_value) => Response::Value(current_value),
+                None => Response::error(ErrorCode::NotFound, "Key not found"),
+            },
+            Command::Append { key, value } => match store.append(&key, &value) {
+                Ok(new_value) => {
+                    publishes.push(Publish::Append(key, new_value.clone()));
+                    Response::Value(new_value)
+                }
+                Err(e) => Response::Error(e.into()),
+            },
+            Command::Prepend { key, value } if value.is_empty() => match store.get(&key) {
+                Some(current_value) => Response::Value(current_value),
+                None => Response::error(ErrorCode::NotFound, "Key not found"),
+            },
+            Command::Prepend { key, value } => match store.prepend(&key, &value) {
+                Ok(new_value) => {
+                    publishes.push(Publish::Prepend(key, new_value.clone()));
+                    Response::Value(new_value)
+                }
+                Err(e) => Response::Error(e.into()),
+            },
+            Command::MultiGet { keys } => {
+                let values: Vec<(String, Response)> = keys
+                    .into_iter()
+                    .map(|key| {
+                        let value = store.get(&key).map_or(Response::NotFound, Response::Value);
+                        (key, value)
+                    })
+                    .collect();
+
+                if values.iter().any(|(_, value)| *value != Response::NotFound) {
+                    Response::Map(MapKind::Values, values)
+                } else {
+                    Response::NotFound
+                }
+            }
+            Command::MultiSet { pairs } => {
+                for (key, value) in pairs {
+                    if let Err(e) = store.set(key.clone(), value.clone()) {
+                        return Response::Error(e.into());
+                    }
+                    publishes.push(Publish::Set(key, value));
+                }
+                Response::Ok
+            }
+            Command::SetAdd { key, members } => {
+                // A cleared hash stays a hash, so every node agrees on the type
+                let current = match store.get_crdt(&key) {
+                    Some(CrdtValue::Set(set)) => Some(set),
+                    Some(_) => None,
+                    None if store.get(&key).is_some() => None,
+                    None => Some(OrSet::new()),
+                };
+                let Some(mut set) = current else { return Response::error(ErrorCode::WrongType, WRONGTYPE) };
+                // Only members not already present get a fresh add tag
+                let mut delta = OrSet::new();
+                let mut added = 0;
+                for member in &members {
+                    if !set.contains(member) {
+                        delta.merge(&set.add(member, new_tag()));
+                        added += 1;
+                    }
+                }
+                if added == 0 {
+                    return Response::Integer(0);
+                }
+                let delta = CrdtValue::Set(delta);
+                match store.merge_crdt(&key, &delta) {
+                    Ok(_) => {
+                        publishes.push(Publish::Crdt(OpKind::SAdd, key, delta));
+                        Response::Integer(added)
+                    }
+                    Err(e) => Response::Error(e.into()),
+                }
+            }
+            Command::SetRemove { key, members } => match self.live_crdt(&key) {
+                None if store.get(&key).is_some() => Response::error(ErrorCode::WrongType, WRONGTYPE),
+                None => Response::Integer(0),
+                Some(CrdtValue::Set(mut set)) => {
+                    // Tombstone only the add tags observed locally
+                    let mut delta = OrSet::new();
+                    let mut removed = 0;
+                    for member in &members {
+                        if let Some(d) = set.remove(member) {
+                            delta.merge(&d);
+                            removed += 1;
+                        }
+                    }
+                    if removed == 0 {
+                        return Response::Integer(0);
+                    }
+                    let delta = CrdtValue::Set(delta);
+                    match store.merge_crdt(&key, &delta) {
+                        Ok(_) => {
+                            publishes.push(Publish::Crdt(OpKind::SRem, key, delta));
+                            Response::Integer(removed)
+                        }
+                        Err(e) => Response::Error(e.into()),
+                    }
+                }
+                Some(_) => Response::error(ErrorCode::WrongType, WRONGTYPE),
+            },
+            Command::SetMembers { key } => match self.live_crdt(&key) {
+                Some(CrdtValue::Set(set)) => {
+                    Response::Array(ArrayKind::Members, set.members().into_iter().map(Response::Value).collect())
+                }
+                Some(_) => Response::error(ErrorCode::WrongType, WRONGTYPE),
+                None if store.get(&key).is_some() => Response::error(ErrorCode::WrongType, WRONGTYPE),
+                None => Response::NotFound,
+            },
+            Command::HashSet { key, field, value } => {
+                let current = match store.get_crdt(&key) {
+                    Some(CrdtValue::Map(map)) => Some(map),
+                    Some(_) => None,
+                    None if store.get(&key).is_some() => None,
+                    None => Some(LwwMap::new()),
+                };
+                let Some(mut map) = current else { return Response::error(ErrorCode::WrongType, WRONGTYPE) };
+                let delta = CrdtValue::Map(map.set(&field, &value, now_nanos(), &self.node_id));
+                match store.merge_crdt(&key, &delta) {
+                    Ok(_) => {
+                        publishes.push(Publish::Crdt(OpKind::HSet, key, delta));
+                        Response::Ok
+                    }
+                    Err(e) => Response::Error(e.into()),
+                }
+            }
+            Command::HashGet { key, field } => match self.live_crdt(&key) {
+                Some(CrdtValue::Map(map)) => match map.get(&field) {
+                    Some(value) => Response::Value(value.to_string()),
+                    None => Response::NotFound,
+                },
+                Some(_) => Response::error(ErrorCode::WrongType, WRONGTYPE),
+                None if store.get(&key).is_some() => Response::error(ErrorCode::WrongType, WRONGTYPE),
+                None => Response::NotFound,
+            },
+            Command::HashDelete { key, field } => match self.live_crdt(&key) {
+                Some(CrdtValue::Map(mut map)) => match map.delete(&field, now_nanos(), &self.node_id) {
+                    Some(delta) => {
+                        let delta = CrdtValue::Map(delta);
+                        match store.merge_crdt(&key, &delta) {
+                            Ok(_) => {
+                                publishes.push(Publish::Crdt(OpKind::HDel, key, delta));
+                                Response::Ok
+                            }
+                            Err(e) => Response::Error(e.into()),
+                        }
+                    }
+                    None => Response::NotFound,
+                },
+                Some(_) => Response::error(ErrorCode::WrongType, WRONGTYPE),
+                None if store.get(&key).is_some() => Response::error(ErrorCode::WrongType, WRONGTYPE),
+                None => Response::NotFound,
+            },
+            Command::Truncate => match store.truncate() {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            },
+            Command::Stats => {
+                let mut fields = self.stats.fields();
+                if let Some(r) = &self.replicator {
+                    fields.extend(r.metrics().stats_fields());
+                }
+                Response::Map(MapKind::Stats, fields)
+            }
+            Command::Info => Response::Map(MapKind::Info, self.info()),
+            Command::Ping => Response::Status("PONG".to_string()),
+            Command::ReplStatus => {
+                let mut fields = counters([("enabled", self.replicator.is_some() as u64)]);
+                if let Some(r) = &self.replicator {
+                    fields.extend(r.status());
+                }
+                Response::Map(MapKind::ReplStatus, fields)
+            }
+            Command::ReplInfo => {
+                let mut fields = counters([("enabled", self.replicator.is_some() as u64)]);
+                if let Some(r) = &self.replicator {
+                    fields.extend(r.metrics().stats_fields());
+                    fields.extend(r.metrics().source_fields());
+                }
+                Response::Map(MapKind::ReplInfo, fields)
+            }
+            // Server version from Cargo.toml
+            Command::Version => Response::Status(format!("VERSION {}", env!("CARGO_PKG_VERSION"))),
+            // Force sync to disk if the storage engine supports it
+            Command::Flush => match store.sync() {
+                Ok(_) => Response::Ok,
+                Err(e) => Response::Error(e.into()),
+            },
+            Command::Unwatch => Response::error(ErrorCode::Syntax, "UNWATCH without an active WATCH"),
+            Command::Replay { from_seq, count, format } => {
+                let log = &self.changelog;
+                match (log.first_seq(), log.range(from_seq, count)) {
+                    (Ok(first_seq), Ok(entries)) => {
+                        // first_seq > from_seq tells the client entries were trimmed
+                        let mut fields = counters([
+                            ("first_seq", first_seq.unwrap_or(log.next_seq())),
+                            ("next_seq", log.next_seq()),
+                            ("count", entries.len() as u64),
+                        ]);
+                        let lines = entries
+                            .iter()
+                            .map(|(seq, ev)| {
+                                let line = render_event(ev, format, Some(*seq));
+                                Response::Value(line.trim_end_matches("\r\n").to_string())
+                            })
+                            .collect();
+                        fields.push(("entries".to_string(), Response::Array(ArrayKind::Entries, lines)));
+                        Response::Map(MapKind::Replay, fields)
+                    }
+                    (Err(e), _) | (_, Err(e)) => Response::Error(e.into()),
+                }
+            }
+            Command::Publish { channel, message } => {
+                let delivered = self.pubsub.publish(&channel, &message);
+                if let Some(r) = &self.replicator {
+                    if let Err(e) = r.publish_message(&channel, &message).await {
+                        warn!("Failed to forward message on '{}' to peers: {}", channel, e);
+                    }
+                }
+                Response::Integer(delivered as i64)
+            }
+            // Nothing is subscribed outside subscribed mode
+            Command::Unsubscribe { .. } | Command::PUnsubscribe { .. } => Response::Ok,
+            Command::Watch { .. } => Response::error(ErrorCode::Unavailable, "WATCH requires a streaming connection"),
+            Command::Subscribe { .. } | Command::PSubscribe { .. } => {
+                Response::error(ErrorCode::Unavailable, "SUBSCRIBE requires a streaming connection")
+            }
+            Command::Shutdown => Response::error(ErrorCode::Unavailable, "SHUTDOWN is not available here"),
+            Command::Format { .. } => Response::error(ErrorCode::Unavailable, "FORMAT applies to a client connection"),
+            Command::Wait { .. } => Response::error(ErrorCode::Syntax, "WAIT only applies to write commands"),
+        }
+    }
+
+    /// Fields of the INFO reply.
+    fn info(&self) -> Vec<(String, Response)> {
+        // Server version from Cargo.toml
+        let mut info = vec![("version".to_string(), Response::Value(env!("CARGO_PKG_VERSION").to_string()))];
+
+        // Server uptime
+        info.extend(counters([("uptime_seconds", self.stats.uptime_seconds())]));
+        info.push(("uptime".to_string(), Response::Value(self.stats.uptime_human())));
+
+        // Current time
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs();
+        info.extend(counters([
+            ("server_time_unix", now),
+            // Key count
+            ("db_keys", self.store.count_keys().unwrap_or(0)),
+        ]));
+
+        // Replication health
+        match &self.replicator {
+            Some(r) => {
+                let totals = r.metrics().totals();
+                info.extend(counters([
+                    ("replication_enabled", 1),
+                    ("replication_sources", r.metrics().sources().len() as u64),
+                    ("replication_last_applied_ts", totals.last_applied_ts),
+                    ("replication_lag_ms", totals.lag_ms),
+                ]));
+            }
+            None => info.extend(counters([("replication_enabled", 0)])),
+        }
+        info.extend(counters([("pubsub_subscribers", self.pubsub.subscriber_count() as u64)]));
+        info
+    }
+
+    /// Root hash of a Merkle tree over the whole dataset (hex, or missing
+    /// for an empty store) and the number of keys it covers.
+    ///
+    /// The tree is rebuilt from the store on every call, so this is O(n log n).
+    pub fn merkle_root(&self) -> Response {
+        let mut tree = MerkleTree::new();
+        let mut keys = 0;
+        for key in self.store.keys() {
+            if let Some(value) = self.store.get(&key) {
+                tree.insert(&key, &value);
+                keys += 1;
+            }
+        }
+        for key in self.store.crdt_keys() {
+            if let Some(value) = self.live_crdt(&key) {
+                tree.insert_crdt(&key, &value);
+                keys += 1;
+            }
+        }
+        let root = match tree.get_root_hash() {
+            Some(hash) => Response::Value(hash.iter().map(|b| format!("{:02x}", b)).collect()),
+            None => Response::NotFound,
+        };
+        let mut fields = vec![("root".to_string(), root)];
+        fields.extend(counters([("keys", keys)]));
+        Response::Map(MapKind::Merkle, fields)
+    }
+
+    /// Publish applied writes to the replicator.
+    ///
+    /// # Returns
+    /// * `Vec<OpId>` - Ids of the queued events, for the write concern
+    async fn publish(&self, publishes: Vec<Publish>) -> Vec<OpId> {
+        let mut op_ids = Vec::new();
+        let Some(r) = &self.replicator else {
+            // Without replication the executor records its own writes
+            for p in publishes {
+                let ev = p.into_event(&self.node_id);
+                if let Err(e) = self.changelog.append(&ev) {
+                    warn!("Failed to record change log entry: {}", e);
+                }
+                let _ = self.events.send(ev); // ignore errors if nobody watches
+            }
+            return op_ids;
+        };
+        for p in publishes {
+            let result = match p {
+                Publish::Set(k, v) => r.publish_set(&k, &v).await,
+                Publish::Delete(k) => r.publish_delete(&k).await,
+                Publish::Incr(k, nv) => r.publish_incr(&k, nv).await,
+                Publish::Decr(k, nv) => r.publish_decr(&k, nv).await,
+                Publish::Append(k, nv) => r.publish_append(&k, &nv).await,
+                Publish::Prepend(k, nv) => r.publish_prepend(&k, &nv).await,
+                Publish::Crdt(op, k, delta) => r.publish_crdt(op, &k, &delta).await,
+            };
+            match result {
+                Ok(Some(op_id)) => op_ids.push(op_id),
+                Ok(None) => {}
+                Err(e) => warn!("Failed to queue replication event: {}", e),
+            }
+        }
+        op_ids
+    }
+
+    /// The set or hash stored under `key`; one emptied by DEL counts as absent.
+    fn live_crdt(&self, key: &str) -> Option<CrdtValue> {
+        self.store.get_crdt(key).filter(|value| !value.is_empty())
+    }
+
+    /// Why a command's keys or values cannot be stored, if they cannot.
+    ///
+    /// The text protocol splits on whitespace and ends commands at line
+    /// breaks, so keys (and set members and hash fields) must not contain
+    /// whitespace and values must not contain CR or LF. Front ends that
+    /// accept arbitrary strings, like the HTTP API, rely on this check.
+    fn invalid_input(command: &Command) -> Option<&'static str> {
+        let (names, values): (Vec<&String>, Vec<&String>) = match command {
+            Command::Get { key }
+            | Command::Delete { key }
+            | Command::Increment { key, .. }
+            | Command::Decrement { key, .. }
+            | Command::SetMembers { key } => (vec![key], Vec::new()),
+            Command::Set { key, value } | Command::Append { key, value } | Command::Prepend { key, value } => {
+                (vec![key], vec![value])
+            }
+            Command::MultiGet { keys } => (keys.iter().collect(), Vec::new()),
+            Command::MultiSet { pairs } => pairs.iter().map(|(key, value)| (key, value)).unzip(),
+            Command::SetAdd { key, members } | Command::SetRemove { key, members } => {
+                (std::iter::once(key).chain(members).collect(), Vec::new())
+            }
+            Command::HashGet { key, field } | Command::HashDelete { key, field } => (vec![key, field], Vec::new()),
+            Command::HashSet { key, field, value } => (vec![key, field], vec![value]),
+            _ => return None,
+        };
+        if names.iter().any(|name| name.is_empty() || name.contains(char::is_whitespace)) {
+            return Some("keys, members and fields must be non-empty and contain no whitespace");
+        }
+        if values.iter().any(|value| value.contains(['\r', '\n'])) {
+            return Some("values must not contain CR or LF");
+        }
+        None
+    }
+
+    /// Keys a command would write a string value to (or delete).
+    fn value_write_keys(command: &Command) -> Vec<String> {
+        match command {
+            Command::Set { key, .. }
+            | Command::Delete { key }
+            | Command::Increment { key, .. }
+            | Command::Decrement { key, .. }
+            | Command::Append { key, .. }
+            | Command::Prepend { key, .. } => vec![key.clone()],
+            Command::MultiSet { pairs } => pairs.iter().map(|(key, _)| key.clone()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Resulting value of each key a plain-value write would change (`None`
+    /// for deletions), used to check the write against its conflict policy.
+    /// Writes that will fail anyway (e.g. INC on a non-number) yield nothing.
+    fn write_candidates(store: &dyn KVEngineStoreTrait, command: &Command) -> Vec<(String, Option<String>)> {
+        let numeric = |key: &str, delta: i64| {
+            let current = store.get(key).map(|v| v.parse::<i64>()).unwrap_or(Ok(0)).ok()?;
+            current.checked_add(delta).map(|n| vec![(key.to_string(), Some(n.to_string()))])
+        };
+        match command {
+            Command::Set { key, value } => vec![(key.clone(), Some(value.clone()))],
+            Command::MultiSet { pairs } => pairs.iter().map(|(k, v)| (k.clone(), Some(v.clone()))).collect(),
+            Command::Delete { key } => vec![(key.clone(), None)],
+            Command::Increment { key, amount } => numeric(key, amount.unwrap_or(1)).unwrap_or_default(),
+            Command::Decrement { key, amount } => {
+                amount.unwrap_or(1).checked_neg().and_then(|delta| numeric(key, delta)).unwrap_or_default()
+            }
+            Command::Append { key, value } => {
+                vec![(key.clone(), Some(format!("{}{}", store.get(key).unwrap_or_default(), value)))]
+            }
+            Command::Prepend { key, value } => {
+                vec![(key.clone(), Some(format!("{}{}", value, store.get(key).unwrap_or_default())))]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Current time in nanoseconds, the timestamp of hash field writes.
+fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Protocol, ResponseFormat};
+    use crate::store::RwLockEngine;
+    use std::sync::atomic::Ordering;
+
+    fn executor() -> CommandExecutor {
+        let store: Arc<dyn KVEngineStoreTrait> = Arc::new(RwLockEngine::new("").unwrap());
+        let changelog = Arc::new(ChangeLog::open("", 100).unwrap());
+        CommandExecutor::new(store, None, changelog, Arc::new(ServerStats::new()), Arc::new(PubSub::new()), "node".to_string())
+    }
+
+    async fn run(executor: &CommandExecutor, line: &str) -> Response {
+        executor.execute(Protocol::new().parse(line).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn watch_sees_local_writes_without_replication() {
+        let ex = executor();
+        let mut events = ex.watch();
+        run(&ex, "SET user:1 alice").await;
+        run(&ex, "INC hits 2").await;
+        run(&ex, "SADD tags red").await;
+        run(&ex, "DEL user:1").await;
+        run(&ex, "GET user:1").await;
+
+        let seen: Vec<(OpKind, String, Option<Vec<u8>>)> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|ev| (ev.op, ev.key, ev.val.filter(|_| !ev.op.is_crdt())))
+            .collect();
+        assert_eq!(
+            seen,
+            vec![
+                (OpKind::Set, "user:1".to_string(), Some(b"alice".to_vec())),
+                (OpKind::Incr, "hits".to_string(), Some(b"2".to_vec())),
+                (OpKind::SAdd, "tags".to_string(), None),
+                (OpKind::Del, "user:1".to_string(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_reads_local_writes_without_replication() {
+        let ex = executor();
+        run(&ex, "SET a 1").await;
+        run(&ex, "DEL a").await;
+        run(&ex, "GET a").await;
+        let replay = run(&ex, "REPLAY 1").await.render(ResponseFormat::Text);
+        assert!(replay.starts_with("REPLAY\r\nfirst_seq:0\r\nnext_seq:2\r\ncount:1\r\n"), "{}", replay);
+        assert!(replay.contains("ENTRY 1 del node "), "{}", replay);
+    }
+
+    #[tokio::test]
+    async fn executes_commands_without_a_connection() {
+        let ex = executor();
+        assert_eq!(run(&ex, "GET k").await, Response::NotFound);
+        assert_eq!(run(&ex, "SET k v").await, Response::Ok);
+        assert_eq!(run(&ex, "GET k").await, Response::Value("v".to_string()));
+        assert_eq!(run(&ex, "APPEND k w").await, Response::Value("vw".to_string()));
+        assert_eq!(run(&ex, "INC n 5").await, Response::Integer(5));
+        assert_eq!(run(&ex, "DEC n").await, Response::Integer(4));
+        assert!(run(&ex, "INC k").await.is_error());
+        assert_eq!(run(&ex, "MGET k x").await.render(ResponseFormat::Text), "VALUES 1\r\nk vw\r\nx NOT_FOUND\r\n");
+
+        assert_eq!(run(&ex, "SADD s a b").await, Response::Integer(2));
+        assert_eq!(run(&ex, "SADD k a").await, Response::error(ErrorCode::WrongType, WRONGTYPE));
+        assert_eq!(run(&ex, "HGET s f").await, Response::error(ErrorCode::WrongType, WRONGTYPE));
+        assert_eq!(run(&ex, "HSET h f v").await, Response::Ok);
+        assert_eq!(run(&ex, "HGET h f").await, Response::Value("v".to_string()));
+
+        assert_eq!(ex.stats().total_commands.load(Ordering::Relaxed), 13);
+        let (_, inc) = ex.stats().commands.snapshot().into_iter().find(|(name, _)| *name == "inc").unwrap();
+        assert_eq!((inc.count.load(Ordering::Relaxed), inc.errors.load(Ordering::Relaxed)), (2, 1));
+        assert_eq!(inc.latency.cumulative().last(), Some(&2));
+    }
+
+    #[tokio::test]
+    async fn strings_and_crdts_share_one_keyspace() {
+        let ex = executor();
+        let wrongtype = Response::error(ErrorCode::WrongType, WRONGTYPE);
+        run(&ex, "SADD s a b").await;
+        run(&ex, "HSET h f v").await;
+        for write in ["SET s v", "MSET k v s v", "INC h", "APPEND h x", "PREPEND s x"] {
+            assert_eq!(run(&ex, write).await, wrongtype, "{}", write);
+        }
+        assert_eq!(run(&ex, "GET k").await, Response::NotFound);
+
+        // DEL clears sets and hashes, after which the key takes a string or
+        // its old collection type again
+        assert_eq!(run(&ex, "DEL s").await, Response::Ok);
+        assert_eq!(run(&ex, "DEL h").await, Response::Ok);
+        assert_eq!(run(&ex, "SMEMBERS s").await, Response::NotFound);
+        assert_eq!(run(&ex, "HGET h f").await, Response::NotFound);
+        assert_eq!(run(&ex, "SET s v").await, Response::Ok);
+        assert_eq!(run(&ex, "SADD h a").await, wrongtype);
+        assert_eq!(run(&ex, "HSET h f w").await, Response::Ok);
+        assert_eq!(run(&ex, "HGET h f").await, Response::Value("w".to_string()));
+    }
+
+    #[tokio::test]
+    async fn rejects_keys_and_values_the_protocol_cannot_carry() {
+        let ex = executor();
+        let set = |key: &str, value: &str| Command::Set { key: key.to_string(), value: value.to_string() };
+        for command in [
+            set("a b", "v"),
+            set("", "v"),
+            set("k", "two\r\nlines"),
+            Command::MultiGet { keys: vec!["ok".to_string(), "new\nline".to_string()] },
+            Command::SetAdd { key: "s".to_string(), members: vec!["a b".to_string()] },
+        ] {
+            match ex.execute(command).await {
+                Response::Error(e) => assert_eq!(e.code, ErrorCode::Syntax),
+                other => panic!("expected a syntax error, got {:?}", other),
+            }
+        }
+        assert_eq!(ex.execute(set("k", "spaces are fine")).await, Response::Ok);
+    }
+
+    #[tokio::test]
+    async fn errors_carry_codes_from_the_engine() {
+        let ex = executor();
+        let code = |response: Response| match response {
+            Response::Error(e) => e.code,
+            other => panic!("expected an error, got {:?}", other),
+        };
+        run(&ex, "SET text hello").await;
+        run(&ex, "SET big 9223372036854775807").await;
+        assert_eq!(code(run(&ex, "INC text").await), ErrorCode::WrongType);
+        assert_eq!(code(run(&ex, "INC big").await), ErrorCode::Overflow);
+        assert_eq!(code(run(&ex, "DEC n -9223372036854775808").await), ErrorCode::Overflow);
+        let empty_append = Command::Append { key: "missing".to_string(), value: String::new() };
+        assert_eq!(code(ex.execute(empty_append).await), ErrorCode::NotFound);
+        assert_eq!(code(run(&ex, "UNWATCH").await), ErrorCode::Syntax);
+        // A failed write leaves the value alone
+        assert_eq!(run(&ex, "GET big").await, Response::Value("9223372036854775807".to_string()));
+        assert_eq!(ex.stats().numeric_commands.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn write_concern_and_session_commands_without_replication() {
+        let ex = executor();
+        assert_eq!(
+            run(&ex, "WAIT 1 10ms SET k v").await,
+            Response::error(ErrorCode::Unavailable, "WAIT requires replication to be enabled")
+        );
+        // The write is refused before it runs, so retrying it is safe
+        assert_eq!(run(&ex, "GET k").await, Response::NotFound);
+        assert!(run(&ex, "WAIT 1 10ms INC n").await.is_error());
+        assert_eq!(run(&ex, "GET n").await, Response::NotFound);
+        assert_eq!(run(&ex, "WAIT 0 10ms SET k w").await, Response::Ok);
+
+        assert!(run(&ex, "WATCH *").await.is_error());
+        assert!(run(&ex, "SUBSCRIBE news").await.is_error());
+        assert_eq!(run(&ex, "UNSUBSCRIBE news").await, Response::Ok);
+        assert_eq!(run(&ex, "PING").await.render(ResponseFormat::Text), "PONG\r\n");
+    }
+}
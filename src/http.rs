@@ -0,0 +1,597 @@
+//! # HTTP/JSON API
+//!
+//! An optional HTTP/1.1 listener (`[http]` in the configuration) for clients
+//! that cannot speak the TCP protocol. Requests are translated into `Command`s
+//! and run by the same `CommandExecutor` as the TCP server, so statistics,
+//! replication and error codes behave identically.
+//!
+//! ## Endpoints
+//!
+//! | Method   | Path           | Body                       | Command |
+//! |----------|----------------|----------------------------|---------|
+//! | `GET`    | `/kv/{key}`    |                            | `GET`   |
+//! | `PUT`    | `/kv/{key}`    | `{"value": "..."}`         | `SET`   |
+//! | `DELETE` | `/kv/{key}`    |                            | `DELETE`|
+//! | `POST`   | `/kv/_mget`    | `{"keys": ["a", "b"]}`     | `MGET`  |
+//! | `GET`    | `/stats`       |                            | `STATS` |
+//! | `GET`    | `/merkle/root` |                            |         |
+//! | `GET`    | `/metrics`     |                            |         |
+//!
+//! Keys are percent-decoded from the path. Response bodies use the JSON
+//! encoding of `FORMAT JSON`:
+//! ```text
+//! {"type":"value","value":"alice"}
+//! {"type":"error","code":"ERR_WRONGTYPE","message":"..."}
+//! ```
+//! `/metrics` is the Prometheus text exposition (see `metrics`).
+//!
+//! The status code follows the outcome: `404` for missing keys and unknown
+//! paths, `400`/`409`/`429`/`503`/`504` for errors by code, `500` otherwise.
+//!
+//! Connections are kept alive unless the client asks otherwise; chunked
+//! request bodies are not supported.
+//!
+//! The `[limits]` of the TCP server apply here too: HTTP connections count
+//! toward `max_connections` (excess ones get `503`), every request takes a
+//! token from the per-IP rate limiter (`429` when throttled), a request must
+//! arrive within `read_timeout_seconds` of its first byte (`408`), and idle
+//! connections close after `idle_timeout_seconds`.
+
+use crate::config::{HttpConfig, LimitsConfig};
+use crate::error::ErrorCode;
+use crate::executor::CommandExecutor;
+use crate::metrics;
+use crate::protocol::{Command, MapKind, Response};
+use crate::ratelimit::RateLimiter;
+use crate::server::Shutdown;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Content type of the Prometheus text exposition format.
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Upper bound on the request line and headers.
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+
+/// Body of `PUT /kv/{key}`.
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+/// Body of `POST /kv/_mget`.
+#[derive(Deserialize)]
+struct MultiGetBody {
+    keys: Vec<String>,
+}
+
+/// Limits applied to every connection.
+#[derive(Clone)]
+struct ConnectionLimits {
+    max_body_bytes: usize,
+    /// Per-IP request rate limiter, shared with the TCP server
+    limiter: Option<Arc<RateLimiter>>,
+    /// Deadline for the rest of a request once its first byte arrived
+    read_timeout: Option<Duration>,
+    /// Deadline for the next request on a kept-alive connection
+    idle_timeout: Option<Duration>,
+}
+
+/// A parsed HTTP request.
+#[derive(Debug)]
+struct Request {
+    method: String,
+    /// Path without the query string
+    path: String,
+    body: Vec<u8>,
+    /// Whether the connection stays open after the response
+    keep_alive: bool,
+}
+
+/// Why a request could not be read.
+enum ReadError {
+    /// Malformed or unsupported request; answered, then the connection closes
+    Reply(u16, Response),
+    /// Connection closed or failed
+    Io(io::Error),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+/// Accept HTTP connections until shutdown is requested, then wait for the
+/// open connections to finish the request they are serving.
+///
+/// # Arguments
+/// * `limits` - Connection limits and timeouts shared with the TCP server
+/// * `limiter` - The TCP server's per-IP rate limiter, if rate limiting is enabled
+pub async fn serve(
+    listener: TcpListener,
+    executor: Arc<CommandExecutor>,
+    config: HttpConfig,
+    limits: LimitsConfig,
+    limiter: Option<Arc<RateLimiter>>,
+    shutdown: Shutdown,
+) {
+    let timeout = |seconds: u64| (seconds > 0).then(|| Duration::from_secs(seconds));
+    let connection_limits = ConnectionLimits {
+        max_body_bytes: config.max_body_bytes,
+        limiter,
+        read_timeout: timeout(limits.read_timeout_seconds),
+        idle_timeout: timeout(limits.idle_timeout_seconds),
+    };
+    let mut connections = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.requested() => break,
+        };
+        while connections.try_join_next().is_some() {}
+        match accepted {
+            Ok((mut socket, addr)) => {
+                let stats = Arc::clone(executor.stats());
+                // TCP and HTTP connections share max_connections
+                let open = stats.active_connections.load(Ordering::Relaxed)
+                    + stats.active_http_connections.load(Ordering::Relaxed);
+                if limits.max_connections > 0 && open >= limits.max_connections as u64 {
+                    warn!("Refusing HTTP connection from {}: max_connections ({}) reached", addr, limits.max_connections);
+                    stats.rejected_connections.fetch_add(1, Ordering::Relaxed);
+                    tokio::spawn(async move {
+                        let refusal = Response::error(ErrorCode::Limit, "max connections reached").to_json().to_string();
+                        let _ = write_response(&mut socket, 503, JSON_CONTENT_TYPE, &refusal, false).await;
+                        // Drain the unread request so closing does not reset the connection
+                        let _ = socket.shutdown().await;
+                        let _ = tokio::time::timeout(Duration::from_secs(1), tokio::io::copy(&mut socket, &mut tokio::io::sink())).await;
+                    });
+                    continue;
+                }
+                stats.total_http_connections.fetch_add(1, Ordering::Relaxed);
+                stats.active_http_connections.fetch_add(1, Ordering::Relaxed);
+                let executor = Arc::clone(&executor);
+                let shutdown = shutdown.clone();
+                let limits = connection_limits.clone();
+                connections.spawn(async move {
+                    if let Err(e) = handle_connection(socket, addr, executor, limits, shutdown).await {
+                        error!("Error handling HTTP connection from {}: {}", addr, e);
+                    }
+                    stats.active_http_connections.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+            Err(e) => error!("Error accepting HTTP connection: {}", e),
+        }
+    }
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+}
+
+/// Await `future`, or `None` once `limit` (if any) has passed.
+async fn within<T>(limit: Option<Duration>, future: impl Future<Output = T>) -> Option<T> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Serve requests on one connection until it closes.
+async fn handle_connection(
+    socket: TcpStream,
+    addr: SocketAddr,
+    executor: Arc<CommandExecutor>,
+    limits: ConnectionLimits,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(socket);
+    loop {
+        // Wait for the first byte of the next request, then for the rest of it
+        let next = tokio::select! {
+            next = within(limits.idle_timeout, reader.fill_buf()) => next,
+            _ = shutdown.requested() => break,
+        };
+        match next {
+            Some(Ok([])) => break,
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e),
+            None => {
+                info!("Closing idle HTTP connection from {}", addr);
+                break;
+            }
+        }
+        let read = tokio::select! {
+            read = within(limits.read_timeout, read_request(&mut reader, limits.max_body_bytes)) => {
+                read.unwrap_or_else(|| Err(ReadError::Reply(408, Response::error(ErrorCode::Timeout, "request not received in time"))))
+            }
+            _ = shutdown.requested() => break,
+        };
+        let (status, content_type, body, keep_alive) = match read {
+            Ok(Some(request)) if limits.limiter.as_ref().is_some_and(|limiter| !limiter.try_acquire(addr.ip())) => {
+                executor.stats().throttled_commands.fetch_add(1, Ordering::Relaxed);
+                let throttled = Response::error(ErrorCode::Limit, "rate limit exceeded");
+                (429, JSON_CONTENT_TYPE, throttled.to_json().to_string(), request.keep_alive)
+            }
+            Ok(Some(request)) if request.method == "GET" && request.path == "/metrics" => {
+                (200, METRICS_CONTENT_TYPE, metrics::render(&executor).await, request.keep_alive)
+            }
+            Ok(Some(request)) => {
+                let (status, response) = route(&executor, &request.method, &request.path, &request.body).await;
+                (status, JSON_CONTENT_TYPE, response.to_json().to_string(), request.keep_alive)
+            }
+            Ok(None) => break,
+            Err(ReadError::Reply(status, response)) => (status, JSON_CONTENT_TYPE, response.to_json().to_string(), false),
+            Err(ReadError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(ReadError::Io(e)) => return Err(e),
+        };
+        write_response(reader.get_mut(), status, content_type, &body, keep_alive).await?;
+        if !keep_alive {
+            break;
+        }
+    }
+    info!("HTTP client {} disconnected", addr);
+    Ok(())
+}
+
+/// Read the next request, or `None` if the client closed the connection
+/// between requests.
+async fn read_request(reader: &mut BufReader<TcpStream>, max_body_bytes: usize) -> Result<Option<Request>, ReadError> {
+    // A line cut short by the size limit, or by the client closing the connection
+    let truncated = |remaining: u64| match remaining {
+        0 => ReadError::Reply(431, Response::error(ErrorCode::Limit, "request head too large")),
+        _ => ReadError::Io(io::ErrorKind::UnexpectedEof.into()),
+    };
+    let bad_request = |message: &str| ReadError::Reply(400, Response::error(ErrorCode::Syntax, message));
+
+    let mut head = (&mut *reader).take(MAX_HEAD_BYTES);
+    let mut line = Vec::new();
+    if head.read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(truncated(head.limit()));
+    }
+    let request_line = String::from_utf8(line).map_err(|_| bad_request("request line is not UTF-8"))?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(bad_request("malformed request line"));
+    };
+    let method = method.to_string();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let mut keep_alive = version != "HTTP/1.0";
+
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    loop {
+        let mut line = Vec::new();
+        head.read_until(b'\n', &mut line).await?;
+        if !line.ends_with(b"\n") {
+            return Err(truncated(head.limit()));
+        }
+        let header = String::from_utf8_lossy(&line);
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(bad_request("malformed header"));
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value.parse().map_err(|_| bad_request("invalid Content-Length"))?;
+            }
+            "transfer-encoding" => return Err(bad_request("chunked request bodies are not supported")),
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => {}
+        }
+    }
+
+    if content_length > max_body_bytes {
+        return Err(ReadError::Reply(413, Response::error(ErrorCode::Limit, "request body too large")));
+    }
+    if expect_continue && content_length > 0 {
+        reader.get_mut().write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(Request { method, path, body, keep_alive }))
+}
+
+/// Run a request and pick its status code.
+async fn route(executor: &CommandExecutor, method: &str, path: &str, body: &[u8]) -> (u16, Response) {
+    let command = match (method, path) {
+        ("POST", "/kv/_mget") => match serde_json::from_slice::<MultiGetBody>(body) {
+            Ok(MultiGetBody { keys }) if keys.is_empty() => {
+                return (400, Response::error(ErrorCode::Syntax, "keys must not be empty"));
+            }
+            // MGET answers NOT_FOUND when no key exists; list them all as missing instead
+            Ok(MultiGetBody { keys }) => {
+                let response = executor.execute(Command::MultiGet { keys: keys.clone() }).await;
+                return match response {
+                    Response::NotFound => {
+                        let values = keys.into_iter().map(|key| (key, Response::NotFound)).collect();
+                        (200, Response::Map(MapKind::Values, values))
+                    }
+                    response => (status(&response), response),
+                };
+            }
+            Err(e) => return (400, Response::error(ErrorCode::Syntax, format!("invalid body: {}", e))),
+        },
+        ("GET", "/stats") => Command::Stats,
+        ("GET", "/merkle/root") => return (200, executor.merkle_root()),
+        (method, path) if path.starts_with("/kv/") && path.len() > "/kv/".len() => {
+            let key = match percent_decode(&path["/kv/".len()..]) {
+                Some(key) => key,
+                None => return (400, Response::error(ErrorCode::Syntax, "invalid percent-encoding in key")),
+            };
+            match method {
+                "GET" => Command::Get { key },
+                "DELETE" => Command::Delete { key },
+                "PUT" => match serde_json::from_slice::<PutBody>(body) {
+                    Ok(PutBody { value }) => Command::Set { key, value },
+                    Err(e) => return (400, Response::error(ErrorCode::Syntax, format!("invalid body: {}", e))),
+                },
+                _ => return (405, Response::error(ErrorCode::Syntax, format!("method {} not allowed", method))),
+            }
+        }
+        (_, path) => return (404, Response::error(ErrorCode::NotFound, format!("no such endpoint: {}", path))),
+    };
+    let response = executor.execute(command).await;
+    (status(&response), response)
+}
+
+/// HTTP status for a command's response.
+fn status(response: &Response) -> u16 {
+    match response {
+        Response::NotFound => 404,
+        Response::Error(e) => match e.code {
+            ErrorCode::Syntax | ErrorCode::Overflow => 400,
+            ErrorCode::NotFound => 404,
+            ErrorCode::WrongType | ErrorCode::Conflict => 409,
+            ErrorCode::Limit => 429,
+            ErrorCode::ReadOnly | ErrorCode::Unavailable => 503,
+            ErrorCode::Timeout => 504,
+            ErrorCode::Internal => 500,
+        },
+        _ => 200,
+    }
+}
+
+/// Decode `%XX` escapes; `None` for bad escapes or non UTF-8 results.
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Write a response.
+async fn write_response(socket: &mut TcpStream, status: u16, content_type: &str, body: &str, keep_alive: bool) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Internal Server Error",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        if keep_alive { "keep-alive" } else { "close" }
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::changelog::ChangeLog;
+    use crate::pubsub::PubSub;
+    use crate::server::ServerStats;
+    use crate::store::{KVEngineStoreTrait, RwLockEngine};
+
+    fn executor() -> Arc<CommandExecutor> {
+        let store: Arc<dyn KVEngineStoreTrait> = Arc::new(RwLockEngine::new("").unwrap());
+        let changelog = Arc::new(ChangeLog::open("", 100).unwrap());
+        Arc::new(CommandExecutor::new(
+            store,
+            None,
+            changelog,
+            Arc::new(ServerStats::new()),
+            Arc::new(PubSub::new()),
+            "node".to_string(),
+        ))
+    }
+
+    /// Send one request on a fresh connection and return the status and body.
+    async fn exchange(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        );
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut reply = String::new();
+        socket.read_to_string(&mut reply).await.unwrap();
+        let (head, body) = reply.split_once("\r\n\r\n").unwrap();
+        (head.split_whitespace().nth(1).unwrap().parse().unwrap(), body.to_string())
+    }
+
+    /// `exchange` with a JSON response body.
+    async fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, serde_json::Value) {
+        let (status, body) = exchange(addr, method, path, body).await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_the_kv_api_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(serve(listener, executor(), HttpConfig::default(), LimitsConfig::default(), None, shutdown.clone()));
+
+        assert_eq!(request(addr, "GET", "/kv/user%3A1", "").await.0, 404);
+        let (status, body) = request(addr, "PUT", "/kv/user%3A1", r#"{"value":"alice smith"}"#).await;
+        assert_eq!((status, body["type"].as_str()), (200, Some("ok")));
+        let (status, body) = request(addr, "GET", "/kv/user:1", "").await;
+        assert_eq!((status, body["value"].as_str()), (200, Some("alice smith")));
+
+        let (status, body) = request(addr, "POST", "/kv/_mget", r#"{"keys":["user:1","nope"]}"#).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["value"], serde_json::json!({"user:1": "alice smith", "nope": null}));
+        let (_, body) = request(addr, "POST", "/kv/_mget", r#"{"keys":["nope"]}"#).await;
+        assert_eq!(body["value"], serde_json::json!({"nope": null}));
+
+        let (status, body) = request(addr, "GET", "/merkle/root", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["value"]["keys"], 1);
+        assert_eq!(body["value"]["root"].as_str().map(str::len), Some(64));
+
+        assert_eq!(request(addr, "DELETE", "/kv/user:1", "").await.0, 200);
+        assert_eq!(request(addr, "GET", "/kv/user:1", "").await.0, 404);
+        let (status, body) = request(addr, "GET", "/stats", "").await;
+        assert_eq!((status, body["value"]["set_commands"].as_i64()), (200, Some(1)));
+
+        let (status, body) = request(addr, "PUT", "/kv/k", r#"{"val":1}"#).await;
+        assert_eq!((status, body["code"].as_str()), (400, Some("ERR_SYNTAX")));
+        assert_eq!(request(addr, "POST", "/kv/k", "").await.0, 405);
+        assert_eq!(request(addr, "GET", "/nowhere", "").await.0, 404);
+
+        shutdown.trigger();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_prometheus_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, executor(), HttpConfig::default(), LimitsConfig::default(), None, Shutdown::new()));
+
+        request(addr, "PUT", "/kv/k", r#"{"value":"v"}"#).await;
+        request(addr, "GET", "/kv/k", "").await;
+        request(addr, "GET", "/kv/missing", "").await;
+        let (status, metrics) = exchange(addr, "GET", "/metrics", "").await;
+        assert_eq!(status, 200);
+        for line in [
+            "merkle_kv_commands_total{command=\"get\"} 2",
+            "merkle_kv_commands_total{command=\"set\"} 1",
+            "merkle_kv_command_errors_total{command=\"get\"} 0",
+            "merkle_kv_command_duration_seconds_bucket{command=\"get\",le=\"+Inf\"} 2",
+            "merkle_kv_command_duration_seconds_count{command=\"set\"} 1",
+            "merkle_kv_keys 1",
+            "merkle_kv_http_connections_active 1",
+            "merkle_kv_http_connections_total 4",
+            "merkle_kv_replication_enabled 0",
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "missing {:?} in\n{}", line, metrics);
+        }
+        assert!(metrics.contains("# TYPE merkle_kv_command_duration_seconds histogram\n"));
+    }
+
+    #[tokio::test]
+    async fn keeps_connections_alive_between_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, executor(), HttpConfig::default(), LimitsConfig::default(), None, Shutdown::new()));
+
+        let mut socket = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        for _ in 0..2 {
+            socket.get_mut().write_all(b"GET /kv/missing HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+            let mut status_line = String::new();
+            socket.read_line(&mut status_line).await.unwrap();
+            assert!(status_line.starts_with("HTTP/1.1 404"), "{}", status_line);
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                socket.read_line(&mut header).await.unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            socket.read_exact(&mut body).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_connection_limits_rate_limits_and_timeouts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let executor = executor();
+        let limits = LimitsConfig { max_connections: 2, read_timeout_seconds: 1, ..LimitsConfig::default() };
+        let limiter = RateLimiter::new(1, 2).map(Arc::new);
+        tokio::spawn(serve(listener, Arc::clone(&executor), HttpConfig::default(), limits, limiter, Shutdown::new()));
+
+        // A request cut short is answered with 408 once the read timeout passes
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        stalled.write_all(b"GET /kv/k HTTP/1.1\r\n").await.unwrap();
+        let mut reply = String::new();
+        stalled.read_to_string(&mut reply).await.unwrap();
+        assert!(reply.starts_with("HTTP/1.1 408"), "{}", reply);
+
+        // The burst of two requests passes, the third is throttled
+        assert_eq!(exchange(addr, "GET", "/kv/k", "").await.0, 404);
+        assert_eq!(exchange(addr, "GET", "/kv/k", "").await.0, 404);
+        let (status, body) = request(addr, "GET", "/kv/k", "").await;
+        assert_eq!((status, body["code"].as_str()), (429, Some("ERR_LIMIT")));
+        assert_eq!(executor.stats().throttled_commands.load(Ordering::Relaxed), 1);
+
+        // Keys the text protocol could not carry are refused by the executor
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let (status, body) = request(addr, "PUT", "/kv/a%20b", r#"{"value":"v"}"#).await;
+        assert_eq!((status, body["code"].as_str()), (400, Some("ERR_SYNTAX")));
+
+        // Open TCP and HTTP connections together count toward max_connections
+        executor.stats().active_connections.fetch_add(2, Ordering::Relaxed);
+        assert_eq!(exchange(addr, "GET", "/kv/k", "").await.0, 503);
+        assert_eq!(executor.stats().rejected_connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%20b%3Ac").as_deref(), Some("a b:c"));
+        assert_eq!(percent_decode("caf%C3%A9").as_deref(), Some("café"));
+        assert_eq!(percent_decode("bad%2"), None);
+        assert_eq!(percent_decode("bad%zz"), None);
+    }
+}
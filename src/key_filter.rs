@@ -0,0 +1,101 @@
+//! # Key Patterns and Replication Filters
+//!
+//! This module provides glob-style key matching and the include/exclude
+//! filter that decides which keys a node replicates.
+//!
+//! ## Pattern Syntax
+//!
+//! - `*` matches any sequence of characters (including none)
+//! - `?` matches exactly one character
+//! - every other character matches itself
+//!
+//! For example `user:*` matches `user:1` and `user:` but not `users:1`.
+//!
+//! ## Filter Semantics
+//!
+//! A key passes the filter if it matches at least one include pattern (or the
+//! include list is empty) and matches no exclude pattern. Exclusion wins.
+
+/// Match `key` against a glob `pattern` (`*` and `?` wildcards).
+pub fn glob_match(pattern: &str, key: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let k: Vec<char> = key.chars().collect();
+    let (mut pi, mut ki) = (0, 0);
+    // Position of the last `*` seen and the key index it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while ki < k.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == k[ki]) {
+            pi += 1;
+            ki += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ki));
+            pi += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` swallow one more character and retry
+            pi = star + 1;
+            ki = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// Include/exclude key filter applied to published and applied events.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyFilter {
+    /// Patterns a key must match (any of); empty means every key
+    include: Vec<String>,
+    /// Patterns that reject a key even if it is included
+    exclude: Vec<String>,
+}
+
+impl KeyFilter {
+    /// Build a filter from include and exclude pattern lists.
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> Self {
+        Self { include, exclude }
+    }
+
+    /// Whether `key` should be replicated.
+    pub fn allows(&self, key: &str) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|p| glob_match(p, key));
+        included && !self.exclude.iter().any(|p| glob_match(p, key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_match("user:*", "user:1"));
+        assert!(glob_match("user:*", "user:"));
+        assert!(!glob_match("user:*", "users:1"));
+        assert!(glob_match("*:tmp", "cache:a:tmp"));
+        assert!(glob_match("a*b*c", "aXXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXXbYbZ"));
+        assert!(glob_match("k?y", "key"));
+        assert!(!glob_match("k?y", "ky"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    #[test]
+    fn filter_include_exclude() {
+        let all = KeyFilter::default();
+        assert!(all.allows("anything"));
+
+        let f = KeyFilter::new(
+            vec!["user:*".into(), "order:*".into()],
+            vec!["*:tmp".into()],
+        );
+        assert!(f.allows("user:1"));
+        assert!(f.allows("order:9"));
+        assert!(!f.allows("session:1"));
+        assert!(!f.allows("user:tmp"));
+    }
+}
@@ -29,11 +29,24 @@ use std::path::PathBuf;
 // Core modules for the MerkleKV system
 mod config; // Configuration management
 mod protocol; // Command parsing and protocol handling
-mod replication; // MQTT-based replication (stub)
+mod executor; // Command execution shared by all front ends
+mod error; // Error codes reported to clients
+mod replication; // Real-time change replication
 mod server; // TCP server for client connections
+mod http; // Optional HTTP/JSON API
+mod metrics; // Prometheus metrics
 mod store; // Storage engine and Merkle tree
 mod sync; // Anti-entropy synchronization (stub)
 mod change_event; // Change event schema & codecs
+mod transport; // Pluggable replication transports (MQTT, TCP, loopback)
+mod outbox; // Durable outbound replication queue
+mod key_filter; // Glob key patterns and replication filters
+mod watch; // Client change streams (WATCH)
+mod pubsub; // Channel messaging (PUBLISH/SUBSCRIBE)
+mod changelog; // Persisted change log with sequence numbers (REPLAY)
+mod conflict; // Pluggable conflict resolution policies
+mod repair; // Targeted anti-entropy after prev hash mismatches
+mod ratelimit; // Per-client command rate limiting
 
 // Import storage engines
 use crate::store::{KVEngineStoreTrait, KvEngine, RwLockEngine};
@@ -56,7 +69,7 @@ use crate::store::{KVEngineStoreTrait, KvEngine, RwLockEngine};
 ///
 /// # Command Line Arguments
 /// * `--config <path>` - Path to configuration file (default: config.toml)
-/// * `--engine <type>` - Storage engine type: "memory", "rwlock", or "sled" (overrides config file)
+/// * `--engine <type>` - Storage engine type: "memory", "rwlock", "sharded", or "sled" (overrides config file)
 /// * `--storage-path <path>` - Storage path (overrides config file)
 fn main() -> Result<()> {
     // Initialize logging - use RUST_LOG environment variable to control verbosity
@@ -111,10 +124,11 @@ fn main() -> Result<()> {
         config.storage.engine = match engine.to_lowercase().as_str() {
             "memory" | "kv" => config::StorageEngine::Memory,
             "rwlock" => config::StorageEngine::RwLock,
+            "sharded" => config::StorageEngine::Sharded,
             "sled" => config::StorageEngine::Sled,
             _ => {
                 eprintln!("Error: Unknown engine type '{}'", engine);
-                eprintln!("Available engines: memory, rwlock, sled");
+                eprintln!("Available engines: memory, rwlock, sharded, sled");
                 std::process::exit(1);
             }
         };
@@ -133,15 +147,17 @@ fn main() -> Result<()> {
         // Initialize the storage engine based on configuration
         let store: Box<dyn KVEngineStoreTrait + Send + Sync> = match config.storage.engine {
             config::StorageEngine::Memory => {
-                println!("⚠️  WARNING: Using non-thread-safe Memory engine!");
-                println!("   This engine is NOT safe for concurrent access.");
-                println!("   Only use this for single-threaded applications or testing.");
+                println!("Using copy-on-write Memory engine");
                 store::create_storage_engine(&config.storage)?
             }
             config::StorageEngine::RwLock => {
                 println!("Using thread-safe RwLock engine");
                 store::create_storage_engine(&config.storage)?
             }
+            config::StorageEngine::Sharded => {
+                println!("Using thread-safe Sharded engine ({} shards)", config.storage.shards);
+                store::create_storage_engine(&config.storage)?
+            }
             config::StorageEngine::Sled => {
                 println!("Using persistent Sled engine at {}", config.storage.path);
                 store::create_storage_engine(&config.storage)?
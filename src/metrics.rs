@@ -0,0 +1,284 @@
+//! # Prometheus Metrics
+//!
+//! Per-command counters and latency histograms, and the Prometheus text
+//! exposition served at `GET /metrics` by the HTTP API:
+//!
+//! ```text
+//! # HELP merkle_kv_commands_total Commands received, by command
+//! # TYPE merkle_kv_commands_total counter
+//! merkle_kv_commands_total{command="get"} 42
+//! # TYPE merkle_kv_command_duration_seconds histogram
+//! merkle_kv_command_duration_seconds_bucket{command="get",le="0.0001"} 40
+//! ...
+//! ```
+//!
+//! Besides commands the exposition covers connections, engine size,
+//! resident memory, replication counters (overall and per source) and the
+//! Merkle repair counters. Metrics are collected when scraped; nothing is
+//! sampled in the background. The key count, a full scan on disk-backed
+//! engines, is taken on a blocking thread so a scrape never stalls the
+//! runtime.
+
+use crate::executor::CommandExecutor;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Upper bounds (seconds) of the command latency histogram buckets.
+pub const LATENCY_BUCKETS: [f64; 14] =
+    [0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Latency histogram with the fixed `LATENCY_BUCKETS`.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    /// Observations per bucket (not cumulative); the last one is `+Inf`
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl LatencyHistogram {
+    /// Record one observation.
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|&le| seconds <= le).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Cumulative bucket counts, ending with the `+Inf` bucket (the total count).
+    pub fn cumulative(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .scan(0, |total, bucket| {
+                *total += bucket.load(Ordering::Relaxed);
+                Some(*total)
+            })
+            .collect()
+    }
+
+    /// Sum of all observations in seconds.
+    pub fn sum_seconds(&self) -> f64 {
+        self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9
+    }
+}
+
+impl Clone for LatencyHistogram {
+    fn clone(&self) -> Self {
+        Self {
+            buckets: std::array::from_fn(|i| AtomicU64::new(self.buckets[i].load(Ordering::Relaxed))),
+            sum_nanos: AtomicU64::new(self.sum_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Counters and latencies of one command.
+#[derive(Debug, Default)]
+pub struct CommandSeries {
+    /// Commands received
+    pub count: AtomicU64,
+    /// Commands that returned an error
+    pub errors: AtomicU64,
+    /// Execution time of commands run by the executor
+    pub latency: LatencyHistogram,
+}
+
+impl Clone for CommandSeries {
+    fn clone(&self) -> Self {
+        Self {
+            count: AtomicU64::new(self.count.load(Ordering::Relaxed)),
+            errors: AtomicU64::new(self.errors.load(Ordering::Relaxed)),
+            latency: self.latency.clone(),
+        }
+    }
+}
+
+/// Per-command series, keyed by `Command::name`.
+#[derive(Debug, Default)]
+pub struct CommandMetrics {
+    series: RwLock<BTreeMap<&'static str, Arc<CommandSeries>>>,
+}
+
+impl CommandMetrics {
+    /// The series of `command`, created on first use.
+    fn series(&self, command: &'static str) -> Arc<CommandSeries> {
+        if let Some(series) = self.series.read().unwrap_or_else(|e| e.into_inner()).get(command) {
+            return Arc::clone(series);
+        }
+        let mut series = self.series.write().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(series.entry(command).or_default())
+    }
+
+    /// Count a received command.
+    pub fn count(&self, command: &'static str) {
+        self.series(command).count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how long a command took and whether it failed.
+    pub fn observe(&self, command: &'static str, elapsed: Duration, failed: bool) {
+        let series = self.series(command);
+        series.latency.observe(elapsed);
+        if failed {
+            series.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Snapshot of every series, ordered by command.
+    pub fn snapshot(&self) -> Vec<(&'static str, Arc<CommandSeries>)> {
+        let series = self.series.read().unwrap_or_else(|e| e.into_inner());
+        series.iter().map(|(name, s)| (*name, Arc::clone(s))).collect()
+    }
+}
+
+impl Clone for CommandMetrics {
+    fn clone(&self) -> Self {
+        let series = self.snapshot().into_iter().map(|(name, s)| (name, Arc::new((*s).clone()))).collect();
+        Self { series: RwLock::new(series) }
+    }
+}
+
+/// Resident set size of this process in kB, from `/proc/self/status`
+/// (`None` where procfs is unavailable).
+pub fn resident_memory_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Builder for the Prometheus text exposition format.
+#[derive(Default)]
+pub struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    /// Start a metric family.
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    /// Write a sample of the current family.
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape(v))).collect();
+            let _ = write!(self.out, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    /// A counter family with a single sample.
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.family(name, "counter", help);
+        self.sample(name, &[], value);
+    }
+
+    /// A gauge family with a single sample.
+    pub fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.family(name, "gauge", help);
+        self.sample(name, &[], value);
+    }
+
+    /// The finished exposition.
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Render every metric of the node served by `executor`.
+pub async fn render(executor: &CommandExecutor) -> String {
+    let store = executor.store().clone();
+    let keys = tokio::task::spawn_blocking(move || store.count_keys()).await;
+    let stats = executor.stats();
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut out = Exposition::default();
+
+    out.gauge("merkle_kv_uptime_seconds", "Seconds since the server started", stats.uptime_seconds());
+
+    // Commands
+    let commands = stats.commands.snapshot();
+    out.family("merkle_kv_commands_total", "counter", "Commands received, by command");
+    for (name, series) in &commands {
+        out.sample("merkle_kv_commands_total", &[("command", name)], load(&series.count));
+    }
+    out.family("merkle_kv_command_errors_total", "counter", "Commands that returned an error, by command");
+    for (name, series) in &commands {
+        out.sample("merkle_kv_command_errors_total", &[("command", name)], load(&series.errors));
+    }
+    out.family("merkle_kv_command_duration_seconds", "histogram", "Command execution time, by command");
+    for (name, series) in &commands {
+        let cumulative = series.latency.cumulative();
+        for (le, count) in LATENCY_BUCKETS.iter().map(f64::to_string).chain(["+Inf".to_string()]).zip(&cumulative) {
+            out.sample("merkle_kv_command_duration_seconds_bucket", &[("command", name), ("le", &le)], count);
+        }
+        out.sample("merkle_kv_command_duration_seconds_sum", &[("command", name)], series.latency.sum_seconds());
+        out.sample("merkle_kv_command_duration_seconds_count", &[("command", name)], cumulative.last().copied().unwrap_or(0));
+    }
+    out.counter("merkle_kv_commands_throttled_total", "Commands refused by per-client rate limiting", load(&stats.throttled_commands));
+
+    // Connections
+    out.gauge("merkle_kv_connections_active", "Open TCP protocol connections", load(&stats.active_connections));
+    out.counter("merkle_kv_connections_total", "TCP protocol connections accepted", load(&stats.total_connections));
+    out.counter(
+        "merkle_kv_connections_rejected_total",
+        "TCP protocol connections refused at max_connections",
+        load(&stats.rejected_connections),
+    );
+    out.gauge("merkle_kv_http_connections_active", "Open HTTP connections", load(&stats.active_http_connections));
+    out.counter("merkle_kv_http_connections_total", "HTTP connections accepted", load(&stats.total_http_connections));
+    out.gauge("merkle_kv_pubsub_subscribers", "Open channel subscriptions", executor.pubsub().subscriber_count() as u64);
+
+    // Engine and process
+    out.gauge("merkle_kv_keys", "Keys in the storage engine", keys.ok().and_then(Result::ok).unwrap_or(0));
+    if let Some(kb) = resident_memory_kb() {
+        out.gauge("merkle_kv_resident_memory_bytes", "Resident set size of the server process", kb * 1024);
+    }
+
+    // Replication and Merkle repair
+    out.gauge("merkle_kv_replication_enabled", "Whether replication is enabled", executor.replicator().is_some() as u64);
+    if let Some(r) = executor.replicator() {
+        r.export_metrics(&mut out);
+    }
+    out.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = LatencyHistogram::default();
+        histogram.observe(Duration::from_micros(30));
+        histogram.observe(Duration::from_micros(30));
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(2));
+        let cumulative = histogram.cumulative();
+        assert_eq!(cumulative.len(), LATENCY_BUCKETS.len() + 1);
+        assert_eq!((cumulative[0], cumulative[5], cumulative[6]), (2, 2, 3));
+        assert_eq!((cumulative[LATENCY_BUCKETS.len() - 1], cumulative[LATENCY_BUCKETS.len()]), (3, 4));
+        assert!((histogram.sum_seconds() - 2.00306).abs() < 1e-9);
+    }
+
+    #[test]
+    fn exposition_escapes_label_values() {
+        let mut out = Exposition::default();
+        out.family("m", "gauge", "help text");
+        out.sample("m", &[("source", "a\"b\\c")], 1);
+        assert_eq!(out.finish(), "# HELP m help text\n# TYPE m gauge\nm{source=\"a\\\"b\\\\c\"} 1\n");
+    }
+
+    #[test]
+    fn reads_resident_memory_from_procfs() {
+        if std::path::Path::new("/proc/self/status").exists() {
+            assert!(resident_memory_kb().unwrap() > 0);
+        }
+    }
+}
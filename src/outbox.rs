@@ -0,0 +1,313 @@
+//! # Durable Outbound Replication Queue
+//!
+//! Every locally produced `ChangeEvent` is appended to this queue *before* it
+//! is handed to the transport. A background drainer in the `Replicator`
+//! publishes the oldest entries and removes them only once the transport has
+//! accepted them, so events survive broker outages and process restarts.
+//!
+//! ## Storage Layout
+//!
+//! Entries live in a dedicated sled database. Keys are big-endian `u64`
+//! sequence numbers (so iteration order is enqueue order). Values are
+//! `[u16 channel_len][channel][payload]`, where the payload is the framed
+//! event produced by `ChangeCodec::encode` and the channel is the transport
+//! channel it must be published on. An empty path selects a temporary,
+//! in-memory database (useful for tests).
+//!
+//! ## Durability
+//!
+//! `push` writes to sled without forcing an fsync: entries reach disk with
+//! sled's periodic flush (every 500 ms by default), so an event pushed less
+//! than one flush interval before a crash can be lost. A clean shutdown
+//! flushes when the database is dropped. With the MQTT transport, entries are
+//! removed only once the broker has acknowledged them (PUBACK).
+//!
+//! ## Backpressure
+//!
+//! The queue is bounded by `queue_max_events`. Once full, `push` fails and the
+//! server refuses further replicated writes until the drainer catches up.
+//! The depth is tracked in a counter rather than read from sled, whose
+//! `len()` walks the whole tree.
+
+use crate::error::{ErrorCode, KvError};
+use anyhow::{anyhow, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// One queued event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxEntry {
+    /// Queue sequence number
+    pub seq: u64,
+    /// Transport channel to publish on
+    pub channel: String,
+    /// Framed event payload
+    pub payload: Vec<u8>,
+}
+
+/// Durable FIFO of encoded change events awaiting publication.
+pub struct Outbox {
+    /// Database owning the queue tree (kept so it is flushed on drop)
+    db: sled::Db,
+
+    /// Sled tree holding `seq -> payload` entries
+    tree: sled::Tree,
+
+    /// Sequence number assigned to the next pushed entry; held while the
+    /// entry is inserted so entries become visible in sequence order
+    next_seq: Mutex<u64>,
+
+    /// Number of entries in the tree
+    depth: AtomicUsize,
+
+    /// Maximum number of queued entries before `push` fails
+    max_events: usize,
+
+    /// Wakes the drainer when new entries arrive
+    notify: Notify,
+}
+
+impl Outbox {
+    /// Open (or create) the queue stored at `path`.
+    ///
+    /// # Arguments
+    /// * `path` - Directory for the sled database; empty for an in-memory queue
+    /// * `max_events` - Queue depth at which `push` starts failing
+    ///
+    /// # Returns
+    /// * `Result<Outbox>` - Queue with any entries left over from a previous run
+    pub fn open(path: &str, max_events: usize) -> Result<Self> {
+        let db = if path.is_empty() {
+            sled::Config::new().temporary(true).open()?
+        } else {
+            sled::open(path)?
+        };
+        let tree = db.open_tree("replication_outbox")?;
+        let next_seq = match tree.last()? {
+            Some((key, _)) => Self::decode_seq(&key)? + 1,
+            None => 0,
+        };
+        let depth = tree.len();
+        Ok(Self {
+            db,
+            tree,
+            next_seq: Mutex::new(next_seq),
+            depth: AtomicUsize::new(depth),
+            max_events,
+            notify: Notify::new(),
+        })
+    }
+
+    /// Append an encoded event for `channel`, failing if the queue is full.
+    pub fn push(&self, channel: &str, payload: Vec<u8>) -> Result<u64> {
+        let channel_len = u16::try_from(channel.len()).map_err(|_| anyhow!("channel name too long"))?;
+        let mut value = Vec::with_capacity(2 + channel.len() + payload.len());
+        value.extend_from_slice(&channel_len.to_be_bytes());
+        value.extend_from_slice(channel.as_bytes());
+        value.extend_from_slice(&payload);
+        // A drainer acknowledging through a later entry must never skip an
+        // earlier one that is not inserted yet
+        let mut next_seq = self.next_seq.lock().unwrap();
+        self.check_capacity()?;
+        let seq = *next_seq;
+        self.tree.insert(seq.to_be_bytes(), value)?;
+        *next_seq += 1;
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        drop(next_seq);
+        self.notify.notify_one();
+        Ok(seq)
+    }
+
+    /// Fail if the queue has reached its limit.
+    pub fn check_capacity(&self) -> Result<()> {
+        if self.len() >= self.max_events {
+            return Err(KvError::new(
+                ErrorCode::ReadOnly,
+                format!("replication queue full ({} events pending)", self.max_events),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Return up to `max_events` of the oldest entries numbered `from_seq` or
+    /// later that share the first one's channel, stopping once their payloads
+    /// total at least `max_bytes`. At least one entry is returned if any is
+    /// queued from `from_seq` on (entries before it are already in flight).
+    pub fn peek(&self, from_seq: u64, max_events: usize, max_bytes: usize) -> Result<Vec<OutboxEntry>> {
+        let mut entries: Vec<OutboxEntry> = Vec::new();
+        let mut bytes = 0;
+        for item in self.tree.range(from_seq.to_be_bytes()..) {
+            if entries.len() >= max_events.max(1) || (bytes >= max_bytes && !entries.is_empty()) {
+                break;
+            }
+            let (key, value) = item?;
+            let entry = Self::decode_entry(&key, &value)?;
+            if entries.first().is_some_and(|first| first.channel != entry.channel) {
+                break;
+            }
+            bytes += entry.payload.len();
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Remove every entry with a sequence number up to and including `seq`.
+    pub fn ack_through(&self, seq: u64) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        let mut removed = 0;
+        for item in self.tree.range(..=seq.to_be_bytes()) {
+            let (key, _) = item?;
+            batch.remove(key);
+            removed += 1;
+        }
+        self.tree.apply_batch(batch)?;
+        self.depth.fetch_sub(removed, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Number of entries waiting to be published.
+    pub fn len(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    /// Whether no entries are waiting.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Configured maximum queue depth.
+    pub fn max_events(&self) -> usize {
+        self.max_events
+    }
+
+    /// Wait until an entry is pushed (returns immediately if one was pushed
+    /// since the last wait).
+    pub async fn notified(&self) {
+        self.notify.notified().await
+    }
+
+    fn decode_entry(key: &[u8], value: &[u8]) -> Result<OutboxEntry> {
+        let corrupt = || anyhow!("corrupt outbox entry");
+        if value.len() < 2 {
+            return Err(corrupt());
+        }
+        let channel_len = u16::from_be_bytes([value[0], value[1]]) as usize;
+        let channel = value.get(2..2 + channel_len).ok_or_else(corrupt)?;
+        Ok(OutboxEntry {
+            seq: Self::decode_seq(key)?,
+            channel: String::from_utf8(channel.to_vec()).map_err(|_| corrupt())?,
+            payload: value[2 + channel_len..].to_vec(),
+        })
+    }
+
+    fn decode_seq(key: &[u8]) -> Result<u64> {
+        let bytes: [u8; 8] = key.try_into().map_err(|_| anyhow!("corrupt outbox key"))?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        // Ensure queued events are on disk when the queue is dropped
+        if let Err(e) = self.db.flush() {
+            log::error!("Failed to flush replication queue on drop: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn push_peek_ack_in_order() {
+        let outbox = Outbox::open("", 10).unwrap();
+        for i in 0..5u8 {
+            outbox.push("events", vec![i; 4]).unwrap();
+        }
+        let first = outbox.peek(0, 3, usize::MAX).unwrap();
+        assert_eq!(first.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(first[2].payload, vec![2; 4]);
+        assert_eq!(first[2].channel, "events");
+
+        outbox.ack_through(first.last().unwrap().seq).unwrap();
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.peek(0, 10, usize::MAX).unwrap()[0].seq, 3);
+
+        // Byte bound stops early but always yields at least one entry
+        assert_eq!(outbox.peek(0, 10, 1).unwrap().len(), 1);
+        assert_eq!(outbox.peek(4, 10, usize::MAX).unwrap()[0].payload, vec![4; 4]);
+        assert!(outbox.peek(5, 10, usize::MAX).unwrap().is_empty());
+    }
+
+    #[test]
+    fn push_fails_when_full() {
+        let outbox = Outbox::open("", 2).unwrap();
+        outbox.push("events", vec![1]).unwrap();
+        outbox.push("events", vec![2]).unwrap();
+        assert!(outbox.check_capacity().is_err());
+        assert!(outbox.push("events", vec![3]).unwrap_err().to_string().contains("full"));
+        outbox.ack_through(0).unwrap();
+        assert!(outbox.push("events", vec![3]).is_ok());
+    }
+
+    #[test]
+    fn peek_stops_at_channel_change() {
+        let outbox = Outbox::open("", 10).unwrap();
+        outbox.push("events/user", vec![1]).unwrap();
+        outbox.push("events/user", vec![2]).unwrap();
+        outbox.push("events/order", vec![3]).unwrap();
+        let batch = outbox.peek(0, 10, usize::MAX).unwrap();
+        assert_eq!(batch.len(), 2);
+        outbox.ack_through(batch[1].seq).unwrap();
+        let next = outbox.peek(0, 10, usize::MAX).unwrap();
+        assert_eq!((next[0].channel.as_str(), next.len()), ("events/order", 1));
+    }
+
+    #[test]
+    fn concurrent_push_and_drain_deliver_each_entry_once() {
+        let outbox = std::sync::Arc::new(Outbox::open("", usize::MAX).unwrap());
+        let writers: Vec<_> = (0..4u8)
+            .map(|w| {
+                let outbox = outbox.clone();
+                std::thread::spawn(move || {
+                    for i in 0..250u8 {
+                        outbox.push("events", vec![w, i]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let mut seen = std::collections::HashSet::new();
+        while seen.len() < 1000 {
+            for entry in outbox.peek(0, 16, usize::MAX).unwrap() {
+                assert!(seen.insert(entry.payload.clone()), "entry {:?} delivered twice", entry.payload);
+                outbox.ack_through(entry.seq).unwrap();
+            }
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert!(outbox.is_empty() && outbox.peek(0, 16, usize::MAX).unwrap().is_empty());
+    }
+
+    #[test]
+    fn entries_survive_reopen() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("outbox");
+        let path = path.to_str().unwrap();
+        {
+            let outbox = Outbox::open(path, 10).unwrap();
+            outbox.push("events", b"a".to_vec()).unwrap();
+            outbox.push("events", b"b".to_vec()).unwrap();
+            outbox.ack_through(0).unwrap();
+        }
+        let outbox = Outbox::open(path, 10).unwrap();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox.push("events", b"c".to_vec()).unwrap(), 2);
+        let pending: Vec<Vec<u8>> = outbox.peek(0, 10, usize::MAX).unwrap().into_iter().map(|e| e.payload).collect();
+        assert_eq!(pending, vec![b"b".to_vec(), b"c".to_vec()]);
+    }
+}
@@ -25,10 +25,46 @@
 //! - `MSET <key1> <value1> <key2> <value2> ...` - Set multiple key-value pairs
 //! - `TRUNCATE` - Clear all keys/values in the store
 //!
+//! ### Set Operations (replicated as an OR-Set CRDT)
+//! - `SADD <key> <member1> [member2 ...]` - Add members to a set
+//! - `SREM <key> <member1> [member2 ...]` - Remove members from a set
+//! - `SMEMBERS <key>` - List all members of a set
+//!
+//! ### Hash Operations (replicated as an LWW-Map CRDT)
+//! - `HSET <key> <field> <value>` - Set a field in a hash
+//! - `HGET <key> <field>` - Get a field from a hash
+//! - `HDEL <key> <field>` - Delete a field from a hash
+//!
 //! ### Statistical Commands
 //! - `STATS` - Return general server statistics (connections, operations, memory usage)
 //! - `INFO` - Return detailed server information (version, uptime, config)
 //! - `PING` - Simple health check command
+//! - `REPLSTATUS` - Show replication status (transport, outbound queue depth)
+//! - `REPLINFO` - Show per-source replication counters and estimated lag
+//!
+//! ### Change Streams
+//! - `WATCH [pattern] [TEXT|JSON]` - Stream changes to keys matching a glob pattern or
+//!   prefix (all keys if omitted); the connection then only accepts `UNWATCH`
+//! - `UNWATCH` - Stop streaming and return to normal command mode
+//!
+//! ### Change Log
+//! - `REPLAY <from_seq> [count] [TEXT|JSON]` - Return up to `count` (default 1000) logged
+//!   change events with sequence numbers `>= from_seq`
+//!
+//! ### Channel Messaging
+//! - `PUBLISH <channel> <message>` - Send a message to subscribers of a channel
+//! - `SUBSCRIBE <channel1> [channel2 ...]` - Receive messages published on channels
+//! - `PSUBSCRIBE <pattern1> [pattern2 ...]` - Receive messages on channels matching glob patterns
+//! - `UNSUBSCRIBE [channel ...]` / `PUNSUBSCRIBE [pattern ...]` - Drop some (or all) subscriptions
+//!
+//! ### Response Formats
+//! - `FORMAT <TEXT|JSON|RESP>` - Encode this connection's responses in the text protocol
+//!   (default), as one JSON object per line, or as RESP2
+//!
+//! ### Write Concern
+//! - `WAIT <replicas> <timeout> <write command>` - Apply the write, then block until
+//!   `replicas` peers acknowledge it or `timeout` (`500ms`, `2s`, or plain milliseconds)
+//!   expires
 //!
 //! ## Example Usage
 //! ```
@@ -42,14 +78,37 @@
 //! PREPEND greeting "Hello,"
 //! MGET user:123 user:456 user:789
 //! MSET user:123 john_doe user:456 jane_smith
+//! SADD tags red green
+//! HSET user:123 name John Doe
 //! TRUNCATE
+//! WAIT 2 500ms SET user:123 john_doe
 //! ```
 //!
 //! ## Response Format
 //! - Success responses: `VALUE <data>`, `OK`
 //! - Error responses: `ERROR <message>`, `NOT_FOUND`
+//! - Multi-line responses: `<KIND> <count>` followed by one value per line (`MEMBERS`,
+//!   `SIBLINGS`), `VALUES <found>` followed by `<key> <value>` lines (MGET), or `<KIND>`
+//!   followed by `key:value` lines (`STATS`, `INFO`, `REPLSTATUS`, `REPLINFO`, `REPLAY`)
+//!
+//! Responses are built as a typed `Response` and serialized per connection in a
+//! `ResponseFormat`; JSON and RESP make multi-line replies self-delimiting.
+
+use crate::watch::WatchFormat;
+use crate::error::{ErrorCode, KvError};
+use std::num::IntErrorKind;
 
-use anyhow::{anyhow, Result};
+type Result<T> = std::result::Result<T, KvError>;
+
+/// A syntax error with a formatted message.
+macro_rules! syntax {
+    ($($arg:tt)*) => {
+        KvError::new(ErrorCode::Syntax, format!($($arg)*))
+    };
+}
+
+/// Number of change log entries REPLAY returns when no count is given.
+pub const DEFAULT_REPLAY_COUNT: usize = 1000;
 
 /// Represents the different commands that clients can send to the server.
 ///
@@ -120,6 +179,54 @@ pub enum Command {
         pairs: Vec<(String, String)>,
     },
 
+    /// Add members to a set
+    SetAdd {
+        /// The set key
+        key: String,
+        /// The members to add
+        members: Vec<String>,
+    },
+
+    /// Remove members from a set
+    SetRemove {
+        /// The set key
+        key: String,
+        /// The members to remove
+        members: Vec<String>,
+    },
+
+    /// List all members of a set
+    SetMembers {
+        /// The set key
+        key: String,
+    },
+
+    /// Set a field in a hash
+    HashSet {
+        /// The hash key
+        key: String,
+        /// The field to set
+        field: String,
+        /// The value to associate with the field
+        value: String,
+    },
+
+    /// Get a field from a hash
+    HashGet {
+        /// The hash key
+        key: String,
+        /// The field to look up
+        field: String,
+    },
+
+    /// Delete a field from a hash
+    HashDelete {
+        /// The hash key
+        key: String,
+        /// The field to delete
+        field: String,
+    },
+
     /// Clear all keys/values in the store
     Truncate,
     
@@ -131,6 +238,12 @@ pub enum Command {
     
     /// Simple health check command
     Ping,
+
+    /// Show replication status (transport, outbound queue depth)
+    ReplStatus,
+
+    /// Show per-source replication counters and estimated lag
+    ReplInfo,
     
     /// Return server version
     Version,
@@ -140,6 +253,406 @@ pub enum Command {
     
     /// Gracefully shut down the server
     Shutdown,
+
+    /// Choose how responses on this connection are encoded
+    Format {
+        /// Response encoding
+        format: ResponseFormat,
+    },
+
+    /// Stream change events for matching keys to this connection
+    Watch {
+        /// Glob pattern or key prefix (empty for all keys)
+        pattern: String,
+        /// Encoding of streamed events
+        format: WatchFormat,
+    },
+
+    /// Stop an active change stream
+    Unwatch,
+
+    /// Return logged change events starting at a sequence number
+    Replay {
+        /// First sequence number to return
+        from_seq: u64,
+        /// Maximum number of entries to return
+        count: usize,
+        /// Encoding of returned events
+        format: WatchFormat,
+    },
+
+    /// Send a message to the subscribers of a channel
+    Publish {
+        /// Channel to publish on
+        channel: String,
+        /// Message body (may contain spaces)
+        message: String,
+    },
+
+    /// Subscribe to exact channel names
+    Subscribe {
+        /// Channels to subscribe to
+        channels: Vec<String>,
+    },
+
+    /// Subscribe to channels matching glob patterns
+    PSubscribe {
+        /// Patterns to subscribe to
+        patterns: Vec<String>,
+    },
+
+    /// Unsubscribe from channels (all channels if empty)
+    Unsubscribe {
+        /// Channels to unsubscribe from
+        channels: Vec<String>,
+    },
+
+    /// Unsubscribe from patterns (all patterns if empty)
+    PUnsubscribe {
+        /// Patterns to unsubscribe from
+        patterns: Vec<String>,
+    },
+
+    /// Run a write command, then wait for replica acknowledgements
+    Wait {
+        /// The write command to execute
+        command: Box<Command>,
+        /// Number of peers that must acknowledge the write
+        replicas: usize,
+        /// Maximum time to wait for acknowledgements, in milliseconds
+        timeout_ms: u64,
+    },
+}
+
+impl Command {
+    /// Whether this command mutates state that is replicated to other nodes.
+    ///
+    /// The server refuses such commands while the replication queue is full.
+    pub fn is_replicated_write(&self) -> bool {
+        if let Command::Wait { command, .. } = self {
+            return command.is_replicated_write();
+        }
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Delete { .. }
+                | Command::Increment { .. }
+                | Command::Decrement { .. }
+                | Command::Append { .. }
+                | Command::Prepend { .. }
+                | Command::MultiSet { .. }
+                | Command::SetAdd { .. }
+                | Command::SetRemove { .. }
+                | Command::HashSet { .. }
+                | Command::HashDelete { .. }
+        )
+    }
+
+    /// Lowercase command keyword, used to label per-command metrics. A
+    /// write concern is named after the write it wraps.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get { .. } => "get",
+            Command::Set { .. } => "set",
+            Command::Delete { .. } => "delete",
+            Command::Increment { .. } => "inc",
+            Command::Decrement { .. } => "dec",
+            Command::Append { .. } => "append",
+            Command::Prepend { .. } => "prepend",
+            Command::MultiGet { .. } => "mget",
+            Command::MultiSet { .. } => "mset",
+            Command::SetAdd { .. } => "sadd",
+            Command::SetRemove { .. } => "srem",
+            Command::SetMembers { .. } => "smembers",
+            Command::HashSet { .. } => "hset",
+            Command::HashGet { .. } => "hget",
+            Command::HashDelete { .. } => "hdel",
+            Command::Truncate => "truncate",
+            Command::Stats => "stats",
+            Command::Info => "info",
+            Command::Ping => "ping",
+            Command::ReplStatus => "replstatus",
+            Command::ReplInfo => "replinfo",
+            Command::Version => "version",
+            Command::Flush => "flush",
+            Command::Shutdown => "shutdown",
+            Command::Format { .. } => "format",
+            Command::Watch { .. } => "watch",
+            Command::Unwatch => "unwatch",
+            Command::Replay { .. } => "replay",
+            Command::Publish { .. } => "publish",
+            Command::Subscribe { .. } => "subscribe",
+            Command::PSubscribe { .. } => "psubscribe",
+            Command::Unsubscribe { .. } => "unsubscribe",
+            Command::PUnsubscribe { .. } => "punsubscribe",
+            Command::Wait { command, .. } => command.name(),
+        }
+    }
+}
+
+/// The result of executing a command, independent of how it is sent to a client.
+///
+/// `render` serializes a response in one of the `ResponseFormat`s.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The command succeeded with nothing to return
+    Ok,
+    /// A single value
+    Value(String),
+    /// The key (or field) does not exist
+    NotFound,
+    /// The command failed
+    Error(KvError),
+    /// A numeric result (counters, INC/DEC results)
+    Integer(i64),
+    /// A bare status word such as `PONG`
+    Status(String),
+    /// An ordered list of values
+    Array(ArrayKind, Vec<Response>),
+    /// An ordered list of named values; names may repeat
+    Map(MapKind, Vec<(String, Response)>),
+}
+
+/// The reply an `Response::Array` carries, which names it in the text format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrayKind {
+    /// Concurrent values of a key (GET under `keep_siblings`)
+    Siblings,
+    /// Members of a set (SMEMBERS)
+    Members,
+    /// Pre-rendered change log entries (REPLAY)
+    Entries,
+}
+
+/// The reply a `Response::Map` carries, which names it in the text format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapKind {
+    /// Values by key (MGET)
+    Values,
+    /// Server statistics (STATS)
+    Stats,
+    /// Server information (INFO)
+    Info,
+    /// Replication status (REPLSTATUS)
+    ReplStatus,
+    /// Replication counters (REPLINFO)
+    ReplInfo,
+    /// Change log range (REPLAY)
+    Replay,
+    /// Merkle tree summary (HTTP `/merkle/root`)
+    Merkle,
+    /// A group of fields nested in another map
+    Fields,
+}
+
+impl ArrayKind {
+    fn name(self) -> &'static str {
+        match self {
+            ArrayKind::Siblings => "SIBLINGS",
+            ArrayKind::Members => "MEMBERS",
+            ArrayKind::Entries => "ENTRIES",
+        }
+    }
+}
+
+impl MapKind {
+    fn name(self) -> &'static str {
+        match self {
+            MapKind::Values => "VALUES",
+            MapKind::Stats => "STATS",
+            MapKind::Info => "INFO",
+            MapKind::ReplStatus => "REPLSTATUS",
+            MapKind::ReplInfo => "REPLINFO",
+            MapKind::Replay => "REPLAY",
+            MapKind::Merkle => "MERKLE",
+            MapKind::Fields => "FIELDS",
+        }
+    }
+}
+
+/// Wire encoding of responses, chosen per connection with `FORMAT`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResponseFormat {
+    /// The line-oriented text protocol (`VALUE x`, `OK`, `STATS` + `key:value` lines)
+    #[default]
+    Text,
+    /// One JSON object per response, on a single line
+    Json,
+    /// RESP2, the Redis serialization protocol
+    Resp,
+}
+
+impl Response {
+    /// An error response.
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Response::Error(KvError::new(code, message))
+    }
+
+    /// Whether this response reports a failure.
+    pub fn is_error(&self) -> bool {
+        matches!(self, Response::Error(_))
+    }
+
+    /// Serialize the response, including the trailing line ending.
+    pub fn render(&self, format: ResponseFormat) -> String {
+        match format {
+            ResponseFormat::Text => self.to_text(),
+            ResponseFormat::Json => format!("{}\r\n", self.to_json()),
+            ResponseFormat::Resp => {
+                let mut out = String::new();
+                self.write_resp(&mut out);
+                out
+            }
+        }
+    }
+
+    /// Text protocol encoding.
+    ///
+    /// Scalars are one line (`OK`, `VALUE x`, `NOT_FOUND`, `ERROR <code> <message>`).
+    /// Arrays are a `<KIND> <count>` line followed by one value per line.
+    /// MGET is `VALUES <found>` followed by `<key> <value>` lines, with
+    /// `NOT_FOUND` for missing keys. Other maps are a `<KIND>` line followed
+    /// by `key:value` lines; a nested field group is written as
+    /// `key f1=v1,f2=v2` and nested arrays as one value per line.
+    fn to_text(&self) -> String {
+        match self {
+            Response::Ok => "OK\r\n".to_string(),
+            Response::Value(value) => format!("VALUE {}\r\n", value),
+            Response::Integer(n) => format!("VALUE {}\r\n", n),
+            Response::NotFound => "NOT_FOUND\r\n".to_string(),
+            Response::Error(e) => format!("ERROR {} {}\r\n", e.code, e.message),
+            Response::Status(status) => format!("{}\r\n", status),
+            Response::Array(kind, items) => {
+                let mut out = format!("{} {}\r\n", kind.name(), items.len());
+                for item in items {
+                    out.push_str(&format!("{}\r\n", item.text_inline()));
+                }
+                out
+            }
+            Response::Map(MapKind::Values, entries) => {
+                let found = entries.iter().filter(|(_, v)| *v != Response::NotFound).count();
+                let mut out = format!("VALUES {}\r\n", found);
+                for (key, value) in entries {
+                    out.push_str(&format!("{} {}\r\n", key, value.text_inline()));
+                }
+                out
+            }
+            Response::Map(kind, entries) => {
+                let mut out = format!("{}\r\n", kind.name());
+                for (key, value) in entries {
+                    match value {
+                        Response::Map(_, fields) => {
+                            let fields: Vec<String> =
+                                fields.iter().map(|(f, v)| format!("{}={}", f, v.text_inline())).collect();
+                            out.push_str(&format!("{} {}\r\n", key, fields.join(",")));
+                        }
+                        Response::Array(_, items) => {
+                            for item in items {
+                                out.push_str(&format!("{}\r\n", item.text_inline()));
+                            }
+                        }
+                        value => out.push_str(&format!("{}:{}\r\n", key, value.text_inline())),
+                    }
+                }
+                out
+            }
+        }
+    }
+
+    /// A value as it appears inside a multi-line text reply.
+    fn text_inline(&self) -> String {
+        match self {
+            Response::Ok => "OK".to_string(),
+            Response::Value(value) | Response::Status(value) => value.clone(),
+            Response::Integer(n) => n.to_string(),
+            Response::NotFound => "NOT_FOUND".to_string(),
+            Response::Error(e) => format!("ERROR {} {}", e.code, e.message),
+            nested => nested.to_text().trim_end().to_string(),
+        }
+    }
+
+    /// JSON encoding: `{"type": ..., "value": ...}`, or
+    /// `{"type": "error", "code": ..., "message": ...}` for errors. Arrays and maps also
+    /// carry their `kind`; maps become objects, so a repeated name keeps
+    /// only its last value. The HTTP API uses it for response bodies.
+    pub fn to_json(&self) -> serde_json::Value {
+        use serde_json::json;
+        match self {
+            Response::Ok => json!({"type": "ok"}),
+            Response::NotFound => json!({"type": "not_found"}),
+            Response::Error(e) => json!({"type": "error", "code": e.code.as_str(), "message": e.message}),
+            Response::Value(_) => json!({"type": "value", "value": self.json_inline()}),
+            Response::Integer(_) => json!({"type": "integer", "value": self.json_inline()}),
+            Response::Status(_) => json!({"type": "status", "value": self.json_inline()}),
+            Response::Array(kind, _) => {
+                json!({"type": "array", "kind": kind.name().to_lowercase(), "value": self.json_inline()})
+            }
+            Response::Map(kind, _) => {
+                json!({"type": "map", "kind": kind.name().to_lowercase(), "value": self.json_inline()})
+            }
+        }
+    }
+
+    /// A value as it appears inside a JSON array or map (`null` for missing keys).
+    fn json_inline(&self) -> serde_json::Value {
+        use serde_json::Value;
+        match self {
+            Response::Ok => Value::from("OK"),
+            Response::Value(value) | Response::Status(value) => Value::from(value.as_str()),
+            Response::Integer(n) => Value::from(*n),
+            Response::NotFound => Value::Null,
+            Response::Error(_) => self.to_json(),
+            Response::Array(_, items) => Value::Array(items.iter().map(Response::json_inline).collect()),
+            Response::Map(_, entries) => {
+                Value::Object(entries.iter().map(|(k, v)| (k.clone(), v.json_inline())).collect())
+            }
+        }
+    }
+
+    /// RESP2 encoding. Values are bulk strings, missing keys the null bulk
+    /// string, errors `-<code> <message>`, and maps flat arrays of
+    /// alternating names and values.
+    fn write_resp(&self, out: &mut String) {
+        match self {
+            Response::Ok => out.push_str("+OK\r\n"),
+            Response::Status(status) => out.push_str(&format!("+{}\r\n", status)),
+            Response::Value(value) => out.push_str(&format!("${}\r\n{}\r\n", value.len(), value)),
+            Response::NotFound => out.push_str("$-1\r\n"),
+            Response::Error(e) => out.push_str(&format!("-{} {}\r\n", e.code, e.message.replace(['\r', '\n'], " "))),
+            Response::Integer(n) => out.push_str(&format!(":{}\r\n", n)),
+            Response::Array(_, items) => {
+                out.push_str(&format!("*{}\r\n", items.len()));
+                for item in items {
+                    item.write_resp(out);
+                }
+            }
+            Response::Map(_, entries) => {
+                out.push_str(&format!("*{}\r\n", entries.len() * 2));
+                for (key, value) in entries {
+                    Response::Value(key.clone()).write_resp(out);
+                    value.write_resp(out);
+                }
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for ResponseFormat {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_uppercase().as_str() {
+            "TEXT" => Ok(ResponseFormat::Text),
+            "JSON" => Ok(ResponseFormat::Json),
+            "RESP" => Ok(ResponseFormat::Resp),
+            other => Err(syntax!("Unknown response format: {}", other)),
+        }
+    }
+}
+
+/// Named counters as map fields (STATS and friends).
+pub fn counters<'a>(counters: impl IntoIterator<Item = (&'a str, u64)>) -> Vec<(String, Response)> {
+    counters.into_iter().map(|(name, n)| (name.to_string(), Response::Integer(n as i64))).collect()
 }
 
 /// Protocol parser that converts text commands into structured Command enums.
@@ -188,17 +701,34 @@ impl Protocol {
         
         // Check for empty input
         if input.is_empty() {
-            return Err(anyhow!("Empty command"));
+            return Err(syntax!("Empty command"));
         }
         
         // Parse command based on the first word (case-insensitive)
 
         // Check for invalid characters (tabs, newlines within the command)
         if input.contains('\t') {
-            return Err(anyhow!("Invalid character: tab character not allowed"));
+            return Err(syntax!("Invalid character: tab character not allowed"));
         }
         if input.contains('\n') {
-            return Err(anyhow!("Invalid character: newline character not allowed"));
+            return Err(syntax!("Invalid character: newline character not allowed"));
+        }
+
+        // `WAIT <replicas> <timeout> <write command>` runs the write with a write concern
+        let mut words = input.splitn(4, ' ');
+        if words.next().is_some_and(|word| word.eq_ignore_ascii_case("WAIT")) {
+            let (Some(replicas), Some(timeout), Some(rest)) = (words.next(), words.next(), words.next()) else {
+                return Err(syntax!("WAIT requires <replicas> <timeout> <write command>"));
+            };
+            let replicas = replicas
+                .parse::<usize>()
+                .map_err(|_| syntax!("Invalid WAIT replica count: {}", replicas))?;
+            let timeout_ms = Self::parse_timeout_ms(timeout)?;
+            let command = self.parse(rest)?;
+            if !command.is_replicated_write() || matches!(command, Command::Wait { .. }) {
+                return Err(syntax!("WAIT only applies to write commands"));
+            }
+            return Ok(Command::Wait { command: Box::new(command), replicas, timeout_ms });
         }
 
         // Split command into parts - for SET we need to split into exactly 3 parts
@@ -208,17 +738,29 @@ impl Protocol {
         if first_space.is_none() {
             // Single word command
             match input.to_uppercase().as_str() {
-                "GET" | "SET" | "DELETE" | "DEL" => {
-                    return Err(anyhow!("{} command requires arguments", input.to_uppercase()));
+                "GET" | "SET" | "DELETE" | "DEL" | "SADD" | "SREM" | "SMEMBERS" | "HSET"
+                | "HGET" | "HDEL" => {
+                    return Err(syntax!("{} command requires arguments", input.to_uppercase()));
                 }
                 "TRUNCATE" => return Ok(Command::Truncate),
                 "STATS" => return Ok(Command::Stats),
                 "INFO" => return Ok(Command::Info),
                 "PING" => return Ok(Command::Ping),
+                "REPLSTATUS" => return Ok(Command::ReplStatus),
+                "REPLINFO" => return Ok(Command::ReplInfo),
+                "WATCH" => {
+                    return Ok(Command::Watch { pattern: String::new(), format: WatchFormat::Text })
+                }
+                "UNWATCH" => return Ok(Command::Unwatch),
+                "PUBLISH" | "SUBSCRIBE" | "PSUBSCRIBE" | "REPLAY" | "FORMAT" => {
+                    return Err(syntax!("{} command requires arguments", input.to_uppercase()));
+                }
+                "UNSUBSCRIBE" => return Ok(Command::Unsubscribe { channels: Vec::new() }),
+                "PUNSUBSCRIBE" => return Ok(Command::PUnsubscribe { patterns: Vec::new() }),
                 "VERSION" => return Ok(Command::Version),
                 "FLUSH" => return Ok(Command::Flush),
                 "SHUTDOWN" => return Ok(Command::Shutdown),
-                _ => return Err(anyhow!("Unknown command: {}", input)),
+                _ => return Err(syntax!("Unknown command: {}", input)),
             }
         }
 
@@ -229,10 +771,10 @@ impl Protocol {
         match command.to_uppercase().as_str() {
             "GET" => {
                 if rest.is_empty() {
-                    return Err(anyhow!("GET command requires a key"));
+                    return Err(syntax!("GET command requires a key"));
                 }
                 if rest.contains(' ') {
-                    return Err(anyhow!("GET command accepts only one argument"));
+                    return Err(syntax!("GET command accepts only one argument"));
                 }
                 Ok(Command::Get {
                     key: rest.to_string(),
@@ -241,13 +783,13 @@ impl Protocol {
             "SET" => {
                 let second_space = rest.find(' ');
                 if second_space.is_none() {
-                    return Err(anyhow!("SET command requires a key and value"));
+                    return Err(syntax!("SET command requires a key and value"));
                 }
                 let key = &rest[..second_space.unwrap()];
                 let value = &rest[second_space.unwrap() + 1..];
                 
                 if key.is_empty() {
-                    return Err(anyhow!("SET command key cannot be empty"));
+                    return Err(syntax!("SET command key cannot be empty"));
                 }
                 
                 Ok(Command::Set {
@@ -258,10 +800,10 @@ impl Protocol {
             // Support both "DEL" and "DELETE" for convenience
             "DEL" | "DELETE" => {
                 if rest.is_empty() {
-                    return Err(anyhow!("DELETE command requires a key"));
+                    return Err(syntax!("DELETE command requires a key"));
                 }
                 if rest.contains(' ') {
-                    return Err(anyhow!("DELETE command accepts only one argument"));
+                    return Err(syntax!("DELETE command accepts only one argument"));
                 }
                 Ok(Command::Delete {
                     key: rest.to_string(),
@@ -269,7 +811,7 @@ impl Protocol {
             }
             "INC" => {
                 if rest.is_empty() {
-                    return Err(anyhow!("INC command requires a key"));
+                    return Err(syntax!("INC command requires a key"));
                 }
                 
                 // Split the rest into key and optional amount
@@ -277,14 +819,17 @@ impl Protocol {
                 
                 // Check if what appears to be the key is actually a number
                 if parts[0].parse::<i64>().is_ok() && parts.len() == 1 {
-                    return Err(anyhow!("INC command requires a key"));
+                    return Err(syntax!("INC command requires a key"));
                 }
                 
                 // Parse optional amount parameter
                 let amount = if parts.len() > 1 {
                     match parts[1].parse::<i64>() {
                         Ok(val) => Some(val),
-                        Err(_) => return Err(anyhow!("INC command amount must be a valid number")),
+                        Err(e) if matches!(e.kind(), IntErrorKind::PosOverflow | IntErrorKind::NegOverflow) => {
+                            return Err(KvError::new(ErrorCode::Overflow, "INC command amount is out of range"))
+                        }
+                        Err(_) => return Err(syntax!("INC command amount must be a valid number")),
                     }
                 } else {
                     None // Default increment of 1 will be applied
@@ -297,7 +842,7 @@ impl Protocol {
             }
             "DEC" => {
                 if rest.is_empty() {
-                    return Err(anyhow!("DEC command requires a key"));
+                    return Err(syntax!("DEC command requires a key"));
                 }
                 
                 // Split the rest into key and optional amount
@@ -305,14 +850,17 @@ impl Protocol {
                 
                 // Check if what appears to be the key is actually a number
                 if parts[0].parse::<i64>().is_ok() && parts.len() == 1 {
-                    return Err(anyhow!("DEC command requires a key"));
+                    return Err(syntax!("DEC command requires a key"));
                 }
                 
                 // Parse optional amount parameter
                 let amount = if parts.len() > 1 {
                     match parts[1].parse::<i64>() {
                         Ok(val) => Some(val),
-                        Err(_) => return Err(anyhow!("DEC command amount must be a valid number")),
+                        Err(e) if matches!(e.kind(), IntErrorKind::PosOverflow | IntErrorKind::NegOverflow) => {
+                            return Err(KvError::new(ErrorCode::Overflow, "DEC command amount is out of range"))
+                        }
+                        Err(_) => return Err(syntax!("DEC command amount must be a valid number")),
                     }
                 } else {
                     None // Default decrement of 1 will be applied
@@ -326,13 +874,13 @@ impl Protocol {
             "APPEND" => {
                 let second_space = rest.find(' ');
                 if second_space.is_none() {
-                    return Err(anyhow!("APPEND command requires a key and value"));
+                    return Err(syntax!("APPEND command requires a key and value"));
                 }
                 let key = &rest[..second_space.unwrap()];
                 let value = &rest[second_space.unwrap() + 1..];
                 
                 if key.is_empty() {
-                    return Err(anyhow!("APPEND command key cannot be empty"));
+                    return Err(syntax!("APPEND command key cannot be empty"));
                 }
                 // Allow empty values for APPEND
                 
@@ -344,13 +892,13 @@ impl Protocol {
             "PREPEND" => {
                 let second_space = rest.find(' ');
                 if second_space.is_none() {
-                    return Err(anyhow!("PREPEND command requires a key and value"));
+                    return Err(syntax!("PREPEND command requires a key and value"));
                 }
                 let key = &rest[..second_space.unwrap()];
                 let value = &rest[second_space.unwrap() + 1..];
                 
                 if key.is_empty() {
-                    return Err(anyhow!("PREPEND command key cannot be empty"));
+                    return Err(syntax!("PREPEND command key cannot be empty"));
                 }
                 // Allow empty values for PREPEND
                 
@@ -361,7 +909,7 @@ impl Protocol {
             }
             "MGET" => {
                 if rest.is_empty() {
-                    return Err(anyhow!("MGET command requires at least one key"));
+                    return Err(syntax!("MGET command requires at least one key"));
                 }
                 
                 // Extract all keys
@@ -370,14 +918,14 @@ impl Protocol {
                     .collect();
                 
                 if keys.is_empty() {
-                    return Err(anyhow!("MGET command requires at least one key"));
+                    return Err(syntax!("MGET command requires at least one key"));
                 }
                 
                 Ok(Command::MultiGet { keys })
             }
             "MSET" => {
                 if rest.is_empty() {
-                    return Err(anyhow!("MSET command requires at least one key-value pair"));
+                    return Err(syntax!("MSET command requires at least one key-value pair"));
                 }
                 
                 // Extract all parts
@@ -385,7 +933,7 @@ impl Protocol {
                 
                 // We need an even number of parts for key-value pairs
                 if args.len() % 2 != 0 {
-                    return Err(anyhow!("MSET command requires an even number of arguments (key-value pairs)"));
+                    return Err(syntax!("MSET command requires an even number of arguments (key-value pairs)"));
                 }
                 
                 let mut pairs = Vec::new();
@@ -398,11 +946,72 @@ impl Protocol {
                 }
                 
                 if pairs.is_empty() {
-                    return Err(anyhow!("MSET command requires at least one key-value pair"));
+                    return Err(syntax!("MSET command requires at least one key-value pair"));
                 }
                 
                 Ok(Command::MultiSet { pairs })
             }
+            "SADD" | "SREM" => {
+                let upper = command.to_uppercase();
+                let parts: Vec<&str> = rest.split_whitespace().collect();
+                if parts.len() < 2 {
+                    return Err(syntax!("{} command requires a key and at least one member", upper));
+                }
+
+                let key = parts[0].to_string();
+                let members: Vec<String> = parts[1..].iter().map(|s| s.to_string()).collect();
+                if upper == "SADD" {
+                    Ok(Command::SetAdd { key, members })
+                } else {
+                    Ok(Command::SetRemove { key, members })
+                }
+            }
+            "SMEMBERS" => {
+                if rest.is_empty() {
+                    return Err(syntax!("SMEMBERS command requires a key"));
+                }
+                if rest.contains(' ') {
+                    return Err(syntax!("SMEMBERS command accepts only one argument"));
+                }
+                Ok(Command::SetMembers {
+                    key: rest.to_string(),
+                })
+            }
+            "HSET" => {
+                // Split into key, field and value - the value may contain spaces
+                let mut parts = rest.splitn(3, ' ');
+                let key = parts.next().unwrap_or("");
+                let field = parts.next().unwrap_or("");
+                let value = match parts.next() {
+                    Some(value) => value,
+                    None => return Err(syntax!("HSET command requires a key, field and value")),
+                };
+
+                if key.is_empty() || field.is_empty() {
+                    return Err(syntax!("HSET command key and field cannot be empty"));
+                }
+
+                Ok(Command::HashSet {
+                    key: key.to_string(),
+                    field: field.to_string(),
+                    value: value.to_string(),
+                })
+            }
+            "HGET" | "HDEL" => {
+                let upper = command.to_uppercase();
+                let parts: Vec<&str> = rest.split_whitespace().collect();
+                if parts.len() != 2 {
+                    return Err(syntax!("{} command requires a key and a field", upper));
+                }
+
+                let key = parts[0].to_string();
+                let field = parts[1].to_string();
+                if upper == "HGET" {
+                    Ok(Command::HashGet { key, field })
+                } else {
+                    Ok(Command::HashDelete { key, field })
+                }
+            }
             "TRUNCATE" => {
                 Ok(Command::Truncate)
             }
@@ -415,9 +1024,88 @@ impl Protocol {
             "PING" => {
                 Ok(Command::Ping)
             }
-            _ => Err(anyhow!("Unknown command: {}", command)),
+            "REPLSTATUS" => {
+                Ok(Command::ReplStatus)
+            }
+            "REPLINFO" => {
+                Ok(Command::ReplInfo)
+            }
+            "WATCH" => {
+                let parts: Vec<&str> = rest.split_whitespace().collect();
+                let format = match parts.get(1).map(|f| f.to_uppercase()).as_deref() {
+                    None | Some("TEXT") => WatchFormat::Text,
+                    Some("JSON") => WatchFormat::Json,
+                    Some(other) => return Err(syntax!("Unknown WATCH format: {}", other)),
+                };
+                if parts.len() > 2 {
+                    return Err(syntax!("WATCH command accepts a pattern and an optional format"));
+                }
+                Ok(Command::Watch { pattern: parts.first().unwrap_or(&"").to_string(), format })
+            }
+            "FORMAT" => {
+                if rest.contains(' ') {
+                    return Err(syntax!("FORMAT command accepts only one argument"));
+                }
+                Ok(Command::Format { format: rest.parse()? })
+            }
+            "REPLAY" => {
+                let parts: Vec<&str> = rest.split_whitespace().collect();
+                if parts.len() > 3 {
+                    return Err(syntax!("REPLAY command accepts a sequence number, a count and a format"));
+                }
+                let from_seq = parts[0]
+                    .parse::<u64>()
+                    .map_err(|_| syntax!("Invalid sequence number: {}", parts[0]))?;
+                let mut count = DEFAULT_REPLAY_COUNT;
+                let mut format = WatchFormat::Text;
+                for part in &parts[1..] {
+                    match part.to_uppercase().as_str() {
+                        "TEXT" => format = WatchFormat::Text,
+                        "JSON" => format = WatchFormat::Json,
+                        _ => {
+                            count = part
+                                .parse::<usize>()
+                                .map_err(|_| syntax!("Invalid REPLAY count or format: {}", part))?
+                        }
+                    }
+                }
+                Ok(Command::Replay { from_seq, count, format })
+            }
+            "PUBLISH" => {
+                // Split into channel and message - the message may contain spaces
+                match rest.split_once(' ') {
+                    Some((channel, message)) if !channel.is_empty() => Ok(Command::Publish {
+                        channel: channel.to_string(),
+                        message: message.to_string(),
+                    }),
+                    _ => Err(syntax!("PUBLISH command requires a channel and a message")),
+                }
+            }
+            "SUBSCRIBE" | "PSUBSCRIBE" | "UNSUBSCRIBE" | "PUNSUBSCRIBE" => {
+                let names: Vec<String> = rest.split_whitespace().map(str::to_string).collect();
+                Ok(match command.to_uppercase().as_str() {
+                    "SUBSCRIBE" => Command::Subscribe { channels: names },
+                    "PSUBSCRIBE" => Command::PSubscribe { patterns: names },
+                    "UNSUBSCRIBE" => Command::Unsubscribe { channels: names },
+                    _ => Command::PUnsubscribe { patterns: names },
+                })
+            }
+            _ => Err(syntax!("Unknown command: {}", command)),
         }
     }
+
+    /// Parse a WAIT timeout: `500ms`, `2s`, or a plain number of milliseconds.
+    fn parse_timeout_ms(token: &str) -> Result<u64> {
+        let lower = token.to_ascii_lowercase();
+        let parsed = if let Some(ms) = lower.strip_suffix("ms") {
+            ms.parse::<u64>().ok()
+        } else if let Some(secs) = lower.strip_suffix('s') {
+            secs.parse::<u64>().ok().and_then(|s| s.checked_mul(1000))
+        } else {
+            lower.parse::<u64>().ok()
+        };
+        parsed.ok_or_else(|| syntax!("Invalid WAIT timeout: {}", token))
+    }
 }
 
 #[cfg(test)]
@@ -607,6 +1295,73 @@ mod tests {
         );
     }
     
+    #[test]
+    fn test_parse_set_commands() {
+        let protocol = Protocol::new();
+
+        let result = protocol.parse("SADD tags red green").unwrap();
+        assert_eq!(
+            result,
+            Command::SetAdd {
+                key: "tags".to_string(),
+                members: vec!["red".to_string(), "green".to_string()]
+            }
+        );
+
+        let result = protocol.parse("srem tags red").unwrap();
+        assert_eq!(
+            result,
+            Command::SetRemove {
+                key: "tags".to_string(),
+                members: vec!["red".to_string()]
+            }
+        );
+
+        let result = protocol.parse("SMEMBERS tags").unwrap();
+        assert_eq!(result, Command::SetMembers { key: "tags".to_string() });
+
+        assert!(protocol.parse("SADD").is_err());
+        assert!(protocol.parse("SADD tags").is_err());
+        assert!(protocol.parse("SMEMBERS a b").is_err());
+    }
+
+    #[test]
+    fn test_parse_hash_commands() {
+        let protocol = Protocol::new();
+
+        let result = protocol.parse("HSET user:1 name John Doe").unwrap();
+        assert_eq!(
+            result,
+            Command::HashSet {
+                key: "user:1".to_string(),
+                field: "name".to_string(),
+                value: "John Doe".to_string()
+            }
+        );
+
+        let result = protocol.parse("HGET user:1 name").unwrap();
+        assert_eq!(
+            result,
+            Command::HashGet {
+                key: "user:1".to_string(),
+                field: "name".to_string()
+            }
+        );
+
+        let result = protocol.parse("HDEL user:1 name").unwrap();
+        assert_eq!(
+            result,
+            Command::HashDelete {
+                key: "user:1".to_string(),
+                field: "name".to_string()
+            }
+        );
+
+        assert!(protocol.parse("HSET user:1 name").is_err());
+        assert!(protocol.parse("HGET user:1").is_err());
+        assert!(protocol.parse("HDEL user:1 a b").is_err());
+    }
+
     #[test]
     fn test_parse_truncate() {
         let protocol = Protocol::new();
@@ -635,6 +1390,116 @@ mod tests {
         assert_eq!(result, Command::Ping);
     }
     
+    #[test]
+    fn test_parse_replstatus() {
+        let protocol = Protocol::new();
+        assert_eq!(protocol.parse("REPLSTATUS").unwrap(), Command::ReplStatus);
+        assert_eq!(protocol.parse("replstatus").unwrap(), Command::ReplStatus);
+        assert!(Command::Set { key: "k".into(), value: "v".into() }.is_replicated_write());
+        assert!(!Command::ReplStatus.is_replicated_write());
+    }
+
+    #[test]
+    fn test_parse_replinfo() {
+        let protocol = Protocol::new();
+        assert_eq!(protocol.parse("REPLINFO").unwrap(), Command::ReplInfo);
+    }
+
+    #[test]
+    fn test_parse_watch() {
+        let protocol = Protocol::new();
+        assert_eq!(
+            protocol.parse("WATCH").unwrap(),
+            Command::Watch { pattern: String::new(), format: WatchFormat::Text }
+        );
+        assert_eq!(
+            protocol.parse("watch user:* json").unwrap(),
+            Command::Watch { pattern: "user:*".to_string(), format: WatchFormat::Json }
+        );
+        assert_eq!(protocol.parse("UNWATCH").unwrap(), Command::Unwatch);
+        assert!(protocol.parse("WATCH user: XML").is_err());
+        assert!(protocol.parse("WATCH a b c").is_err());
+    }
+
+    #[test]
+    fn test_parse_replay() {
+        let protocol = Protocol::new();
+        assert_eq!(
+            protocol.parse("REPLAY 42").unwrap(),
+            Command::Replay { from_seq: 42, count: DEFAULT_REPLAY_COUNT, format: WatchFormat::Text }
+        );
+        assert_eq!(
+            protocol.parse("replay 0 10 json").unwrap(),
+            Command::Replay { from_seq: 0, count: 10, format: WatchFormat::Json }
+        );
+        assert!(protocol.parse("REPLAY").is_err());
+        assert!(protocol.parse("REPLAY -1").is_err());
+        assert!(protocol.parse("REPLAY 1 lots").is_err());
+    }
+
+    #[test]
+    fn test_parse_pubsub() {
+        let protocol = Protocol::new();
+        assert_eq!(
+            protocol.parse("PUBLISH alerts disk is full").unwrap(),
+            Command::Publish { channel: "alerts".to_string(), message: "disk is full".to_string() }
+        );
+        assert!(protocol.parse("PUBLISH alerts").is_err());
+        assert!(protocol.parse("PUBLISH").is_err());
+        assert_eq!(
+            protocol.parse("subscribe a b").unwrap(),
+            Command::Subscribe { channels: vec!["a".to_string(), "b".to_string()] }
+        );
+        assert_eq!(
+            protocol.parse("PSUBSCRIBE news.*").unwrap(),
+            Command::PSubscribe { patterns: vec!["news.*".to_string()] }
+        );
+        assert_eq!(protocol.parse("UNSUBSCRIBE").unwrap(), Command::Unsubscribe { channels: vec![] });
+        assert_eq!(
+            protocol.parse("PUNSUBSCRIBE p").unwrap(),
+            Command::PUnsubscribe { patterns: vec!["p".to_string()] }
+        );
+    }
+
+    #[test]
+    fn test_parse_wait_write_concern() {
+        let protocol = Protocol::new();
+        assert_eq!(
+            protocol.parse("WAIT 2 500ms SET key hello world").unwrap(),
+            Command::Wait {
+                command: Box::new(Command::Set { key: "key".to_string(), value: "hello world".to_string() }),
+                replicas: 2,
+                timeout_ms: 500,
+            }
+        );
+        match protocol.parse("wait 1 2s del key").unwrap() {
+            Command::Wait { command, replicas: 1, timeout_ms: 2000 } => {
+                assert_eq!(*command, Command::Delete { key: "key".to_string() })
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+        match protocol.parse("WAIT 1 250 INC counter").unwrap() {
+            command @ Command::Wait { timeout_ms: 250, .. } => assert_eq!(command.name(), "inc"),
+            other => panic!("unexpected command: {:?}", other),
+        }
+
+        // Only writes take a write concern, and the arguments must be valid
+        assert!(protocol.parse("WAIT 1 100ms GET key").is_err());
+        assert!(protocol.parse("WAIT x 100ms SET key v").is_err());
+        assert!(protocol.parse("WAIT 1 soon SET key v").is_err());
+        assert!(protocol.parse("WAIT 1 100ms").is_err());
+        assert!(protocol.parse("WAIT 1 100ms WAIT 1 100ms SET key v").is_err());
+        // A value that reads like a write concern is still just a value
+        assert_eq!(
+            protocol.parse("SET note I will WAIT 2 days").unwrap(),
+            Command::Set { key: "note".to_string(), value: "I will WAIT 2 days".to_string() }
+        );
+        assert_eq!(
+            protocol.parse("SET key v WAIT 1 100ms").unwrap(),
+            Command::Set { key: "key".to_string(), value: "v WAIT 1 100ms".to_string() }
+        );
+    }
+    
     #[test]
     fn test_parse_version() {
         let protocol = Protocol::new();
@@ -649,6 +1514,65 @@ mod tests {
         assert_eq!(result, Command::Flush);
     }
     
+    #[test]
+    fn test_parse_format() {
+        let protocol = Protocol::new();
+        assert_eq!(protocol.parse("format json").unwrap(), Command::Format { format: ResponseFormat::Json });
+        assert_eq!(protocol.parse("FORMAT RESP").unwrap(), Command::Format { format: ResponseFormat::Resp });
+        assert!(protocol.parse("FORMAT").is_err());
+        assert!(protocol.parse("FORMAT XML").is_err());
+        assert!(protocol.parse("FORMAT json text").is_err());
+    }
+
+    #[test]
+    fn test_parse_error_codes() {
+        let protocol = Protocol::new();
+        assert_eq!(protocol.parse("BOGUS").unwrap_err().code, ErrorCode::Syntax);
+        assert_eq!(protocol.parse("GET").unwrap_err().code, ErrorCode::Syntax);
+        assert_eq!(protocol.parse("INC n abc").unwrap_err().code, ErrorCode::Syntax);
+        assert_eq!(protocol.parse("INC n 99999999999999999999").unwrap_err().code, ErrorCode::Overflow);
+        assert_eq!(protocol.parse("DEC n -99999999999999999999").unwrap_err().code, ErrorCode::Overflow);
+    }
+
+    #[test]
+    fn test_response_serializers() {
+        let members = Response::Array(ArrayKind::Members, vec![Response::Value("a".into()), Response::Value("b".into())]);
+        assert_eq!(members.render(ResponseFormat::Text), "MEMBERS 2\r\na\r\nb\r\n");
+        assert_eq!(members.render(ResponseFormat::Resp), "*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(
+            members.render(ResponseFormat::Json),
+            "{\"kind\":\"members\",\"type\":\"array\",\"value\":[\"a\",\"b\"]}\r\n"
+        );
+
+        let mget = Response::Map(
+            MapKind::Values,
+            vec![("k".into(), Response::Value("v w".into())), ("x".into(), Response::NotFound)],
+        );
+        assert_eq!(mget.render(ResponseFormat::Text), "VALUES 1\r\nk v w\r\nx NOT_FOUND\r\n");
+        assert_eq!(mget.render(ResponseFormat::Resp), "*4\r\n$1\r\nk\r\n$3\r\nv w\r\n$1\r\nx\r\n$-1\r\n");
+
+        let mut fields = counters([("enabled", 1)]);
+        fields.push(("source:n1".into(), Response::Map(MapKind::Fields, counters([("received", 3), ("lag_ms", 4)]))));
+        let info = Response::Map(MapKind::ReplInfo, fields);
+        assert_eq!(info.render(ResponseFormat::Text), "REPLINFO\r\nenabled:1\r\nsource:n1 received=3,lag_ms=4\r\n");
+        assert_eq!(
+            info.render(ResponseFormat::Json),
+            "{\"kind\":\"replinfo\",\"type\":\"map\",\"value\":{\"enabled\":1,\"source:n1\":{\"lag_ms\":4,\"received\":3}}}\r\n"
+        );
+
+        assert_eq!(Response::Integer(5).render(ResponseFormat::Text), "VALUE 5\r\n");
+        assert_eq!(Response::Integer(5).render(ResponseFormat::Resp), ":5\r\n");
+        assert_eq!(Response::NotFound.render(ResponseFormat::Json), "{\"type\":\"not_found\"}\r\n");
+        let error = Response::Error(KvError::new(ErrorCode::WrongType, "bad\r\nthing"));
+        assert_eq!(error.render(ResponseFormat::Text), "ERROR ERR_WRONGTYPE bad\r\nthing\r\n");
+        assert_eq!(error.render(ResponseFormat::Resp), "-ERR_WRONGTYPE bad  thing\r\n");
+        assert_eq!(
+            error.render(ResponseFormat::Json),
+            "{\"code\":\"ERR_WRONGTYPE\",\"message\":\"bad\\r\\nthing\",\"type\":\"error\"}\r\n"
+        );
+        assert_eq!(Response::Status("PONG".into()).render(ResponseFormat::Resp), "+PONG\r\n");
+    }
+
     #[test]
     fn test_parse_shutdown() {
         let protocol = Protocol::new();
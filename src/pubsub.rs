@@ -0,0 +1,212 @@
+//! # Channel Messaging (PUBLISH / SUBSCRIBE)
+//!
+//! A lightweight message broker for client notifications. Unlike key changes,
+//! messages are not stored: each one is delivered to the connections
+//! subscribed at the moment it is published and then forgotten.
+//!
+//! ## Subscriptions
+//!
+//! A connection subscribes to exact channel names (`SUBSCRIBE`) and/or glob
+//! patterns (`PSUBSCRIBE`, see `key_filter` for the syntax). A message is
+//! delivered once per matching subscription, tagged with the pattern that
+//! matched when it came from a pattern subscription.
+//!
+//! ## Cluster Fan-out
+//!
+//! With `replication.pubsub_cluster` enabled, the `Replicator` also forwards
+//! published messages to peers over the replication transport and delivers
+//! messages from peers to local subscribers. Delivery is at-most-once: the
+//! transport is used directly (not the durable outbox), and messages for a
+//! subscriber whose buffer is full are dropped.
+
+use crate::key_filter::glob_match;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Messages buffered per subscriber before new ones are dropped.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// A message delivered to a subscriber.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    /// Pattern that matched, for pattern subscriptions
+    pub pattern: Option<String>,
+    /// Channel the message was published on
+    pub channel: String,
+    /// Message body
+    pub message: String,
+}
+
+impl Delivery {
+    /// Render as a protocol line: `MESSAGE <channel> <message>` or
+    /// `PMESSAGE <pattern> <channel> <message>`.
+    pub fn render(&self) -> String {
+        match &self.pattern {
+            Some(pattern) => format!("PMESSAGE {} {} {}\r\n", pattern, self.channel, self.message),
+            None => format!("MESSAGE {} {}\r\n", self.channel, self.message),
+        }
+    }
+}
+
+/// Message exchanged between nodes when cluster fan-out is enabled.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClusterMessage {
+    /// Publishing node (used to ignore our own messages)
+    pub src: String,
+    /// Channel the message was published on
+    pub channel: String,
+    /// Message body
+    pub message: String,
+}
+
+/// Registered interest of one connection.
+struct Subscriber {
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+    tx: mpsc::Sender<Delivery>,
+}
+
+/// Node-local message broker shared by all client connections.
+#[derive(Default)]
+pub struct PubSub {
+    /// Id assigned to the next subscription
+    next_id: AtomicU64,
+    /// Active subscriptions by id
+    subscribers: Mutex<HashMap<u64, Subscriber>>,
+}
+
+impl PubSub {
+    /// Create an empty broker.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Deliver `message` to local subscribers of `channel`.
+    ///
+    /// # Returns
+    /// * `usize` - Number of subscriptions the message was delivered to
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        let mut delivered = 0;
+        for sub in subscribers.values() {
+            let exact = sub.channels.contains(channel).then_some(None);
+            let patterns = sub.patterns.iter().filter(|p| glob_match(p, channel)).map(|p| Some(p.clone()));
+            for pattern in exact.into_iter().chain(patterns) {
+                let delivery = Delivery { pattern, channel: channel.to_string(), message: message.to_string() };
+                if sub.tx.try_send(delivery).is_ok() {
+                    delivered += 1;
+                }
+            }
+        }
+        delivered
+    }
+
+    /// Open a subscription with no channels; add some with `subscribe`/`psubscribe`.
+    pub fn open(self: &Arc<Self>) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        let subscriber = Subscriber { channels: BTreeSet::new(), patterns: BTreeSet::new(), tx };
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).insert(id, subscriber);
+        Subscription { id, pubsub: Arc::clone(self), rx }
+    }
+
+    /// Number of active subscriptions (connections in subscribed mode).
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    fn update<R>(&self, id: u64, f: impl FnOnce(&mut Subscriber) -> R) -> R {
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        f(subscribers.get_mut(&id).expect("subscription is registered until dropped"))
+    }
+}
+
+/// One connection's subscriptions; unregistered when dropped.
+pub struct Subscription {
+    id: u64,
+    pubsub: Arc<PubSub>,
+    rx: mpsc::Receiver<Delivery>,
+}
+
+impl Subscription {
+    /// Subscribe to exact channel names.
+    pub fn subscribe(&self, channels: &[String]) {
+        self.pubsub.update(self.id, |s| s.channels.extend(channels.iter().cloned()));
+    }
+
+    /// Subscribe to channel patterns.
+    pub fn psubscribe(&self, patterns: &[String]) {
+        self.pubsub.update(self.id, |s| s.patterns.extend(patterns.iter().cloned()));
+    }
+
+    /// Unsubscribe from the given channels, or from all channels if empty.
+    pub fn unsubscribe(&self, channels: &[String]) {
+        self.pubsub.update(self.id, |s| match channels {
+            [] => s.channels.clear(),
+            _ => s.channels.retain(|c| !channels.contains(c)),
+        });
+    }
+
+    /// Unsubscribe from the given patterns, or from all patterns if empty.
+    pub fn punsubscribe(&self, patterns: &[String]) {
+        self.pubsub.update(self.id, |s| match patterns {
+            [] => s.patterns.clear(),
+            _ => s.patterns.retain(|p| !patterns.contains(p)),
+        });
+    }
+
+    /// Number of channels plus patterns currently subscribed.
+    pub fn count(&self) -> usize {
+        self.pubsub.update(self.id, |s| s.channels.len() + s.patterns.len())
+    }
+
+    /// Wait for the next delivered message.
+    pub async fn recv(&mut self) -> Option<Delivery> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.pubsub.subscribers.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn exact_and_pattern_delivery() {
+        let pubsub = Arc::new(PubSub::new());
+        let mut sub = pubsub.open();
+        sub.subscribe(&["news".to_string()]);
+        sub.psubscribe(&["news*".to_string()]);
+        assert_eq!(sub.count(), 2);
+
+        assert_eq!(pubsub.publish("news", "hello world"), 2);
+        assert_eq!(pubsub.publish("weather", "rain"), 0);
+        assert_eq!(sub.recv().await.unwrap().render(), "MESSAGE news hello world\r\n");
+        assert_eq!(sub.recv().await.unwrap().render(), "PMESSAGE news* news hello world\r\n");
+
+        sub.unsubscribe(&[]);
+        assert_eq!(pubsub.publish("news", "again"), 1);
+        sub.punsubscribe(&["news*".to_string()]);
+        assert_eq!(sub.count(), 0);
+        assert_eq!(pubsub.publish("news", "nobody"), 0);
+    }
+
+    #[test]
+    fn dropped_subscription_is_unregistered() {
+        let pubsub = Arc::new(PubSub::new());
+        let sub = pubsub.open();
+        sub.subscribe(&["a".to_string()]);
+        assert_eq!(pubsub.subscriber_count(), 1);
+        drop(sub);
+        assert_eq!(pubsub.subscriber_count(), 0);
+        assert_eq!(pubsub.publish("a", "x"), 0);
+    }
+}
@@ -0,0 +1,131 @@
+//! # Client Rate Limiting
+//!
+//! A token bucket per client IP address, shared by all of that client's
+//! connections. Each bucket holds up to `burst` tokens and refills at `rate`
+//! tokens per second; every command takes one token, and a command arriving
+//! at an empty bucket is throttled (answered with an error, not executed).
+//!
+//! Buckets that have refilled completely carry no state worth keeping and
+//! are pruned once the table grows, at most once per `PRUNE_INTERVAL`, so
+//! short-lived clients do not accumulate and many active clients do not turn
+//! every command into a scan of the table.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Table size beyond which full buckets are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Shortest time between two prunes of the table.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tokens left in one client's bucket.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Per-IP token bucket rate limiter.
+pub struct RateLimiter {
+    /// Tokens added per second
+    rate: f64,
+    /// Bucket capacity
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+/// Buckets by client IP, and when full ones were last pruned.
+struct Buckets {
+    by_ip: HashMap<IpAddr, Bucket>,
+    pruned: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `rate` commands per second per IP, with
+    /// bursts of up to `burst` commands (`rate` when `burst` is 0).
+    ///
+    /// # Returns
+    /// * `Option<RateLimiter>` - `None` when `rate` is 0 (unlimited)
+    pub fn new(rate: u32, burst: u32) -> Option<Self> {
+        if rate == 0 {
+            return None;
+        }
+        let burst = if burst == 0 { rate } else { burst };
+        let buckets = Buckets { by_ip: HashMap::new(), pruned: Instant::now() };
+        Some(Self { rate: rate as f64, burst: burst as f64, buckets: Mutex::new(buckets) })
+    }
+
+    /// Take a token for one command from `ip`.
+    ///
+    /// # Returns
+    /// * `bool` - `false` if the command must be throttled
+    pub fn try_acquire(&self, ip: IpAddr) -> bool {
+        self.try_acquire_at(ip, Instant::now())
+    }
+
+    fn try_acquire_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.by_ip.len() >= PRUNE_THRESHOLD && now.saturating_duration_since(buckets.pruned) >= PRUNE_INTERVAL {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.by_ip.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst);
+            buckets.pruned = now;
+        }
+        let bucket = buckets.by_ip.entry(ip).or_insert(Bucket { tokens: self.burst, updated: now });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bursts_then_refills_per_ip() {
+        assert!(RateLimiter::new(0, 10).is_none());
+
+        let limiter = RateLimiter::new(10, 3).unwrap();
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.try_acquire_at(a, start));
+        }
+        assert!(!limiter.try_acquire_at(a, start));
+        // Other clients have their own bucket
+        assert!(limiter.try_acquire_at(b, start));
+        // One token per 100ms at 10/s
+        assert!(limiter.try_acquire_at(a, start + Duration::from_millis(100)));
+        assert!(!limiter.try_acquire_at(a, start + Duration::from_millis(150)));
+        // Refills cap at the burst size
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..5).filter(|_| limiter.try_acquire_at(a, later)).count(), 3);
+    }
+
+    #[test]
+    fn prunes_full_buckets_at_most_once_per_interval() {
+        let limiter = RateLimiter::new(10, 1).unwrap();
+        let start = Instant::now() + PRUNE_INTERVAL;
+        for i in 0..PRUNE_THRESHOLD as u32 {
+            assert!(limiter.try_acquire_at(IpAddr::from(i.to_be_bytes()), start));
+        }
+        let len = || limiter.buckets.lock().unwrap().by_ip.len();
+        limiter.buckets.lock().unwrap().pruned = start;
+        // Every bucket has refilled, but the table was pruned too recently
+        let refilled = start + Duration::from_secs(1);
+        limiter.try_acquire_at("10.0.0.1".parse().unwrap(), refilled);
+        assert_eq!(len(), PRUNE_THRESHOLD + 1);
+        limiter.buckets.lock().unwrap().pruned = start - PRUNE_INTERVAL;
+        limiter.try_acquire_at("10.0.0.2".parse().unwrap(), refilled);
+        assert_eq!(len(), 2);
+    }
+}
@@ -0,0 +1,294 @@
+//! # Targeted Anti-Entropy
+//!
+//! Every replicated write carries `ChangeEvent::prev`: the Merkle leaf hash
+//! of the key on the publisher just before the write (all zeros for an
+//! absent key). A receiver whose own leaf hash for the key differs has
+//! diverged from the publisher, typically because it missed an event, and
+//! repairs the key's range with that peer right away instead of waiting for
+//! a full anti-entropy round.
+//!
+//! ## Key Ranges
+//!
+//! A range is the key's namespace: every key starting with everything up to
+//! and including the first `namespace_separator` (`user:` for `user:42`),
+//! or just the key itself when it has no namespace (repairing `foo` leaves
+//! `foobar` alone).
+//!
+//! ## Repair Exchange
+//!
+//! 1. The receiver sends a `RepairRequest` to `repair/{publisher}/request`
+//!    and pushes its own latest events for the range to `repair/{publisher}`.
+//! 2. The publisher answers with its latest events for the range on
+//!    `repair/{receiver}`.
+//!
+//! Events come from the change log (the newest per key and writer, read
+//! through its key index) and are applied like any other event: idempotently
+//! by `op_id` and through the key's conflict policy. Their `prev` is
+//! ignored, so a repair never starts another one. Both sides throttle
+//! repairs per peer and range by `repair_cooldown_ms`: the receiver before
+//! requesting one, the publisher before answering one.
+//!
+//! The publisher only knows `prev` for keys it has written or applied since
+//! startup, and CRDT deltas carry none; such events are not checked.
+//!
+//! ## Catch-Up
+//!
+//! A node returning after downtime may have missed writes whose keys are
+//! never written again, so no `prev` check would ever notice. When its apply
+//! loop starts (`catch_up_on_start`) it broadcasts a `CatchUpRequest` on
+//! `catchup` with the newest write timestamp its change log holds from each
+//! writer. Every peer answers on `repair/{requester}` with its own logged
+//! writes newer than its mark (all of them when the requester has none),
+//! paging through the log's timestamp index. Writes of a peer that is itself
+//! down, and entries trimmed from the log, are left to anti-entropy. A mark
+//! assumes a writer's timestamps increase. Peers answer a node at most once
+//! per `repair_cooldown_ms`.
+
+use crate::change_event::ChangeEvent;
+use crate::changelog::ChangeLog;
+use crate::store::merkle::MerkleTree;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Keys covered by a repair.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct KeyRange {
+    /// Key prefix, or the key itself when `exact`
+    pub prefix: String,
+    /// Whether the range is the single key `prefix`
+    #[serde(default)]
+    pub exact: bool,
+}
+
+impl KeyRange {
+    /// Every key.
+    pub fn all() -> Self {
+        Self { prefix: String::new(), exact: false }
+    }
+}
+
+impl std::fmt::Display for KeyRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.exact {
+            write!(f, "key '{}'", self.prefix)
+        } else {
+            write!(f, "key range '{}'", self.prefix)
+        }
+    }
+}
+
+/// Request for a peer's latest events in a key range.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepairRequest {
+    /// Requesting node (where the answer goes)
+    pub src: String,
+    /// Keys to repair
+    #[serde(flatten)]
+    pub range: KeyRange,
+}
+
+/// Request for the logged events a returning node missed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatchUpRequest {
+    /// Requesting node (where the answer goes)
+    pub src: String,
+    /// Newest write timestamp the requester has logged, per writer
+    pub since: BTreeMap<String, u64>,
+}
+
+/// Key range repaired when `key` is found out of sync.
+pub fn key_range(key: &str, separator: &str) -> KeyRange {
+    match key.find(separator) {
+        Some(i) if !separator.is_empty() => KeyRange { prefix: key[..i + separator.len()].to_string(), exact: false },
+        _ => KeyRange { prefix: key.to_string(), exact: true },
+    }
+}
+
+/// Newest change log event per key and writer for the keys in `range`.
+pub fn latest_events(changelog: &ChangeLog, range: &KeyRange) -> Result<Vec<ChangeEvent>> {
+    // Entries of a key come oldest first, so the last one per writer wins
+    let mut latest: BTreeMap<(String, String), ChangeEvent> = BTreeMap::new();
+    for (_, ev) in changelog.for_keys(&range.prefix, range.exact)? {
+        latest.insert((ev.key.clone(), ev.src.clone()), ev);
+    }
+    Ok(latest.into_values().collect())
+}
+
+/// Position in the change log's write-time order: `(ts, seq)`.
+pub type LogPosition = (u64, u64);
+
+/// One page of `node_id`'s own change log events missing from the node
+/// behind `req` (those newer than its mark for `node_id`). Reads at most
+/// `limit` entries, starting at `from` (the first missing write if `None`).
+///
+/// Each node answers only for its own writes, so a returning node gets every
+/// write once rather than once per peer.
+///
+/// # Returns
+/// * `(events, next)` - The page, and where the next page starts (`None`
+///   once the log is exhausted)
+pub fn missed_events(
+    changelog: &ChangeLog,
+    req: &CatchUpRequest,
+    node_id: &str,
+    from: Option<LogPosition>,
+    limit: usize,
+) -> Result<(Vec<ChangeEvent>, Option<LogPosition>)> {
+    let mark = req.since.get(node_id).copied();
+    let (ts, seq) = match from {
+        Some(from) => from,
+        // Nothing to read if the requester already has this node's newest write
+        None if changelog.sources()?.get(node_id).is_none_or(|&newest| mark.is_some_and(|mark| newest <= mark)) => {
+            return Ok((Vec::new(), None))
+        }
+        None => (mark.map_or(0, |mark| mark + 1), 0),
+    };
+    let entries = changelog.since(ts, seq, limit)?;
+    let next = match entries.last() {
+        Some((seq, ev)) if entries.len() == limit => Some((ev.ts, seq + 1)),
+        _ => None,
+    };
+    let events = entries.into_iter().map(|(_, ev)| ev).filter(|ev| ev.src == node_id && req.src != node_id).collect();
+    Ok((events, next))
+}
+
+/// Leaf hash of every plain-value key as of the last write this node
+/// published or applied for it (the publisher's view behind `prev`).
+#[derive(Default)]
+pub struct LeafIndex {
+    leaves: Mutex<HashMap<String, [u8; 32]>>,
+}
+
+impl LeafIndex {
+    /// Record that `key` now holds `value` (`None` once deleted).
+    ///
+    /// # Returns
+    /// * `Option<[u8; 32]>` - The key's previous leaf hash, if known
+    pub fn update(&self, key: &str, value: Option<&str>) -> Option<[u8; 32]> {
+        let mut leaves = self.leaves.lock().unwrap_or_else(|e| e.into_inner());
+        leaves.insert(key.to_string(), MerkleTree::leaf_hash(key, value))
+    }
+}
+
+/// Rate limit on repairs per peer and key range.
+pub struct RepairThrottle {
+    /// Minimum time between repairs of the same range with the same peer
+    cooldown: Duration,
+    /// When each `(peer, range)` was last repaired
+    last: Mutex<HashMap<(String, KeyRange), Instant>>,
+}
+
+impl RepairThrottle {
+    /// Create a throttle allowing one request per `cooldown`.
+    pub fn new(cooldown: Duration) -> Self {
+        Self { cooldown, last: Mutex::new(HashMap::new()) }
+    }
+
+    /// Whether a repair of `range` with `peer` may start now (and, if so,
+    /// record that it did).
+    pub fn try_start(&self, peer: &str, range: &KeyRange) -> bool {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        last.retain(|_, at| now.duration_since(*at) < self.cooldown);
+        let slot = (peer.to_string(), range.clone());
+        if last.contains_key(&slot) {
+            return false;
+        }
+        last.insert(slot, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::change_event::OpKind;
+
+    #[test]
+    fn key_ranges_and_throttle() {
+        let prefix = |prefix: &str| KeyRange { prefix: prefix.to_string(), exact: false };
+        let key = |key: &str| KeyRange { prefix: key.to_string(), exact: true };
+        assert_eq!(key_range("user:42", ":"), prefix("user:"));
+        assert_eq!(key_range("user:42:name", ":"), prefix("user:"));
+        assert_eq!(key_range("plain", ":"), key("plain"));
+        assert_eq!(key_range("user:42", ""), key("user:42"));
+
+        let throttle = RepairThrottle::new(Duration::from_secs(60));
+        assert!(throttle.try_start("n1", &prefix("user:")));
+        assert!(!throttle.try_start("n1", &prefix("user:")));
+        assert!(throttle.try_start("n1", &key("user:")));
+        assert!(throttle.try_start("n2", &prefix("user:")));
+        assert!(RepairThrottle::new(Duration::ZERO).try_start("n1", &prefix("user:")));
+
+        let request = RepairRequest { src: "n1".to_string(), range: key("foo") };
+        let json = serde_json::to_string(&request).unwrap();
+        assert_eq!(json, r#"{"src":"n1","prefix":"foo","exact":true}"#);
+        assert_eq!(serde_json::from_str::<RepairRequest>(&json).unwrap(), request);
+    }
+
+    #[test]
+    fn latest_events_per_key_and_writer() {
+        let log = ChangeLog::open("", 100).unwrap();
+        let ev = |key: &str, val: &str, src: &str| {
+            ChangeEvent::with_str_value(1, OpKind::Set, key, Some(val), 1, src, None, None)
+        };
+        for e in [
+            ev("user:1", "a", "n1"),
+            ev("user:1", "b", "n1"),
+            ev("user:1", "c", "n2"),
+            ev("order:1", "x", "n1"),
+            ev("foo", "f", "n1"),
+            ev("foobar", "g", "n1"),
+        ] {
+            log.append(&e).unwrap();
+        }
+        let values = |range: &KeyRange| -> Vec<String> {
+            latest_events(&log, range).unwrap().into_iter().map(|e| String::from_utf8(e.val.unwrap()).unwrap()).collect()
+        };
+        assert_eq!(values(&key_range("user:7", ":")), vec!["b".to_string(), "c".to_string()]);
+        // A key without a namespace is repaired on its own
+        assert_eq!(values(&key_range("foo", ":")), vec!["f".to_string()]);
+
+        let index = LeafIndex::default();
+        assert_eq!(index.update("k", Some("v")), None);
+        assert_eq!(index.update("k", None), Some(MerkleTree::leaf_hash("k", Some("v"))));
+        assert_eq!(index.update("k", Some("w")), Some([0; 32]));
+    }
+
+    #[test]
+    fn missed_events_follow_the_marks_per_writer() {
+        let log = ChangeLog::open("", 100).unwrap();
+        let ev = |key: &str, ts: u64, src: &str| {
+            ChangeEvent::with_str_value(1, OpKind::Set, key, Some("v"), ts, src, None, None)
+        };
+        for e in [ev("a", 10, "n1"), ev("b", 20, "n2"), ev("c", 30, "n1"), ev("d", 40, "n3"), ev("e", 50, "n2")] {
+            log.append(&e).unwrap();
+        }
+        // Pages of two log entries, as answered by `node_id`
+        let keys = |node_id: &str, since: &[(&str, u64)]| -> Vec<String> {
+            let req = CatchUpRequest {
+                src: "n3".to_string(),
+                since: since.iter().map(|(src, ts)| (src.to_string(), *ts)).collect(),
+            };
+            let (mut keys, mut from) = (Vec::new(), None);
+            loop {
+                let (events, next) = missed_events(&log, &req, node_id, from, 2).unwrap();
+                keys.extend(events.into_iter().map(|e| e.key));
+                match next {
+                    Some(next) => from = Some(next),
+                    None => return keys,
+                }
+            }
+        };
+        // Each node answers only for its own writes
+        assert_eq!((keys("n1", &[]), keys("n2", &[])), (vec!["a".into(), "c".into()], vec!["b".into(), "e".into()]));
+        assert_eq!(keys("n1", &[("n1", 10), ("n2", 50)]), vec!["c"]);
+        assert_eq!(keys("n2", &[("n1", 30), ("n2", 20)]), vec!["e"]);
+        assert!(keys("n1", &[("n1", 30), ("n2", 50)]).is_empty());
+        // The requester's own writes are never sent back
+        assert!(keys("n3", &[]).is_empty());
+    }
+}
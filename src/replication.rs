@@ -1,140 +1,906 @@
-//! # MQTT-based Replication System
+//! # Real-time Replication System
 //!
 //! This module implements real-time replication of write operations across
-//! MerkleKV nodes using MQTT as the message transport. Unlike the anti-entropy
-//! sync system, replication provides immediate propagation of changes.
+//! MerkleKV nodes. Unlike the anti-entropy sync system, replication provides
+//! immediate propagation of changes.
 //!
 //! ## How Replication Works
 //! 
 //! 1. **Write Operations**: When a client writes data (SET/DELETE), the operation
-//!    is first applied locally, then published to MQTT
-//! 2. **Message Distribution**: MQTT broker distributes the message to all
-//!    subscribed nodes in the cluster
-//! 3. **Remote Application**: Other nodes receive the message and apply the
+//!    is first applied locally, then published as a `ChangeEvent`
+//! 2. **Message Distribution**: The configured `ReplicationTransport` delivers
+//!    the encoded event to every other node in the cluster
+//! 3. **Remote Application**: Other nodes receive the event and apply the
 //!    same operation to their local storage
 //! 4. **Loop Prevention**: Nodes ignore messages from themselves
 //! 
-//! ## Message Format
+//! ## Transports
 //! 
-//! Replication messages are JSON-serialized with this structure:
-//! ```json
-//! {
-//!   "operation": "SET|DELETE",
-//!   "key": "user:123", 
-//!   "value": "john_doe",  // null for DELETE
-//!   "source_node": "node1"
-//! }
-//! ```
-//!
-//! ## Current Implementation Status
+//! The transport is selected by `replication.transport`:
+//! - `mqtt` (default): publish/subscribe through an MQTT broker
+//! - `tcp`: direct peer-to-peer push to the addresses in `replication.tcp_peers`
+//! 
+//! Tests use the in-process `LoopbackHub` so no broker is required.
 //! 
-//! **⚠️ This is a STUB implementation!**
+//! ## Message Format
 //! 
-//! The current code provides MQTT connectivity and message structure but
-//! lacks integration with the storage engine and server. Missing pieces:
-//! - Integration with the TCP server for write operations
-//! - Handling of received replication messages
-//! - Proper error handling and retry logic
-//! - Conflict resolution for concurrent writes
+//! Events are `ChangeEvent`s encoded with the codec selected by
+//! `replication.codec` (CBOR by default) behind a one-byte codec/version
+//! header, and published on the `events` channel (`{topic_prefix}/events`
+//! over MQTT). Receivers read the header, so nodes may use different codecs.
+//!
+//! With `batch_max_events > 1` writes are coalesced into batch envelopes
+//! (optionally zstd/lz4 compressed), flushed by size or after
+//! `batch_linger_ms`. Receivers unpack batches transparently.
+//!
+//! During a rolling upgrade from a build without framing, set
+//! `legacy_wire_format`: events are then sent as bare codec bodies, one per
+//! publish, which older nodes decode by trial. Repair and catch-up answers
+//! go only to nodes of this build and stay framed.
+//!
+//! ## Filtering and Namespaces
+//!
+//! `include_keys`/`exclude_keys` glob patterns limit which keys a node
+//! publishes and applies. With `namespace_topics` enabled, events are routed
+//! to `events/{namespace}` (the key prefix before `namespace_separator`), and
+//! MQTT nodes listing `namespaces` subscribe only to those subtopics.
+//!
+//! ## Channel Messaging
+//!
+//! With `pubsub_cluster` on, client `PUBLISH` messages are also sent on the
+//! `pubsub` channel (JSON `ClusterMessage`) and delivered to local
+//! subscribers on every other node. See `pubsub` for the semantics.
+//!
+//! ## Write Concern
+//!
+//! Every node acknowledges each event it accepts from a peer on
+//! `acks/{source}`. A writer can then block with `wait_for_acks` until N
+//! distinct peers hold its write (the `WAIT n timeout` command prefix).
+//! Acknowledgements are queued for a single task that sends whatever has
+//! accumulated as one message per writer; when the queue is full they are
+//! dropped and the writer's WAIT times out.
+//!
+//! ## Targeted Repair
+//!
+//! Plain-value events carry the key's pre-write Merkle leaf hash in `prev`.
+//! A receiver whose copy of the key hashes differently exchanges the latest
+//! events for the key's range with the publisher (see `repair`).
+//!
+//! ## Catch-Up
+//!
+//! When its apply loop starts, a node asks its peers on `catchup` for the
+//! writes they made since the newest one it logged from them, so writes
+//! missed while it was down are replayed (see `repair`).
+//!
+//! ## Delivery Guarantees
+//!
+//! Encoded events are first appended to a durable `Outbox` (sled). A drainer
+//! task publishes the oldest entries and removes them only once the transport
+//! accepts them, retrying with exponential backoff while the transport is
+//! down. When the queue reaches `queue_max_events` new writes are refused.
 
 use anyhow::Result;
-use log::{error, info, warn};
-use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
-use std::collections::{HashMap, HashSet};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio::sync::{broadcast, Mutex};
+use base64::Engine;
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use tokio::sync::{broadcast, MutexGuard, Notify};
+use tokio::time::{sleep, timeout_at, Duration, Instant};
 use std::sync::Arc;
 
-use crate::config::Config;
-use crate::store::KVEngineStoreTrait;
-use crate::change_event::{ChangeCodec, ChangeEvent, OpKind};
+use crate::config::{Config, ReplicationConfig, ReplicationTransportKind};
+use crate::error::{ErrorCode, KvError};
+use crate::store::{CrdtValue, KVEngineStoreTrait};
+use crate::change_event::{
+    decode_payload, encode_batch, ChangeCodec, ChangeEvent, Compression, OpKind, WireFormat, SCHEMA_VERSION,
+};
+use crate::key_filter::KeyFilter;
+use crate::metrics::Exposition;
+use crate::changelog::ChangeLog;
+use crate::conflict::{KeyLocks, Resolvers, Version, VersionTable};
+use crate::outbox::Outbox;
+use crate::protocol::{counters, MapKind, Response};
+use crate::pubsub::{ClusterMessage, PubSub};
+use crate::repair::{key_range, latest_events, missed_events, CatchUpRequest, KeyRange, LeafIndex, RepairRequest, RepairThrottle};
+use crate::store::merkle::MerkleTree;
+use crate::transport::{Delivery, MqttTransport, ReplicationTransport, TcpTransport};
+
+/// Transport channel carrying change events.
+const EVENTS_CHANNEL: &str = "events";
+
+/// Transport channel carrying client PUBLISH messages between nodes.
+const PUBSUB_CHANNEL: &str = "pubsub";
+
+/// Prefix of the per-node channels carrying acknowledgements (`acks/{node_id}`).
+const ACKS_CHANNEL: &str = "acks";
+
+/// Prefix of the per-node repair channels: `repair/{node_id}` carries events,
+/// `repair/{node_id}/request` carries `RepairRequest`s.
+const REPAIR_CHANNEL: &str = "repair";
+
+/// Transport channel carrying `CatchUpRequest`s from returning nodes; answers
+/// go to the requester's repair channel.
+const CATCHUP_CHANNEL: &str = "catchup";
+
+/// Events per batch envelope when answering a repair or catch-up.
+const REPAIR_BATCH_EVENTS: usize = 256;
 
-/// Handles MQTT-based replication of write operations.
+/// Change log entries read at a time when answering a catch-up.
+const CATCH_UP_PAGE: usize = 4096;
+
+/// First retry delay after a failed publish.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Upper bound on the retry delay while the transport stays down.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Stripes of the per-key locks serializing conflict checks with writes.
+const KEY_LOCK_STRIPES: usize = 256;
+
+/// Replication counters for one originating node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMetrics {
+    /// Events received from this source (after loop prevention)
+    pub received: u64,
+    /// Events applied to the local store
+    pub applied: u64,
+    /// Events dropped because a newer write for the key was already applied
+    pub stale: u64,
+    /// Events dropped because their op_id had already been applied
+    pub duplicates: u64,
+    /// Events dropped because their key is excluded by the key filter
+    pub filtered: u64,
+    /// Timestamp (`ChangeEvent::ts`) of the last applied event
+    pub last_applied_ts: u64,
+    /// Wall-clock delay between the source writing and us applying the last event
+    pub lag_ms: u64,
+}
+
+/// Replication health counters shared by the Replicator's background tasks.
+#[derive(Debug, Default)]
+pub struct ReplicationMetrics {
+    /// Per-source counters keyed by node id
+    sources: std::sync::Mutex<BTreeMap<String, SourceMetrics>>,
+    /// Payloads or CRDT deltas that could not be decoded
+    decode_failures: AtomicU64,
+    /// Events lost because the apply loop fell behind the receive channel
+    lagged: AtomicU64,
+    /// Events handed to the transport successfully
+    published: AtomicU64,
+    /// Failed publish attempts (each is retried)
+    publish_failures: AtomicU64,
+    /// Events whose `prev` hash did not match the local copy of the key
+    prev_mismatches: AtomicU64,
+    /// Repairs started with a peer after a `prev` mismatch
+    repairs_requested: AtomicU64,
+    /// Events sent to peers while repairing
+    repair_events_sent: AtomicU64,
+    /// Events replayed to peers catching up after downtime
+    catch_up_events_sent: AtomicU64,
+    /// Repair and catch-up requests from peers left unanswered by the cooldown
+    repair_requests_throttled: AtomicU64,
+}
+
+impl ReplicationMetrics {
+    /// Update the counters of `src` in place.
+    fn record(&self, src: &str, f: impl FnOnce(&mut SourceMetrics)) {
+        let mut sources = self.sources.lock().unwrap_or_else(|e| e.into_inner());
+        f(sources.entry(src.to_string()).or_default());
+    }
+
+    /// Snapshot of the per-source counters, ordered by node id.
+    pub fn sources(&self) -> BTreeMap<String, SourceMetrics> {
+        self.sources.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Totals across all sources.
+    pub fn totals(&self) -> SourceMetrics {
+        self.sources().values().fold(SourceMetrics::default(), |mut acc, m| {
+            acc.received += m.received;
+            acc.applied += m.applied;
+            acc.stale += m.stale;
+            acc.duplicates += m.duplicates;
+            acc.filtered += m.filtered;
+            acc.last_applied_ts = acc.last_applied_ts.max(m.last_applied_ts);
+            acc.lag_ms = acc.lag_ms.max(m.lag_ms);
+            acc
+        })
+    }
+
+    /// Aggregate counters for STATS.
+    pub fn stats_fields(&self) -> Vec<(String, Response)> {
+        let totals = self.totals();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        counters([
+            ("repl_events_received", totals.received),
+            ("repl_events_applied", totals.applied),
+            ("repl_events_stale", totals.stale),
+            ("repl_events_duplicate", totals.duplicates),
+            ("repl_events_filtered", totals.filtered),
+            ("repl_decode_failures", load(&self.decode_failures)),
+            ("repl_events_lagged", load(&self.lagged)),
+            ("repl_events_published", load(&self.published)),
+            ("repl_publish_failures", load(&self.publish_failures)),
+            ("repl_prev_mismatches", load(&self.prev_mismatches)),
+            ("repl_repairs_requested", load(&self.repairs_requested)),
+            ("repl_repair_events_sent", load(&self.repair_events_sent)),
+            ("repl_catch_up_events_sent", load(&self.catch_up_events_sent)),
+            ("repl_repair_requests_throttled", load(&self.repair_requests_throttled)),
+        ])
+    }
+
+    /// One field group per source for REPLINFO, written in the text format as
+    /// `source:node2 received=10,applied=9,stale=1,duplicates=0,filtered=0,last_applied_ts=...,lag_ms=3`.
+    pub fn source_fields(&self) -> Vec<(String, Response)> {
+        self.sources()
+            .iter()
+            .map(|(src, m)| {
+                let fields = counters([
+                    ("received", m.received),
+                    ("applied", m.applied),
+                    ("stale", m.stale),
+                    ("duplicates", m.duplicates),
+                    ("filtered", m.filtered),
+                    ("last_applied_ts", m.last_applied_ts),
+                    ("lag_ms", m.lag_ms),
+                ]);
+                (format!("source:{}", src), Response::Map(MapKind::Fields, fields))
+            })
+            .collect()
+    }
+
+    /// Prometheus metrics: publishing, decoding, Merkle repair, and per-source
+    /// counters labelled with the source node id.
+    pub fn export(&self, out: &mut Exposition) {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        out.counter("merkle_kv_repl_events_published_total", "Events handed to the transport", load(&self.published));
+        out.counter("merkle_kv_repl_publish_failures_total", "Failed publish attempts (each is retried)", load(&self.publish_failures));
+        out.counter("merkle_kv_repl_decode_failures_total", "Payloads or CRDT deltas that could not be decoded", load(&self.decode_failures));
+        out.counter("merkle_kv_repl_events_lagged_total", "Events lost because the apply loop fell behind", load(&self.lagged));
+        out.counter(
+            "merkle_kv_merkle_prev_mismatches_total",
+            "Received events whose prev Merkle leaf hash did not match the local key",
+            load(&self.prev_mismatches),
+        );
+        out.counter("merkle_kv_merkle_repairs_requested_total", "Merkle repairs started with a peer", load(&self.repairs_requested));
+        out.counter("merkle_kv_merkle_repair_events_sent_total", "Events sent to peers while repairing", load(&self.repair_events_sent));
+        out.counter(
+            "merkle_kv_repl_catch_up_events_sent_total",
+            "Events replayed to peers catching up after downtime",
+            load(&self.catch_up_events_sent),
+        );
+        out.counter(
+            "merkle_kv_merkle_repair_requests_throttled_total",
+            "Repair and catch-up requests from peers left unanswered by the cooldown",
+            load(&self.repair_requests_throttled),
+        );
+
+        let sources = self.sources();
+        // (name, type, help, value) of each per-source family
+        type Family = (&'static str, &'static str, &'static str, fn(&SourceMetrics) -> u64);
+        let per_source: [Family; 7] = [
+            ("merkle_kv_repl_events_received_total", "counter", "Events received, by source", |m| m.received),
+            ("merkle_kv_repl_events_applied_total", "counter", "Events applied to the local store, by source", |m| m.applied),
+            ("merkle_kv_repl_events_stale_total", "counter", "Events dropped as older than the local write, by source", |m| m.stale),
+            ("merkle_kv_repl_events_duplicate_total", "counter", "Events dropped as already applied, by source", |m| m.duplicates),
+            ("merkle_kv_repl_events_filtered_total", "counter", "Events dropped by the key filter, by source", |m| m.filtered),
+            ("merkle_kv_repl_last_applied_timestamp", "gauge", "Timestamp (ns) of the last applied event, by source", |m| m.last_applied_ts),
+            ("merkle_kv_repl_lag_milliseconds", "gauge", "Delay applying the last event, by source", |m| m.lag_ms),
+        ];
+        for (name, kind, help, value) in per_source {
+            out.family(name, kind, help);
+            for (src, m) in &sources {
+                out.sample(name, &[("source", src)], value(m));
+            }
+        }
+    }
+}
+
+/// Identifier of a change event (`ChangeEvent::op_id`).
+pub type OpId = [u8; 16];
+
+/// Number of own events whose acknowledgements are remembered.
+const ACK_TRACKER_CAPACITY: usize = 10_000;
+
+/// Acknowledgements waiting to be sent before new ones are dropped.
+const ACK_QUEUE: usize = 4096;
+
+/// Most acknowledgements gathered into one round of ack messages.
+const ACK_BATCH_MAX: usize = 512;
+
+/// Acknowledgements received from peers for this node's own events.
+///
+/// Acks are recorded for every own event (not only those being waited on)
+/// so an ack racing ahead of `wait_for_acks` is never lost; the LRU bound
+/// keeps memory flat.
+struct AckTracker {
+    /// Distinct acknowledging nodes per op id
+    acks: std::sync::Mutex<LruCache<OpId, HashSet<String>>>,
+    /// Wakes waiters whenever an ack arrives
+    notify: Notify,
+}
+
+impl AckTracker {
+    fn new() -> Self {
+        let capacity = NonZeroUsize::new(ACK_TRACKER_CAPACITY).expect("non-zero capacity");
+        Self { acks: std::sync::Mutex::new(LruCache::new(capacity)), notify: Notify::new() }
+    }
+
+    fn record(&self, op_ids: Vec<OpId>, node: String) {
+        {
+            let mut acks = self.acks.lock().unwrap_or_else(|e| e.into_inner());
+            for op_id in op_ids {
+                acks.get_or_insert_mut(op_id, HashSet::new).insert(node.clone());
+            }
+        }
+        self.notify.notify_waiters();
+    }
+
+    fn count(&self, op_id: &OpId) -> usize {
+        let mut acks = self.acks.lock().unwrap_or_else(|e| e.into_inner());
+        acks.get(op_id).map(|nodes| nodes.len()).unwrap_or(0)
+    }
+}
+
+/// Ack payload: `[u16 count][count op ids (16 bytes each)][acknowledging
+/// node id (UTF-8)]`.
+fn encode_ack(op_ids: &[OpId], node_id: &str) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(2 + 16 * op_ids.len() + node_id.len());
+    payload.extend_from_slice(&(op_ids.len() as u16).to_be_bytes());
+    for op_id in op_ids {
+        payload.extend_from_slice(op_id);
+    }
+    payload.extend_from_slice(node_id.as_bytes());
+    payload
+}
+
+fn decode_ack(payload: &[u8]) -> Option<(Vec<OpId>, String)> {
+    let count = u16::from_be_bytes(payload.get(..2)?.try_into().ok()?) as usize;
+    let ids = payload.get(2..2 + 16 * count)?;
+    let op_ids = ids.chunks_exact(16).map(|id| id.try_into().expect("16-byte chunk")).collect();
+    let node = String::from_utf8(payload[2 + 16 * count..].to_vec()).ok()?;
+    Some((op_ids, node))
+}
+
+/// Sends acknowledgements for applied peer events from one task.
+#[derive(Clone)]
+struct Acker {
+    /// Queue of (writer, op id) to acknowledge
+    tx: tokio::sync::mpsc::Sender<(String, OpId)>,
+}
+
+impl Acker {
+    /// Start the sending task.
+    fn spawn(transport: Arc<dyn ReplicationTransport>, node_id: String) -> Self {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<(String, OpId)>(ACK_QUEUE);
+        tokio::spawn(async move {
+            while let Some(first) = rx.recv().await {
+                // Everything queued while the last round was sent goes out now
+                let mut pending: BTreeMap<String, Vec<OpId>> = BTreeMap::new();
+                let mut next = Some(first);
+                let mut gathered = 0;
+                while let Some((src, op_id)) = next {
+                    pending.entry(src).or_default().push(op_id);
+                    gathered += 1;
+                    next = if gathered < ACK_BATCH_MAX { rx.try_recv().ok() } else { None };
+                }
+                for (src, op_ids) in pending {
+                    let channel = format!("{}/{}", ACKS_CHANNEL, src);
+                    if let Err(e) = transport.publish(&channel, encode_ack(&op_ids, &node_id)).await {
+                        debug!("Failed to acknowledge {} events from {}: {}", op_ids.len(), src, e);
+                    }
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// Tell the event's writer that this node now holds the write.
+    fn acknowledge(&self, ev: &ChangeEvent) {
+        if self.tx.try_send((ev.src.clone(), ev.op_id)).is_err() {
+            debug!("Acknowledgement queue full, dropping ack for event from {}", ev.src);
+        }
+    }
+}
+
+/// Current time as unix nanoseconds.
+fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+/// Handles replication of write operations over a pluggable transport.
 /// 
-/// The Replicator connects to an MQTT broker and provides methods to
-/// publish local write operations and handle incoming replication messages.
+/// The Replicator encodes local writes as `ChangeEvent`s, hands them to a
+/// `ReplicationTransport` (MQTT, direct TCP, or in-process loopback), and
+/// decodes incoming payloads for the apply loop.
 #[derive(Clone)]
 pub struct Replicator {
-    /// MQTT client for publishing and receiving messages
-    client: AsyncClient,
-    
-    /// Prefix for MQTT topics (e.g., "merkle_kv")
-    topic_prefix: String,
+    /// Transport moving encoded events between nodes
+    transport: Arc<dyn ReplicationTransport>,
     
     /// Unique identifier for this node
     node_id: String,
 
-    /// Preferred codec for on-wire messages
-    codec: ChangeCodec,
+    /// Codec and framing of outgoing events
+    wire: WireFormat,
+
+    /// Durable queue of encoded events awaiting publication
+    outbox: Arc<Outbox>,
+
+    /// Persisted log of produced and applied events (served by REPLAY)
+    changelog: Arc<ChangeLog>,
+
+    /// Conflict policy per key prefix
+    resolvers: Resolvers,
+
+    /// Current versions of plain-value keys, as resolved by `resolvers`
+    versions: Arc<VersionTable>,
+
+    /// Held from a write's conflict check until the store holds its outcome
+    key_locks: Arc<KeyLocks>,
+
+    /// Which keys this node publishes and applies
+    filter: KeyFilter,
+
+    /// Separator splitting the namespace off a key, when routing per namespace
+    namespace_separator: Option<String>,
+
+    /// Replication health counters
+    metrics: Arc<ReplicationMetrics>,
 
-    /// Channel carrying decoded ChangeEvents from the MQTT eventloop
+    /// Acknowledgements received for this node's own events
+    acks: Arc<AckTracker>,
+
+    /// Acknowledges events applied from peers (`None` unless `send_acks`)
+    acker: Option<Acker>,
+
+    /// Whether client PUBLISH messages are fanned out to peers
+    pubsub_cluster: bool,
+
+    /// Whether local writes carry per-key version vectors
+    version_vectors: bool,
+
+    /// Leaf hash of each key as last published or applied (source of `prev`)
+    leaves: Arc<LeafIndex>,
+
+    /// Throttle for repairs after `prev` mismatches; `None` when disabled
+    repair: Option<Arc<RepairThrottle>>,
+
+    /// Separator delimiting the key range repaired after a mismatch
+    range_separator: String,
+
+    /// Whether to ask peers for missed writes when the apply loop starts
+    catch_up_on_start: bool,
+
+    /// Channel carrying decoded ChangeEvents from the transport
     tx: broadcast::Sender<ChangeEvent>,
 }
 
 impl Replicator {
-    /// Create a new replicator and connect to MQTT broker.
+    /// Create a new replicator using the transport selected in config.
     /// 
     /// # Arguments
-    /// * `config` - Configuration containing MQTT broker details
+    /// * `config` - Configuration containing transport details
     /// 
     /// # Returns
     /// * `Result<Replicator>` - New replicator instance or connection error
     /// 
     /// # Behavior
-    /// - Connects to the MQTT broker specified in config
-    /// - Subscribes to replication topic pattern
-    /// - Starts background task to handle incoming messages
+    /// - `mqtt`: connects to the broker and subscribes to `{topic_prefix}/#`
+    /// - `tcp`: listens on `tcp_listen` and pushes to every `tcp_peers` entry
+    /// - Starts background task to decode incoming messages
     /// 
-    /// # MQTT Topics
+    /// # Topics
     /// - Publishes to: `{topic_prefix}/events`
-    /// - Subscribes to: `{topic_prefix}/events/#`
-    pub async fn new(config: &Config) -> Result<Self> {
-        // Configure MQTT client options
-        let mut mqtt_options = MqttOptions::new(
-            &config.replication.client_id,
-            &config.replication.mqtt_broker,
-            config.replication.mqtt_port,
-        );
-        mqtt_options.set_keep_alive(Duration::from_secs(30));
-        
-    // Create MQTT client and event loop
-    let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
-        
-        // Subscribe to the replication topic pattern
-        let topic = format!("{}/events/#", config.replication.topic_prefix);
-        client.subscribe(&topic, QoS::AtLeastOnce).await?;
-
-        // Create broadcast channel and spawn the MQTT poller
+    ///
+    /// Produced and applied events are recorded in `changelog`, which the
+    /// server shares with the executor for REPLAY.
+    pub async fn new(config: &Config, changelog: Arc<ChangeLog>) -> Result<Self> {
+        let repl = &config.replication;
+        let transport: Arc<dyn ReplicationTransport> = match repl.transport {
+            ReplicationTransportKind::Mqtt => Arc::new(MqttTransport::connect(repl).await?),
+            ReplicationTransportKind::Tcp => {
+                let tcp = TcpTransport::bind(&repl.tcp_listen, repl.tcp_peers.clone()).await?;
+                info!("Replication listening for peers on {}", tcp.local_addr());
+                Arc::new(tcp)
+            }
+        };
+        info!("Replication using {} transport", transport.name());
+        Self::with_changelog(transport, repl, changelog)
+    }
+
+    /// Create a replicator on top of an existing transport, with its own
+    /// change log opened at `changelog_path`.
+    ///
+    /// This is how tests wire several replicators to a `LoopbackHub`.
+    ///
+    /// # Arguments
+    /// * `transport` - Transport used to publish and receive events
+    /// * `repl` - Replication settings (node id, codec, batching, queue)
+    ///
+    /// # Returns
+    /// * `Result<Replicator>` - New replicator, or an error opening the queue or log
+    #[cfg(test)]
+    pub fn with_transport(transport: Arc<dyn ReplicationTransport>, repl: &ReplicationConfig) -> Result<Self> {
+        let changelog = Arc::new(ChangeLog::open(&repl.changelog_path, repl.changelog_max_events)?);
+        Self::with_changelog(transport, repl, changelog)
+    }
+
+    /// Create a replicator on top of an existing transport that records
+    /// events in a shared `changelog`.
+    pub fn with_changelog(
+        transport: Arc<dyn ReplicationTransport>,
+        repl: &ReplicationConfig,
+        changelog: Arc<ChangeLog>,
+    ) -> Result<Self> {
+        // Subscribe before spawning so no message published after this call is missed
+        let mut incoming = transport.subscribe();
         let (tx, _rx_unused) = broadcast::channel::<ChangeEvent>(1024);
         let tx_clone = tx.clone();
+        let metrics = Arc::new(ReplicationMetrics::default());
+        let metrics_clone = metrics.clone();
+        let acks = Arc::new(AckTracker::new());
+        let acks_clone = acks.clone();
+        let my_acks_channel = format!("{}/{}", ACKS_CHANNEL, repl.client_id);
+        let my_repair_channel = format!("{}/{}", REPAIR_CHANNEL, repl.client_id);
+        let my_repair_requests = format!("{}/request", my_repair_channel);
+        let my_id = repl.client_id.clone();
+        // Answers to repair and catch-up requests, per requesting peer and range
+        let answers = RepairThrottle::new(Duration::from_millis(repl.repair_cooldown_ms));
+        let (changelog_clone, transport_clone, codec) = (changelog.clone(), transport.clone(), repl.codec);
         tokio::spawn(async move {
             loop {
-                match eventloop.poll().await {
-                    Ok(Event::Incoming(Incoming::Publish(p))) => {
-                        match ChangeEvent::decode_any(&p.payload) {
-                            Ok(ev) => {
-                                let _ = tx_clone.send(ev); // ignore errors if no receivers
+                let msg = match incoming.recv().await {
+                    Ok(msg) => msg,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Replication transport dropped {} messages", n);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if msg.channel == my_acks_channel {
+                    match decode_ack(&msg.payload) {
+                        Some((op_ids, node)) => acks_clone.record(op_ids, node),
+                        None => warn!("Dropping malformed replication ack"),
+                    }
+                    continue;
+                }
+                if msg.channel == my_repair_requests {
+                    match serde_json::from_slice::<RepairRequest>(&msg.payload) {
+                        Ok(req) if !answers.try_start(&req.src, &req.range) => {
+                            metrics_clone.repair_requests_throttled.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Ok(req) => {
+                            let (transport, changelog, metrics) = (transport_clone.clone(), changelog_clone.clone(), metrics_clone.clone());
+                            tokio::spawn(async move {
+                                Self::send_repair(&*transport, &changelog, codec, &metrics, &req.src, &req.range).await;
+                            });
+                        }
+                        Err(e) => warn!("Dropping malformed repair request: {}", e),
+                    }
+                    continue;
+                }
+                if msg.channel == CATCHUP_CHANNEL {
+                    match serde_json::from_slice::<CatchUpRequest>(&msg.payload) {
+                        Ok(req) if req.src == my_id => {}
+                        Ok(req) if !answers.try_start(&req.src, &KeyRange::all()) => {
+                            metrics_clone.repair_requests_throttled.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Ok(req) => {
+                            let (transport, changelog, metrics) = (transport_clone.clone(), changelog_clone.clone(), metrics_clone.clone());
+                            let node_id = my_id.clone();
+                            tokio::spawn(async move {
+                                Self::send_catch_up(&*transport, &changelog, codec, &metrics, &node_id, &req).await;
+                            });
+                        }
+                        Err(e) => warn!("Dropping malformed catch-up request: {}", e),
+                    }
+                    continue;
+                }
+                let repairing = msg.channel == my_repair_channel;
+                if !repairing && msg.channel != EVENTS_CHANNEL && !msg.channel.starts_with("events/") {
+                    continue;
+                }
+                match decode_payload(&msg.payload) {
+                    Ok(events) => {
+                        for mut ev in events {
+                            if repairing {
+                                ev.prev = None; // an old pointer, not a divergence
                             }
-                            Err(e) => warn!("Failed to decode ChangeEvent: {}", e),
+                            let _ = tx_clone.send(ev); // ignore errors if no receivers
                         }
                     }
-                    Ok(_) => {}
                     Err(e) => {
-                        error!("MQTT eventloop error: {}", e);
-                        tokio::time::sleep(Duration::from_secs(3)).await;
+                        metrics_clone.decode_failures.fetch_add(1, Ordering::Relaxed);
+                        warn!("Failed to decode ChangeEvent: {}", e);
                     }
                 }
             }
         });
-        
+
+        let outbox = Arc::new(Outbox::open(&repl.queue_path, repl.queue_max_events)?);
+        if !outbox.is_empty() {
+            info!("Resuming replication with {} queued events", outbox.len());
+        }
+        tokio::spawn(Self::run_drainer(transport.clone(), outbox.clone(), metrics.clone(), repl.clone()));
+        let acker = repl.send_acks.then(|| Acker::spawn(transport.clone(), repl.client_id.clone()));
+
         Ok(Self {
-            client,
-            topic_prefix: config.replication.topic_prefix.clone(),
-            node_id: config.replication.client_id.clone(),
-            codec: ChangeCodec::Cbor,
+            transport,
+            node_id: repl.client_id.clone(),
+            wire: WireFormat { codec: repl.codec, legacy: repl.legacy_wire_format },
+            outbox,
+            changelog,
+            resolvers: Resolvers::from_config(repl),
+            versions: Arc::new(VersionTable::default()),
+            key_locks: Arc::new(KeyLocks::new(KEY_LOCK_STRIPES)),
+            filter: KeyFilter::new(repl.include_keys.clone(), repl.exclude_keys.clone()),
+            namespace_separator: repl.namespace_topics.then(|| repl.namespace_separator.clone()),
+            metrics,
+            acks,
+            acker,
+            pubsub_cluster: repl.pubsub_cluster,
+            version_vectors: repl.version_vectors,
+            leaves: Arc::new(LeafIndex::default()),
+            repair: repl
+                .repair_on_mismatch
+                .then(|| Arc::new(RepairThrottle::new(Duration::from_millis(repl.repair_cooldown_ms)))),
+            range_separator: repl.namespace_separator.clone(),
+            catch_up_on_start: repl.catch_up_on_start,
             tx,
         })
     }
+
+    /// Publish queued events, oldest first, until the queue is empty.
+    ///
+    /// Entries are removed only after the transport confirms them; on failure
+    /// the unconfirmed entries are resent with exponential backoff. Up to the
+    /// transport's `max_in_flight` batches await confirmation at once, and the
+    /// queue is trimmed in order as they are confirmed. With batching enabled,
+    /// up to `batch_max_events` entries (bounded by `batch_max_bytes`) are sent
+    /// as one envelope, waiting `batch_linger_ms` for a batch to fill.
+    async fn run_drainer(
+        transport: Arc<dyn ReplicationTransport>,
+        outbox: Arc<Outbox>,
+        metrics: Arc<ReplicationMetrics>,
+        repl: ReplicationConfig,
+    ) {
+        // Nodes from before framing cannot read batch envelopes
+        let max_events = if repl.legacy_wire_format { 1 } else { repl.batch_max_events };
+        let batching = max_events > 1;
+        let linger = Duration::from_millis(repl.batch_linger_ms);
+        let window = transport.max_in_flight().max(1);
+        let mut retry_delay = INITIAL_RETRY_DELAY;
+        // Sent batches awaiting confirmation, oldest first: (last seq, events, delivery)
+        let mut in_flight: VecDeque<(u64, u64, Delivery)> = VecDeque::new();
+        loop {
+            if in_flight.len() < window {
+                let from_seq = in_flight.back().map_or(0, |(seq, _, _)| seq + 1);
+                let entries = match outbox.peek(from_seq, max_events, repl.batch_max_bytes) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        warn!("Failed to read replication queue: {}", e);
+                        sleep(retry_delay).await;
+                        continue;
+                    }
+                };
+                if !entries.is_empty() {
+                    let last_seq = entries[entries.len() - 1].seq;
+                    let count = entries.len() as u64;
+                    let channel = entries[0].channel.clone();
+                    let payload = if entries.len() == 1 && (repl.compression == Compression::None || repl.legacy_wire_format) {
+                        Ok(entries.into_iter().next().expect("one entry").payload)
+                    } else {
+                        let payloads: Vec<Vec<u8>> = entries.into_iter().map(|e| e.payload).collect();
+                        encode_batch(&payloads, repl.compression).map_err(|e| anyhow::anyhow!(e))
+                    };
+                    let sent = match payload {
+                        Ok(payload) => transport.send(&channel, payload).await,
+                        Err(e) => Err(e),
+                    };
+                    match sent {
+                        Ok(delivery) => in_flight.push_back((last_seq, count, delivery)),
+                        Err(e) => {
+                            in_flight.clear();
+                            Self::publish_failed(&metrics, &outbox, &mut retry_delay, e).await;
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            let has_room = in_flight.len() < window;
+            let Some((_, _, delivery)) = in_flight.front_mut() else {
+                outbox.notified().await;
+                if batching {
+                    sleep(linger).await;
+                }
+                continue;
+            };
+            // With room in the window, new entries are sent without waiting
+            // for the oldest batch to be confirmed
+            let result = if has_room {
+                tokio::select! {
+                    result = delivery => result,
+                    _ = outbox.notified() => continue,
+                }
+            } else {
+                delivery.await
+            };
+            let (last_seq, count, _) = in_flight.pop_front().expect("oldest batch");
+            match result {
+                Ok(()) => {
+                    metrics.published.fetch_add(count, Ordering::Relaxed);
+                    retry_delay = INITIAL_RETRY_DELAY;
+                    if let Err(e) = outbox.ack_through(last_seq) {
+                        warn!("Failed to trim replication queue: {}", e);
+                    }
+                }
+                Err(e) => {
+                    // Later batches are resent too; receivers deduplicate
+                    in_flight.clear();
+                    Self::publish_failed(&metrics, &outbox, &mut retry_delay, e).await;
+                }
+            }
+        }
+    }
+
+    /// Count a failed publish and back off before the drainer retries.
+    async fn publish_failed(
+        metrics: &ReplicationMetrics,
+        outbox: &Outbox,
+        retry_delay: &mut Duration,
+        e: anyhow::Error,
+    ) {
+        metrics.publish_failures.fetch_add(1, Ordering::Relaxed);
+        warn!("Replication publish failed ({} queued), retrying in {:?}: {}", outbox.len(), retry_delay, e);
+        sleep(*retry_delay).await;
+        *retry_delay = (*retry_delay * 2).min(MAX_RETRY_DELAY);
+    }
+
+    /// Wait until every queued event has been handed to the transport, or
+    /// until `timeout` expires (used during shutdown).
+    ///
+    /// # Returns
+    /// * `bool` - Whether the queue drained; undrained events stay queued on disk
+    pub async fn flush_outbox(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.outbox.is_empty() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        true
+    }
+
+    /// Disconnect the transport once it has sent what it buffered, waiting at
+    /// most `timeout` (after `flush_outbox`, during shutdown).
+    pub async fn close(&self, timeout: Duration) {
+        self.transport.close(timeout).await;
+    }
+
+    /// Fail if the outbound queue is full, so callers can refuse a write
+    /// before applying it locally.
+    pub fn check_capacity(&self) -> Result<()> {
+        self.outbox.check_capacity()
+    }
+
+    /// Forward a client PUBLISH to peers (no-op unless `pubsub_cluster` is on).
+    pub async fn publish_message(&self, channel: &str, message: &str) -> Result<()> {
+        if !self.pubsub_cluster {
+            return Ok(());
+        }
+        let msg = ClusterMessage { src: self.node_id.clone(), channel: channel.to_string(), message: message.to_string() };
+        self.transport.publish(PUBSUB_CHANNEL, serde_json::to_vec(&msg)?).await
+    }
+
+    /// Deliver PUBLISH messages from peers to local subscribers (no-op unless
+    /// `pubsub_cluster` is on).
+    pub fn start_pubsub_bridge(&self, pubsub: Arc<PubSub>) {
+        if !self.pubsub_cluster {
+            return;
+        }
+        let mut rx = self.transport.subscribe();
+        let node_id = self.node_id.clone();
+        tokio::spawn(async move {
+            loop {
+                let msg = match rx.recv().await {
+                    Ok(msg) => msg,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Pub/sub bridge fell behind, {} transport messages skipped", n);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if msg.channel != PUBSUB_CHANNEL {
+                    continue;
+                }
+                match serde_json::from_slice::<ClusterMessage>(&msg.payload) {
+                    Ok(m) if m.src != node_id => {
+                        pubsub.publish(&m.channel, &m.message);
+                    }
+                    Ok(_) => {} // our own message echoed back by the broker
+                    Err(e) => warn!("Dropping malformed pub/sub message: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Subscribe to every change event seen by this node: local writes as
+    /// they are published and peer events as they arrive (used by WATCH).
+    pub fn watch(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Whether local writes must be checked with `admit` before they are
+    /// applied (false when every key uses LWW, where local writes always win).
+    pub fn checks_local_writes(&self) -> bool {
+        !self.resolvers.is_lww_only()
+    }
+
+    /// Lock `keys` against concurrent writes, local and replicated. Hold the
+    /// guards from `admit` until the write is published.
+    pub async fn lock_keys<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Vec<MutexGuard<'_, ()>> {
+        self.key_locks.lock(keys).await
+    }
+
+    /// Check a local write (`None` for a delete) against the key's conflict
+    /// policy, so a write that would lose on peers is refused up front.
+    /// `current` is the key's value in the store, for keys written before
+    /// this node started.
+    pub fn admit(&self, key: &str, current: Option<&str>, value: Option<&str>) -> Result<()> {
+        let resolver = self.resolvers.for_key(key);
+        self.versions.seed(resolver, key, || current.map(str::to_string));
+        let ts = now_nanos();
+        let vv = self.version_vectors.then(|| self.versions.next_vv(key, &self.node_id, ts));
+        let candidate = Version { value: value.map(str::to_string), ts, src: self.node_id.clone(), vv };
+        if !self.versions.admits(resolver, key, &candidate) {
+            let message = format!("write to '{}' rejected by {} policy", key, resolver.name());
+            return Err(KvError::new(ErrorCode::Conflict, message).into());
+        }
+        Ok(())
+    }
+
+    /// Concurrent values of a key under the keep_siblings policy (empty
+    /// unless there is more than one).
+    pub fn siblings(&self, key: &str) -> Vec<String> {
+        self.versions.siblings(self.resolvers.for_key(key), key)
+    }
+
+    /// Persisted change log of produced and applied events (for REPLAY).
+    #[cfg(test)]
+    pub fn changelog(&self) -> &ChangeLog {
+        &self.changelog
+    }
+
+    /// Replication health counters (for STATS, INFO and REPLINFO).
+    pub fn metrics(&self) -> &ReplicationMetrics {
+        &self.metrics
+    }
+
+    /// Prometheus metrics: outbound queue, conflicts and `ReplicationMetrics`.
+    pub fn export_metrics(&self, out: &mut Exposition) {
+        out.gauge("merkle_kv_repl_queue_depth", "Events waiting in the outbound replication queue", self.outbox.len() as u64);
+        out.gauge("merkle_kv_repl_queue_max_events", "Capacity of the outbound replication queue", self.outbox.max_events() as u64);
+        out.counter("merkle_kv_repl_conflicts_total", "Writes found concurrent with a stored version", self.versions.conflicts());
+        self.metrics.export(out);
+    }
+
+    /// Replication status fields for the REPLSTATUS command.
+    pub fn status(&self) -> Vec<(String, Response)> {
+        let mut fields = vec![("transport".to_string(), Response::Value(self.transport.name().to_string()))];
+        fields.extend(counters([
+            ("queue_depth", self.outbox.len() as u64),
+            ("queue_max_events", self.outbox.max_events() as u64),
+            ("changelog_next_seq", self.changelog.next_seq()),
+            ("conflicts", self.versions.conflicts()),
+        ]));
+        fields
+    }
     
     /// Publish a SET operation to other nodes.
     /// 
@@ -156,9 +922,9 @@ impl Replicator {
     ///     replicator.publish_set(&key, &value).await?;
     /// }
     /// ```
-    pub async fn publish_set(&self, key: &str, value: &str) -> Result<()> {
-        let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let ev = ChangeEvent::with_str_value(1, OpKind::Set, key, Some(value), ts, self.node_id.clone(), None, None);
+    pub async fn publish_set(&self, key: &str, value: &str) -> Result<Option<OpId>> {
+        let ts = now_nanos();
+        let ev = ChangeEvent::with_str_value(SCHEMA_VERSION, OpKind::Set, key, Some(value), ts, self.node_id.clone(), None, None);
         self.publish_event(ev).await
     }
     
@@ -181,96 +947,232 @@ impl Replicator {
     ///     replicator.publish_delete(&key).await?;
     /// }
     /// ```
-    pub async fn publish_delete(&self, key: &str) -> Result<()> {
-        let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let ev = ChangeEvent::with_str_value(1, OpKind::Del, key, None, ts, self.node_id.clone(), None, None);
+    pub async fn publish_delete(&self, key: &str) -> Result<Option<OpId>> {
+        let ts = now_nanos();
+        let ev = ChangeEvent::with_str_value(SCHEMA_VERSION, OpKind::Del, key, None, ts, self.node_id.clone(), None, None);
         self.publish_event(ev).await
     }
 
     /// Publish an INCR with resulting numeric value.
-    pub async fn publish_incr(&self, key: &str, new_value: i64) -> Result<()> {
-        let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let ev = ChangeEvent::with_str_value(1, OpKind::Incr, key, Some(&new_value.to_string()), ts, self.node_id.clone(), None, None);
+    pub async fn publish_incr(&self, key: &str, new_value: i64) -> Result<Option<OpId>> {
+        let ts = now_nanos();
+        let ev = ChangeEvent::with_str_value(SCHEMA_VERSION, OpKind::Incr, key, Some(&new_value.to_string()), ts, self.node_id.clone(), None, None);
         self.publish_event(ev).await
     }
 
     /// Publish a DECR with resulting numeric value.
-    pub async fn publish_decr(&self, key: &str, new_value: i64) -> Result<()> {
-        let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let ev = ChangeEvent::with_str_value(1, OpKind::Decr, key, Some(&new_value.to_string()), ts, self.node_id.clone(), None, None);
+    pub async fn publish_decr(&self, key: &str, new_value: i64) -> Result<Option<OpId>> {
+        let ts = now_nanos();
+        let ev = ChangeEvent::with_str_value(SCHEMA_VERSION, OpKind::Decr, key, Some(&new_value.to_string()), ts, self.node_id.clone(), None, None);
         self.publish_event(ev).await
     }
 
     /// Publish an APPEND with resulting value.
-    pub async fn publish_append(&self, key: &str, new_value: &str) -> Result<()> {
-        let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let ev = ChangeEvent::with_str_value(1, OpKind::Append, key, Some(new_value), ts, self.node_id.clone(), None, None);
+    pub async fn publish_append(&self, key: &str, new_value: &str) -> Result<Option<OpId>> {
+        let ts = now_nanos();
+        let ev = ChangeEvent::with_str_value(SCHEMA_VERSION, OpKind::Append, key, Some(new_value), ts, self.node_id.clone(), None, None);
         self.publish_event(ev).await
     }
 
     /// Publish a PREPEND with resulting value.
-    pub async fn publish_prepend(&self, key: &str, new_value: &str) -> Result<()> {
-        let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
-        let ev = ChangeEvent::with_str_value(1, OpKind::Prepend, key, Some(new_value), ts, self.node_id.clone(), None, None);
+    pub async fn publish_prepend(&self, key: &str, new_value: &str) -> Result<Option<OpId>> {
+        let ts = now_nanos();
+        let ev = ChangeEvent::with_str_value(SCHEMA_VERSION, OpKind::Prepend, key, Some(new_value), ts, self.node_id.clone(), None, None);
         self.publish_event(ev).await
     }
 
-    /// Serialize and publish a change event to MQTT with QoS 1 (at-least-once).
-    async fn publish_event(&self, ev: ChangeEvent) -> Result<()> {
-        let topic = format!("{}/events", self.topic_prefix);
-        let payload = self.codec.encode(&ev).map_err(|e| anyhow::anyhow!(e))?;
-        self.client
-            .publish(&topic, QoS::AtLeastOnce, false, payload)
-            .await?;
-        Ok(())
+    /// Publish a CRDT delta (SADD/SREM/HSET/HDEL) for receivers to merge.
+    pub async fn publish_crdt(&self, op: OpKind, key: &str, delta: &CrdtValue) -> Result<Option<OpId>> {
+        let ts = now_nanos();
+        let ev = ChangeEvent::new(SCHEMA_VERSION, op, key, Some(delta.canonical_bytes()), ts, self.node_id.clone(), None, None);
+        self.publish_event(ev).await
+    }
+
+    /// Serialize a change event and append it to the durable outbound queue.
+    ///
+    /// Returns the event's op id once it is queued; the drainer publishes it.
+    /// Events for keys rejected by the key filter are skipped and return
+    /// `None`. Fails if the queue is full.
+    async fn publish_event(&self, mut ev: ChangeEvent) -> Result<Option<OpId>> {
+        if !ev.op.is_crdt() {
+            if self.version_vectors {
+                let vv = self.versions.next_vv(&ev.key, &self.node_id, ev.ts);
+                ev = ev.with_vv(vv);
+            }
+            ev.prev = self.leaves.update(&ev.key, Self::version_of(&ev).value.as_deref());
+        }
+        // Local writes are logged and visible to WATCH streams whether or not they replicate
+        self.changelog.append(&ev)?;
+        if !ev.op.is_crdt() {
+            let resolver = self.resolvers.for_key(&ev.key);
+            if self.versions.apply(resolver, &ev.key, &Self::version_of(&ev)).is_none() {
+                debug!("Local write to '{}' lost to a concurrent write under {}", ev.key, resolver.name());
+            }
+        }
+        let _ = self.tx.send(ev.clone());
+        if !self.filter.allows(&ev.key) {
+            return Ok(None);
+        }
+        let payload = self.wire.encode(&ev).map_err(|e| anyhow::anyhow!(e))?;
+        self.outbox.push(&self.channel_for(&ev.key), payload)?;
+        Ok(Some(ev.op_id))
+    }
+
+    /// Wait until every event in `op_ids` has been acknowledged by at least
+    /// `replicas` distinct peers, or until `timeout` expires.
+    ///
+    /// # Returns
+    /// The smallest acknowledgement count among `op_ids` at the time of
+    /// return (so `>= replicas` means the write concern was met). Returns
+    /// `replicas` immediately when `op_ids` is empty: a write that replicated
+    /// nothing (a no-op delete, an excluded key) has nothing to wait for.
+    pub async fn wait_for_acks(&self, op_ids: &[OpId], replicas: usize, timeout: Duration) -> usize {
+        if op_ids.is_empty() {
+            return replicas;
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Create the notification future before checking, so an ack that
+            // lands between the check and the wait is not missed
+            let notified = self.acks.notify.notified();
+            let acked = op_ids.iter().map(|id| self.acks.count(id)).min().unwrap_or(0);
+            if acked >= replicas || timeout_at(deadline, notified).await.is_err() {
+                return op_ids.iter().map(|id| self.acks.count(id)).min().unwrap_or(0);
+            }
+        }
+    }
+
+    /// Transport channel for events on `key`: `events/{namespace}` when
+    /// namespace routing is enabled and the key has a namespace, else `events`.
+    fn channel_for(&self, key: &str) -> String {
+        let namespace = self
+            .namespace_separator
+            .as_deref()
+            .and_then(|sep| key.split_once(sep))
+            .map(|(ns, _)| ns)
+            .filter(|ns| !ns.is_empty() && !ns.contains(['/', '#', '+']));
+        match namespace {
+            Some(ns) => format!("{}/{}", EVENTS_CHANNEL, ns),
+            None => EVENTS_CHANNEL.to_string(),
+        }
     }
     
-    /// Start background tasks for (1) forwarding MQTT publish packets into a
-    /// channel, and (2) applying them to local storage with idempotency and LWW.
+    /// Start the background task applying decoded events to local storage
+    /// with idempotency and LWW, then (with `catch_up_on_start`) ask peers to
+    /// replay the writes this node missed while it was down.
     ///
-    /// Teaching note: We separate transport concerns (MQTT event loop) from
+    /// Teaching note: We separate transport concerns (MQTT, TCP, ...) from
     /// application concerns (idempotent LWW apply) with a channel. This models
     /// the classic “ingress queue” in replicated systems.
-    pub async fn start_replication_handler(&self, store: Arc<Mutex<Box<dyn KVEngineStoreTrait + Send + Sync>>>) {
+    pub async fn start_replication_handler(&self, store: Arc<dyn KVEngineStoreTrait>) {
         // Subscribe to broadcasted events from the MQTT poller
         let mut rx = self.tx.subscribe();
         let node_id = self.node_id.clone();
+        let metrics = self.metrics.clone();
+        let filter = self.filter.clone();
+        let acker = self.acker.clone();
+        let changelog = self.changelog.clone();
+        let resolvers = self.resolvers.clone();
+        let versions = self.versions.clone();
+        let key_locks = self.key_locks.clone();
+        let leaves = self.leaves.clone();
+        let repairer = self.clone();
         tokio::spawn(async move {
             let mut seen: HashSet<[u8; 16]> = HashSet::new();
-            let mut last_ts: HashMap<String, u64> = HashMap::new();
             loop {
                 let ev = match rx.recv().await {
                     Ok(ev) => ev,
-                    Err(e) => {
-                        warn!("Replication handler receive error: {}", e);
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        metrics.lagged.fetch_add(n, Ordering::Relaxed);
+                        warn!("Replication handler fell behind, {} events lost", n);
                         continue;
                     }
+                    Err(broadcast::error::RecvError::Closed) => break,
                 };
                 if ev.src == node_id { continue; } // loop prevention
-                if seen.contains(&ev.op_id) { continue; } // idempotency
-                let current_ts = last_ts.get(&ev.key).cloned().unwrap_or(0);
-                if ev.ts < current_ts { continue; } // LWW
-
-                let mut guard = store.lock().await;
-                match ev.op {
-                    OpKind::Del => {
-                        guard.delete(&ev.key);
+                metrics.record(&ev.src, |m| m.received += 1);
+                if !filter.allows(&ev.key) { // key outside this node's replication scope
+                    metrics.record(&ev.src, |m| m.filtered += 1);
+                    continue;
+                }
+                if seen.contains(&ev.op_id) { // idempotency
+                    metrics.record(&ev.src, |m| m.duplicates += 1);
+                    if let Some(acker) = &acker { acker.acknowledge(&ev); }
+                    continue;
+                }
+
+                // CRDT deltas merge commutatively, so they skip the LWW check
+                if ev.op.is_crdt() {
+                    let delta = match ev.val.as_deref().map(CrdtValue::from_bytes) {
+                        Some(Ok(delta)) => delta,
+                        Some(Err(e)) => {
+                            metrics.decode_failures.fetch_add(1, Ordering::Relaxed);
+                            warn!("Dropping {:?} event for '{}': {}", ev.op, ev.key, e);
+                            continue;
+                        }
+                        None => {
+                            metrics.decode_failures.fetch_add(1, Ordering::Relaxed);
+                            warn!("Dropping {:?} event for '{}': missing delta", ev.op, ev.key);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = store.merge_crdt(&ev.key, &delta) {
+                        warn!("Failed to merge CRDT event into store: {}", e);
                     }
-                    _ => {
-                        if let Some(bytes) = ev.val.clone() {
-                            // Interpret as UTF-8 if possible, otherwise store base64 string
-                            let value = String::from_utf8(bytes.clone())
-                                .unwrap_or_else(|_| base64::encode(bytes));
-                            // We apply by writing the resulting value (idempotent)
-                            if let Err(e) = guard.set(ev.key.clone(), value) {
-                                warn!("Failed to apply event to store: {}", e);
-                            }
+                    seen.insert(ev.op_id);
+                    Self::record_applied(&metrics, &ev, &changelog);
+                    if let Some(acker) = &acker { acker.acknowledge(&ev); }
+                    continue;
+                }
+
+                if ev.op != OpKind::Del && ev.val.is_none() {
+                    metrics.decode_failures.fetch_add(1, Ordering::Relaxed);
+                    warn!("Dropping {:?} event for '{}': missing value", ev.op, ev.key);
+                    continue;
+                }
+
+                // A different pre-write hash means we and the writer have diverged
+                if let Some(prev) = ev.prev {
+                    let local = MerkleTree::leaf_hash(&ev.key, store.get(&ev.key).as_deref());
+                    if local != prev {
+                        metrics.prev_mismatches.fetch_add(1, Ordering::Relaxed);
+                        debug!("Leaf hash of '{}' differs from {}'s before its write", ev.key, ev.src);
+                        repairer.start_repair(&ev.src, &ev.key);
+                    }
+                }
+
+                // The key's conflict policy decides whether this write wins;
+                // local writes to the key wait until the store holds the outcome
+                let _lock = key_locks.lock([ev.key.as_str()]).await;
+                let resolver = resolvers.for_key(&ev.key);
+                versions.seed(resolver, &ev.key, || store.get(&ev.key));
+                let resolved = match versions.apply(resolver, &ev.key, &Self::version_of(&ev)) {
+                    Some(resolved) => resolved,
+                    None => {
+                        metrics.record(&ev.src, |m| m.stale += 1);
+                        // A winning write already holds the key, so this one is settled here too
+                        if let Some(acker) = &acker { acker.acknowledge(&ev); }
+                        continue;
+                    }
+                };
+
+                // The store holds the newest live version (the only one unless
+                // siblings are kept); we apply by writing it (idempotent)
+                let newest = resolved.iter().rev().find_map(|v| v.value.clone());
+                match &newest {
+                    Some(value) => {
+                        if let Err(e) = store.set(ev.key.clone(), value.clone()) {
+                            warn!("Failed to apply event to store: {}", e);
                         }
                     }
+                    None => {
+                        store.delete(&ev.key);
+                    }
                 }
-                // Update LWW state and dedupe set
-                last_ts.insert(ev.key.clone(), ev.ts);
+                leaves.update(&ev.key, newest.as_deref());
                 seen.insert(ev.op_id);
+                Self::record_applied(&metrics, &ev, &changelog);
+                if let Some(acker) = &acker { acker.acknowledge(&ev); }
 
                 // TODO: Update Merkle tree – in this prototype the store engines
                 // are in-memory maps without an exposed Merkle instance. The
@@ -278,37 +1180,770 @@ impl Replicator {
                 // would invoke an incremental Merkle update here.
             }
         });
+
+        // Subscribed above, so the replayed events are not missed
+        if self.catch_up_on_start {
+            self.request_catch_up();
+        }
+    }
+
+    /// Start repairing the range of `key` with `peer` (unless disabled or
+    /// throttled): ask for the peer's latest events and send it ours.
+    fn start_repair(&self, peer: &str, key: &str) {
+        let Some(throttle) = &self.repair else { return };
+        let range = key_range(key, &self.range_separator);
+        if !throttle.try_start(peer, &range) {
+            return;
+        }
+        self.metrics.repairs_requested.fetch_add(1, Ordering::Relaxed);
+        info!("Repairing {} with {}", range, peer);
+        let this = self.clone();
+        let peer = peer.to_string();
+        tokio::spawn(async move {
+            let request = RepairRequest { src: this.node_id.clone(), range: range.clone() };
+            let channel = format!("{}/{}/request", REPAIR_CHANNEL, peer);
+            match serde_json::to_vec(&request) {
+                Ok(payload) => {
+                    if let Err(e) = this.transport.publish(&channel, payload).await {
+                        warn!("Failed to request repair from {}: {}", peer, e);
+                    }
+                }
+                Err(e) => warn!("Failed to encode repair request: {}", e),
+            }
+            Self::send_repair(&*this.transport, &this.changelog, this.wire.codec, &this.metrics, &peer, &range).await;
+        });
+    }
+
+    /// Send `peer` our latest change log events for the keys in `range`.
+    async fn send_repair(
+        transport: &dyn ReplicationTransport,
+        changelog: &ChangeLog,
+        codec: ChangeCodec,
+        metrics: &ReplicationMetrics,
+        peer: &str,
+        range: &KeyRange,
+    ) {
+        let events = match latest_events(changelog, range) {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("Failed to read change log for repair of {}: {}", range, e);
+                return;
+            }
+        };
+        // The peer would ignore its own events
+        let events: Vec<ChangeEvent> = events.into_iter().filter(|ev| ev.src != peer).collect();
+        Self::send_events(transport, codec, &metrics.repair_events_sent, peer, &events).await;
+    }
+
+    /// Replay to a returning peer the writes of `node_id` (this node) that
+    /// it missed, reading the change log a page at a time.
+    async fn send_catch_up(
+        transport: &dyn ReplicationTransport,
+        changelog: &ChangeLog,
+        codec: ChangeCodec,
+        metrics: &ReplicationMetrics,
+        node_id: &str,
+        req: &CatchUpRequest,
+    ) {
+        let (mut from, mut replayed) = (None, 0);
+        loop {
+            let (events, next) = match missed_events(changelog, req, node_id, from, CATCH_UP_PAGE) {
+                Ok(page) => page,
+                Err(e) => {
+                    warn!("Failed to read change log for catch-up of {}: {}", req.src, e);
+                    return;
+                }
+            };
+            if !Self::send_events(transport, codec, &metrics.catch_up_events_sent, &req.src, &events).await {
+                return;
+            }
+            replayed += events.len();
+            match next {
+                Some(next) => from = Some(next),
+                None => break,
+            }
+        }
+        if replayed > 0 {
+            info!("Replayed {} missed events to {}", replayed, req.src);
+        }
+    }
+
+    /// Send `events` to `peer`'s repair channel in batches, counting the sent
+    /// ones in `sent`; stops at the first failed publish.
+    ///
+    /// # Returns
+    /// * `bool` - Whether every event was sent
+    async fn send_events(
+        transport: &dyn ReplicationTransport,
+        codec: ChangeCodec,
+        sent: &AtomicU64,
+        peer: &str,
+        events: &[ChangeEvent],
+    ) -> bool {
+        let framed: Vec<Vec<u8>> = events.iter().filter_map(|ev| codec.encode(ev).ok()).collect();
+        let channel = format!("{}/{}", REPAIR_CHANNEL, peer);
+        for chunk in framed.chunks(REPAIR_BATCH_EVENTS) {
+            let result = match encode_batch(chunk, Compression::None) {
+                Ok(envelope) => transport.publish(&channel, envelope).await,
+                Err(e) => Err(anyhow::anyhow!(e)),
+            };
+            match result {
+                Ok(()) => {
+                    sent.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    warn!("Failed to send change log events to {}: {}", peer, e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Ask every peer for its own writes this node missed: those newer than
+    /// the newest one this node logged from it.
+    fn request_catch_up(&self) {
+        let since = match self.changelog.sources() {
+            Ok(mut since) => {
+                since.remove(&self.node_id);
+                since
+            }
+            Err(e) => {
+                warn!("Failed to read change log for catch-up: {}", e);
+                return;
+            }
+        };
+        let request = CatchUpRequest { src: self.node_id.clone(), since };
+        let transport = self.transport.clone();
+        tokio::spawn(async move {
+            match serde_json::to_vec(&request) {
+                Ok(payload) => {
+                    if let Err(e) = transport.publish(CATCHUP_CHANNEL, payload).await {
+                        warn!("Failed to request catch-up from peers: {}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to encode catch-up request: {}", e),
+            }
+        });
+    }
+
+    /// Count an applied event, estimate lag from its write timestamp and
+    /// append it to the change log.
+    fn record_applied(metrics: &ReplicationMetrics, ev: &ChangeEvent, changelog: &ChangeLog) {
+        if let Err(e) = changelog.append(ev) {
+            warn!("Failed to append applied event to change log: {}", e);
+        }
+        let lag_ms = now_nanos().saturating_sub(ev.ts) / 1_000_000;
+        metrics.record(&ev.src, |m| {
+            m.applied += 1;
+            m.last_applied_ts = m.last_applied_ts.max(ev.ts);
+            m.lag_ms = lag_ms;
+        });
+    }
+
+    /// Conflict-resolution view of a plain-value event.
+    fn version_of(ev: &ChangeEvent) -> Version {
+        let value = match ev.op {
+            OpKind::Del => None,
+            // Interpret as UTF-8 if possible, otherwise store base64 string
+            _ => ev.val.clone().map(|bytes| String::from_utf8(bytes.clone()).unwrap_or_else(|_| base64::engine::general_purpose::STANDARD.encode(bytes))),
+        };
+        Version { value, ts: ev.ts, src: ev.src.clone(), vv: ev.vv.clone() }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    // TODO: Implement comprehensive tests for replication logic
-    // When the actual implementation is integrated, tests should cover:
-    // 
-    // 1. Message serialization/deserialization
-    // 2. MQTT connectivity and reconnection
-    // 3. Publishing SET and DELETE operations
-    // 4. Receiving and applying remote operations
-    // 5. Loop prevention (ignoring own messages)
-    // 6. Error handling for malformed messages
-    // 
-    // Example test structure:
-    // #[test]
-    // fn test_replication_message_serialization() {
-    //     let msg = ReplicationMessage {
-    //         operation: "SET".to_string(),
-    //         key: "test".to_string(), 
-    //         value: Some("value".to_string()),
-    //         source_node: "node1".to_string(),
-    //     };
-    //     let json = serde_json::to_string(&msg).unwrap();
-    //     let parsed: ReplicationMessage = serde_json::from_str(&json).unwrap();
-    //     assert_eq!(msg.operation, parsed.operation);
-    // }
-    // 
-    // #[tokio::test]
-    // async fn test_mqtt_integration() {
-    //     // Mock MQTT broker and test publish/subscribe flow
-    // }
+    use super::*;
+    use crate::change_event::VersionVector;
+    use crate::store::crdt::{new_tag, OrSet};
+    use crate::store::RwLockEngine;
+    use crate::protocol::ResponseFormat;
+    use crate::transport::LoopbackHub;
+    use std::time::Duration;
+
+    type SharedStore = Arc<dyn KVEngineStoreTrait>;
+
+    fn repl_config(node_id: &str, codec: ChangeCodec) -> ReplicationConfig {
+        let mut repl = Config::default().replication;
+        repl.client_id = node_id.to_string();
+        repl.codec = codec;
+        repl.queue_path = String::new();
+        repl.changelog_path = String::new();
+        repl
+    }
+
+    fn new_store() -> SharedStore {
+        Arc::new(RwLockEngine::new("").unwrap())
+    }
+
+    /// A STATS/REPLSTATUS counter field.
+    fn counter(name: &str, value: i64) -> (String, Response) {
+        (name.to_string(), Response::Integer(value))
+    }
+
+    /// Poll `store` until `key` has a value or the timeout expires.
+    async fn wait_for(store: &SharedStore, key: &str) -> Option<String> {
+        for _ in 0..100 {
+            if let Some(v) = store.get(key) {
+                return Some(v);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn set_is_applied_on_peer() {
+        let hub = LoopbackHub::new();
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-b", ChangeCodec::Json)).unwrap();
+        let store_b = new_store();
+        b.start_replication_handler(store_b.clone()).await;
+
+        a.publish_set("k", "v").await.unwrap();
+        assert_eq!(wait_for(&store_b, "k").await.as_deref(), Some("v"));
+
+        a.publish_delete("k").await.unwrap();
+        for _ in 0..100 {
+            if store_b.get("k").is_none() { break; }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(store_b.get("k"), None);
+    }
+
+    #[tokio::test]
+    async fn own_events_are_ignored() {
+        let hub = LoopbackHub::new();
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        let store_a = new_store();
+        a.start_replication_handler(store_a.clone()).await;
+
+        a.publish_set("k", "v").await.unwrap();
+        assert_eq!(wait_for(&store_a, "k").await, None);
+    }
+
+    #[tokio::test]
+    async fn crdt_delta_is_merged_on_peer() {
+        let hub = LoopbackHub::new();
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-b", ChangeCodec::Json)).unwrap();
+        let store_b = new_store();
+        b.start_replication_handler(store_b.clone()).await;
+
+        let mut set = OrSet::new();
+        let delta = CrdtValue::Set(set.add("m", new_tag()));
+        a.publish_crdt(OpKind::SAdd, "s", &delta).await.unwrap();
+
+        for _ in 0..100 {
+            if store_b.get_crdt("s").is_some() { break; }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let merged = store_b.get_crdt("s");
+        match merged {
+            Some(CrdtValue::Set(s)) => assert!(s.contains("m")),
+            other => panic!("expected merged set, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn batched_compressed_events_are_applied_on_peer() {
+        let hub = LoopbackHub::new();
+        let mut repl = repl_config("node-a", ChangeCodec::Cbor);
+        repl.batch_max_events = 16;
+        repl.batch_linger_ms = 20;
+        repl.compression = Compression::Zstd;
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl).unwrap();
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-b", ChangeCodec::Cbor)).unwrap();
+        let store_b = new_store();
+        b.start_replication_handler(store_b.clone()).await;
+
+        // Observe the raw transport: 40 writes must arrive in far fewer messages
+        let mut raw = hub.connect().subscribe();
+        for i in 0..40 {
+            a.publish_set(&format!("k{}", i), "v").await.unwrap();
+        }
+        assert_eq!(wait_for(&store_b, "k39").await.as_deref(), Some("v"));
+        assert_eq!(wait_for(&store_b, "k0").await.as_deref(), Some("v"));
+
+        let mut messages = 0;
+        while let Ok(msg) = raw.try_recv() {
+            if msg.channel == EVENTS_CHANNEL {
+                messages += 1;
+            }
+        }
+        assert!(messages > 0 && messages < 40, "expected batching, got {} messages", messages);
+    }
+
+    #[tokio::test]
+    async fn legacy_wire_format_sends_bare_unbatched_events() {
+        let hub = LoopbackHub::new();
+        let mut repl = repl_config("node-a", ChangeCodec::Cbor);
+        repl.legacy_wire_format = true;
+        repl.batch_max_events = 16;
+        repl.compression = Compression::Zstd;
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl).unwrap();
+        let mut raw = hub.connect().subscribe();
+        for i in 0..3 {
+            a.publish_set(&format!("k{}", i), "v").await.unwrap();
+        }
+        assert!(a.flush_outbox(Duration::from_secs(2)).await);
+
+        // Every message is one event a node from before framing can decode
+        let keys: Vec<String> = std::iter::from_fn(|| raw.try_recv().ok())
+            .filter(|msg| msg.channel == EVENTS_CHANNEL)
+            .map(|msg| ChangeEvent::decode_any(&msg.payload).unwrap().key)
+            .collect();
+        assert_eq!(keys, vec!["k0", "k1", "k2"]);
+    }
+
+    /// Loopback endpoint whose publishes fail while `down` is set.
+    struct FlakyTransport {
+        inner: crate::transport::LoopbackTransport,
+        down: std::sync::atomic::AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl ReplicationTransport for FlakyTransport {
+        async fn publish(&self, channel: &str, payload: Vec<u8>) -> Result<()> {
+            if self.down.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("transport down"));
+            }
+            self.inner.publish(channel, payload).await
+        }
+
+        fn subscribe(&self) -> broadcast::Receiver<crate::transport::TransportMessage> {
+            self.inner.subscribe()
+        }
+
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+    }
+
+    #[tokio::test]
+    async fn queued_events_are_delivered_after_transport_recovers() {
+        let hub = LoopbackHub::new();
+        let flaky = Arc::new(FlakyTransport {
+            inner: hub.connect(),
+            down: std::sync::atomic::AtomicBool::new(true),
+        });
+        let a = Replicator::with_transport(flaky.clone(), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-b", ChangeCodec::Cbor)).unwrap();
+        let store_b = new_store();
+        b.start_replication_handler(store_b.clone()).await;
+
+        a.publish_set("k1", "v1").await.unwrap();
+        a.publish_set("k2", "v2").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(a.status().contains(&counter("queue_depth", 2)));
+        assert_eq!(store_b.get("k1"), None);
+
+        flaky.down.store(false, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(wait_for(&store_b, "k2").await.as_deref(), Some("v2"));
+        assert_eq!(store_b.get("k1").as_deref(), Some("v1"));
+        assert!(a.status().contains(&counter("queue_depth", 0)));
+    }
+
+    #[tokio::test]
+    async fn full_queue_refuses_writes() {
+        let hub = LoopbackHub::new();
+        let flaky = Arc::new(FlakyTransport {
+            inner: hub.connect(),
+            down: std::sync::atomic::AtomicBool::new(true),
+        });
+        let mut repl = repl_config("node-a", ChangeCodec::Cbor);
+        repl.queue_max_events = 2;
+        let a = Replicator::with_transport(flaky, &repl).unwrap();
+
+        a.publish_set("k1", "v").await.unwrap();
+        a.publish_set("k2", "v").await.unwrap();
+        assert!(a.check_capacity().is_err());
+        assert!(a.publish_set("k3", "v").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn version_vectors_order_writes_by_causality() {
+        let hub = LoopbackHub::new();
+        let mut repl = repl_config("node-a", ChangeCodec::Cbor);
+        repl.version_vectors = true;
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl).unwrap();
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-b", ChangeCodec::Cbor)).unwrap();
+        let store_b = new_store();
+        b.start_replication_handler(store_b.clone()).await;
+
+        a.publish_set("k", "from-a").await.unwrap();
+        assert_eq!(wait_for(&store_b, "k").await.as_deref(), Some("from-a"));
+        let seen = a.changelog().range(0, 1).unwrap()[0].1.vv.clone().expect("local write carries a vector");
+        assert!(seen.get("node-a") > 0);
+
+        // node-c saw node-a's write but its clock is far behind: it still wins
+        let raw = hub.connect();
+        let mut after = seen.clone();
+        after.advance("node-c", 1);
+        let successor = ChangeEvent::with_str_value(SCHEMA_VERSION, OpKind::Set, "k", Some("from-c"), 1, "node-c", None, None).with_vv(after);
+        raw.publish(EVENTS_CHANNEL, ChangeCodec::Cbor.encode(&successor).unwrap()).await.unwrap();
+
+        // node-d never saw either write: a conflict, settled by LWW
+        let mut unaware = VersionVector::new();
+        unaware.advance("node-d", 2);
+        let concurrent = ChangeEvent::with_str_value(SCHEMA_VERSION, OpKind::Set, "k", Some("from-d"), 2, "node-d", None, None).with_vv(unaware);
+        raw.publish(EVENTS_CHANNEL, ChangeCodec::Cbor.encode(&concurrent).unwrap()).await.unwrap();
+
+        for _ in 0..100 {
+            if b.metrics().totals().received == 3 { break; }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(store_b.get("k").as_deref(), Some("from-d"));
+        assert!(b.status().contains(&counter("conflicts", 1)));
+        assert_eq!(b.metrics().sources()["node-c"].applied, 1);
+    }
+
+    #[tokio::test]
+    async fn prev_mismatch_repairs_key_range_from_publisher() {
+        let hub = LoopbackHub::new();
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        let mut repl_b = repl_config("node-b", ChangeCodec::Cbor);
+        repl_b.catch_up_on_start = false;
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl_b).unwrap();
+
+        // node-b is not applying yet, so it misses these writes
+        a.publish_set("user:1", "v1").await.unwrap();
+        a.publish_set("user:2", "x").await.unwrap();
+        a.publish_set("order:1", "o").await.unwrap();
+        while a.metrics().published.load(Ordering::Relaxed) < 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let store_b = new_store();
+        b.start_replication_handler(store_b.clone()).await;
+        a.publish_set("user:1", "v2").await.unwrap();
+        assert_eq!(wait_for(&store_b, "user:2").await.as_deref(), Some("x"));
+        assert_eq!(store_b.get("user:1").as_deref(), Some("v2"));
+        assert_eq!(store_b.get("order:1"), None); // outside the repaired range
+
+        let stats = b.metrics().stats_fields();
+        assert!(stats.contains(&counter("repl_prev_mismatches", 1)), "{:?}", stats);
+        assert!(stats.contains(&counter("repl_repairs_requested", 1)));
+        assert!(a.metrics().stats_fields().contains(&counter("repl_repair_events_sent", 2)));
+    }
+
+    #[tokio::test]
+    async fn repair_answers_are_throttled_per_peer_and_range() {
+        let hub = LoopbackHub::new();
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        a.publish_set("foo", "1").await.unwrap();
+        a.publish_set("foobar", "2").await.unwrap();
+
+        let raw = hub.connect();
+        let mut answers = raw.subscribe();
+        let ask = |prefix: &str, exact: bool| {
+            let range = KeyRange { prefix: prefix.to_string(), exact };
+            serde_json::to_vec(&RepairRequest { src: "node-x".to_string(), range }).unwrap()
+        };
+        for (prefix, exact) in [("foo", true), ("foo", true), ("foo", false)] {
+            raw.publish("repair/node-a/request", ask(prefix, exact)).await.unwrap();
+        }
+
+        // One answer for the key, one for the range; the repeat is dropped
+        let mut sent = Vec::new();
+        while sent.len() < 2 {
+            let msg = tokio::time::timeout(Duration::from_secs(2), answers.recv()).await.unwrap().unwrap();
+            if msg.channel == "repair/node-x" {
+                sent.push(decode_payload(&msg.payload).unwrap().into_iter().map(|ev| ev.key).collect::<Vec<_>>());
+            }
+        }
+        sent.sort();
+        assert_eq!(sent, vec![vec!["foo".to_string()], vec!["foo".to_string(), "foobar".to_string()]]);
+        assert!(a.metrics().stats_fields().contains(&counter("repl_repair_requests_throttled", 1)));
+    }
+
+    #[tokio::test]
+    async fn returning_node_catches_up_on_missed_writes() {
+        let hub = LoopbackHub::new();
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        let c = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-c", ChangeCodec::Cbor)).unwrap();
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-b", ChangeCodec::Cbor)).unwrap();
+        let store_a = new_store();
+        a.start_replication_handler(store_a.clone()).await;
+        c.start_replication_handler(new_store()).await;
+
+        // node-b saw the first write, then went down
+        let store_b = new_store();
+        store_b.set("k1".into(), "v1".into()).unwrap();
+        b.changelog().append(&ChangeEvent::with_str_value(1, OpKind::Set, "k1", Some("v1"), now_nanos(), "node-a", None, None)).unwrap();
+        a.publish_set("k2", "v2").await.unwrap();
+        c.publish_set("k3", "v3").await.unwrap();
+        assert_eq!(wait_for(&store_a, "k3").await.as_deref(), Some("v3"));
+
+        let sent = |r: &Replicator| r.metrics().catch_up_events_sent.load(Ordering::Relaxed);
+        let before = (sent(&a), sent(&c));
+
+        b.start_replication_handler(store_b.clone()).await;
+        assert_eq!(wait_for(&store_b, "k2").await.as_deref(), Some("v2"));
+        assert_eq!(wait_for(&store_b, "k3").await.as_deref(), Some("v3"));
+        // Both peers hold both writes; each replays only its own write
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!((sent(&a) - before.0, sent(&c) - before.1), (1, 1));
+        assert_eq!(b.metrics().sources()["node-a"].applied + b.metrics().sources()["node-c"].applied, 2);
+    }
+
+    #[tokio::test]
+    async fn flush_outbox_waits_for_the_transport() {
+        let hub = LoopbackHub::new();
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        a.publish_set("k", "v").await.unwrap();
+        assert!(a.flush_outbox(Duration::from_secs(2)).await);
+        assert!(a.status().contains(&counter("queue_depth", 0)));
+
+        let flaky = Arc::new(FlakyTransport {
+            inner: hub.connect(),
+            down: std::sync::atomic::AtomicBool::new(true),
+        });
+        let b = Replicator::with_transport(flaky, &repl_config("node-b", ChangeCodec::Cbor)).unwrap();
+        b.publish_set("k", "v").await.unwrap();
+        assert!(!b.flush_outbox(Duration::from_millis(100)).await);
+    }
+
+    /// Transport whose sends are confirmed only when the test says so.
+    struct ManualTransport {
+        confirms: std::sync::Mutex<Vec<tokio::sync::oneshot::Sender<()>>>,
+        window: usize,
+        tx: broadcast::Sender<crate::transport::TransportMessage>,
+    }
+
+    #[async_trait::async_trait]
+    impl ReplicationTransport for ManualTransport {
+        async fn publish(&self, channel: &str, payload: Vec<u8>) -> Result<()> {
+            self.send(channel, payload).await?.await
+        }
+
+        async fn send(&self, _channel: &str, _payload: Vec<u8>) -> Result<Delivery> {
+            let (confirm, confirmed) = tokio::sync::oneshot::channel();
+            self.confirms.lock().unwrap().push(confirm);
+            Ok(Box::pin(async move { confirmed.await.map_err(|_| anyhow::anyhow!("send dropped")) }))
+        }
+
+        fn subscribe(&self) -> broadcast::Receiver<crate::transport::TransportMessage> {
+            self.tx.subscribe()
+        }
+
+        fn name(&self) -> &'static str {
+            "manual"
+        }
+
+        fn max_in_flight(&self) -> usize {
+            self.window
+        }
+    }
+
+    #[tokio::test]
+    async fn drainer_pipelines_sends_and_trims_in_order() {
+        let transport = Arc::new(ManualTransport {
+            confirms: std::sync::Mutex::new(Vec::new()),
+            window: 3,
+            tx: broadcast::channel(16).0,
+        });
+        let a = Replicator::with_transport(transport.clone(), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        for i in 0..5 {
+            a.publish_set(&format!("k{}", i), "v").await.unwrap();
+        }
+        let sent = || transport.confirms.lock().unwrap().len();
+        for _ in 0..100 {
+            if sent() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // The window is full: nothing more is sent until the oldest is confirmed
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!((sent(), a.outbox.len()), (3, 5));
+
+        let mut confirms: Vec<_> = transport.confirms.lock().unwrap().drain(..).collect();
+        confirms.remove(1).send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(a.outbox.len(), 5, "a later confirmation must not trim earlier entries");
+
+        confirms.remove(0).send(()).unwrap();
+        assert!(!a.flush_outbox(Duration::from_millis(200)).await);
+        assert_eq!((sent(), a.outbox.len()), (2, 3));
+    }
+
+    #[tokio::test]
+    async fn metrics_track_applied_duplicate_and_stale_events() {
+        let hub = LoopbackHub::new();
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-b", ChangeCodec::Cbor)).unwrap();
+        let store_b = new_store();
+        b.start_replication_handler(store_b.clone()).await;
+
+        // Hand-crafted events from node-a so timestamps and op ids are controlled
+        let raw = hub.connect();
+        let newer = ChangeEvent::with_str_value(SCHEMA_VERSION, OpKind::Set, "k", Some("new"), now_nanos(), "node-a", None, None);
+        let older = ChangeEvent::with_str_value(SCHEMA_VERSION, OpKind::Set, "k", Some("old"), 1, "node-a", None, None);
+        for ev in [&newer, &newer, &older] {
+            raw.publish(EVENTS_CHANNEL, ChangeCodec::Cbor.encode(ev).unwrap()).await.unwrap();
+        }
+        raw.publish(EVENTS_CHANNEL, vec![0x1f, 0xff]).await.unwrap();
+
+        for _ in 0..100 {
+            if b.metrics().totals().received == 3 { break; }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let m = &b.metrics().sources()["node-a"];
+        assert_eq!((m.received, m.applied, m.duplicates, m.stale), (3, 1, 1, 1));
+        assert_eq!(m.last_applied_ts, newer.ts);
+        assert!(b.metrics().stats_fields().contains(&counter("repl_decode_failures", 1)));
+        let sources = Response::Map(MapKind::ReplInfo, b.metrics().source_fields()).render(ResponseFormat::Text);
+        assert!(sources.starts_with("REPLINFO\r\nsource:node-a received=3,applied=1,stale=1,duplicates=1,filtered=0,"));
+    }
+
+    #[tokio::test]
+    async fn key_filter_and_namespace_routing() {
+        let hub = LoopbackHub::new();
+        let mut repl_a = repl_config("node-a", ChangeCodec::Cbor);
+        repl_a.exclude_keys = vec!["*:tmp".into()];
+        repl_a.namespace_topics = true;
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl_a).unwrap();
+        let mut repl_b = repl_config("node-b", ChangeCodec::Cbor);
+        repl_b.include_keys = vec!["user:*".into()];
+        repl_b.catch_up_on_start = false; // counts only live events
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl_b).unwrap();
+        let store_b = new_store();
+        b.start_replication_handler(store_b.clone()).await;
+
+        let mut raw = hub.connect().subscribe();
+        a.publish_set("user:tmp", "skipped by publisher").await.unwrap();
+        a.publish_set("order:1", "skipped by receiver").await.unwrap();
+        a.publish_set("user:1", "v").await.unwrap();
+        assert_eq!(wait_for(&store_b, "user:1").await.as_deref(), Some("v"));
+        assert_eq!(store_b.get("order:1"), None);
+        assert_eq!(b.metrics().sources()["node-a"].filtered, 1);
+
+        let channels: Vec<String> = std::iter::from_fn(|| raw.try_recv().ok())
+            .map(|m| m.channel)
+            .filter(|c| c.starts_with("events/"))
+            .collect();
+        assert_eq!(channels, vec!["events/order".to_string(), "events/user".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn wait_for_acks_counts_distinct_peers() {
+        let hub = LoopbackHub::new();
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        let mut peers = Vec::new();
+        for id in ["node-b", "node-c"] {
+            let peer = Replicator::with_transport(Arc::new(hub.connect()), &repl_config(id, ChangeCodec::Cbor)).unwrap();
+            peer.start_replication_handler(new_store()).await;
+            peers.push(peer);
+        }
+
+        let op_id = a.publish_set("k", "v").await.unwrap().unwrap();
+        assert_eq!(a.wait_for_acks(&[op_id], 2, Duration::from_secs(2)).await, 2);
+        // Only two peers exist, so asking for three times out with the count reached
+        assert_eq!(a.wait_for_acks(&[op_id], 3, Duration::from_millis(50)).await, 2);
+        // Nothing replicated means nothing to wait for
+        let started = Instant::now();
+        assert_eq!(a.wait_for_acks(&[], 3, Duration::from_secs(5)).await, 3);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let mut quiet = repl_config("node-b", ChangeCodec::Cbor);
+        quiet.send_acks = false;
+        let hub2 = LoopbackHub::new();
+        let writer = Replicator::with_transport(Arc::new(hub2.connect()), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        let silent = Replicator::with_transport(Arc::new(hub2.connect()), &quiet).unwrap();
+        silent.start_replication_handler(new_store()).await;
+        let op_id = writer.publish_set("k", "v").await.unwrap().unwrap();
+        assert_eq!(writer.wait_for_acks(&[op_id], 1, Duration::from_millis(100)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn acks_round_trip_in_batches() {
+        let ids = [[1u8; 16], [2u8; 16], [3u8; 16]];
+        assert_eq!(decode_ack(&encode_ack(&ids, "node-b")), Some((ids.to_vec(), "node-b".to_string())));
+        assert_eq!(decode_ack(&encode_ack(&ids, "node-b")[..40]), None);
+
+        let hub = LoopbackHub::new();
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-b", ChangeCodec::Cbor)).unwrap();
+        b.start_replication_handler(new_store()).await;
+        let mut op_ids = Vec::new();
+        for i in 0..50 {
+            op_ids.push(a.publish_set(&format!("k{}", i), "v").await.unwrap().unwrap());
+        }
+        assert_eq!(a.wait_for_acks(&op_ids, 1, Duration::from_secs(2)).await, 1);
+    }
+
+    #[tokio::test]
+    async fn watch_sees_local_and_replicated_events() {
+        let hub = LoopbackHub::new();
+        let mut repl_a = repl_config("node-a", ChangeCodec::Cbor);
+        repl_a.exclude_keys = vec!["*:tmp".into()];
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl_a).unwrap();
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-b", ChangeCodec::Cbor)).unwrap();
+        let mut events = a.watch();
+
+        // Local writes are streamed even when filtered out of replication
+        a.publish_set("session:tmp", "local only").await.unwrap();
+        b.publish_set("user:1", "from b").await.unwrap();
+
+        let first = tokio::time::timeout(Duration::from_secs(2), events.recv()).await.unwrap().unwrap();
+        assert_eq!((first.src.as_str(), first.key.as_str()), ("node-a", "session:tmp"));
+        let second = tokio::time::timeout(Duration::from_secs(2), events.recv()).await.unwrap().unwrap();
+        assert_eq!((second.src.as_str(), second.key.as_str()), ("node-b", "user:1"));
+    }
+
+    #[tokio::test]
+    async fn pubsub_messages_fan_out_to_peers() {
+        let hub = LoopbackHub::new();
+        let mut repl = repl_config("node-a", ChangeCodec::Cbor);
+        repl.pubsub_cluster = true;
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl).unwrap();
+        repl.client_id = "node-b".to_string();
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl).unwrap();
+
+        let (pubsub_a, pubsub_b) = (Arc::new(PubSub::new()), Arc::new(PubSub::new()));
+        a.start_pubsub_bridge(pubsub_a.clone());
+        b.start_pubsub_bridge(pubsub_b.clone());
+        let mut sub_a = pubsub_a.open();
+        sub_a.subscribe(&["alerts".to_string()]);
+        let mut sub_b = pubsub_b.open();
+        sub_b.subscribe(&["alerts".to_string()]);
+
+        a.publish_message("alerts", "disk full").await.unwrap();
+        let got = tokio::time::timeout(Duration::from_secs(2), sub_b.recv()).await.unwrap().unwrap();
+        assert_eq!((got.channel.as_str(), got.message.as_str()), ("alerts", "disk full"));
+        // The publisher's own node delivers locally via PubSub::publish, not the bridge
+        assert!(tokio::time::timeout(Duration::from_millis(100), sub_a.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn changelog_records_local_and_applied_events() {
+        let hub = LoopbackHub::new();
+        let a = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-a", ChangeCodec::Cbor)).unwrap();
+        let b = Replicator::with_transport(Arc::new(hub.connect()), &repl_config("node-b", ChangeCodec::Cbor)).unwrap();
+        let store_b = new_store();
+        b.start_replication_handler(store_b.clone()).await;
+
+        b.publish_set("local", "1").await.unwrap();
+        a.publish_set("remote", "2").await.unwrap();
+        assert_eq!(wait_for(&store_b, "remote").await.as_deref(), Some("2"));
+        for _ in 0..100 {
+            if b.changelog().next_seq() == 2 { break; }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let log: Vec<(u64, String, String)> = b
+            .changelog()
+            .range(0, 10)
+            .unwrap()
+            .into_iter()
+            .map(|(seq, ev)| (seq, ev.src, ev.key))
+            .collect();
+        assert_eq!(
+            log,
+            vec![(0, "node-b".into(), "local".into()), (1, "node-a".into(), "remote".into())]
+        );
+    }
+
 }
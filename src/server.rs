@@ -8,7 +8,7 @@
 //! The server uses an asynchronous, multi-connection design:
 //! - Main server loop accepts incoming connections
 //! - Each connection spawns a separate async task
-//! - Commands are parsed and executed against the shared storage
+//! - Commands are parsed and run by the shared `CommandExecutor`
 //! - Responses are sent back to the client
 //!
 //! ## Protocol
@@ -18,29 +18,60 @@
 //! - Numeric Operations: `INC key [amount]`, `DEC key [amount]`
 //! - String Operations: `APPEND key value`, `PREPEND key value`
 //! - Bulk Operations: `MGET key1 key2 ...`, `MSET key1 value1 key2 value2 ...`, `TRUNCATE`
+//! - Set Operations: `SADD key m1 m2 ...`, `SREM key m1 m2 ...`, `SMEMBERS key`
+//! - Hash Operations: `HSET key field value`, `HGET key field`, `HDEL key field`
 //! - Responses: `VALUE data`, `VALUES count\r\nkey1 value1\r\nkey2 value2...`, `OK`, `NOT_FOUND`, `ERROR message`
 //! - All messages are terminated with `\r\n`
 //!
 //! ## Concurrency
 //!
-//! The storage engine is wrapped in `Arc<Mutex<>>` to allow safe concurrent access
-//! from multiple client connections. Each connection gets its own task but shares
-//! the same underlying storage.
+//! The storage engine is shared as `Arc<dyn KVEngineStoreTrait>` by all client
+//! connections and the replication handler. There is no server-level lock:
+//! every engine is thread-safe and synchronizes internally, so reads and
+//! writes on different connections proceed concurrently.
+//!
+//! ## Shutdown
+//!
+//! SIGTERM, SIGINT or the `SHUTDOWN` command stop the accept loop. Connections
+//! finish the command they are executing and close; those still busy after
+//! `shutdown_timeout_seconds` are aborted. The replication queue then gets
+//! the same deadline to drain, the transport the same deadline to send what
+//! it buffered and disconnect, and the store is synced to disk before `run`
+//! returns.
+//!
+//! ## Limits
+//!
+//! The `[limits]` configuration caps concurrent connections (new ones get an
+//! `ERROR` and are closed), rate limits commands per client IP (throttled
+//! commands get an `ERROR` and are not executed), and closes connections
+//! that stay idle or stall in the middle of a command. Rejections and
+//! throttling are counted in `STATS`.
 
 use crate::store::KVEngineStoreTrait;
 use anyhow::Result;
-use log::{error, info};
+use log::{error, info, warn};
+use std::future::Future;
+use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::task::JoinSet;
 
+use crate::changelog::ChangeLog;
 use crate::config::Config;
-use crate::protocol::{Command, Protocol};
+use crate::error::ErrorCode;
+use crate::executor::CommandExecutor;
+use crate::metrics::{resident_memory_kb, CommandMetrics};
+use crate::protocol::{counters, Command, Protocol, Response, ResponseFormat};
+use crate::change_event::ChangeEvent;
+use crate::pubsub::{PubSub, Subscription};
+use crate::ratelimit::RateLimiter;
 use crate::replication::Replicator;
+use crate::watch::Watch;
+use tokio::sync::broadcast;
 
 /// Server statistics for monitoring and diagnostics.
 ///
@@ -75,12 +106,33 @@ pub struct ServerStats {
     /// Number of bulk operations (MGET/MSET/TRUNCATE) processed
     pub bulk_commands: AtomicU64,
     
+    /// Number of set/hash operations (SADD/SREM/SMEMBERS/HSET/HGET/HDEL) processed
+    pub collection_commands: AtomicU64,
+    
+    /// Number of messaging commands (PUBLISH/SUBSCRIBE/WATCH and their counterparts) processed
+    pub pubsub_commands: AtomicU64,
+    
     /// Number of statistical commands (STATS/INFO/PING) processed
     pub stat_commands: AtomicU64,
     
-    /// Number of server management commands (VERSION/FLUSH/SHUTDOWN) processed
+    /// Number of server management commands (VERSION/FLUSH/SHUTDOWN/FORMAT) processed
     pub management_commands: AtomicU64,
     
+    /// Number of connections refused because `max_connections` was reached
+    pub rejected_connections: AtomicU64,
+    
+    /// Number of commands refused by per-client rate limiting
+    pub throttled_commands: AtomicU64,
+    
+    /// Total number of HTTP API connections since server start
+    pub total_http_connections: AtomicU64,
+    
+    /// Current number of open HTTP API connections
+    pub active_http_connections: AtomicU64,
+    
+    /// Per-command counts and latencies, exported as Prometheus metrics
+    pub commands: CommandMetrics,
+    
     /// Server start time
     pub start_time: Instant,
 }
@@ -97,8 +149,15 @@ impl Clone for ServerStats {
             numeric_commands: AtomicU64::new(self.numeric_commands.load(Ordering::Relaxed)),
             string_commands: AtomicU64::new(self.string_commands.load(Ordering::Relaxed)),
             bulk_commands: AtomicU64::new(self.bulk_commands.load(Ordering::Relaxed)),
+            collection_commands: AtomicU64::new(self.collection_commands.load(Ordering::Relaxed)),
+            pubsub_commands: AtomicU64::new(self.pubsub_commands.load(Ordering::Relaxed)),
             stat_commands: AtomicU64::new(self.stat_commands.load(Ordering::Relaxed)),
             management_commands: AtomicU64::new(self.management_commands.load(Ordering::Relaxed)),
+            rejected_connections: AtomicU64::new(self.rejected_connections.load(Ordering::Relaxed)),
+            throttled_commands: AtomicU64::new(self.throttled_commands.load(Ordering::Relaxed)),
+            total_http_connections: AtomicU64::new(self.total_http_connections.load(Ordering::Relaxed)),
+            active_http_connections: AtomicU64::new(self.active_http_connections.load(Ordering::Relaxed)),
+            commands: self.commands.clone(),
             start_time: self.start_time,
         }
     }
@@ -124,8 +183,15 @@ impl ServerStats {
             numeric_commands: AtomicU64::new(0),
             string_commands: AtomicU64::new(0),
             bulk_commands: AtomicU64::new(0),
+            collection_commands: AtomicU64::new(0),
+            pubsub_commands: AtomicU64::new(0),
             stat_commands: AtomicU64::new(0),
             management_commands: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            throttled_commands: AtomicU64::new(0),
+            total_http_connections: AtomicU64::new(0),
+            active_http_connections: AtomicU64::new(0),
+            commands: CommandMetrics::default(),
             start_time: Instant::now(),
         }
     }
@@ -148,7 +214,12 @@ impl ServerStats {
     
     /// Increment the counter for a specific command type
     pub fn increment_command_counter(&self, command: &Command) {
+        if let Command::Wait { command, .. } = command {
+            // A write concern is counted as the write it wraps
+            return self.increment_command_counter(command);
+        }
         self.total_commands.fetch_add(1, Ordering::Relaxed);
+        self.commands.count(command.name());
         
         match command {
             Command::Get { .. } => {
@@ -169,51 +240,147 @@ impl ServerStats {
             Command::MultiGet { .. } | Command::MultiSet { .. } | Command::Truncate => {
                 self.bulk_commands.fetch_add(1, Ordering::Relaxed);
             }
-            Command::Stats | Command::Info | Command::Ping => {
+            Command::SetAdd { .. }
+            | Command::SetRemove { .. }
+            | Command::SetMembers { .. }
+            | Command::HashSet { .. }
+            | Command::HashGet { .. }
+            | Command::HashDelete { .. } => {
+                self.collection_commands.fetch_add(1, Ordering::Relaxed);
+            }
+            Command::Publish { .. }
+            | Command::Subscribe { .. }
+            | Command::PSubscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::PUnsubscribe { .. }
+            | Command::Watch { .. }
+            | Command::Unwatch => {
+                self.pubsub_commands.fetch_add(1, Ordering::Relaxed);
+            }
+            Command::Stats
+            | Command::Info
+            | Command::Ping
+            | Command::ReplStatus
+            | Command::ReplInfo
+            | Command::Replay { .. } => {
                 self.stat_commands.fetch_add(1, Ordering::Relaxed);
             }
-            Command::Version | Command::Flush | Command::Shutdown => {
+            Command::Version | Command::Flush | Command::Shutdown | Command::Format { .. } => {
                 self.management_commands.fetch_add(1, Ordering::Relaxed);
             }
+            Command::Wait { .. } => {} // counted as its inner command above
         }
     }
     
-    /// Format all statistics as a multi-line string for the STATS command
-    pub fn format_stats(&self) -> String {
-        let mut result = String::new();
-        
-        result.push_str(&format!("uptime_seconds:{}\r\n", self.uptime_seconds()));
-        result.push_str(&format!("uptime:{}\r\n", self.uptime_human()));
-        result.push_str(&format!("total_connections:{}\r\n", self.total_connections.load(Ordering::Relaxed)));
-        result.push_str(&format!("active_connections:{}\r\n", self.active_connections.load(Ordering::Relaxed)));
-        result.push_str(&format!("total_commands:{}\r\n", self.total_commands.load(Ordering::Relaxed)));
-        result.push_str(&format!("get_commands:{}\r\n", self.get_commands.load(Ordering::Relaxed)));
-        result.push_str(&format!("set_commands:{}\r\n", self.set_commands.load(Ordering::Relaxed)));
-        result.push_str(&format!("delete_commands:{}\r\n", self.delete_commands.load(Ordering::Relaxed)));
-        result.push_str(&format!("numeric_commands:{}\r\n", self.numeric_commands.load(Ordering::Relaxed)));
-        result.push_str(&format!("string_commands:{}\r\n", self.string_commands.load(Ordering::Relaxed)));
-        result.push_str(&format!("bulk_commands:{}\r\n", self.bulk_commands.load(Ordering::Relaxed)));
-        result.push_str(&format!("stat_commands:{}\r\n", self.stat_commands.load(Ordering::Relaxed)));
-        result.push_str(&format!("management_commands:{}\r\n", self.management_commands.load(Ordering::Relaxed)));
-        
-        // Add memory usage estimate (this is a very rough estimate)
-        let estimated_memory_kb = std::process::Command::new("ps")
-            .args(&["-o", "rss=", "-p", &std::process::id().to_string()])
-            .output()
-            .map(|output| {
-                String::from_utf8_lossy(&output.stdout)
-                    .trim()
-                    .parse::<u64>()
-                    .unwrap_or(0)
-            })
-            .unwrap_or(0);
+    /// All statistics as fields of the STATS reply
+    pub fn fields(&self) -> Vec<(String, Response)> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut fields = counters([("uptime_seconds", self.uptime_seconds())]);
+        fields.push(("uptime".to_string(), Response::Value(self.uptime_human())));
+        fields.extend(counters([
+            ("total_connections", load(&self.total_connections)),
+            ("active_connections", load(&self.active_connections)),
+            ("total_commands", load(&self.total_commands)),
+            ("get_commands", load(&self.get_commands)),
+            ("set_commands", load(&self.set_commands)),
+            ("delete_commands", load(&self.delete_commands)),
+            ("numeric_commands", load(&self.numeric_commands)),
+            ("string_commands", load(&self.string_commands)),
+            ("bulk_commands", load(&self.bulk_commands)),
+            ("collection_commands", load(&self.collection_commands)),
+            ("pubsub_commands", load(&self.pubsub_commands)),
+            ("stat_commands", load(&self.stat_commands)),
+            ("management_commands", load(&self.management_commands)),
+            ("rejected_connections", load(&self.rejected_connections)),
+            ("throttled_commands", load(&self.throttled_commands)),
+        ]));
         
-        result.push_str(&format!("used_memory_kb:{}\r\n", estimated_memory_kb));
-        
-        result
+        // Resident set size (0 where /proc is unavailable)
+        fields.extend(counters([("used_memory_kb", resident_memory_kb().unwrap_or(0))]));
+        fields
+    }
+}
+
+/// Server-wide shutdown signal, raised once by a signal or `SHUTDOWN` and
+/// observed by the accept loop and every connection.
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<tokio::sync::watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self { tx: Arc::new(tokio::sync::watch::Sender::new(false)) }
+    }
+
+    /// Ask the server to shut down.
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    /// Resolve once shutdown has been requested.
+    pub async fn requested(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx.wait_for(|&requested| requested).await;
+    }
+}
+
+/// Resolve on SIGINT (Ctrl+C) or, on Unix, SIGTERM.
+async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("Cannot listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Handles shared by every client connection.
+#[derive(Clone)]
+struct Shared {
+    /// Command semantics over the store, replicator, statistics and broker
+    executor: Arc<CommandExecutor>,
+    shutdown: Shutdown,
+    /// Per-IP command rate limiter, if rate limiting is enabled
+    limiter: Option<Arc<RateLimiter>>,
+    /// Deadline for the rest of a command spanning several reads
+    read_timeout: Option<Duration>,
+    /// Deadline for the next command
+    idle_timeout: Option<Duration>,
+}
+
+/// Upper bound on a single command assembled from several reads.
+const MAX_COMMAND_BYTES: usize = 1024 * 1024;
+
+/// Await `read`, failing with `TimedOut` after `limit` (if any).
+async fn timed_read(limit: Option<Duration>, read: impl Future<Output = io::Result<usize>>) -> io::Result<usize> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, read)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"))),
+        None => read.await,
     }
 }
 
+/// `None` for a zero (disabled) timeout.
+fn timeout_secs(seconds: u64) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
 /// TCP server for handling client connections.
 ///
 /// The server binds to a specified address and port, then accepts incoming
@@ -223,7 +390,7 @@ pub struct Server {
     config: Config,
 
     /// The storage engine that will be shared across all client connections
-    store: Box<dyn KVEngineStoreTrait + Send + Sync>,
+    store: Box<dyn KVEngineStoreTrait>,
     
     /// Server statistics for monitoring and diagnostics
     stats: ServerStats,
@@ -248,12 +415,12 @@ impl Server {
 
     /// Start the server and begin accepting connections.
     ///
-    /// This method runs indefinitely, accepting new connections and spawning
-    /// tasks to handle them. Each connection gets its own async task but all
-    /// share the same storage engine.
+    /// This method accepts new connections and spawns a task to handle each
+    /// one until shutdown is requested (see the module docs), then drains
+    /// connections and replication and syncs the store.
     ///
     /// # Returns
-    /// * `Result<()>` - Never returns normally, only on bind errors
+    /// * `Result<()>` - `Ok` after a clean shutdown, an error on bind failures
     ///
     /// # Errors
     /// Returns an error if:
@@ -265,52 +432,115 @@ impl Server {
     /// let config = Config::default();
     /// let store = Box::new(RwLockEngine::new("./data")?);
     /// let server = Server::new(config, store);
-    /// server.run().await?; // Runs forever
+    /// server.run().await?; // Runs until SIGTERM, SIGINT or SHUTDOWN
     /// ```
     pub async fn run(self) -> Result<()> {
         let addr = format!("{}:{}", self.config.host, self.config.port);
         let listener = TcpListener::bind(&addr).await?;
         info!("Server listening on {}", addr);
 
-        // Wrap the storage in `Arc<Mutex<>>` for safe concurrent access
-        let store = Arc::new(Mutex::new(self.store));
+        // Share the storage; engines synchronize concurrent access themselves
+        let store: Arc<dyn KVEngineStoreTrait> = Arc::from(self.store);
         
         // Share server statistics across all connections
         let stats = Arc::new(self.stats.clone());
 
+        // The change log serves REPLAY in every mode; replication records
+        // its produced and applied events in it
+        let repl = &self.config.replication;
+        let changelog = Arc::new(ChangeLog::open(&repl.changelog_path, repl.changelog_max_events)?);
+
         // Initialize replication if enabled
-        let replicator_opt: Option<Replicator> = if self.config.replication.enabled {
-            let r = Replicator::new(&self.config).await?;
+        let replicator_opt: Option<Replicator> = if repl.enabled {
+            let r = Replicator::new(&self.config, Arc::clone(&changelog)).await?;
             // Start background apply loop
             r.start_replication_handler(Arc::clone(&store)).await;
             Some(r)
         } else { None };
 
-        // TODO: Add graceful shutdown handling
-        // TODO: Add connection limits and rate limiting
+        // Channel messaging broker, bridged to peers when pubsub_cluster is on
+        let pubsub = Arc::new(PubSub::new());
+        if let Some(r) = &replicator_opt {
+            r.start_pubsub_bridge(Arc::clone(&pubsub));
+        }
+
+        let shutdown = Shutdown::new();
+        let on_signal = shutdown.clone();
+        tokio::spawn(async move {
+            termination_signal().await;
+            info!("Termination signal received");
+            on_signal.trigger();
+        });
+
+        let shared = Shared {
+            executor: Arc::new(CommandExecutor::new(
+                Arc::clone(&store),
+                replicator_opt.clone(),
+                changelog,
+                Arc::clone(&stats),
+                pubsub,
+                self.config.replication.client_id.clone(),
+            )),
+            shutdown: shutdown.clone(),
+            limiter: RateLimiter::new(self.config.limits.rate_limit_per_sec, self.config.limits.rate_limit_burst).map(Arc::new),
+            read_timeout: timeout_secs(self.config.limits.read_timeout_seconds),
+            idle_timeout: timeout_secs(self.config.limits.idle_timeout_seconds),
+        };
+        // Optional HTTP/JSON API on the same executor
+        let http = if self.config.http.enabled {
+            let listener = TcpListener::bind(&self.config.http.listen).await?;
+            info!("HTTP API listening on {}", self.config.http.listen);
+            Some(tokio::spawn(crate::http::serve(
+                listener,
+                Arc::clone(&shared.executor),
+                self.config.http.clone(),
+                self.config.limits.clone(),
+                shared.limiter.clone(),
+                shutdown.clone(),
+            )))
+        } else {
+            None
+        };
+        let max_connections = self.config.limits.max_connections;
+        let mut connections = JoinSet::new();
 
         loop {
-            match listener.accept().await {
-                Ok((socket, addr)) => {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.requested() => break,
+            };
+            // Reap finished connection tasks so the set does not grow unbounded
+            while connections.try_join_next().is_some() {}
+            match accepted {
+                Ok((mut socket, addr)) => {
+                    // HTTP connections count toward the limit too
+                    let open = stats.active_connections.load(Ordering::Relaxed)
+                        + stats.active_http_connections.load(Ordering::Relaxed);
+                    if max_connections > 0 && open >= max_connections as u64 {
+                        warn!("Refusing connection from {}: max_connections ({}) reached", addr, max_connections);
+                        stats.rejected_connections.fetch_add(1, Ordering::Relaxed);
+                        tokio::spawn(async move {
+                            let refusal = Response::error(ErrorCode::Limit, "max connections reached");
+                            let _ = socket.write_all(refusal.render(ResponseFormat::Text).as_bytes()).await;
+                        });
+                        continue;
+                    }
                     info!("Accepted connection from {}", addr);
                     
-                    // Clone the Arc for this connection
-                    let store_clone = Arc::clone(&store);
-                    let stats_clone = Arc::clone(&stats);
-                    
                     // Update connection statistics
-                    stats_clone.total_connections.fetch_add(1, Ordering::Relaxed);
-                    stats_clone.active_connections.fetch_add(1, Ordering::Relaxed);
+                    stats.total_connections.fetch_add(1, Ordering::Relaxed);
+                    stats.active_connections.fetch_add(1, Ordering::Relaxed);
                     
                     // Spawn a new task for each client connection
-                    let repl_clone = replicator_opt.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::handle_connection(socket, addr, store_clone, stats_clone.clone(), repl_clone).await {
+                    let shared = shared.clone();
+                    connections.spawn(async move {
+                        let stats = Arc::clone(shared.executor.stats());
+                        if let Err(e) = Self::handle_connection(socket, addr, shared).await {
                             error!("Error handling connection from {}: {}", addr, e);
                         }
                         
                         // Decrement active connections when the connection ends
-                        stats_clone.active_connections.fetch_sub(1, Ordering::Relaxed);
+                        stats.active_connections.fetch_sub(1, Ordering::Relaxed);
                     });
                 }
                 Err(e) => {
@@ -318,6 +548,38 @@ impl Server {
                 }
             }
         }
+
+        // Stop accepting, then give connections the deadline to finish their command
+        drop(listener);
+        let deadline = Duration::from_secs(self.config.shutdown_timeout_seconds);
+        info!("Shutting down: draining {} connections", connections.len());
+        let drained = tokio::time::timeout(deadline, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!("Aborting {} connections still busy after {:?}", connections.len(), deadline);
+            connections.shutdown().await;
+        }
+        if let Some(mut http) = http {
+            if tokio::time::timeout(deadline, &mut http).await.is_err() {
+                warn!("Aborting HTTP connections still busy after {:?}", deadline);
+                http.abort();
+            }
+        }
+
+        if let Some(r) = &replicator_opt {
+            if !r.flush_outbox(deadline).await {
+                warn!("Replication queue not drained after {:?}; remaining events stay queued on disk", deadline);
+            }
+            r.close(deadline).await;
+        }
+        let synced = store.sync();
+        if let Err(e) = synced {
+            error!("Failed to sync storage during shutdown: {}", e);
+        }
+        info!("Shutdown complete");
+        Ok(())
     }
 
     /// Handle a single client connection.
@@ -329,332 +591,331 @@ impl Server {
     /// # Arguments
     /// * `socket` - The TCP stream for this client connection
     /// * `addr` - Client's address (for logging)
-    /// * `store` - Shared reference to the storage engine
-    /// * `stats` - Shared reference to server statistics
+    /// * `shared` - Command executor, shutdown signal and connection limits
     /// 
     /// # Returns
     /// * `Result<()>` - Success when client disconnects normally, error on failures
     /// 
     /// # Protocol Handling
-    /// - Reads commands from the socket in 1KB chunks
+    /// - Reads commands from the socket until a line ending arrives
     /// - Parses commands using the Protocol parser
-    /// - Executes commands against the storage engine
+    /// - Handles SHUTDOWN, WATCH and (P)SUBSCRIBE, which act on the connection
+    /// - Runs every other command through the `CommandExecutor`
     /// - Sends appropriate responses back to the client
     /// 
     /// # Error Handling
     /// - Invalid commands result in ERROR responses
     /// - Network errors terminate the connection
     /// - Storage errors are converted to ERROR responses
-    async fn handle_connection(
-        mut socket: TcpStream,
-        addr: SocketAddr,
-        store: Arc<Mutex<Box<dyn KVEngineStoreTrait + Send + Sync>>>,
-        stats: Arc<ServerStats>,
-    replicator: Option<Replicator>,
-    ) -> Result<()> {
+    async fn handle_connection(mut socket: TcpStream, addr: SocketAddr, shared: Shared) -> Result<()> {
+        let Shared { executor, shutdown, limiter, read_timeout, idle_timeout } = shared;
         let mut buffer = [0; 1024];
         let protocol = Protocol::new();
-
-        // Local helper describing what to publish after the storage write.
-        enum Publish {
-            Set(String, String),
-            Delete(String),
-            Incr(String, i64),
-            Decr(String, i64),
-            Append(String, String),
-            Prepend(String, String),
-        }
+        // Response encoding, chosen by the client with FORMAT
+        let mut format = ResponseFormat::Text;
 
         loop {
-            // Read data from the client
-            let n = match socket.read(&mut buffer).await {
+            // Read data from the client; the command in flight (if any) has
+            // completed, so a shutdown closes the connection here
+            let read = tokio::select! {
+                read = timed_read(idle_timeout, socket.read(&mut buffer)) => read,
+                _ = shutdown.requested() => {
+                    info!("Closing connection from {} for shutdown", addr);
+                    break;
+                }
+            };
+            let n = match read {
                 Ok(0) => {
                     // Client closed the connection
                     info!("Client {} disconnected", addr);
                     break;
                 }
                 Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    info!("Closing idle connection from {}", addr);
+                    break;
+                }
                 Err(e) => {
                     error!("Error reading from client {}: {}", addr, e);
                     break;
                 }
             };
 
-            // Convert received bytes to string
-            let request = std::str::from_utf8(&buffer[..n])?;
-            
-            match protocol.parse(request) {
-                Ok(command) => {
-                    // Update command statistics
-                    stats.increment_command_counter(&command);
-                    
-                    // Process the command. We avoid holding the store lock across awaits
-                    // by computing an optional publish action and performing it afterward.
-                    let mut publishes: Vec<Publish> = Vec::new();
-                    let response = match command.clone() {
-                        Command::Get { key } => {
-                            let store = store.lock().await;
-                            match store.get(&key) {
-                                Some(value) => format!("VALUE {}\r\n", value),
-                                None => "NOT_FOUND\r\n".to_string(),
-                            }
-                        }
-                        Command::Set { key, value } => {
-                            let store = store.lock().await;
-                            match store.set(key.clone(), value.clone()) {
-                                Ok(_) => {
-                                    publishes.push(Publish::Set(key.clone(), value.clone()));
-                                    "OK\r\n".to_string()
-                                }
-                                Err(e) => format!("ERROR {}\r\n", e),
-                            }
+            // Until a line ending arrives the command continues, however the
+            // client split it into writes
+            let mut bytes = buffer[..n].to_vec();
+            while bytes.last() != Some(&b'\n') {
+                let error = if bytes.len() >= MAX_COMMAND_BYTES {
+                    Response::error(ErrorCode::Limit, "command too long")
+                } else {
+                    match timed_read(read_timeout, socket.read(&mut buffer)).await {
+                        Ok(0) => break,
+                        Ok(m) => {
+                            bytes.extend_from_slice(&buffer[..m]);
+                            continue;
                         }
-                        Command::Delete { key } => {
-                            {
-                                let store = store.lock().await;
-                                store.delete(&key);
-                            }
-                            publishes.push(Publish::Delete(key.clone()));
-                            "OK\r\n".to_string()
-                        }
-                        Command::Increment { key, amount } => {
-                            // Check if the key already exists
-                            let exists = { let store = store.lock().await; store.get(&key).is_some() };
-                            
-                            // If the key doesn't exist, create it with value 1 or the specified amount
-                            if !exists {
-                                let value = amount.unwrap_or(1).to_string();
-                                {
-                                    let store = store.lock().await;
-                                    match store.set(key.clone(), value.clone()) {
-                                        Ok(_) => {
-                                            let nv = value.parse().unwrap_or(1);
-                                            publishes.push(Publish::Incr(key.clone(), nv));
-                                            format!("VALUE {}\r\n", value)
-                                        }
-                                        Err(e) => format!("ERROR {}\r\n", e),
-                                    }
-                                }
-                            } else {
-                                // Otherwise, increment the existing value
-                                let res = { let store = store.lock().await; store.increment(&key, amount) };
-                                match res {
-                                    Ok(new_value) => { publishes.push(Publish::Incr(key.clone(), new_value)); format!("VALUE {}\r\n", new_value) },
-                                    Err(e) => format!("ERROR {}\r\n", e),
-                                }
-                            }
-                        }
-                        Command::Decrement { key, amount } => {
-                            // Check if the key already exists
-                            let exists = { let store = store.lock().await; store.get(&key).is_some() };
-                            
-                            // If the key doesn't exist, create it with value -1 or the negative of the specified amount
-                            if !exists {
-                                let value = (-(amount.unwrap_or(1))).to_string();
-                                {
-                                    let store = store.lock().await;
-                                    match store.set(key.clone(), value.clone()) {
-                                        Ok(_) => { let v: i64 = value.parse().unwrap_or(-1); publishes.push(Publish::Decr(key.clone(), v)); format!("VALUE {}\r\n", value) },
-                                        Err(e) => format!("ERROR {}\r\n", e),
-                                    }
-                                }
-                            } else {
-                                // Otherwise, decrement the existing value
-                                let res = { let store = store.lock().await; store.decrement(&key, amount) };
-                                match res {
-                                    Ok(new_value) => { publishes.push(Publish::Decr(key.clone(), new_value)); format!("VALUE {}\r\n", new_value) },
-                                    Err(e) => format!("ERROR {}\r\n", e),
-                                }
-                            }
-                        }
-                        Command::Append { key, value } => {
-                            // Handle empty values for APPEND
-                            if value.is_empty() {
-                                let store = store.lock().await;
-                                match store.get(&key) {
-                                    Some(current_value) => format!("VALUE {}\r\n", current_value),
-                                    None => "ERROR Key not found\r\n".to_string(),
-                                }
-                            } else {
-                                // Try to get the key first
-                                let current_value = { let store = store.lock().await; store.get(&key) };
-                                
-                                // If the key doesn't exist, create it with the value
-                                if current_value.is_none() {
-                                    let res = { let store = store.lock().await; store.set(key.clone(), value.clone()) };
-                                    match res {
-                                        Ok(_) => { publishes.push(Publish::Append(key.clone(), value.clone())); format!("VALUE {}\r\n", value) },
-                                        Err(e) => format!("ERROR {}\r\n", e),
-                                    }
-                                } else {
-                                    // Otherwise, append to the existing value
-                                    let res = { let store = store.lock().await; store.append(&key, &value) };
-                                    match res {
-                                        Ok(new_value) => { publishes.push(Publish::Append(key.clone(), new_value.clone())); format!("VALUE {}\r\n", new_value) },
-                                        Err(e) => format!("ERROR {}\r\n", e),
-                                    }
-                                }
-                            }
-                        }
-                        Command::Prepend { key, value } => {
-                            // Handle empty values for PREPEND
-                            if value.is_empty() {
-                                let store = store.lock().await;
-                                match store.get(&key) {
-                                    Some(current_value) => format!("VALUE {}\r\n", current_value),
-                                    None => "ERROR Key not found\r\n".to_string(),
-                                }
-                            } else {
-                                // Try to get the key first
-                                let current_value = { let store = store.lock().await; store.get(&key) };
-                                
-                                // If the key doesn't exist, create it with the value
-                                if current_value.is_none() {
-                                    let res = { let store = store.lock().await; store.set(key.clone(), value.clone()) };
-                                    match res {
-                                        Ok(_) => { publishes.push(Publish::Prepend(key.clone(), value.clone())); format!("VALUE {}\r\n", value) },
-                                        Err(e) => format!("ERROR {}\r\n", e),
-                                    }
-                                } else {
-                                    // Otherwise, prepend to the existing value
-                                    let res = { let store = store.lock().await; store.prepend(&key, &value) };
-                                    match res {
-                                        Ok(new_value) => { publishes.push(Publish::Prepend(key.clone(), new_value.clone())); format!("VALUE {}\r\n", new_value) },
-                                        Err(e) => format!("ERROR {}\r\n", e),
-                                    }
-                                }
-                            }
-                        }
-                        Command::MultiGet { keys } => {
-                            let store = store.lock().await;
-                            let mut response = String::new();
-                            let mut found_count = 0;
-                            
-                            for key in keys {
-                                match store.get(&key) {
-                                    Some(value) => {
-                                        response.push_str(&format!("{} {}\r\n", key, value));
-                                        found_count += 1;
-                                    }
-                                    None => {
-                                        response.push_str(&format!("{} NOT_FOUND\r\n", key));
-                                    }
-                                }
-                            }
-                            
-                            if found_count > 0 {
-                                format!("VALUES {}\r\n{}", found_count, response)
-                            } else {
-                                "NOT_FOUND\r\n".to_string()
-                            }
-                        }
-                        Command::MultiSet { pairs } => {
-                            let mut result = "OK\r\n".to_string();
-                            for (key, value) in pairs {
-                                let res = { let store = store.lock().await; store.set(key.clone(), value.clone()) };
-                                if let Err(e) = res {
-                                    result = format!("ERROR {}\r\n", e);
-                                    break;
-                                }
-                                publishes.push(Publish::Set(key.clone(), value.clone()));
-                            }
-                            result
-                        }
-                        Command::Truncate => {
-                            let res = { let store = store.lock().await; store.truncate() };
-                            match res {
-                                Ok(_) => "OK\r\n".to_string(),
-                                Err(e) => format!("ERROR {}\r\n", e),
-                            }
-                        }
-                        Command::Stats => {
-                            format!("STATS\r\n{}", stats.format_stats())
-                        }
-                        Command::Info => {
-                            let mut info = String::new();
-                            
-                            // Server version from Cargo.toml
-                            info.push_str(&format!("version:{}\r\n", env!("CARGO_PKG_VERSION")));
-                            
-                            // Server uptime
-                            info.push_str(&format!("uptime_seconds:{}\r\n", stats.uptime_seconds()));
-                            info.push_str(&format!("uptime:{}\r\n", stats.uptime_human()));
-                            
-                            // Current time
-                            let now = SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap_or(Duration::from_secs(0))
-                                .as_secs();
-                            info.push_str(&format!("server_time_unix:{}\r\n", now));
-                            
-                            // Key count
-                            let key_count = { let store = store.lock().await; store.count_keys().unwrap_or(0) };
-                            info.push_str(&format!("db_keys:{}\r\n", key_count));
-                            
-                            format!("INFO\r\n{}", info)
-                        }
-                        Command::Ping => {
-                            "PONG\r\n".to_string()
-                        }
-                        Command::Version => {
-                            // Return the server version from Cargo.toml
-                            format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION"))
-                        }
-                        Command::Flush => {
-                            // Force sync to disk if the storage engine supports it
-                            let res = { let store = store.lock().await; store.sync() };
-                            match res {
-                                Ok(_) => "OK\r\n".to_string(),
-                                Err(e) => format!("ERROR {}\r\n", e),
-                            }
-                        }
-                        Command::Shutdown => {
-                            // Send OK response before shutting down
-                            let response = "OK\r\n".to_string();
-                            if let Err(e) = socket.write_all(response.as_bytes()).await {
-                                error!("Error writing to client {}: {}", addr, e);
-                            }
-                            
-                            // Log shutdown request
-                            info!("Shutdown requested by client {}", addr);
-                            
-                            // Exit the process gracefully
-                            // Note: In a production system, we would want to do a more graceful
-                            // shutdown, such as closing all connections, flushing data to disk, etc.
-                            std::process::exit(0);
-                        }
-                    };
-                    // Perform publishes after the store operations (lock released)
-                    if let Some(r) = &replicator {
-                        for p in publishes {
-                            match p {
-                                Publish::Set(k, v) => { let _ = r.publish_set(&k, &v).await; }
-                                Publish::Delete(k) => { let _ = r.publish_delete(&k).await; }
-                                Publish::Incr(k, nv) => { let _ = r.publish_incr(&k, nv).await; }
-                                Publish::Decr(k, nv) => { let _ = r.publish_decr(&k, nv).await; }
-                                Publish::Append(k, nv) => { let _ = r.publish_append(&k, &nv).await; }
-                                Publish::Prepend(k, nv) => { let _ = r.publish_prepend(&k, &nv).await; }
-                            }
+                        Err(e) if e.kind() == io::ErrorKind::TimedOut => Response::error(ErrorCode::Timeout, "read timeout"),
+                        Err(e) => {
+                            error!("Error reading from client {}: {}", addr, e);
+                            return Ok(());
                         }
                     }
-                    
-                    // Send response back to client
-                    if let Err(e) = socket.write_all(response.as_bytes()).await {
+                };
+                let error = error.render(format);
+                warn!("Closing connection from {}: {}", addr, error.trim_end());
+                let _ = socket.write_all(error.as_bytes()).await;
+                return Ok(());
+            }
+
+            // Refuse the command outright if this client is over its rate limit
+            if let Some(limiter) = &limiter {
+                if !limiter.try_acquire(addr.ip()) {
+                    executor.stats().throttled_commands.fetch_add(1, Ordering::Relaxed);
+                    let throttled = Response::error(ErrorCode::Limit, "rate limit exceeded").render(format);
+                    if let Err(e) = socket.write_all(throttled.as_bytes()).await {
                         error!("Error writing to client {}: {}", addr, e);
                         break;
                     }
+                    continue;
                 }
-                Err(e) => {
-                    // Send error response for invalid commands
-                    let error_msg = format!("ERROR {}\r\n", e);
-                    if let Err(e) = socket.write_all(error_msg.as_bytes()).await {
+            }
+
+            // Convert received bytes to string
+            let request = std::str::from_utf8(&bytes)?;
+            
+            let response = match protocol.parse(request) {
+                // Commands that act on this connection or the server itself
+                Ok(Command::Shutdown) => {
+                    executor.stats().increment_command_counter(&Command::Shutdown);
+                    // The OK is sent before this connection closes with the others
+                    info!("Shutdown requested by client {}", addr);
+                    shutdown.trigger();
+                    Response::Ok
+                }
+                Ok(command @ Command::Format { .. }) => {
+                    executor.stats().increment_command_counter(&command);
+                    let Command::Format { format: chosen } = command else { unreachable!() };
+                    // The OK is already in the new format
+                    format = chosen;
+                    Response::Ok
+                }
+                Ok(command @ Command::Watch { .. }) => {
+                    executor.stats().increment_command_counter(&command);
+                    let Command::Watch { pattern, format: watch_format } = command else { unreachable!() };
+                    let watch = Watch::new(pattern, watch_format);
+                    let mut events = executor.watch();
+                    if let Err(e) = socket.write_all(Response::Ok.render(format).as_bytes()).await {
+                        error!("Error writing to client {}: {}", addr, e);
+                        break;
+                    }
+                    if !Self::stream_changes(&mut socket, &mut buffer, &mut events, &watch, &shutdown).await? {
+                        info!("Client {} disconnected while watching", addr);
+                        break;
+                    }
+                    // Acknowledge the UNWATCH that ended the stream
+                    Response::Ok
+                }
+                Ok(command @ (Command::Subscribe { .. } | Command::PSubscribe { .. })) => {
+                    executor.stats().increment_command_counter(&command);
+                    let mut subscription = executor.pubsub().open();
+                    Self::update_subscription(&subscription, &command);
+                    if let Err(e) = socket.write_all(Response::Ok.render(format).as_bytes()).await {
                         error!("Error writing to client {}: {}", addr, e);
                         break;
                     }
+                    if !Self::stream_messages(&mut socket, &mut buffer, &mut subscription, format, &shutdown).await? {
+                        info!("Client {} disconnected while subscribed", addr);
+                        break;
+                    }
+                    // Acknowledge the unsubscribe that ended subscribed mode
+                    Response::Ok
                 }
+                Ok(command) => executor.execute(command).await,
+                // Send error response for invalid commands
+                Err(e) => Response::Error(e),
+            };
+
+            // Send response back to client
+            if let Err(e) = socket.write_all(response.render(format).as_bytes()).await {
+                error!("Error writing to client {}: {}", addr, e);
+                break;
             }
         }
 
         Ok(())
     }
+    /// Stream matching change events to a watching client.
+    ///
+    /// While streaming, the only accepted command is `UNWATCH`; anything else
+    /// is answered with an error. Events the client was too slow to receive
+    /// are reported as `LAGGED <count>`.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` after `UNWATCH`, `false` if the client
+    ///   disconnected or the server is shutting down
+    async fn stream_changes(
+        socket: &mut TcpStream,
+        buffer: &mut [u8],
+        events: &mut broadcast::Receiver<ChangeEvent>,
+        watch: &Watch,
+        shutdown: &Shutdown,
+    ) -> Result<bool> {
+        loop {
+            tokio::select! {
+                _ = shutdown.requested() => return Ok(false),
+                received = events.recv() => {
+                    let line = match received {
+                        Ok(ev) if watch.matches(&ev.key) => watch.render(&ev),
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(n)) => format!("LAGGED {}\r\n", n),
+                        Err(broadcast::error::RecvError::Closed) => return Ok(false),
+                    };
+                    if socket.write_all(line.as_bytes()).await.is_err() {
+                        return Ok(false);
+                    }
+                }
+                read = socket.read(buffer) => {
+                    let n = match read {
+                        Ok(0) | Err(_) => return Ok(false),
+                        Ok(n) => n,
+                    };
+                    let request = std::str::from_utf8(&buffer[..n]).unwrap_or_default();
+                    if matches!(Protocol::new().parse(request), Ok(Command::Unwatch)) {
+                        return Ok(true);
+                    }
+                    socket.write_all(b"ERROR only UNWATCH is allowed while watching\r\n").await?;
+                }
+            }
+        }
+    }
+    /// Apply a (P)SUBSCRIBE or (P)UNSUBSCRIBE command to a subscription.
+    fn update_subscription(subscription: &Subscription, command: &Command) {
+        match command {
+            Command::Subscribe { channels } => subscription.subscribe(channels),
+            Command::PSubscribe { patterns } => subscription.psubscribe(patterns),
+            Command::Unsubscribe { channels } => subscription.unsubscribe(channels),
+            Command::PUnsubscribe { patterns } => subscription.punsubscribe(patterns),
+            _ => {}
+        }
+    }
+
+    /// Deliver channel messages to a subscribed client.
+    ///
+    /// While subscribed the client may only send SUBSCRIBE, PSUBSCRIBE,
+    /// UNSUBSCRIBE, PUNSUBSCRIBE and PING. Subscribed mode ends once no
+    /// channels or patterns remain. Replies use the connection's `format`.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - `true` once unsubscribed from everything, `false` if
+    ///   the client disconnected or the server is shutting down
+    async fn stream_messages(
+        socket: &mut TcpStream,
+        buffer: &mut [u8],
+        subscription: &mut Subscription,
+        format: ResponseFormat,
+        shutdown: &Shutdown,
+    ) -> Result<bool> {
+        loop {
+            tokio::select! {
+                _ = shutdown.requested() => return Ok(false),
+                delivery = subscription.recv() => {
+                    let Some(delivery) = delivery else { return Ok(false) };
+                    if socket.write_all(delivery.render().as_bytes()).await.is_err() {
+                        return Ok(false);
+                    }
+                }
+                read = socket.read(buffer) => {
+                    let n = match read {
+                        Ok(0) | Err(_) => return Ok(false),
+                        Ok(n) => n,
+                    };
+                    let request = std::str::from_utf8(&buffer[..n]).unwrap_or_default();
+                    let reply = match Protocol::new().parse(request) {
+                        Ok(command @ (Command::Subscribe { .. }
+                        | Command::PSubscribe { .. }
+                        | Command::Unsubscribe { .. }
+                        | Command::PUnsubscribe { .. })) => {
+                            Self::update_subscription(subscription, &command);
+                            if subscription.count() == 0 {
+                                return Ok(true);
+                            }
+                            Response::Ok
+                        }
+                        Ok(Command::Ping) => Response::Status("PONG".to_string()),
+                        _ => Response::error(
+                            ErrorCode::Unavailable,
+                            "only (P)SUBSCRIBE, (P)UNSUBSCRIBE and PING are allowed while subscribed",
+                        ),
+                    };
+                    socket.write_all(reply.render(format).as_bytes()).await?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::RwLockEngine;
+
+    /// Serve one connection with `read_timeout` and return the client end.
+    async fn connect(read_timeout: Option<Duration>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, addr) = listener.accept().await.unwrap();
+        let store: Arc<dyn KVEngineStoreTrait> = Arc::new(RwLockEngine::new("").unwrap());
+        let stats = Arc::new(ServerStats::new());
+        let changelog = Arc::new(ChangeLog::open("", 100).unwrap());
+        let executor = CommandExecutor::new(store, None, changelog, stats, Arc::new(PubSub::new()), "node".to_string());
+        let shared = Shared {
+            executor: Arc::new(executor),
+            shutdown: Shutdown::new(),
+            limiter: None,
+            read_timeout,
+            idle_timeout: None,
+        };
+        tokio::spawn(Server::handle_connection(socket, addr, shared));
+        client
+    }
+
+    async fn reply(client: &mut TcpStream) -> String {
+        let mut buffer = [0; 1024];
+        let n = client.read(&mut buffer).await.unwrap();
+        String::from_utf8_lossy(&buffer[..n]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn assembles_commands_split_across_writes() {
+        let mut client = connect(None).await;
+        client.write_all(b"SET greeting hel").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(b"lo\r\n").await.unwrap();
+        assert_eq!(reply(&mut client).await, "OK\r\n");
+        client.write_all(b"GET greeting\r\n").await.unwrap();
+        assert_eq!(reply(&mut client).await, "VALUE hello\r\n");
+    }
+
+    #[tokio::test]
+    async fn subscribed_replies_follow_the_connection_format() {
+        let mut client = connect(None).await;
+        client.write_all(b"FORMAT JSON\r\n").await.unwrap();
+        reply(&mut client).await;
+        client.write_all(b"SUBSCRIBE news\r\n").await.unwrap();
+        assert_eq!(reply(&mut client).await, "{\"type\":\"ok\"}\r\n");
+        client.write_all(b"PING\r\n").await.unwrap();
+        assert_eq!(reply(&mut client).await, "{\"type\":\"status\",\"value\":\"PONG\"}\r\n");
+        client.write_all(b"GET k\r\n").await.unwrap();
+        assert!(reply(&mut client).await.contains("\"code\":\"ERR_UNAVAILABLE\""));
+        client.write_all(b"UNSUBSCRIBE news\r\n").await.unwrap();
+        assert_eq!(reply(&mut client).await, "{\"type\":\"ok\"}\r\n");
+    }
+
+    #[tokio::test]
+    async fn unfinished_commands_time_out() {
+        let mut client = connect(Some(Duration::from_millis(100))).await;
+        client.write_all(b"GET greeting").await.unwrap();
+        assert!(reply(&mut client).await.starts_with("ERROR ERR_TIMEOUT"));
+        assert_eq!(reply(&mut client).await, "");
+    }
 }
@@ -0,0 +1,441 @@
+//! # Replicated Collection Types (CRDTs)
+//!
+//! This module provides the state-based CRDTs behind the set (`SADD`/`SREM`/
+//! `SMEMBERS`) and hash (`HSET`/`HGET`/`HDEL`) commands. Unlike plain string
+//! values, which are replicated by overwriting with Last-Write-Wins, these
+//! types are replicated by *merging*: every node can apply deltas in any
+//! order, any number of times, and still converge to the same state.
+//!
+//! ## Types
+//!
+//! - **`OrSet`** (Observed-Remove Set): every add is tagged with a unique id;
+//!   a remove only tombstones the tags it has observed. A concurrent add with a
+//!   fresh tag therefore survives a remove ("add wins").
+//! - **`LwwMap`** (Last-Write-Wins Map): every field carries a `(ts, src)`
+//!   stamp; the greater stamp wins. Deletions are stored as tombstones so that
+//!   an older write arriving late cannot resurrect a deleted field.
+//!
+//! ## Merge Laws
+//!
+//! `merge` is commutative, associative and idempotent for both types, which is
+//! what allows replication to skip ordering and deduplication checks for CRDT
+//! deltas entirely.
+//!
+//! ## Canonical Serialization
+//!
+//! All internal collections are `BTreeMap`/`BTreeSet`, so the CBOR encoding
+//! produced by `CrdtValue::canonical_bytes` is deterministic. The Merkle tree
+//! hashes this encoding, so two nodes holding the same CRDT state produce the
+//! same leaf hash.
+
+use crate::error::{ErrorCode, KvError};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Unique identifier attached to each OR-Set add (UUID v4 bytes).
+pub type Tag = [u8; 16];
+
+/// Generate a fresh, globally unique add tag.
+pub fn new_tag() -> Tag {
+    uuid::Uuid::new_v4().into_bytes()
+}
+
+/// Observed-Remove Set of strings.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrSet {
+    /// Live add tags per element
+    adds: BTreeMap<String, BTreeSet<Tag>>,
+    /// Tags that have been removed (tombstones)
+    removed: BTreeSet<Tag>,
+}
+
+impl OrSet {
+    /// Create an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `member` under a fresh tag and return the delta to replicate.
+    pub fn add(&mut self, member: &str, tag: Tag) -> OrSet {
+        let mut delta = OrSet::new();
+        delta.adds.entry(member.to_string()).or_default().insert(tag);
+        self.merge(&delta);
+        delta
+    }
+
+    /// Remove `member` by tombstoning every tag observed locally.
+    ///
+    /// Returns the delta to replicate, or `None` if the member is not present.
+    pub fn remove(&mut self, member: &str) -> Option<OrSet> {
+        let observed = self.adds.get(member)?.clone();
+        if observed.is_empty() {
+            return None;
+        }
+        let mut delta = OrSet::new();
+        delta.removed = observed;
+        self.merge(&delta);
+        Some(delta)
+    }
+
+    /// Remove every member observed locally (DEL of a set key).
+    ///
+    /// Returns the delta to replicate, or `None` if the set is already empty.
+    /// Concurrent adds on other replicas carry tags this delta has not seen,
+    /// so they survive the clear.
+    pub fn clear(&mut self) -> Option<OrSet> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut delta = OrSet::new();
+        delta.removed = self.adds.values().flatten().copied().collect();
+        self.merge(&delta);
+        Some(delta)
+    }
+
+    /// Check whether `member` is currently in the set.
+    pub fn contains(&self, member: &str) -> bool {
+        self.adds.get(member).map(|tags| !tags.is_empty()).unwrap_or(false)
+    }
+
+    /// Return the current members in lexicographic order.
+    pub fn members(&self) -> Vec<String> {
+        self.adds
+            .iter()
+            .filter(|(_, tags)| !tags.is_empty())
+            .map(|(m, _)| m.clone())
+            .collect()
+    }
+
+    /// Number of members currently in the set.
+    pub fn len(&self) -> usize {
+        self.adds.values().filter(|tags| !tags.is_empty()).count()
+    }
+
+    /// Check whether the set has no members.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Merge another replica's state (or a delta) into this one.
+    pub fn merge(&mut self, other: &OrSet) {
+        self.removed.extend(other.removed.iter().copied());
+        for (member, tags) in &other.adds {
+            self.adds.entry(member.clone()).or_default().extend(tags.iter().copied());
+        }
+        // Drop tombstoned tags from the live set; the tombstone itself is kept
+        // so that a late-arriving add with the same tag stays removed.
+        let removed = &self.removed;
+        for tags in self.adds.values_mut() {
+            tags.retain(|t| !removed.contains(t));
+        }
+        self.adds.retain(|_, tags| !tags.is_empty());
+    }
+}
+
+/// A single field of an `LwwMap`, stamped for conflict resolution.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwEntry {
+    /// Field value; `None` marks a deleted field (tombstone)
+    pub value: Option<String>,
+    /// Timestamp of the write (unix nanos)
+    pub ts: u64,
+    /// Originating node id, used to break timestamp ties deterministically
+    pub src: String,
+}
+
+impl LwwEntry {
+    /// Whether this entry wins over `other` under LWW with a `src` tie-break.
+    fn supersedes(&self, other: &LwwEntry) -> bool {
+        (self.ts, &self.src, &self.value) > (other.ts, &other.src, &other.value)
+    }
+}
+
+/// Last-Write-Wins Map from field names to string values.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwMap {
+    entries: BTreeMap<String, LwwEntry>,
+}
+
+impl LwwMap {
+    /// Create an empty map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set `field` to `value` and return the delta to replicate.
+    pub fn set(&mut self, field: &str, value: &str, ts: u64, src: &str) -> LwwMap {
+        self.write(field, Some(value.to_string()), ts, src)
+    }
+
+    /// Delete `field` and return the delta to replicate, or `None` if absent.
+    pub fn delete(&mut self, field: &str, ts: u64, src: &str) -> Option<LwwMap> {
+        self.get(field)?;
+        Some(self.write(field, None, ts, src))
+    }
+
+    /// Delete every live field (DEL of a hash key) and return the delta to
+    /// replicate, or `None` if the map has no live fields.
+    pub fn clear(&mut self, ts: u64, src: &str) -> Option<LwwMap> {
+        let fields: Vec<String> = self.entries.iter().filter(|(_, e)| e.value.is_some()).map(|(f, _)| f.clone()).collect();
+        if fields.is_empty() {
+            return None;
+        }
+        let mut delta = LwwMap::new();
+        for field in fields {
+            delta.merge(&self.write(&field, None, ts, src));
+        }
+        Some(delta)
+    }
+
+    /// Check whether the map has no live fields.
+    pub fn is_empty(&self) -> bool {
+        self.entries.values().all(|e| e.value.is_none())
+    }
+
+    fn write(&mut self, field: &str, value: Option<String>, ts: u64, src: &str) -> LwwMap {
+        let mut delta = LwwMap::new();
+        delta.entries.insert(
+            field.to_string(),
+            LwwEntry { value, ts, src: src.to_string() },
+        );
+        self.merge(&delta);
+        delta
+    }
+
+    /// Get the current value of `field`.
+    pub fn get(&self, field: &str) -> Option<&str> {
+        self.entries.get(field).and_then(|e| e.value.as_deref())
+    }
+
+    /// Merge another replica's state (or a delta) into this one.
+    pub fn merge(&mut self, other: &LwwMap) {
+        for (field, theirs) in &other.entries {
+            match self.entries.get(field) {
+                Some(ours) if !theirs.supersedes(ours) => {}
+                _ => {
+                    self.entries.insert(field.clone(), theirs.clone());
+                }
+            }
+        }
+    }
+}
+
+/// A CRDT value as stored by the engines and carried in replication deltas.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CrdtValue {
+    /// Observed-Remove Set
+    Set(OrSet),
+    /// Last-Write-Wins Map
+    Map(LwwMap),
+}
+
+impl CrdtValue {
+    /// Human-readable type name, used in WRONGTYPE errors.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            CrdtValue::Set(_) => "set",
+            CrdtValue::Map(_) => "hash",
+        }
+    }
+
+    /// Whether the value has no members or live fields. An empty value only
+    /// keeps tombstones and is treated as a missing key.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            CrdtValue::Set(set) => set.is_empty(),
+            CrdtValue::Map(map) => map.is_empty(),
+        }
+    }
+
+    /// Merge `other` into `self`. Fails if the two values are of different
+    /// types, even if `self` is empty: its tombstones must survive, and
+    /// whether a delta arrived before or after a clear must not decide the
+    /// key's type.
+    pub fn merge(&mut self, other: &CrdtValue) -> Result<()> {
+        match (self, other) {
+            (CrdtValue::Set(a), CrdtValue::Set(b)) => a.merge(b),
+            (CrdtValue::Map(a), CrdtValue::Map(b)) => a.merge(b),
+            (a, b) => {
+                return Err(KvError::new(
+                    ErrorCode::WrongType,
+                    format!("cannot merge {} into {}", b.type_name(), a.type_name()),
+                )
+                .into())
+            }
+        }
+        Ok(())
+    }
+
+    /// Deterministic CBOR encoding of the full state (used for Merkle hashing
+    /// and as the replication/persistence payload).
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        // BTree-backed fields make this encoding deterministic; serializing an
+        // in-memory value cannot fail.
+        serde_cbor::to_vec(self).expect("CRDT values are always serializable")
+    }
+
+    /// Decode a value produced by `canonical_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        serde_cbor::from_slice(bytes).map_err(|e| anyhow!("Invalid CRDT payload: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orset_add_remove_members() {
+        let mut s = OrSet::new();
+        s.add("a", new_tag());
+        s.add("b", new_tag());
+        assert_eq!(s.members(), vec!["a".to_string(), "b".to_string()]);
+        assert!(s.remove("a").is_some());
+        assert!(s.remove("missing").is_none());
+        assert_eq!(s.members(), vec!["b".to_string()]);
+        assert!(!s.contains("a"));
+    }
+
+    #[test]
+    fn orset_concurrent_add_wins_over_remove() {
+        let mut a = OrSet::new();
+        a.add("x", new_tag());
+        let mut b = a.clone();
+
+        // Node A removes "x" while node B concurrently re-adds it.
+        let rem = a.remove("x").unwrap();
+        let add = b.add("x", new_tag());
+
+        a.merge(&add);
+        b.merge(&rem);
+        assert_eq!(a, b);
+        assert!(a.contains("x"));
+    }
+
+    #[test]
+    fn orset_merge_is_idempotent_and_commutative() {
+        let mut base = OrSet::new();
+        let d1 = base.add("1", new_tag());
+        let d2 = base.add("2", new_tag());
+        let d3 = base.remove("1").unwrap();
+
+        let mut fwd = OrSet::new();
+        for d in [&d1, &d2, &d3, &d3, &d1] {
+            fwd.merge(d);
+        }
+        let mut rev = OrSet::new();
+        for d in [&d3, &d2, &d1] {
+            rev.merge(d);
+        }
+        assert_eq!(fwd, rev);
+        assert_eq!(fwd.members(), vec!["2".to_string()]);
+    }
+
+    #[test]
+    fn lww_map_latest_write_wins() {
+        let mut m = LwwMap::new();
+        let newer = m.set("f", "new", 20, "a");
+        let mut other = LwwMap::new();
+        let older = other.set("f", "old", 10, "b");
+
+        m.merge(&older);
+        other.merge(&newer);
+        assert_eq!(m.get("f"), Some("new"));
+        assert_eq!(m, other);
+    }
+
+    #[test]
+    fn lww_map_tombstone_blocks_stale_write() {
+        let mut m = LwwMap::new();
+        m.set("f", "v", 10, "a");
+        let del = m.delete("f", 20, "a").unwrap();
+        assert_eq!(m.get("f"), None);
+        assert!(m.delete("f", 30, "a").is_none());
+
+        let mut late = LwwMap::new();
+        late.set("f", "stale", 15, "b");
+        late.merge(&del);
+        m.merge(&late);
+        assert_eq!(m.get("f"), None);
+        assert_eq!(late, m);
+    }
+
+    #[test]
+    fn lww_map_equal_timestamps_tie_break_on_src() {
+        let mut a = LwwMap::new();
+        let da = a.set("f", "from-a", 5, "a");
+        let mut b = LwwMap::new();
+        let db = b.set("f", "from-b", 5, "b");
+        a.merge(&db);
+        b.merge(&da);
+        assert_eq!(a.get("f"), Some("from-b"));
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn clear_tombstones_observed_state_only() {
+        let mut a = OrSet::new();
+        a.add("x", new_tag());
+        let mut b = a.clone();
+        let clear = a.clear().unwrap();
+        assert!(a.is_empty() && a.clear().is_none());
+        // A concurrent add on another replica survives the clear
+        let add = b.add("y", new_tag());
+        b.merge(&clear);
+        a.merge(&add);
+        assert_eq!((a.members(), b.members()), (vec!["y".to_string()], vec!["y".to_string()]));
+
+        let mut m = LwwMap::new();
+        m.set("f", "1", 10, "a");
+        m.set("g", "2", 10, "a");
+        let clear = m.clear(20, "a").unwrap();
+        assert!(m.is_empty() && m.clear(30, "a").is_none());
+        let mut replica = LwwMap::new();
+        replica.set("f", "1", 10, "a");
+        replica.merge(&clear);
+        assert!(replica.is_empty());
+
+        // A deleted (empty) value keeps its type and tombstones
+        let mut deleted = CrdtValue::Map(m.clone());
+        assert!(deleted.merge(&CrdtValue::Set(a)).is_err());
+        assert_eq!(deleted, CrdtValue::Map(m));
+    }
+
+    #[test]
+    fn type_mismatch_outcome_does_not_depend_on_delivery_order() {
+        let mut set = OrSet::new();
+        set.add("x", new_tag());
+        let replica = CrdtValue::Set(set.clone());
+        let clear = CrdtValue::Set(set.clear().unwrap());
+        let mut fields = LwwMap::new();
+        let hash = CrdtValue::Map(fields.set("f", "v", 10, "b"));
+
+        let apply = |deltas: [&CrdtValue; 2]| {
+            let mut value = replica.clone();
+            for delta in deltas {
+                let _ = value.merge(delta);
+            }
+            value
+        };
+        let (clear_first, hash_first) = (apply([&clear, &hash]), apply([&hash, &clear]));
+        assert_eq!(clear_first, hash_first);
+        assert!(matches!(clear_first, CrdtValue::Set(ref s) if s.is_empty()));
+    }
+
+    #[test]
+    fn crdt_value_type_mismatch_and_canonical_bytes() {
+        let mut s = OrSet::new();
+        s.add("m", [1; 16]);
+        let mut v = CrdtValue::Set(s.clone());
+        assert!(v.merge(&CrdtValue::Map(LwwMap::new())).is_err());
+
+        let bytes = v.canonical_bytes();
+        assert_eq!(CrdtValue::from_bytes(&bytes).unwrap(), v);
+        // Same state built independently must serialize identically.
+        let mut s2 = OrSet::new();
+        s2.add("m", [1; 16]);
+        assert_eq!(CrdtValue::Set(s2).canonical_bytes(), bytes);
+    }
+}
@@ -4,20 +4,24 @@ use super::{
     kv_engine::KvEngine,
     rwlock_engine::RwLockEngine,
     sled_engine::{SledEngine, SledConfig},
-    KVEngineStoreTrait,
+    KVEngineStoreTrait, ShardedEngine,
 };
 use crate::config::StorageConfig;
 
 pub fn create_storage_engine(config: &StorageConfig) -> Result<Box<dyn KVEngineStoreTrait>> {
     match config.engine {
         crate::config::StorageEngine::Memory => {
-            log::info!("Creating in-memory storage engine (non-thread-safe)");
+            log::info!("Creating copy-on-write in-memory storage engine");
             Ok(Box::new(KvEngine::new(&config.path)?))
         }
         crate::config::StorageEngine::RwLock => {
             log::info!("Creating thread-safe in-memory storage engine");
             Ok(Box::new(RwLockEngine::new(&config.path)?))
         }
+        crate::config::StorageEngine::Sharded => {
+            log::info!("Creating sharded in-memory storage engine ({} shards)", config.shards);
+            Ok(Box::new(ShardedEngine::new(&config.path, config.shards)?))
+        }
         crate::config::StorageEngine::Sled => {
             log::info!("Creating persistent Sled storage engine at {}", config.path);
             
@@ -51,6 +55,11 @@ pub fn create_storage_engine_simple(
             path: storage_path.to_string(),
             ..Default::default()
         },
+        "sharded" => StorageConfig {
+            engine: crate::config::StorageEngine::Sharded,
+            path: storage_path.to_string(),
+            ..Default::default()
+        },
         "sled" => StorageConfig {
             engine: crate::config::StorageEngine::Sled,
             path: storage_path.to_string(),
@@ -58,7 +67,7 @@ pub fn create_storage_engine_simple(
         },
         _ => {
             return Err(anyhow::anyhow!(
-                "Unknown engine type: {}. Available engines: memory, rwlock, sled",
+                "Unknown engine type: {}. Available engines: memory, rwlock, sharded, sled",
                 engine_type
             ));
         }
@@ -99,6 +108,19 @@ mod tests {
         assert_eq!(engine.get("key1"), Some("value1".to_string()));
     }
 
+    #[test]
+    fn test_create_sharded_engine() {
+        let config = StorageConfig {
+            engine: StorageEngine::Sharded,
+            shards: 4,
+            ..Default::default()
+        };
+
+        let engine = create_storage_engine(&config).unwrap();
+        assert!(engine.set("key1".to_string(), "value1".to_string()).is_ok());
+        assert_eq!(engine.get("key1"), Some("value1".to_string()));
+    }
+
     #[test]
     fn test_create_sled_engine() {
         let temp_dir = tempdir().unwrap();
@@ -111,6 +133,7 @@ mod tests {
             cache_size_mb: 50,
             flush_interval_ms: 500,
             max_db_size_mb: 100,
+            shards: 16,
         };
 
         let engine = create_storage_engine(&config).unwrap();
@@ -6,8 +6,9 @@
 //! ## Current Implementation
 //!
 //! The current implementation is a simple in-memory store that:
-//! - Uses `Arc<HashMap<String, String>>` for thread-safe access
-//! - Creates new HashMap instances on every write (copy-on-write pattern)
+//! - Keeps the data in an `Arc<HashMap<String, String>>` behind an `RwLock`
+//! - Copies the map on write only while a reader still holds a snapshot of it
+//!   (copy-on-write via `Arc::make_mut`)
 //! - Provides basic get/set/delete operations
 //! - Supports numeric operations (increment/decrement)
 //! - Supports string operations (append/prepend)
@@ -25,23 +26,28 @@
 
 use anyhow::Result;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 
-use super::kv_trait::KVEngineStoreTrait;
+use super::crdt::CrdtValue;
+use super::kv_trait::{add_to_value, negated_amount, KVEngineStoreTrait};
 
 /// In-memory key-value storage engine.
 ///
 /// This is a simplified storage implementation that keeps all data in memory.
-/// The `Arc<HashMap>` allows for efficient cloning of the engine while sharing
-/// the underlying data until a write operation occurs.
+/// Clones of the engine share the same data. Writes are serialized by the
+/// engine's lock, so the engine is safe to share across threads.
 ///
 /// **Note**: This implementation is not persistent! All data is lost when
 /// the process terminates.
 #[derive(Clone)]
 pub struct KvEngine {
-    /// Shared reference to the key-value data
-    /// Using Arc allows multiple readers while writes create new instances
-    data: Arc<HashMap<String, String>>,
+    /// Current version of the key-value data. Readers take a cheap snapshot
+    /// (an `Arc` clone) and release the lock; writers update the map in place
+    /// unless a snapshot is still alive, in which case it is copied first.
+    data: Arc<RwLock<Arc<HashMap<String, String>>>>,
+    /// CRDT values (sets and hashes). Guarded by a Mutex because CRDT merges
+    /// are read-modify-write operations.
+    crdts: Arc<Mutex<HashMap<String, CrdtValue>>>,
     // TODO: Add persistent storage implementation
     // In a real implementation, this would use a persistent storage engine like Sled:
     // storage_path: PathBuf,
@@ -69,10 +75,23 @@ impl KvEngine {
         // Ok(Self { storage_path: storage_path.into(), sled_db: db })
 
         Ok(Self {
-            data: Arc::new(HashMap::new()),
+            data: Arc::new(RwLock::new(Arc::new(HashMap::new()))),
+            crdts: Arc::new(Mutex::new(HashMap::new())),
         })
     }
 
+    /// Snapshot of the current data; later writes do not affect it.
+    fn snapshot(&self) -> Arc<HashMap<String, String>> {
+        self.data.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Run `f` on the data under the write lock, copying the map first only
+    /// if a snapshot of it is still in use.
+    fn update<R>(&self, f: impl FnOnce(&mut HashMap<String, String>) -> R) -> R {
+        let mut data = self.data.write().unwrap_or_else(|e| e.into_inner());
+        f(Arc::make_mut(&mut data))
+    }
+
     /// Retrieve a value by its key.
     ///
     /// # Arguments
@@ -89,225 +108,7 @@ impl KvEngine {
     /// }
     /// ```
     pub fn get(&self, key: &str) -> Option<String> {
-        self.data.get(key).cloned()
-    }
-
-    /// Store a key-value pair.
-    ///
-    /// This operation creates a new HashMap with the updated data due to the
-    /// immutable nature of the `Arc<HashMap>` design. This is inefficient but
-    /// simple for the current prototype.
-    ///
-    /// # Arguments
-    /// * `key` - The key to store
-    /// * `value` - The value to associate with the key
-    ///
-    /// # Example
-    /// ```rust
-    /// let mut engine = KvEngine::new("./data")?;
-    /// engine.set("user:123".to_string(), "john_doe".to_string());
-    /// ```
-    ///
-    /// # Performance Note
-    /// This implementation is O(n) in the number of keys due to HashMap cloning.
-    /// A persistent storage engine would be much more efficient.
-    pub fn set(&mut self, key: String, value: String) {
-        // Create a new HashMap with the updated value
-        // TODO: Replace with efficient persistent storage writes
-        let mut new_data = (*self.data).clone();
-        new_data.insert(key, value);
-        self.data = Arc::new(new_data);
-    }
-
-    /// Delete a key-value pair.
-    ///
-    /// Like `set`, this creates a new HashMap without the deleted key.
-    ///
-    /// # Arguments
-    /// * `key` - The key to delete
-    ///
-    /// # Example
-    /// ```rust
-    /// let mut engine = KvEngine::new("./data")?;
-    /// engine.delete("user:123");
-    /// ```
-    pub fn delete(&mut self, key: &str) {
-        // Create a new HashMap without the deleted key
-        // TODO: Replace with efficient persistent storage deletes
-        let mut new_data = (*self.data).clone();
-        new_data.remove(key);
-        self.data = Arc::new(new_data);
-    }
-
-    /// Get all keys currently stored in the engine.
-    ///
-    /// This is primarily used by the Merkle tree to rebuild its state
-    /// and for debugging purposes.
-    ///
-    /// # Returns
-    /// * `Vec<String>` - Vector of all keys in the store
-    ///
-    /// # Performance Note
-    /// This operation is O(n) and creates a new vector. In a production
-    /// system, this should be replaced with an iterator-based approach.
-    pub fn keys(&self) -> Vec<String> {
-        self.data.keys().cloned().collect()
-    }
-
-    /// Increment a numeric value stored at the given key.
-    /// 
-    /// If the key doesn't exist, it will be created with the increment amount.
-    /// If the key exists but doesn't contain a valid number, an error message is returned.
-    /// 
-    /// # Arguments
-    /// * `key` - The key to increment
-    /// * `amount` - The amount to increment by (defaults to 1 if None)
-    /// 
-    /// # Returns
-    /// * `Result<i64, String>` - The new value after incrementing, or an error message
-    /// 
-    /// # Example
-    /// ```rust
-    /// let mut engine = KvEngine::new("./data")?;
-    /// match engine.increment("counter", Some(5)) {
-    ///     Ok(new_value) => println!("New counter value: {}", new_value),
-    ///     Err(e) => println!("Error: {}", e),
-    /// }
-    /// ```
-    pub fn increment(&mut self, key: &str, amount: Option<i64>) -> Result<i64, String> {
-        let increment_by = amount.unwrap_or(1);
-        let mut new_data = (*self.data).clone();
-        
-        let new_value = match new_data.get(key) {
-            Some(value) => {
-                // Try to parse the existing value as a number
-                match value.parse::<i64>() {
-                    Ok(num) => num + increment_by,
-                    Err(_) => return Err(format!("Value for key '{}' is not a valid number", key)),
-                }
-            }
-            None => increment_by, // Key doesn't exist, start with the increment amount
-        };
-        
-        // Store the new value
-        new_data.insert(key.to_string(), new_value.to_string());
-        self.data = Arc::new(new_data);
-        
-        Ok(new_value)
-    }
-
-    /// Decrement a numeric value stored at the given key.
-    /// 
-    /// If the key doesn't exist, it will be created with the negative of the decrement amount.
-    /// If the key exists but doesn't contain a valid number, an error message is returned.
-    /// 
-    /// # Arguments
-    /// * `key` - The key to decrement
-    /// * `amount` - The amount to decrement by (defaults to 1 if None)
-    /// 
-    /// # Returns
-    /// * `Result<i64, String>` - The new value after decrementing, or an error message
-    /// 
-    /// # Example
-    /// ```rust
-    /// let mut engine = KvEngine::new("./data")?;
-    /// match engine.decrement("counter", Some(3)) {
-    ///     Ok(new_value) => println!("New counter value: {}", new_value),
-    ///     Err(e) => println!("Error: {}", e),
-    /// }
-    /// ```
-    pub fn decrement(&mut self, key: &str, amount: Option<i64>) -> Result<i64, String> {
-        let decrement_by = amount.unwrap_or(1);
-        // Decrement is just a negative increment
-        self.increment(key, Some(-decrement_by))
-    }
-
-    /// Append a value to an existing string.
-    /// 
-    /// If the key doesn't exist, it will be created with the value.
-    /// 
-    /// # Arguments
-    /// * `key` - The key to append to
-    /// * `value` - The value to append
-    /// 
-    /// # Returns
-    /// * `String` - The new value after appending
-    /// 
-    /// # Example
-    /// ```rust
-    /// let mut engine = KvEngine::new("./data")?;
-    /// let new_value = engine.append("greeting", " World!");
-    /// println!("New value: {}", new_value); // e.g., "Hello World!"
-    /// ```
-    pub fn append(&mut self, key: &str, value: &str) -> String {
-        let mut new_data = (*self.data).clone();
-        
-        let new_value = match new_data.get(key) {
-            Some(existing) => {
-                let mut result = existing.clone();
-                result.push_str(value);
-                result
-            }
-            None => value.to_string(), // Key doesn't exist, use the value as is
-        };
-        
-        // Store the new value
-        new_data.insert(key.to_string(), new_value.clone());
-        self.data = Arc::new(new_data);
-        
-        new_value
-    }
-
-    /// Prepend a value to an existing string.
-    /// 
-    /// If the key doesn't exist, it will be created with the value.
-    /// 
-    /// # Arguments
-    /// * `key` - The key to prepend to
-    /// * `value` - The value to prepend
-    /// 
-    /// # Returns
-    /// * `String` - The new value after prepending
-    /// 
-    /// # Example
-    /// ```rust
-    /// let mut engine = KvEngine::new("./data")?;
-    /// let new_value = engine.prepend("greeting", "Hello, ");
-    /// println!("New value: {}", new_value); // e.g., "Hello, World!"
-    /// ```
-    pub fn prepend(&mut self, key: &str, value: &str) -> String {
-        let mut new_data = (*self.data).clone();
-        
-        let new_value = match new_data.get(key) {
-            Some(existing) => {
-                let mut result = value.to_string();
-                result.push_str(existing);
-                result
-            }
-            None => value.to_string(), // Key doesn't exist, use the value as is
-        };
-        
-        // Store the new value
-        new_data.insert(key.to_string(), new_value.clone());
-        self.data = Arc::new(new_data);
-        
-        new_value
-    }
-
-    /// Clear all keys/values in the store.
-    /// 
-    /// This operation removes all data from the store, effectively resetting it
-    /// to an empty state.
-    /// 
-    /// # Example
-    /// ```rust
-    /// let mut engine = KvEngine::new("./data")?;
-    /// engine.truncate();
-    /// assert_eq!(engine.keys().len(), 0);
-    /// ```
-    pub fn truncate(&mut self) {
-        // Create a new empty HashMap
-        self.data = Arc::new(HashMap::new());
+        self.data.read().unwrap_or_else(|e| e.into_inner()).get(key).cloned()
     }
 }
 
@@ -320,69 +121,31 @@ impl KVEngineStoreTrait for KvEngine {
     /// # Returns
     /// * `Option<String>` - The value if found, None otherwise
     fn get(&self, key: &str) -> Option<String> {
-        self.data.get(key).cloned()
+        KvEngine::get(self, key)
     }
 
     /// Store a key-value pair.
     ///
-    /// ⚠️ **WARNING**: This method is NOT thread-safe!
-    ///
-    /// This operation creates a new HashMap with the updated data due to the
-    /// immutable nature of the `Arc<HashMap>` design. This is inefficient but
-    /// simple for the current prototype.
-    ///
     /// # Arguments
     /// * `key` - The key to store
     /// * `value` - The value to associate with the key
     ///
     /// # Returns
     /// * `Result<()>` - Success or error
-    ///
-    /// # Thread Safety
-    /// ⚠️ This method is NOT safe for concurrent access!
-    /// Concurrent writes can lead to data corruption or lost updates.
     fn set(&self, key: String, value: String) -> Result<()> {
-        // This is unsafe for concurrent access!
-        // We need to clone the HashMap, modify it, and create a new Arc
-        let mut new_data = HashMap::clone(&self.data);
-        new_data.insert(key, value);
-        // This is a race condition if multiple threads do this simultaneously
-        unsafe {
-            let arc_ptr = Arc::into_raw(self.data.clone());
-            let mutex_ptr = arc_ptr as *mut HashMap<String, String>;
-            *mutex_ptr = new_data;
-            let _ = Arc::from_raw(arc_ptr);
-        }
+        self.update(|data| data.insert(key, value));
         Ok(())
     }
 
     /// Delete a key-value pair.
     ///
-    /// ⚠️ **WARNING**: This method is NOT thread-safe!
-    ///
-    /// Like `set`, this creates a new HashMap without the deleted key.
-    ///
     /// # Arguments
     /// * `key` - The key to delete
     ///
     /// # Returns
     /// * `bool` - True if the key existed and was deleted, false otherwise
-    ///
-    /// # Thread Safety
-    /// ⚠️ This method is NOT safe for concurrent access!
     fn delete(&self, key: &str) -> bool {
-        // This is unsafe for concurrent access!
-        let mut new_data = HashMap::clone(&self.data);
-        let existed = new_data.remove(key).is_some();
-        if existed {
-            unsafe {
-                let arc_ptr = Arc::into_raw(self.data.clone());
-                let mutex_ptr = arc_ptr as *mut HashMap<String, String>;
-                *mutex_ptr = new_data;
-                let _ = Arc::from_raw(arc_ptr);
-            }
-        }
-        existed
+        self.update(|data| data.remove(key).is_some())
     }
 
     /// Get all keys currently stored in the engine.
@@ -390,7 +153,7 @@ impl KVEngineStoreTrait for KvEngine {
     /// # Returns
     /// * `Vec<String>` - Vector of all keys in the store
     fn keys(&self) -> Vec<String> {
-        self.data.keys().cloned().collect()
+        self.snapshot().keys().cloned().collect()
     }
 
     /// Get the number of key-value pairs in the store.
@@ -398,7 +161,7 @@ impl KVEngineStoreTrait for KvEngine {
     /// # Returns
     /// * `usize` - Number of key-value pairs
     fn len(&self) -> usize {
-        self.data.len()
+        self.snapshot().len()
     }
 
     /// Check if the store is empty.
@@ -406,11 +169,14 @@ impl KVEngineStoreTrait for KvEngine {
     /// # Returns
     /// * `bool` - True if the store is empty, false otherwise
     fn is_empty(&self) -> bool {
-        self.data.is_empty()
+        self.snapshot().is_empty()
     }
     
     /// Increment a numeric value.
     ///
+    /// The read and the write happen under one write lock, so concurrent
+    /// increments are not lost.
+    ///
     /// # Arguments
     /// * `key` - The key to increment
     /// * `amount` - The amount to increment by (default: 1)
@@ -418,37 +184,15 @@ impl KVEngineStoreTrait for KvEngine {
     /// # Returns
     /// * `Result<i64>` - The new value after incrementing, or error if not a valid number
     fn increment(&self, key: &str, amount: Option<i64>) -> Result<i64> {
-        // This is unsafe for concurrent access!
-        let mut new_data = HashMap::clone(&self.data);
-        
         // Default increment amount is 1
         let increment_by = amount.unwrap_or(1);
         
-        // Get the current value or initialize to 0
-        let current_value = match new_data.get(key) {
-            Some(value) => {
-                // Try to parse the current value as a number
-                value.parse::<i64>().map_err(|_| {
-                    anyhow::anyhow!("Value for key '{}' is not a valid number", key)
-                })?
-            }
-            None => 0, // Key doesn't exist, start from 0
-        };
-        
-        // Calculate the new value
-        let new_value = current_value + increment_by;
-        
-        // Store the new value
-        new_data.insert(key.to_string(), new_value.to_string());
-        
-        unsafe {
-            let arc_ptr = Arc::into_raw(self.data.clone());
-            let mutex_ptr = arc_ptr as *mut HashMap<String, String>;
-            *mutex_ptr = new_data;
-            let _ = Arc::from_raw(arc_ptr);
-        }
-        
-        Ok(new_value)
+        self.update(|data| {
+            // Calculate and store the new value (a missing key starts from 0)
+            let new_value = add_to_value(key, data.get(key).map(String::as_str), increment_by)?;
+            data.insert(key.to_string(), new_value.to_string());
+            Ok(new_value)
+        })
     }
     
     /// Decrement a numeric value.
@@ -461,12 +205,13 @@ impl KVEngineStoreTrait for KvEngine {
     /// * `Result<i64>` - The new value after decrementing, or error if not a valid number
     fn decrement(&self, key: &str, amount: Option<i64>) -> Result<i64> {
         // Decrement is just a negative increment
-        let decrement_by = amount.unwrap_or(1);
-        self.increment(key, Some(-decrement_by))
+        self.increment(key, Some(negated_amount(amount)?))
     }
     
     /// Append a value to an existing string.
     ///
+    /// If the key doesn't exist, it is created with the value.
+    ///
     /// # Arguments
     /// * `key` - The key to append to
     /// * `value` - The value to append
@@ -474,42 +219,17 @@ impl KVEngineStoreTrait for KvEngine {
     /// # Returns
     /// * `Result<String>` - The new value after appending
     fn append(&self, key: &str, value: &str) -> Result<String> {
-        // This is unsafe for concurrent access!
-        let mut new_data = HashMap::clone(&self.data);
-        
-        // Check if the key exists
-        if let Some(current_value) = new_data.get(key) {
-            // Append the new value
-            let new_value = format!("{}{}", current_value, value);
-            
-            // Store the new value
-            new_data.insert(key.to_string(), new_value.clone());
-            
-            unsafe {
-                let arc_ptr = Arc::into_raw(self.data.clone());
-                let mutex_ptr = arc_ptr as *mut HashMap<String, String>;
-                *mutex_ptr = new_data;
-                let _ = Arc::from_raw(arc_ptr);
-            }
-            
-            Ok(new_value)
-        } else {
-            // Key doesn't exist, create it with the value
-            new_data.insert(key.to_string(), value.to_string());
-            
-            unsafe {
-                let arc_ptr = Arc::into_raw(self.data.clone());
-                let mutex_ptr = arc_ptr as *mut HashMap<String, String>;
-                *mutex_ptr = new_data;
-                let _ = Arc::from_raw(arc_ptr);
-            }
-            
-            Ok(value.to_string())
-        }
+        Ok(self.update(|data| {
+            let entry = data.entry(key.to_string()).or_default();
+            entry.push_str(value);
+            entry.clone()
+        }))
     }
     
     /// Prepend a value to an existing string.
     ///
+    /// If the key doesn't exist, it is created with the value.
+    ///
     /// # Arguments
     /// * `key` - The key to prepend to
     /// * `value` - The value to prepend
@@ -517,38 +237,11 @@ impl KVEngineStoreTrait for KvEngine {
     /// # Returns
     /// * `Result<String>` - The new value after prepending
     fn prepend(&self, key: &str, value: &str) -> Result<String> {
-        // This is unsafe for concurrent access!
-        let mut new_data = HashMap::clone(&self.data);
-        
-        // Check if the key exists
-        if let Some(current_value) = new_data.get(key) {
-            // Prepend the new value
-            let new_value = format!("{}{}", value, current_value);
-            
-            // Store the new value
-            new_data.insert(key.to_string(), new_value.clone());
-            
-            unsafe {
-                let arc_ptr = Arc::into_raw(self.data.clone());
-                let mutex_ptr = arc_ptr as *mut HashMap<String, String>;
-                *mutex_ptr = new_data;
-                let _ = Arc::from_raw(arc_ptr);
-            }
-            
-            Ok(new_value)
-        } else {
-            // Key doesn't exist, create it with the value
-            new_data.insert(key.to_string(), value.to_string());
-            
-            unsafe {
-                let arc_ptr = Arc::into_raw(self.data.clone());
-                let mutex_ptr = arc_ptr as *mut HashMap<String, String>;
-                *mutex_ptr = new_data;
-                let _ = Arc::from_raw(arc_ptr);
-            }
-            
-            Ok(value.to_string())
-        }
+        Ok(self.update(|data| {
+            let entry = data.entry(key.to_string()).or_default();
+            entry.insert_str(0, value);
+            entry.clone()
+        }))
     }
     
     /// Clear all keys/values in the store.
@@ -556,13 +249,8 @@ impl KVEngineStoreTrait for KvEngine {
     /// # Returns
     /// * `Result<()>` - Success or error
     fn truncate(&self) -> Result<()> {
-        // This is unsafe for concurrent access!
-        unsafe {
-            let arc_ptr = Arc::into_raw(self.data.clone());
-            let mutex_ptr = arc_ptr as *mut HashMap<String, String>;
-            *mutex_ptr = HashMap::new();
-            let _ = Arc::from_raw(arc_ptr);
-        }
+        *self.data.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(HashMap::new());
+        self.crdts.lock().unwrap().clear();
         
         Ok(())
     }
@@ -572,7 +260,7 @@ impl KVEngineStoreTrait for KvEngine {
     /// # Returns
     /// * `Result<u64>` - Number of key-value pairs or error
     fn count_keys(&self) -> Result<u64> {
-        Ok(self.data.len() as u64)
+        Ok(self.len() as u64)
     }
     
     /// Force synchronization of pending changes to persistent storage.
@@ -585,6 +273,33 @@ impl KVEngineStoreTrait for KvEngine {
         // In a persistent storage engine, this would flush data to disk
         Ok(())
     }
+
+    /// Retrieve the CRDT value stored under a key.
+    fn get_crdt(&self, key: &str) -> Option<CrdtValue> {
+        self.crdts.lock().unwrap().get(key).cloned()
+    }
+
+    /// Merge a CRDT delta into the value stored under a key.
+    ///
+    /// The merge runs under the CRDT map's Mutex, so it is atomic.
+    fn merge_crdt(&self, key: &str, delta: &CrdtValue) -> Result<CrdtValue> {
+        let mut crdts = self.crdts.lock().unwrap();
+        match crdts.get_mut(key) {
+            Some(current) => {
+                current.merge(delta)?;
+                Ok(current.clone())
+            }
+            None => {
+                crdts.insert(key.to_string(), delta.clone());
+                Ok(delta.clone())
+            }
+        }
+    }
+
+    /// Get all keys currently holding CRDT values.
+    fn crdt_keys(&self) -> Vec<String> {
+        self.crdts.lock().unwrap().keys().cloned().collect()
+    }
 }
 
 #[cfg(test)]
@@ -736,4 +451,27 @@ mod tests {
         assert_eq!(engine.keys().len(), 1);
         assert_eq!(engine.get("new_key"), Some("new_value".to_string()));
     }
+
+    #[test]
+    fn test_concurrent_writes_through_shared_engine() {
+        let engine: Arc<dyn KVEngineStoreTrait> = Arc::new(KvEngine::new("").unwrap());
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let engine = Arc::clone(&engine);
+                std::thread::spawn(move || {
+                    for i in 0..100 {
+                        engine.increment("counter", None).unwrap();
+                        engine.set(format!("key{}:{}", t, i), "v".to_string()).unwrap();
+                        engine.get("counter");
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(engine.get("counter"), Some("800".to_string()));
+        assert_eq!(engine.len(), 801);
+    }
 }
@@ -7,15 +7,22 @@
 //! ## Implementations
 //!
 //! - `RwLockEngine`: Thread-safe in-memory storage using RwLock<HashMap>
-//! - `KvEngine`: Non-thread-safe in-memory storage using Arc<HashMap>
+//! - `ShardedEngine`: Thread-safe in-memory storage striped over N locked shards
+//! - `KvEngine`: In-memory storage with copy-on-write `Arc<HashMap>` snapshots
 //! - Future: Persistent storage engines (RocksDB, Sled, etc.)
 
 use anyhow::Result;
 
+use super::crdt::CrdtValue;
+use crate::error::{ErrorCode, KvError};
+
 /// Common interface for all key-value storage engines.
 ///
 /// This trait defines the core operations that any storage engine must implement.
-/// All engines should be safe to share across multiple threads (Send + Sync).
+/// All engines must be safe to share across multiple threads (Send + Sync):
+/// the server shares one engine as `Arc<dyn KVEngineStoreTrait>` between all
+/// connections without any outer lock, so each engine synchronizes its own
+/// state and every method must be atomic on its own.
 /// 
 /// The trait includes basic operations (get, set, delete), numeric operations
 /// (increment, decrement), string operations (append, prepend), and bulk operations
@@ -129,4 +136,63 @@ pub trait KVEngineStoreTrait: Send + Sync {
     /// # Returns
     /// * `Result<()>` - Success or error
     fn sync(&self) -> Result<()>;
+
+    /// Retrieve the CRDT value (set or hash) stored under a key.
+    ///
+    /// CRDT values live in a keyspace separate from plain string values.
+    ///
+    /// # Arguments
+    /// * `key` - The key to look up
+    ///
+    /// # Returns
+    /// * `Option<CrdtValue>` - The CRDT state if found, None otherwise
+    fn get_crdt(&self, key: &str) -> Option<CrdtValue>;
+
+    /// Merge a CRDT delta into the value stored under a key.
+    ///
+    /// If the key doesn't exist, it will be created from the delta. The merge
+    /// must be atomic with respect to other writers of the same key.
+    ///
+    /// # Arguments
+    /// * `key` - The key to merge into
+    /// * `delta` - The CRDT state or delta to merge
+    ///
+    /// # Returns
+    /// * `Result<CrdtValue>` - The merged state, or error if the stored value has a different type
+    fn merge_crdt(&self, key: &str, delta: &CrdtValue) -> Result<CrdtValue>;
+
+    /// Get all keys currently holding CRDT values.
+    ///
+    /// # Returns
+    /// * `Vec<String>` - Vector of all CRDT keys in the store
+    fn crdt_keys(&self) -> Vec<String>;
+}
+
+/// Value of `key` after adding `amount` to its `current` value (absent counts
+/// as 0), shared by the engines' `increment`/`decrement`.
+///
+/// # Errors
+/// `ERR_WRONGTYPE` if the current value is not an integer, `ERR_OVERFLOW` if
+/// the result does not fit in an `i64`.
+pub fn add_to_value(key: &str, current: Option<&str>, amount: i64) -> Result<i64> {
+    let current_value = match current {
+        Some(value) => value.parse::<i64>().map_err(|_| {
+            KvError::new(ErrorCode::WrongType, format!("Value for key '{}' is not a valid number", key))
+        })?,
+        None => 0,
+    };
+    current_value
+        .checked_add(amount)
+        .ok_or_else(|| KvError::new(ErrorCode::Overflow, format!("Value for key '{}' would overflow", key)).into())
+}
+
+/// The amount a `decrement` by `amount` (default 1) adds.
+///
+/// # Errors
+/// `ERR_OVERFLOW` for `i64::MIN`, which has no positive counterpart.
+pub fn negated_amount(amount: Option<i64>) -> Result<i64> {
+    amount
+        .unwrap_or(1)
+        .checked_neg()
+        .ok_or_else(|| KvError::new(ErrorCode::Overflow, "Decrement amount is out of range").into())
 }
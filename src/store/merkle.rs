@@ -1,12 +1,18 @@
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
+use super::crdt::CrdtValue;
+
 // === Safe leaf encoding: length-prefix (u32 big-endian) ===
 // Why? Concatenating "key:value" is ambiguous (e.g., "a::b").
 // Length-prefixing eliminates ambiguity and is robust to any bytes (including NUL).
 fn encode_leaf(key: &str, value: &str) -> Vec<u8> {
+    encode_leaf_bytes(key, value.as_bytes())
+}
+
+// Same encoding for raw values (e.g., the canonical CBOR of a CRDT).
+fn encode_leaf_bytes(key: &str, vb: &[u8]) -> Vec<u8> {
     let kb = key.as_bytes();
-    let vb = value.as_bytes();
     let mut out = Vec::with_capacity(8 + kb.len() + vb.len());
     out.extend_from_slice(&(kb.len() as u32).to_be_bytes());
     out.extend_from_slice(kb);
@@ -48,6 +54,15 @@ impl MerkleTree {
         hasher.finalize().to_vec()
     }
 
+    /// Leaf hash of `key` holding `value`, as a fixed-size array; an absent
+    /// key (`None`) hashes to all zeros. Used for `ChangeEvent::prev`.
+    pub fn leaf_hash(key: &str, value: Option<&str>) -> [u8; 32] {
+        match value {
+            Some(value) => Sha256::digest(encode_leaf(key, value)).into(),
+            None => [0; 32],
+        }
+    }
+
     /// Insert or update a (key, value). The leaf hash is recomputed and the tree rebuilt.
     pub fn insert(&mut self, key: &str, value: &str) {
         let hash = Self::compute_leaf_hash(key, value);
@@ -55,6 +70,15 @@ impl MerkleTree {
         self.rebuild();
     }
 
+    /// Insert or update a CRDT value (set or hash) under `key`.
+    /// The leaf covers the canonical serialization of the full CRDT state.
+    pub fn insert_crdt(&mut self, key: &str, value: &CrdtValue) {
+        let mut hasher = Sha256::new();
+        hasher.update(encode_leaf_bytes(key, &value.canonical_bytes()));
+        self.leaf_map.insert(key.to_string(), hasher.finalize().to_vec());
+        self.rebuild();
+    }
+
     /// Remove a key (if it exists) and rebuild the tree.
     pub fn remove(&mut self, key: &str) {
         self.leaf_map.remove(key);
@@ -227,6 +251,14 @@ mod tests {
 
     // ───────────────────────── Basic tests ─────────────────────────
 
+    #[test]
+    fn leaf_hash_matches_tree_leaves() {
+        let mut t = MerkleTree::new();
+        t.insert("k", "v");
+        assert_eq!(MerkleTree::leaf_hash("k", Some("v")).to_vec(), t.leaves()[0].1);
+        assert_eq!(MerkleTree::leaf_hash("k", None), [0; 32]);
+    }
+
     #[test]
    fn test_single_leaf_root_equals_leaf_hash() {
     // 0) 🧪 Empty tree → no root
@@ -1180,5 +1212,22 @@ mod tests {
         let pre = t.preorder_hashes();
         assert_eq!(pre.len(), t.node_count());
     }
-    
+
+    // 23) CRDT leaves hash the canonical state: same state → same root, merge → new root
+    #[test]
+    fn t23_crdt_leaf_covers_canonical_state() {
+        use crate::store::crdt::OrSet;
+
+        let mut s = OrSet::new();
+        s.add("m1", [7; 16]);
+        let mut t1 = MerkleTree::new();
+        t1.insert_crdt("set", &CrdtValue::Set(s.clone()));
+        let mut t2 = MerkleTree::new();
+        t2.insert_crdt("set", &CrdtValue::Set(s.clone()));
+        assert_eq!(t1.get_root_hash(), t2.get_root_hash());
+
+        s.add("m2", [8; 16]);
+        t2.insert_crdt("set", &CrdtValue::Set(s));
+        assert_eq!(t1.diff_keys(&t2), vec!["set".to_string()]);
+    }
 }
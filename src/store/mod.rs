@@ -4,8 +4,10 @@
 //!
 //! - **`kv_trait`**: Common interface for all storage engines
 //! - **`rwlock_engine`**: Thread-safe in-memory storage using RwLock<HashMap>
-//! - **`kv_engine`**: Non-thread-safe in-memory storage using Arc<HashMap>
+//! - **`sharded_engine`**: Thread-safe in-memory storage striped over N locked shards
+//! - **`kv_engine`**: In-memory storage with copy-on-write `Arc<HashMap>` snapshots
 //! - **`merkle`**: Merkle tree implementation for efficient synchronization
+//! - **`crdt`**: Replicated set and map types (OR-Set, LWW-Map)
 //!
 //! ## Design Philosophy
 //!
@@ -21,16 +23,20 @@
 //! - Add support for range queries and iteration
 //! - Optimize Merkle tree for incremental updates
 
+pub mod crdt;
 pub mod kv_engine;
 pub mod kv_trait;
 pub mod merkle;
 pub mod rwlock_engine;
+pub mod sharded_engine;
 pub mod sled_engine;
 pub mod factory;
 
 // Re-export the trait and engines for convenience
+pub use crdt::CrdtValue;
 pub use kv_engine::KvEngine;
 pub use kv_trait::KVEngineStoreTrait;
 pub use rwlock_engine::RwLockEngine;
+pub use sharded_engine::ShardedEngine;
 pub use sled_engine::SledEngine;
 pub use factory::{create_storage_engine, create_storage_engine_simple};
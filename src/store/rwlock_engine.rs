@@ -24,7 +24,8 @@ use anyhow::Result;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
-use super::kv_trait::KVEngineStoreTrait;
+use super::crdt::CrdtValue;
+use super::kv_trait::{add_to_value, negated_amount, KVEngineStoreTrait};
 
 /// Thread-safe in-memory key-value storage engine.
 ///
@@ -40,6 +41,8 @@ pub struct RwLockEngine {
     /// Thread-safe shared reference to the key-value data
     /// Using RwLock allows multiple readers or a single writer
     data: Arc<RwLock<HashMap<String, String>>>,
+    /// CRDT values (sets and hashes), kept apart from plain string values
+    crdts: Arc<RwLock<HashMap<String, CrdtValue>>>,
     // TODO: Add persistent storage implementation
     // In a real implementation, this would use a persistent storage engine like Sled:
     // storage_path: PathBuf,
@@ -65,6 +68,7 @@ impl RwLockEngine {
 
         Ok(Self {
             data: Arc::new(RwLock::new(HashMap::new())),
+            crdts: Arc::new(RwLock::new(HashMap::new())),
         })
     }
 }
@@ -216,19 +220,8 @@ impl KVEngineStoreTrait for RwLockEngine {
         // Default increment amount is 1
         let increment_by = amount.unwrap_or(1);
         
-        // Get the current value or initialize to 0
-        let current_value = match data.get(key) {
-            Some(value) => {
-                // Try to parse the current value as a number
-                value.parse::<i64>().map_err(|_| {
-                    anyhow::anyhow!("Value for key '{}' is not a valid number", key)
-                })?
-            }
-            None => 0, // Key doesn't exist, start from 0
-        };
-        
-        // Calculate the new value
-        let new_value = current_value + increment_by;
+        // Calculate the new value (a missing key starts from 0)
+        let new_value = add_to_value(key, data.get(key).map(String::as_str), increment_by)?;
         
         // Store the new value
         data.insert(key.to_string(), new_value.to_string());
@@ -257,21 +250,10 @@ impl KVEngineStoreTrait for RwLockEngine {
         let mut data = self.data.write().unwrap();
         
         // Default decrement amount is 1
-        let decrement_by = amount.unwrap_or(1);
-        
-        // Get the current value or initialize to 0
-        let current_value = match data.get(key) {
-            Some(value) => {
-                // Try to parse the current value as a number
-                value.parse::<i64>().map_err(|_| {
-                    anyhow::anyhow!("Value for key '{}' is not a valid number", key)
-                })?
-            }
-            None => 0, // Key doesn't exist, start from 0
-        };
+        let decrement_by = negated_amount(amount)?;
         
-        // Calculate the new value
-        let new_value = current_value - decrement_by;
+        // Calculate the new value (a missing key starts from 0)
+        let new_value = add_to_value(key, data.get(key).map(String::as_str), decrement_by)?;
         
         // Store the new value
         data.insert(key.to_string(), new_value.to_string());
@@ -365,6 +347,7 @@ impl KVEngineStoreTrait for RwLockEngine {
         
         // Clear all entries
         data.clear();
+        self.crdts.write().unwrap().clear();
         
         Ok(())
     }
@@ -394,6 +377,40 @@ impl KVEngineStoreTrait for RwLockEngine {
         // In a persistent storage engine, this would flush data to disk
         Ok(())
     }
+
+    /// Retrieve the CRDT value stored under a key.
+    ///
+    /// # Thread Safety
+    /// Multiple threads can call this method concurrently without issues.
+    fn get_crdt(&self, key: &str) -> Option<CrdtValue> {
+        let crdts = self.crdts.read().unwrap();
+        crdts.get(key).cloned()
+    }
+
+    /// Merge a CRDT delta into the value stored under a key.
+    ///
+    /// # Thread Safety
+    /// The read-merge-write runs under a single **exclusive write lock**, so
+    /// concurrent merges into the same key cannot lose updates.
+    fn merge_crdt(&self, key: &str, delta: &CrdtValue) -> Result<CrdtValue> {
+        let mut crdts = self.crdts.write().unwrap();
+        match crdts.get_mut(key) {
+            Some(current) => {
+                current.merge(delta)?;
+                Ok(current.clone())
+            }
+            None => {
+                crdts.insert(key.to_string(), delta.clone());
+                Ok(delta.clone())
+            }
+        }
+    }
+
+    /// Get all keys currently holding CRDT values.
+    fn crdt_keys(&self) -> Vec<String> {
+        let crdts = self.crdts.read().unwrap();
+        crdts.keys().cloned().collect()
+    }
 }
 
 #[cfg(test)]
@@ -551,4 +568,31 @@ mod tests {
         // Final verification
         assert_eq!(engine.len(), 100);
     }
+
+    #[test]
+    fn test_crdt_merge_and_truncate() {
+        use crate::store::crdt::{new_tag, LwwMap, OrSet};
+
+        let engine = RwLockEngine::new("./test_data").unwrap();
+
+        let mut s = OrSet::new();
+        let delta = CrdtValue::Set(s.add("a", new_tag()));
+        engine.merge_crdt("tags", &delta).unwrap();
+        let mut s2 = OrSet::new();
+        let merged = engine
+            .merge_crdt("tags", &CrdtValue::Set(s2.add("b", new_tag())))
+            .unwrap();
+        match merged {
+            CrdtValue::Set(set) => assert_eq!(set.members(), vec!["a", "b"]),
+            other => panic!("unexpected value: {:?}", other),
+        }
+
+        // CRDT keys live apart from string keys, and types cannot be mixed
+        assert_eq!(engine.get("tags"), None);
+        assert_eq!(engine.crdt_keys(), vec!["tags".to_string()]);
+        assert!(engine.merge_crdt("tags", &CrdtValue::Map(LwwMap::new())).is_err());
+
+        engine.truncate().unwrap();
+        assert!(engine.get_crdt("tags").is_none());
+    }
 }
@@ -0,0 +1,293 @@
+//! # Sharded Key-Value Storage Engine
+//!
+//! This module provides a thread-safe in-memory storage engine that stripes
+//! the keyspace over N independently locked shards.
+//! Implements the `KVEngineStoreTrait` interface for consistent API across all engines.
+//!
+//! ## Lock Striping
+//!
+//! `RwLockEngine` guards the whole keyspace with one `RwLock`, so a single
+//! writer blocks every reader. Here each key is hashed to one shard, and
+//! each shard has its own `RwLock<HashMap>`:
+//! - **Single-key operations** lock only the key's shard, so operations on
+//!   keys in different shards never wait for each other
+//! - **Read-modify-write operations** (INC/DEC/APPEND/PREPEND, CRDT merges)
+//!   run under the shard's write lock and stay atomic
+//! - **Whole-store operations** (`keys`, `len`, `truncate`) visit the shards
+//!   one at a time, so they are not a point-in-time snapshot
+//!
+//! More shards reduce contention at a small memory cost; a few times the
+//! number of cores is a good default (`storage.shards`, default 16).
+
+use anyhow::Result;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, RwLock};
+
+use super::crdt::CrdtValue;
+use super::kv_trait::{add_to_value, negated_amount, KVEngineStoreTrait};
+
+/// One stripe of the keyspace.
+#[derive(Default)]
+struct Shard {
+    data: RwLock<HashMap<String, String>>,
+    /// CRDT values (sets and hashes), kept apart from plain string values
+    crdts: RwLock<HashMap<String, CrdtValue>>,
+}
+
+/// Thread-safe in-memory key-value storage engine with lock striping.
+///
+/// **Note**: This implementation is not persistent! All data is lost when
+/// the process terminates.
+#[derive(Clone)]
+pub struct ShardedEngine {
+    shards: Arc<[Shard]>,
+}
+
+impl ShardedEngine {
+    /// Create a new storage engine instance.
+    ///
+    /// # Arguments
+    /// * `_storage_path` - Path where data should be stored (currently unused)
+    /// * `shards` - Number of shards (at least 1)
+    ///
+    /// # Returns
+    /// * `Result<ShardedEngine>` - New storage engine instance or error
+    pub fn new(_storage_path: &str, shards: usize) -> Result<Self> {
+        Ok(Self { shards: (0..shards.max(1)).map(|_| Shard::default()).collect() })
+    }
+
+    /// The shard holding `key`.
+    fn shard(&self, key: &str) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+
+    /// Read-modify-write of `key`'s value under its shard's write lock.
+    fn update<R>(&self, key: &str, f: impl FnOnce(&mut HashMap<String, String>) -> R) -> R {
+        let mut data = self.shard(key).data.write().unwrap();
+        f(&mut data)
+    }
+}
+
+impl KVEngineStoreTrait for ShardedEngine {
+    /// Retrieve a value by its key, locking only the key's shard for reading.
+    fn get(&self, key: &str) -> Option<String> {
+        self.shard(key).data.read().unwrap().get(key).cloned()
+    }
+
+    /// Store a key-value pair, locking only the key's shard for writing.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).data.write().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    /// Delete a key-value pair.
+    fn delete(&self, key: &str) -> bool {
+        self.update(key, |data| data.remove(key).is_some())
+    }
+
+    /// Get all keys, visiting the shards one at a time.
+    fn keys(&self) -> Vec<String> {
+        self.shards.iter().flat_map(|s| s.data.read().unwrap().keys().cloned().collect::<Vec<_>>()).collect()
+    }
+
+    /// Get the number of key-value pairs in the store.
+    fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.data.read().unwrap().len()).sum()
+    }
+
+    /// Check if the store is empty.
+    fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.data.read().unwrap().is_empty())
+    }
+
+    /// Increment a numeric value atomically within the key's shard.
+    fn increment(&self, key: &str, amount: Option<i64>) -> Result<i64> {
+        let increment_by = amount.unwrap_or(1);
+        self.update(key, |data| {
+            // A missing key starts from 0
+            let new_value = add_to_value(key, data.get(key).map(String::as_str), increment_by)?;
+            data.insert(key.to_string(), new_value.to_string());
+            Ok(new_value)
+        })
+    }
+
+    /// Decrement a numeric value atomically within the key's shard.
+    fn decrement(&self, key: &str, amount: Option<i64>) -> Result<i64> {
+        // Decrement is just a negative increment
+        self.increment(key, Some(negated_amount(amount)?))
+    }
+
+    /// Append a value to an existing string (creating the key if missing).
+    fn append(&self, key: &str, value: &str) -> Result<String> {
+        Ok(self.update(key, |data| {
+            let entry = data.entry(key.to_string()).or_default();
+            entry.push_str(value);
+            entry.clone()
+        }))
+    }
+
+    /// Prepend a value to an existing string (creating the key if missing).
+    fn prepend(&self, key: &str, value: &str) -> Result<String> {
+        Ok(self.update(key, |data| {
+            let entry = data.entry(key.to_string()).or_default();
+            entry.insert_str(0, value);
+            entry.clone()
+        }))
+    }
+
+    /// Clear all keys/values in the store, one shard at a time.
+    fn truncate(&self) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard.data.write().unwrap().clear();
+            shard.crdts.write().unwrap().clear();
+        }
+        Ok(())
+    }
+
+    /// Get the number of key-value pairs in the store.
+    fn count_keys(&self) -> Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    /// This is an in-memory engine, so there's nothing to sync.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Retrieve the CRDT value stored under a key.
+    fn get_crdt(&self, key: &str) -> Option<CrdtValue> {
+        self.shard(key).crdts.read().unwrap().get(key).cloned()
+    }
+
+    /// Merge a CRDT delta into the value stored under a key, under the
+    /// shard's CRDT write lock.
+    fn merge_crdt(&self, key: &str, delta: &CrdtValue) -> Result<CrdtValue> {
+        let mut crdts = self.shard(key).crdts.write().unwrap();
+        match crdts.get_mut(key) {
+            Some(current) => {
+                current.merge(delta)?;
+                Ok(current.clone())
+            }
+            None => {
+                crdts.insert(key.to_string(), delta.clone());
+                Ok(delta.clone())
+            }
+        }
+    }
+
+    /// Get all keys currently holding CRDT values.
+    fn crdt_keys(&self) -> Vec<String> {
+        self.shards.iter().flat_map(|s| s.crdts.read().unwrap().keys().cloned().collect::<Vec<_>>()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::RwLockEngine;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_kv_operations_across_shards() {
+        let engine = ShardedEngine::new("", 8).unwrap();
+        assert_eq!(engine.shards.len(), 8);
+        assert_eq!(ShardedEngine::new("", 0).unwrap().shards.len(), 1);
+
+        for i in 0..100 {
+            engine.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        assert_eq!(engine.len(), 100);
+        assert_eq!(engine.keys().len(), 100);
+        assert_eq!(engine.get("key42"), Some("value42".to_string()));
+        assert!(engine.delete("key42"));
+        assert!(!engine.delete("key42"));
+
+        assert_eq!(engine.increment("n", Some(5)).unwrap(), 5);
+        assert_eq!(engine.decrement("n", None).unwrap(), 4);
+        assert!(engine.increment("key1", None).is_err());
+        assert_eq!(engine.append("s", "b").unwrap(), "b");
+        assert_eq!(engine.prepend("s", "a").unwrap(), "ab");
+
+        engine.truncate().unwrap();
+        assert!(engine.is_empty());
+        assert_eq!(engine.count_keys().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_increments_are_not_lost() {
+        let engine = Arc::new(ShardedEngine::new("", 4).unwrap());
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let engine = Arc::clone(&engine);
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        engine.increment("counter", None).unwrap();
+                        engine.increment(&format!("c{}", i % 10), None).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(engine.get("counter"), Some("800".to_string()));
+        assert_eq!(engine.get("c3"), Some("80".to_string()));
+    }
+
+    /// Operations per second for `threads` threads running a 90% read /
+    /// 10% write mix over 10,000 keys for `duration`.
+    fn throughput(engine: Arc<dyn KVEngineStoreTrait>, threads: usize, duration: Duration) -> f64 {
+        for i in 0..10_000 {
+            engine.set(format!("key{}", i), "value".to_string()).unwrap();
+        }
+        let start = Instant::now();
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let engine = Arc::clone(&engine);
+                thread::spawn(move || {
+                    let mut ops = 0u64;
+                    let mut i = t * 7919;
+                    while start.elapsed() < duration {
+                        for _ in 0..100 {
+                            i = (i + 7919) % 10_000;
+                            let key = format!("key{}", i);
+                            if i % 10 == 0 {
+                                engine.set(key, "updated".to_string()).unwrap();
+                            } else {
+                                engine.get(&key);
+                            }
+                        }
+                        ops += 100;
+                    }
+                    ops
+                })
+            })
+            .collect();
+        let ops: u64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        ops as f64 / start.elapsed().as_secs_f64()
+    }
+
+    /// Concurrency benchmark against `RwLockEngine`. Run with
+    /// `cargo test --release sharded_vs_rwlock_benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn sharded_vs_rwlock_benchmark() {
+        let duration = Duration::from_secs(2);
+        for threads in [1, 2, 4, 8, 16] {
+            let rwlock = throughput(Arc::new(RwLockEngine::new("").unwrap()), threads, duration);
+            let sharded = throughput(Arc::new(ShardedEngine::new("", 16).unwrap()), threads, duration);
+            println!(
+                "threads={:>2}  rwlock={:>12.0} ops/s  sharded={:>12.0} ops/s  speedup={:.2}x",
+                threads,
+                rwlock,
+                sharded,
+                sharded / rwlock
+            );
+        }
+    }
+}
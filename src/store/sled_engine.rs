@@ -16,7 +16,9 @@
 //!
 //! The SledEngine combines Sled's persistent storage with an in-memory LRU cache:
 //! - **Sled Database**: Handles all persistent storage operations
-//! - **LRU Cache**: Improves performance for hot keys
+//! - **LRU Cache**: Improves performance for hot keys. Writes go to the tree
+//!   first and then evict the key; a read fills the cache only if no write
+//!   happened since it read the tree, so a stale value is never cached.
 //! - **Tree Structure**: Organized storage using Sled's tree abstraction
 //! - **Error Handling**: Comprehensive error handling and recovery
 
@@ -25,9 +27,11 @@ use lru::LruCache;
 use sled::{Db, Tree};
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
-use super::kv_trait::KVEngineStoreTrait;
+use super::crdt::CrdtValue;
+use super::kv_trait::{add_to_value, negated_amount, KVEngineStoreTrait};
 
 /// Configuration options for the Sled storage engine.
 #[derive(Debug, Clone)]
@@ -75,8 +79,12 @@ pub struct SledEngine {
     db: Arc<Db>,
     /// Tree for key-value storage
     tree: Arc<Tree>,
+    /// Tree for CRDT values (sets and hashes), stored as canonical CBOR
+    crdt_tree: Arc<Tree>,
     /// In-memory LRU cache for frequently accessed data
     cache: Arc<Mutex<LruCache<String, String>>>,
+    /// Bumped (under the cache lock) by every write after it reaches the tree
+    write_generation: Arc<AtomicU64>,
     /// Configuration options
     config: SledConfig,
 }
@@ -115,6 +123,9 @@ impl SledEngine {
         let tree = db
             .open_tree(b"merkle_kv")
             .map_err(|e| anyhow!("Failed to open Sled tree: {}", e))?;
+        let crdt_tree = db
+            .open_tree(b"merkle_kv_crdt")
+            .map_err(|e| anyhow!("Failed to open Sled CRDT tree: {}", e))?;
 
         // Create LRU cache with the specified size
         let cache_size = NonZeroUsize::new(config.cache_size)
@@ -124,7 +135,9 @@ impl SledEngine {
         Ok(Self {
             db: Arc::new(db),
             tree: Arc::new(tree),
+            crdt_tree: Arc::new(crdt_tree),
             cache,
+            write_generation: Arc::new(AtomicU64::new(0)),
             config,
         })
     }
@@ -141,6 +154,7 @@ impl SledEngine {
         }
 
         // If not in cache, get from database
+        let generation = self.write_generation.load(Ordering::Acquire);
         let value = self
             .tree
             .get(key.as_bytes())
@@ -150,9 +164,11 @@ impl SledEngine {
             let value_str = String::from_utf8(value_bytes.to_vec())
                 .map_err(|e| anyhow!("Invalid UTF-8 in value for key '{}': {}", key, e))?;
 
-            // Add to cache
+            // Add to cache, unless a write may have replaced the value since
             if let Ok(mut cache) = self.cache.lock() {
-                cache.put(key.to_string(), value_str.clone());
+                if self.write_generation.load(Ordering::Acquire) == generation {
+                    cache.put(key.to_string(), value_str.clone());
+                }
             }
 
             Ok(Some(value_str))
@@ -161,37 +177,79 @@ impl SledEngine {
         }
     }
 
-    /// Set a value in both the cache and database.
-    fn set_internal(&self, key: String, value: String) -> Result<()> {
-        // Update cache
-        if let Ok(mut cache) = self.cache.lock() {
-            cache.put(key.clone(), value.clone());
+    /// Evict `key` (every key if `None`) after a write reached the tree, and
+    /// stop reads started before the write from caching what they read.
+    fn invalidate(&self, key: Option<&str>) {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        match key {
+            Some(key) => {
+                cache.pop(key);
+            }
+            None => cache.clear(),
         }
+        self.write_generation.fetch_add(1, Ordering::AcqRel);
+    }
 
+    /// Set a value in the database and evict it from the cache.
+    fn set_internal(&self, key: String, value: String) -> Result<()> {
         // Store in database
         self.tree
             .insert(key.as_bytes(), value.as_bytes())
             .map_err(|e| anyhow!("Failed to set key '{}' in database: {}", key, e))?;
 
+        self.invalidate(Some(&key));
         Ok(())
     }
 
-    /// Delete a key from both the cache and database.
+    /// Delete a key from both the database and cache.
     fn delete_internal(&self, key: &str) -> Result<bool> {
-        // Remove from cache
-        if let Ok(mut cache) = self.cache.lock() {
-            cache.pop(key);
-        }
-
         // Remove from database
         let result = self
             .tree
             .remove(key.as_bytes())
             .map_err(|e| anyhow!("Failed to delete key '{}' from database: {}", key, e))?;
 
+        self.invalidate(Some(key));
         Ok(result.is_some())
     }
 
+    /// Atomically replace a key's value with `f(current value)`.
+    ///
+    /// Runs on sled's `update_and_fetch`, which retries `f` on contention, so
+    /// concurrent updates of the same key are never lost; `f` may run more
+    /// than once and must stay pure. If `f` fails, the value is left as is.
+    ///
+    /// # Returns
+    /// * `Result<String>` - The new value
+    fn update_internal(&self, key: &str, f: impl Fn(Option<&str>) -> Result<String>) -> Result<String> {
+        let mut update = None;
+        self.tree
+            .update_and_fetch(key.as_bytes(), |old| {
+                let current = match old.map(std::str::from_utf8).transpose() {
+                    Ok(current) => current,
+                    Err(e) => {
+                        update = Some(Err(anyhow!("Invalid UTF-8 in value for key '{}': {}", key, e)));
+                        return old.map(|b| b.to_vec());
+                    }
+                };
+                match f(current) {
+                    Ok(new_value) => {
+                        let bytes = new_value.as_bytes().to_vec();
+                        update = Some(Ok(new_value));
+                        Some(bytes)
+                    }
+                    Err(e) => {
+                        update = Some(Err(e));
+                        old.map(|b| b.to_vec())
+                    }
+                }
+            })
+            .map_err(|e| anyhow!("Failed to update key '{}' in database: {}", key, e))?;
+
+        self.invalidate(Some(key));
+        update.unwrap_or_else(|| Err(anyhow!("Update of key '{}' did not run", key)))
+    }
+
     /// Get all keys from the database.
     fn keys_internal(&self) -> Result<Vec<String>> {
         let mut keys = Vec::new();
@@ -305,65 +363,30 @@ impl KVEngineStoreTrait for SledEngine {
 
     fn increment(&self, key: &str, amount: Option<i64>) -> Result<i64> {
         let increment_by = amount.unwrap_or(1);
-        
-        // Get current value
-        let current_value = match self.get(key) {
-            Some(value) => value.parse::<i64>().unwrap_or(0),
-            None => 0,
-        };
-        
-        let new_value = current_value + increment_by;
-        
-        // Store new value
-        self.set(key.to_string(), new_value.to_string())?;
-        
-        Ok(new_value)
+        let new_value = self.update_internal(key, |current| Ok(add_to_value(key, current, increment_by)?.to_string()))?;
+        Ok(new_value.parse()?)
     }
 
     fn decrement(&self, key: &str, amount: Option<i64>) -> Result<i64> {
-        let decrement_by = amount.unwrap_or(1);
-        
-        // Get current value
-        let current_value = match self.get(key) {
-            Some(value) => value.parse::<i64>().unwrap_or(0),
-            None => 0,
-        };
-        
-        let new_value = current_value - decrement_by;
-        
-        // Store new value
-        self.set(key.to_string(), new_value.to_string())?;
-        
-        Ok(new_value)
+        // Decrement is just a negative increment
+        self.increment(key, Some(negated_amount(amount)?))
     }
 
     fn append(&self, key: &str, value: &str) -> Result<String> {
-        let current_value = self.get(key).unwrap_or_default();
-        let new_value = format!("{}{}", current_value, value);
-        
-        self.set(key.to_string(), new_value.clone())?;
-        
-        Ok(new_value)
+        self.update_internal(key, |current| Ok(format!("{}{}", current.unwrap_or_default(), value)))
     }
 
     fn prepend(&self, key: &str, value: &str) -> Result<String> {
-        let current_value = self.get(key).unwrap_or_default();
-        let new_value = format!("{}{}", value, current_value);
-        
-        self.set(key.to_string(), new_value.clone())?;
-        
-        Ok(new_value)
+        self.update_internal(key, |current| Ok(format!("{}{}", value, current.unwrap_or_default())))
     }
 
     fn truncate(&self) -> Result<()> {
-        // Clear cache
-        if let Ok(mut cache) = self.cache.lock() {
-            cache.clear();
-        }
-        
         // Clear database
         self.tree.clear().map_err(|e| anyhow!("Failed to clear database: {}", e))?;
-        
+        self.crdt_tree.clear().map_err(|e| anyhow!("Failed to clear database: {}", e))?;
+
+        // Clear cache
+        self.invalidate(None);
         Ok(())
     }
 
@@ -374,6 +397,63 @@ impl KVEngineStoreTrait for SledEngine {
     fn sync(&self) -> Result<()> {
         self.flush()
     }
+
+    fn get_crdt(&self, key: &str) -> Option<CrdtValue> {
+        let bytes = match self.crdt_tree.get(key.as_bytes()) {
+            Ok(bytes) => bytes?,
+            Err(e) => {
+                log::error!("Failed to get CRDT key '{}': {}", key, e);
+                return None;
+            }
+        };
+        match CrdtValue::from_bytes(&bytes) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::error!("Corrupt CRDT value for key '{}': {}", key, e);
+                None
+            }
+        }
+    }
+
+    fn merge_crdt(&self, key: &str, delta: &CrdtValue) -> Result<CrdtValue> {
+        // `update_and_fetch` retries the closure on contention, so the merge is
+        // atomic; the closure may run more than once and must stay pure.
+        let mut merge_error = None;
+        let merged = self
+            .crdt_tree
+            .update_and_fetch(key.as_bytes(), |old| {
+                merge_error = None;
+                let mut value = match old.map(CrdtValue::from_bytes) {
+                    Some(Ok(value)) => value,
+                    Some(Err(e)) => {
+                        merge_error = Some(e);
+                        return old.map(|b| b.to_vec());
+                    }
+                    None => return Some(delta.canonical_bytes()),
+                };
+                if let Err(e) = value.merge(delta) {
+                    merge_error = Some(e);
+                    return old.map(|b| b.to_vec());
+                }
+                Some(value.canonical_bytes())
+            })
+            .map_err(|e| anyhow!("Failed to merge CRDT key '{}': {}", key, e))?;
+
+        if let Some(e) = merge_error {
+            return Err(e);
+        }
+        let bytes = merged.ok_or_else(|| anyhow!("CRDT key '{}' vanished during merge", key))?;
+        CrdtValue::from_bytes(&bytes)
+    }
+
+    fn crdt_keys(&self) -> Vec<String> {
+        self.crdt_tree
+            .iter()
+            .keys()
+            .filter_map(|k| k.ok())
+            .filter_map(|k| String::from_utf8(k.to_vec()).ok())
+            .collect()
+    }
 }
 
 impl Drop for SledEngine {
@@ -466,6 +546,37 @@ mod tests {
         assert_eq!(result, 3);
     }
 
+    #[test]
+    fn test_sled_read_modify_write_is_atomic() {
+        let temp_dir = tempdir().unwrap();
+        let storage_path = temp_dir.path().join("test.db");
+
+        let engine = Arc::new(SledEngine::new(storage_path.to_str().unwrap()).unwrap());
+
+        // Concurrent increments and appends are never lost
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let engine = Arc::clone(&engine);
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        engine.increment("counter", None).unwrap();
+                        engine.append("log", "x").unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(engine.get("counter"), Some("200".to_string()));
+        assert_eq!(engine.get("log").map(|v| v.len()), Some(200));
+
+        // A non-numeric value is an error and is left untouched
+        engine.set("text".to_string(), "hello".to_string()).unwrap();
+        assert!(engine.increment("text", None).is_err());
+        assert_eq!(engine.get("text"), Some("hello".to_string()));
+    }
+
     #[test]
     fn test_sled_string_operations() {
         let temp_dir = tempdir().unwrap();
@@ -501,6 +612,54 @@ mod tests {
         assert!(engine.is_empty());
     }
 
+    #[test]
+    fn test_sled_crdt_persistence() {
+        use crate::store::crdt::{LwwMap, OrSet};
+
+        let temp_dir = tempdir().unwrap();
+        let storage_path = temp_dir.path().join("test.db");
+
+        {
+            let engine = SledEngine::new(storage_path.to_str().unwrap()).unwrap();
+            let mut m = LwwMap::new();
+            engine.merge_crdt("h", &CrdtValue::Map(m.set("f", "1", 10, "a"))).unwrap();
+            let mut m2 = LwwMap::new();
+            engine.merge_crdt("h", &CrdtValue::Map(m2.set("f", "0", 5, "b"))).unwrap();
+            assert!(engine.merge_crdt("h", &CrdtValue::Set(OrSet::new())).is_err());
+        }
+
+        let engine = SledEngine::new(storage_path.to_str().unwrap()).unwrap();
+        match engine.get_crdt("h") {
+            Some(CrdtValue::Map(m)) => assert_eq!(m.get("f"), Some("1")),
+            other => panic!("unexpected value: {:?}", other),
+        }
+        assert_eq!(engine.crdt_keys(), vec!["h".to_string()]);
+        engine.truncate().unwrap();
+        assert!(engine.get_crdt("h").is_none());
+    }
+
+    #[test]
+    fn test_sled_cache_never_holds_replaced_values() {
+        let temp_dir = tempdir().unwrap();
+        let storage_path = temp_dir.path().join("test.db");
+
+        let engine = SledEngine::new(storage_path.to_str().unwrap()).unwrap();
+        engine.set("key1".to_string(), "old".to_string()).unwrap();
+        assert_eq!(engine.get("key1"), Some("old".to_string()));
+        assert!(engine.cache.lock().unwrap().contains("key1"));
+
+        // Writes evict instead of caching
+        engine.set("key1".to_string(), "new".to_string()).unwrap();
+        assert!(!engine.cache.lock().unwrap().contains("key1"));
+        assert_eq!(engine.get("key1"), Some("new".to_string()));
+
+        // A read that started before a write does not cache what it read
+        let generation = engine.write_generation.load(Ordering::Acquire);
+        engine.append("key1", "er").unwrap();
+        assert_ne!(engine.write_generation.load(Ordering::Acquire), generation);
+        assert_eq!(engine.get("key1"), Some("newer".to_string()));
+    }
+
     #[test]
     fn test_sled_config() {
         let temp_dir = tempdir().unwrap();
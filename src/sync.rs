@@ -215,6 +215,13 @@ impl SyncManager {
                 self.merkle_tree.insert(&key, &value);
             }
         }
+
+        // CRDT values (sets and hashes) are hashed by their canonical encoding
+        for key in self.store.crdt_keys() {
+            if let Some(value) = self.store.get_crdt(&key).filter(|value| !value.is_empty()) {
+                self.merkle_tree.insert_crdt(&key, &value);
+            }
+        }
     }
 }
 
@@ -0,0 +1,822 @@
+//! # Replication Transports
+//!
+//! This module decouples *how* replication payloads travel between nodes from
+//! *what* they contain. The `Replicator` encodes `ChangeEvent`s and hands the
+//! bytes to a `ReplicationTransport`; incoming bytes come back through a
+//! broadcast channel and are decoded by the `Replicator`.
+//!
+//! ## Implementations
+//!
+//! - **`MqttTransport`**: publishes through an MQTT broker (the default).
+//! - **`TcpTransport`**: pushes frames directly to a static list of peers over
+//!   TCP and accepts frames from peers on a listen address. No broker needed.
+//! - **`LoopbackTransport`**: an in-process hub, used by tests to run several
+//!   replicators in one process without any network I/O.
+//!
+//! ## Channels
+//!
+//! Every message is published on a *channel*, a topic name relative to the
+//! cluster (e.g. `events`). The MQTT transport maps channels to
+//! `{topic_prefix}/{channel}`; the other transports carry the channel name
+//! inside the message.
+//!
+//! ## Delivery Semantics
+//!
+//! Transports are at-least-once at best and may echo a node's own messages
+//! back to it (MQTT does). Receivers must therefore deduplicate by `op_id` and
+//! drop events whose `src` is the local node, as `Replicator` already does.
+//!
+//! ## Pipelining
+//!
+//! `send` returns as soon as a payload is handed off, with a `Delivery` that
+//! completes once the payload is confirmed. The MQTT transport confirms on
+//! PUBACK and allows up to `mqtt_inflight` unconfirmed sends; the other
+//! transports confirm before `send` returns.
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::{error, warn};
+use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, Outgoing, QoS, Transport};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, oneshot, Mutex};
+
+use crate::config::ReplicationConfig;
+
+/// Capacity of the broadcast channel carrying received messages.
+const RECEIVE_BUFFER: usize = 1024;
+
+/// How long an MQTT publish waits for the broker's PUBACK.
+const PUBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the TCP transport waits to connect to a peer.
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the TCP transport waits for a peer to take one frame.
+const PEER_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the TCP transport first skips a peer after a failed push; doubles
+/// on each further failure up to `MAX_PEER_RETRY_DELAY`.
+const PEER_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest time the TCP transport skips an unreachable peer.
+const MAX_PEER_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Largest frame accepted by the TCP transport (guards against garbage input).
+const MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+
+/// A message received from (or sent to) peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportMessage {
+    /// Channel name relative to the cluster (e.g. "events")
+    pub channel: String,
+    /// Opaque payload (an encoded `ChangeEvent` for the "events" channel)
+    pub payload: Vec<u8>,
+}
+
+/// Completes once a sent payload is confirmed (e.g. by the broker's PUBACK).
+pub type Delivery = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// A way of moving replication payloads between nodes.
+///
+/// Implementations must be cheap to share (`Arc<dyn ReplicationTransport>`)
+/// and safe to call from many tasks at once.
+#[async_trait]
+pub trait ReplicationTransport: Send + Sync {
+    /// Publish `payload` on `channel` to all peers.
+    ///
+    /// # Returns
+    /// * `Result<()>` - Error if the payload could not be handed to the broker
+    ///   (or to any peer); callers may retry, receivers deduplicate.
+    async fn publish(&self, channel: &str, payload: Vec<u8>) -> Result<()>;
+
+    /// Hand `payload` off for `channel` without waiting for confirmation.
+    ///
+    /// # Returns
+    /// * `Result<Delivery>` - Completes when the payload is confirmed; the
+    ///   default publishes first, so the delivery is already complete
+    async fn send(&self, channel: &str, payload: Vec<u8>) -> Result<Delivery> {
+        self.publish(channel, payload).await?;
+        Ok(Box::pin(async { Ok(()) }))
+    }
+
+    /// Number of sends that may await confirmation at once.
+    fn max_in_flight(&self) -> usize {
+        1
+    }
+
+    /// Subscribe to messages received from peers.
+    ///
+    /// Only messages received after this call are delivered to the receiver.
+    fn subscribe(&self) -> broadcast::Receiver<TransportMessage>;
+
+    /// Short transport name for logs and diagnostics.
+    fn name(&self) -> &'static str;
+
+    /// Disconnect from peers once buffered messages are sent, waiting at most
+    /// `timeout` (used during shutdown).
+    async fn close(&self, _timeout: Duration) {}
+}
+
+// ───────────────────────────── MQTT ─────────────────────────────
+
+/// Transport that publishes through an MQTT broker with QoS 1. A publish
+/// completes when the broker's PUBACK arrives.
+///
+/// # MQTT Topics
+/// - Publishes to: `{topic_prefix}/{channel}`
+/// - Subscribes to: `{topic_prefix}/#`, or `{topic_prefix}/events/{ns}` per
+///   configured namespace
+pub struct MqttTransport {
+    /// MQTT client for publishing and receiving messages
+    client: AsyncClient,
+
+    /// Prefix for MQTT topics (e.g., "merkle_kv")
+    topic_prefix: String,
+
+    /// Messages received from the broker, with the topic prefix stripped
+    tx: broadcast::Sender<TransportMessage>,
+
+    /// Whether the event loop currently holds a broker connection
+    connected: Arc<AtomicBool>,
+
+    /// Publishes awaiting their PUBACK
+    acks: Arc<std::sync::Mutex<PubAcks>>,
+
+    /// Keeps `acks` in the order publishes enter the client's request queue
+    send_order: Mutex<()>,
+
+    /// Publishes the client may have awaiting PUBACK (`mqtt_inflight`)
+    inflight: usize,
+
+    /// Task polling the event loop; finishes once the disconnect is sent
+    eventloop: Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+/// Matches PUBACKs to the publishes waiting for them.
+///
+/// The event loop writes publishes in request order and reports each with
+/// `Outgoing::Publish(pkid)`, so the first report of a packet id belongs to
+/// the oldest queued waiter. Later reports of the same id are retransmissions
+/// after a reconnect and keep their waiter.
+#[derive(Default)]
+struct PubAcks {
+    /// Waiters of publishes not yet written, in request order
+    queued: VecDeque<oneshot::Sender<()>>,
+    /// Waiters of written publishes by packet id
+    inflight: HashMap<u16, oneshot::Sender<()>>,
+}
+
+impl PubAcks {
+    /// A publish was written to the broker.
+    fn sent(&mut self, pkid: u16) {
+        if !self.inflight.contains_key(&pkid) {
+            if let Some(waiter) = self.queued.pop_front() {
+                self.inflight.insert(pkid, waiter);
+            }
+        }
+    }
+
+    /// The broker acknowledged a publish.
+    fn acked(&mut self, pkid: u16) {
+        if let Some(waiter) = self.inflight.remove(&pkid) {
+            let _ = waiter.send(()); // the publisher may have timed out
+        }
+    }
+}
+
+impl MqttTransport {
+    /// Connect to the MQTT broker described by `config` and start polling.
+    ///
+    /// # Arguments
+    /// * `config` - Replication configuration containing MQTT broker details
+    ///
+    /// # Returns
+    /// * `Result<MqttTransport>` - Connected transport or subscription error
+    pub async fn connect(config: &ReplicationConfig) -> Result<Self> {
+        let mqtt_options = Self::options(config)?;
+
+        // Create MQTT client and event loop
+        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
+
+        // Channels this node listens on; subscribed on each ConnAck
+        let topics: Vec<String> = config
+            .subscribe_channels()
+            .iter()
+            .map(|channel| format!("{}/{}", config.topic_prefix, channel))
+            .collect();
+
+        let (tx, _rx_unused) = broadcast::channel::<TransportMessage>(RECEIVE_BUFFER);
+        let tx_clone = tx.clone();
+        let prefix = format!("{}/", config.topic_prefix);
+        let connected = Arc::new(AtomicBool::new(false));
+        let connected_clone = connected.clone();
+        let resubscribe = client.clone();
+        let acks = Arc::new(std::sync::Mutex::new(PubAcks::default()));
+        let acks_clone = acks.clone();
+        let eventloop = tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                        // Subscriptions do not survive a clean-session reconnect
+                        connected_clone.store(true, Ordering::SeqCst);
+                        for topic in &topics {
+                            if let Err(e) = resubscribe.try_subscribe(topic, QoS::AtLeastOnce) {
+                                error!("MQTT subscribe to {} failed: {}", topic, e);
+                            }
+                        }
+                    }
+                    Ok(Event::Incoming(Incoming::Publish(p))) => {
+                        let channel = match p.topic.strip_prefix(&prefix) {
+                            Some(channel) => channel.to_string(),
+                            None => continue,
+                        };
+                        let msg = TransportMessage { channel, payload: p.payload.to_vec() };
+                        let _ = tx_clone.send(msg); // ignore errors if no receivers
+                    }
+                    Ok(Event::Outgoing(Outgoing::Publish(pkid))) => {
+                        acks_clone.lock().unwrap_or_else(|e| e.into_inner()).sent(pkid);
+                    }
+                    Ok(Event::Incoming(Incoming::PubAck(ack))) => {
+                        acks_clone.lock().unwrap_or_else(|e| e.into_inner()).acked(ack.pkid);
+                    }
+                    // Everything queued before the disconnect has been written
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        connected_clone.store(false, Ordering::SeqCst);
+                        error!("MQTT eventloop error: {}", e);
+                        tokio::time::sleep(Duration::from_secs(3)).await;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            client,
+            topic_prefix: config.topic_prefix.clone(),
+            tx,
+            connected,
+            acks,
+            send_order: Mutex::new(()),
+            inflight: config.mqtt_inflight as usize,
+            eventloop: Mutex::new(Some(eventloop)),
+        })
+    }
+}
+
+impl MqttTransport {
+    /// Build client options from the replication config: credentials, TLS
+    /// (optionally mutual), clean session, keep-alive and inflight window.
+    ///
+    /// # Errors
+    /// Returns an error if a certificate/key file cannot be read, if only one
+    /// of client cert and key is given, or if `mqtt_inflight` is zero.
+    fn options(config: &ReplicationConfig) -> Result<MqttOptions> {
+        let mut options = MqttOptions::new(&config.client_id, &config.mqtt_broker, config.mqtt_port);
+        options.set_keep_alive(Duration::from_secs(config.mqtt_keep_alive_secs));
+        options.set_clean_session(config.mqtt_clean_session);
+        if config.mqtt_inflight == 0 {
+            return Err(anyhow!("mqtt_inflight must be at least 1"));
+        }
+        options.set_inflight(config.mqtt_inflight);
+
+        if let Some(username) = &config.mqtt_username {
+            options.set_credentials(username, config.mqtt_password.clone().unwrap_or_default());
+        }
+
+        if config.mqtt_tls {
+            let read = |path: &str| {
+                std::fs::read(path).map_err(|e| anyhow!("cannot read TLS file {}: {}", path, e))
+            };
+            let client_auth = match (&config.mqtt_client_cert_file, &config.mqtt_client_key_file) {
+                (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+                (None, None) => None,
+                _ => return Err(anyhow!("mqtt_client_cert_file and mqtt_client_key_file must be set together")),
+            };
+            let transport = match &config.mqtt_ca_file {
+                Some(ca) => Transport::tls(read(ca)?, client_auth, None),
+                None if client_auth.is_some() => {
+                    return Err(anyhow!("mqtt_ca_file is required for client certificate authentication"))
+                }
+                // No CA given: verify the broker against the platform roots
+                None => Transport::tls_with_default_config(),
+            };
+            options.set_transport(transport);
+        }
+
+        Ok(options)
+    }
+}
+
+#[async_trait]
+impl ReplicationTransport for MqttTransport {
+    async fn publish(&self, channel: &str, payload: Vec<u8>) -> Result<()> {
+        self.send(channel, payload).await?.await
+    }
+
+    async fn send(&self, channel: &str, payload: Vec<u8>) -> Result<Delivery> {
+        // Fail fast while disconnected so the caller keeps the event queued
+        if !self.connected.load(Ordering::SeqCst) {
+            return Err(anyhow!("not connected to MQTT broker"));
+        }
+        let topic = format!("{}/{}", self.topic_prefix, channel);
+        let (ack_tx, ack_rx) = oneshot::channel();
+        {
+            let _order = self.send_order.lock().await;
+            self.acks.lock().unwrap_or_else(|e| e.into_inner()).queued.push_back(ack_tx);
+            if let Err(e) = self.client.publish(&topic, QoS::AtLeastOnce, false, payload).await {
+                self.acks.lock().unwrap_or_else(|e| e.into_inner()).queued.pop_back();
+                return Err(e.into());
+            }
+        }
+        // Only a PUBACK means the broker owns the message; until then the
+        // caller keeps it queued
+        Ok(Box::pin(async move {
+            match tokio::time::timeout(PUBACK_TIMEOUT, ack_rx).await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(_)) => Err(anyhow!("MQTT publish was dropped before its PUBACK")),
+                Err(_) => Err(anyhow!("no PUBACK from the MQTT broker within {:?}", PUBACK_TIMEOUT)),
+            }
+        }))
+    }
+
+    fn max_in_flight(&self) -> usize {
+        self.inflight
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<TransportMessage> {
+        self.tx.subscribe()
+    }
+
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    async fn close(&self, timeout: Duration) {
+        let Some(mut eventloop) = self.eventloop.lock().await.take() else { return };
+        if let Err(e) = self.client.disconnect().await {
+            warn!("MQTT disconnect failed: {}", e);
+        } else if tokio::time::timeout(timeout, &mut eventloop).await.is_ok() {
+            return;
+        } else {
+            warn!("MQTT disconnect not sent within {:?}", timeout);
+        }
+        eventloop.abort();
+    }
+}
+
+// ───────────────────────────── TCP peer push ─────────────────────────────
+
+/// Transport that pushes frames directly to peers over TCP.
+///
+/// ## Frame Format
+/// `[u16 channel_len][channel][u32 payload_len][payload]`, big-endian lengths.
+///
+/// Outbound connections are opened lazily and reused; a failed write drops the
+/// connection so the next publish reconnects. Each peer has its own lock and
+/// connects and writes are bounded by `PEER_CONNECT_TIMEOUT` and
+/// `PEER_WRITE_TIMEOUT`, so an unresponsive peer holds up publishes for at
+/// most that long and never blocks another publish's push to other peers.
+///
+/// A publish succeeds once any peer has taken the frame. A peer whose push
+/// failed is skipped with exponential backoff, so one dead peer neither stalls
+/// replication to the live ones nor fills the outbox; the writes it misses
+/// reach it through catch-up and repair.
+pub struct TcpTransport {
+    /// Outbound connections, one per peer
+    peers: Vec<PeerConnection>,
+
+    /// Address the inbound listener is bound to
+    local_addr: SocketAddr,
+
+    /// Longest wait for a peer to accept a connection
+    connect_timeout: Duration,
+
+    /// Longest wait for a peer to take one frame
+    write_timeout: Duration,
+
+    /// First backoff after a failed push to a peer
+    retry_delay: Duration,
+
+    /// Messages received from peers
+    tx: broadcast::Sender<TransportMessage>,
+}
+
+/// Outbound connection to one peer, opened lazily.
+struct PeerConnection {
+    /// Peer address (e.g. "10.0.0.2:7380")
+    addr: String,
+
+    /// Open connection, `None` until connected or after a failed write
+    stream: Mutex<Option<TcpStream>>,
+
+    /// Backoff after failed pushes, `None` while the peer is reachable
+    backoff: std::sync::Mutex<Option<PeerBackoff>>,
+}
+
+/// When a failing peer is next tried, and for how long it was last skipped.
+#[derive(Debug, Clone, Copy)]
+struct PeerBackoff {
+    retry_at: tokio::time::Instant,
+    delay: Duration,
+}
+
+impl TcpTransport {
+    /// Bind the inbound listener and prepare to push to `peers`.
+    ///
+    /// # Arguments
+    /// * `listen_addr` - Address to accept peer connections on (e.g. "0.0.0.0:7380")
+    /// * `peers` - Addresses of the other nodes' listeners
+    ///
+    /// # Returns
+    /// * `Result<TcpTransport>` - Transport or bind error
+    pub async fn bind(listen_addr: &str, peers: Vec<String>) -> Result<Self> {
+        let listener = TcpListener::bind(listen_addr).await?;
+        let local_addr = listener.local_addr()?;
+
+        let (tx, _rx_unused) = broadcast::channel::<TransportMessage>(RECEIVE_BUFFER);
+        let tx_clone = tx.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, addr)) => {
+                        let tx = tx_clone.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = Self::read_frames(socket, tx).await {
+                                warn!("Replication peer {} disconnected: {}", addr, e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        error!("Error accepting replication peer: {}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            peers: peers
+                .into_iter()
+                .map(|addr| PeerConnection { addr, stream: Mutex::new(None), backoff: std::sync::Mutex::new(None) })
+                .collect(),
+            local_addr,
+            connect_timeout: PEER_CONNECT_TIMEOUT,
+            write_timeout: PEER_WRITE_TIMEOUT,
+            retry_delay: PEER_RETRY_DELAY,
+            tx,
+        })
+    }
+
+    /// Use other connect and write timeouts and peer backoff (tests use
+    /// short ones).
+    #[cfg(test)]
+    fn with_timeouts(mut self, connect_timeout: Duration, write_timeout: Duration, retry_delay: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self.write_timeout = write_timeout;
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Whether `peer` is backing off after a failed push.
+    fn backing_off(&self, peer: &PeerConnection) -> bool {
+        let backoff = peer.backoff.lock().unwrap_or_else(|e| e.into_inner());
+        backoff.is_some_and(|b| tokio::time::Instant::now() < b.retry_at)
+    }
+
+    /// Record the outcome of a push to `peer`, extending its backoff on failure.
+    fn record(&self, peer: &PeerConnection, ok: bool) {
+        let mut backoff = peer.backoff.lock().unwrap_or_else(|e| e.into_inner());
+        *backoff = if ok {
+            None
+        } else {
+            let delay = backoff.map_or(self.retry_delay, |b| (b.delay * 2).min(MAX_PEER_RETRY_DELAY));
+            Some(PeerBackoff { retry_at: tokio::time::Instant::now() + delay, delay })
+        };
+    }
+
+    /// Write one frame to `peer`, connecting first if needed.
+    async fn push(&self, peer: &PeerConnection, frame: &[u8]) -> Result<()> {
+        let mut stream = peer.stream.lock().await;
+        let connection = match &mut *stream {
+            Some(connection) => connection,
+            None => {
+                let connected = tokio::time::timeout(self.connect_timeout, TcpStream::connect(&peer.addr))
+                    .await
+                    .map_err(|_| anyhow!("connect timed out after {:?}", self.connect_timeout))??;
+                stream.insert(connected)
+            }
+        };
+        let result = match tokio::time::timeout(self.write_timeout, connection.write_all(frame)).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(anyhow!("write timed out after {:?}", self.write_timeout)),
+        };
+        if result.is_err() {
+            // Drop the connection (a timed-out write may have sent part of
+            // the frame); the next publish reconnects
+            *stream = None;
+        }
+        result
+    }
+
+    /// Address the inbound listener is bound to (useful when binding to port 0).
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Read frames from one inbound peer connection until it closes.
+    async fn read_frames(mut socket: TcpStream, tx: broadcast::Sender<TransportMessage>) -> Result<()> {
+        loop {
+            let channel_len = match socket.read_u16().await {
+                Ok(n) => n as usize,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let mut channel = vec![0u8; channel_len];
+            socket.read_exact(&mut channel).await?;
+            let payload_len = socket.read_u32().await? as usize;
+            if payload_len > MAX_FRAME_BYTES {
+                return Err(anyhow!("frame of {} bytes exceeds limit", payload_len));
+            }
+            let mut payload = vec![0u8; payload_len];
+            socket.read_exact(&mut payload).await?;
+
+            let channel = String::from_utf8(channel).map_err(|e| anyhow!("invalid channel: {}", e))?;
+            let _ = tx.send(TransportMessage { channel, payload }); // ignore errors if no receivers
+        }
+    }
+
+    /// Encode one frame.
+    fn encode_frame(channel: &str, payload: &[u8]) -> Result<Vec<u8>> {
+        let channel_len = u16::try_from(channel.len()).map_err(|_| anyhow!("channel name too long"))?;
+        let payload_len = u32::try_from(payload.len()).map_err(|_| anyhow!("payload too large"))?;
+        let mut frame = Vec::with_capacity(6 + channel.len() + payload.len());
+        frame.extend_from_slice(&channel_len.to_be_bytes());
+        frame.extend_from_slice(channel.as_bytes());
+        frame.extend_from_slice(&payload_len.to_be_bytes());
+        frame.extend_from_slice(payload);
+        Ok(frame)
+    }
+}
+
+#[async_trait]
+impl ReplicationTransport for TcpTransport {
+    async fn publish(&self, channel: &str, payload: Vec<u8>) -> Result<()> {
+        let frame = Self::encode_frame(channel, &payload)?;
+        let mut delivered = 0;
+        let mut failed = Vec::new();
+
+        for peer in &self.peers {
+            if self.backing_off(peer) {
+                failed.push(format!("{} (backing off)", peer.addr));
+                continue;
+            }
+            let result = self.push(peer, &frame).await;
+            self.record(peer, result.is_ok());
+            match result {
+                Ok(()) => delivered += 1,
+                Err(e) => {
+                    warn!("Replication push to {} failed: {}", peer.addr, e);
+                    failed.push(format!("{} ({})", peer.addr, e));
+                }
+            }
+        }
+
+        // Peers that missed the frame catch up later; only a publish no peer
+        // took stays queued for a retry
+        if delivered == 0 && !failed.is_empty() {
+            return Err(anyhow!("failed to push to peers: {}", failed.join(", ")));
+        }
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<TransportMessage> {
+        self.tx.subscribe()
+    }
+
+    fn name(&self) -> &'static str {
+        "tcp"
+    }
+}
+
+// ───────────────────────────── Loopback ─────────────────────────────
+
+/// In-process message hub shared by `LoopbackTransport` endpoints.
+///
+/// Every endpoint receives every message, including its own, which mirrors
+/// how an MQTT broker echoes publishes back to the publisher. Used by tests
+/// to exercise replication without a broker.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct LoopbackHub {
+    tx: broadcast::Sender<TransportMessage>,
+}
+
+#[cfg(test)]
+impl Default for LoopbackHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+impl LoopbackHub {
+    /// Create an empty hub.
+    pub fn new() -> Self {
+        let (tx, _rx_unused) = broadcast::channel::<TransportMessage>(RECEIVE_BUFFER);
+        Self { tx }
+    }
+
+    /// Create a new endpoint attached to this hub.
+    pub fn connect(&self) -> LoopbackTransport {
+        LoopbackTransport { tx: self.tx.clone() }
+    }
+}
+
+/// Transport endpoint attached to a `LoopbackHub`.
+#[cfg(test)]
+pub struct LoopbackTransport {
+    tx: broadcast::Sender<TransportMessage>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl ReplicationTransport for LoopbackTransport {
+    async fn publish(&self, channel: &str, payload: Vec<u8>) -> Result<()> {
+        let msg = TransportMessage { channel: channel.to_string(), payload };
+        let _ = self.tx.send(msg); // ignore errors if no receivers
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<TransportMessage> {
+        self.tx.subscribe()
+    }
+
+    fn name(&self) -> &'static str {
+        "loopback"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn loopback_delivers_to_every_endpoint() {
+        let hub = LoopbackHub::new();
+        let a = hub.connect();
+        let b = hub.connect();
+        let mut rx_a = a.subscribe();
+        let mut rx_b = b.subscribe();
+
+        a.publish("events", b"hello".to_vec()).await.unwrap();
+
+        let expected = TransportMessage { channel: "events".into(), payload: b"hello".to_vec() };
+        assert_eq!(rx_a.recv().await.unwrap(), expected);
+        assert_eq!(rx_b.recv().await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn tcp_pushes_frames_to_peers() {
+        let receiver = TcpTransport::bind("127.0.0.1:0", vec![]).await.unwrap();
+        let mut rx = receiver.subscribe();
+        let sender = TcpTransport::bind("127.0.0.1:0", vec![receiver.local_addr().to_string()])
+            .await
+            .unwrap();
+
+        sender.publish("events", vec![1, 2, 3]).await.unwrap();
+        sender.publish("events/ns", vec![]).await.unwrap();
+
+        let first = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(first, TransportMessage { channel: "events".into(), payload: vec![1, 2, 3] });
+        let second = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(second, TransportMessage { channel: "events/ns".into(), payload: vec![] });
+    }
+
+    #[tokio::test]
+    async fn tcp_publish_reports_unreachable_peer() {
+        // Bind and immediately drop a listener to get a port nobody listens on
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let sender = TcpTransport::bind("127.0.0.1:0", vec![format!("127.0.0.1:{}", port)])
+            .await
+            .unwrap();
+        assert!(sender.publish("events", vec![0]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tcp_publish_gives_up_on_a_stalled_peer() {
+        // A peer that accepts connections but never reads
+        let stalled = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled_addr = stalled.local_addr().unwrap().to_string();
+        let accepted = tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = stalled.accept().await {
+                sockets.push(socket);
+            }
+        });
+        let sender = TcpTransport::bind("127.0.0.1:0", vec![stalled_addr])
+            .await
+            .unwrap()
+            .with_timeouts(Duration::from_millis(500), Duration::from_millis(200), Duration::from_millis(100));
+
+        // Large enough to fill the socket buffers
+        let started = std::time::Instant::now();
+        let err = sender.publish("events", vec![0; 32 * 1024 * 1024]).await.unwrap_err();
+        assert!(err.to_string().contains("write timed out"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        // The peer is skipped while backing off; afterwards the half-written
+        // connection is replaced by a fresh one
+        assert!(sender.publish("events", vec![1]).await.unwrap_err().to_string().contains("backing off"));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        sender.publish("events", vec![1]).await.unwrap();
+        accepted.abort();
+    }
+
+    #[tokio::test]
+    async fn tcp_publish_reaches_live_peers_past_a_dead_one() {
+        let live = TcpTransport::bind("127.0.0.1:0", vec![]).await.unwrap();
+        let mut rx = live.subscribe();
+        // Nothing listens on the dead peer's port
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let sender = TcpTransport::bind("127.0.0.1:0", vec![dead, live.local_addr().to_string()]).await.unwrap();
+
+        for i in 0..3u8 {
+            sender.publish("events", vec![i]).await.unwrap();
+            let msg = tokio::time::timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+            assert_eq!(msg.payload, vec![i]);
+        }
+        assert!(sender.backing_off(&sender.peers[0]) && !sender.backing_off(&sender.peers[1]));
+    }
+
+    #[test]
+    fn pubacks_resolve_waiters_by_packet_id() {
+        let mut acks = PubAcks::default();
+        let (first_tx, mut first) = oneshot::channel();
+        let (second_tx, mut second) = oneshot::channel();
+        acks.queued.extend([first_tx, second_tx]);
+        acks.sent(1);
+        acks.sent(2);
+        // A retransmission after reconnecting keeps the original waiter
+        acks.sent(1);
+        acks.acked(2);
+        assert_eq!((first.try_recv().is_ok(), second.try_recv().is_ok()), (false, true));
+        acks.acked(1);
+        assert!(first.try_recv().is_ok());
+        assert!(acks.queued.is_empty() && acks.inflight.is_empty());
+    }
+
+    #[test]
+    fn mqtt_options_apply_auth_and_session_settings() {
+        let mut repl = Config::default().replication;
+        repl.mqtt_username = Some("replicator".to_string());
+        repl.mqtt_password = Some("secret".to_string());
+        repl.mqtt_clean_session = false;
+        repl.mqtt_keep_alive_secs = 45;
+        repl.mqtt_inflight = 20;
+        let options = MqttTransport::options(&repl).unwrap();
+        assert_eq!(options.credentials(), Some(("replicator".to_string(), "secret".to_string())));
+        assert!(!options.clean_session());
+        assert_eq!(options.keep_alive(), Duration::from_secs(45));
+        assert_eq!(options.inflight(), 20);
+        assert!(matches!(options.transport(), Transport::Tcp));
+
+        repl.mqtt_inflight = 0;
+        assert!(MqttTransport::options(&repl).is_err());
+    }
+
+    #[test]
+    fn mqtt_options_mutual_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, b"-----BEGIN CERTIFICATE-----\n").unwrap();
+            Some(path.to_str().unwrap().to_string())
+        };
+        let mut repl = Config::default().replication;
+        repl.mqtt_tls = true;
+        repl.mqtt_ca_file = write("ca.pem");
+        repl.mqtt_client_cert_file = write("client.pem");
+        repl.mqtt_client_key_file = write("client.key");
+        let options = MqttTransport::options(&repl).unwrap();
+        match options.transport() {
+            Transport::Tls(rumqttc::TlsConfiguration::Simple { client_auth, .. }) => assert!(client_auth.is_some()),
+            _ => panic!("expected TLS transport with client auth"),
+        }
+
+        // Cert without key, and unreadable files, are configuration errors
+        repl.mqtt_client_key_file = None;
+        assert!(MqttTransport::options(&repl).is_err());
+        repl.mqtt_client_cert_file = None;
+        repl.mqtt_ca_file = Some("/nonexistent/ca.pem".to_string());
+        assert!(MqttTransport::options(&repl).is_err());
+    }
+
+}
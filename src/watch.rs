@@ -0,0 +1,154 @@
+//! # Client Change Streams
+//!
+//! `WATCH <pattern> [TEXT|JSON]` turns a client connection into a stream of
+//! the node's `ChangeEvent`s (see `CommandExecutor::watch`): writes made on
+//! this node, and with replication enabled the events flowing through the
+//! `Replicator` from peers. Replicated events are streamed as they arrive,
+//! before the LWW/idempotency checks, so a consumer may see a stale or
+//! duplicate event; treat each one as an invalidation hint.
+//!
+//! ## Matching
+//!
+//! A pattern containing `*` or `?` is matched as a glob (see `key_filter`);
+//! any other pattern is a key prefix, so `WATCH user:` and `WATCH user:*`
+//! are equivalent.
+//!
+//! ## Output Formats
+//!
+//! Text (default), one line per event; the value is omitted for deletions
+//! and for set/hash deltas, and non UTF-8 values are base64 encoded:
+//! ```text
+//! EVENT <op> <src> <ts> <key> [<value>]
+//! ```
+//!
+//! JSON, one object per line:
+//! ```text
+//! {"key":"user:1","op":"set","op_id":"9f0c...","src":"node1","ts":1700000000000000000,"value":"alice"}
+//! ```
+
+use crate::change_event::ChangeEvent;
+use crate::key_filter::glob_match;
+use base64::Engine;
+
+/// Encoding of streamed change events.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WatchFormat {
+    /// `EVENT ...` text lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// An active watch: which keys to stream and how to encode them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watch {
+    /// Glob pattern or key prefix
+    pattern: String,
+    /// Output encoding
+    format: WatchFormat,
+}
+
+impl Watch {
+    /// Create a watch for keys matching `pattern`.
+    pub fn new(pattern: impl Into<String>, format: WatchFormat) -> Self {
+        Self { pattern: pattern.into(), format }
+    }
+
+    /// Whether events for `key` belong to this watch.
+    pub fn matches(&self, key: &str) -> bool {
+        if self.pattern.contains(['*', '?']) {
+            glob_match(&self.pattern, key)
+        } else {
+            key.starts_with(&self.pattern)
+        }
+    }
+
+    /// Render an event as a protocol line (including the trailing `\r\n`).
+    pub fn render(&self, ev: &ChangeEvent) -> String {
+        render_event(ev, self.format, None)
+    }
+}
+
+/// Render an event as a protocol line (including the trailing `\r\n`).
+///
+/// With a change log sequence number (REPLAY), text lines start with
+/// `ENTRY <seq>` instead of `EVENT` and JSON objects gain a `seq` field.
+pub fn render_event(ev: &ChangeEvent, format: WatchFormat, seq: Option<u64>) -> String {
+    let op = serde_json::to_value(ev.op)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default();
+    // Deletions carry no value and collection deltas are internal encodings
+    let value = ev.val.as_deref().filter(|_| !ev.op.is_crdt()).map(|bytes| {
+        String::from_utf8(bytes.to_vec())
+            .unwrap_or_else(|_| base64::engine::general_purpose::STANDARD.encode(bytes))
+    });
+    match format {
+        WatchFormat::Text => {
+            let prefix = match seq {
+                Some(seq) => format!("ENTRY {}", seq),
+                None => "EVENT".to_string(),
+            };
+            match value {
+                Some(value) => format!("{} {} {} {} {} {}\r\n", prefix, op, ev.src, ev.ts, ev.key, value),
+                None => format!("{} {} {} {} {}\r\n", prefix, op, ev.src, ev.ts, ev.key),
+            }
+        }
+        WatchFormat::Json => {
+            let op_id: String = ev.op_id.iter().map(|b| format!("{:02x}", b)).collect();
+            let mut json = serde_json::json!({
+                "op": op,
+                "key": ev.key,
+                "value": value,
+                "ts": ev.ts,
+                "src": ev.src,
+                "op_id": op_id,
+            });
+            if let Some(seq) = seq {
+                json["seq"] = seq.into();
+            }
+            format!("{}\r\n", json)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::change_event::OpKind;
+
+    #[test]
+    fn prefix_and_glob_matching() {
+        let prefix = Watch::new("user:", WatchFormat::Text);
+        assert!(prefix.matches("user:1"));
+        assert!(!prefix.matches("order:1"));
+
+        let glob = Watch::new("*:tmp", WatchFormat::Text);
+        assert!(glob.matches("cache:tmp"));
+        assert!(!glob.matches("cache:tmp:1"));
+
+        assert!(Watch::new("", WatchFormat::Text).matches("anything"));
+    }
+
+    #[test]
+    fn render_text_and_json() {
+        let set = ChangeEvent::new(1, OpKind::Set, "user:1", Some(b"hello world".to_vec()), 42, "node1", None, None);
+        let text = Watch::new("user:", WatchFormat::Text);
+        assert_eq!(text.render(&set), "EVENT set node1 42 user:1 hello world\r\n");
+
+        let del = ChangeEvent::new(1, OpKind::Del, "user:1", None, 43, "node2", None, None);
+        assert_eq!(text.render(&del), "EVENT del node2 43 user:1\r\n");
+
+        let json = Watch::new("user:", WatchFormat::Json).render(&set);
+        let parsed: serde_json::Value = serde_json::from_str(json.trim_end()).unwrap();
+        assert_eq!(parsed["op"], "set");
+        assert_eq!(parsed["value"], "hello world");
+        assert_eq!(parsed["ts"], 42);
+        assert_eq!(parsed["op_id"].as_str().unwrap().len(), 32);
+
+        assert_eq!(render_event(&del, WatchFormat::Text, Some(7)), "ENTRY 7 del node2 43 user:1\r\n");
+        let json = render_event(&set, WatchFormat::Json, Some(7));
+        assert!(json.contains("\"seq\":7"));
+    }
+}